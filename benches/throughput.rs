@@ -0,0 +1,12 @@
+//! `cargo bench`: the request rate the simulator sustains over loopback, for a single connection
+//! and for several concurrent ones. `rtu-sim --bench` measures the same from the binary.
+
+use rtu_sim::bench::{measure_throughput, DEFAULT_BENCH_CONNECTIONS, DEFAULT_BENCH_DURATION};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    for connections in [1, DEFAULT_BENCH_CONNECTIONS] {
+        println!("{}", measure_throughput(connections, DEFAULT_BENCH_DURATION).await?);
+    }
+    Ok(())
+}
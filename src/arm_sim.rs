@@ -0,0 +1,574 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::time::{self, Instant, MissedTickBehavior};
+use crate::FAULT_BUSY;
+use crate::mb_stuff::SharedModbusState;
+
+/// Settings for the in-process simulated arm.
+#[derive(Clone, Debug)]
+pub struct ArmSimConfig {
+    /// How long a sub routine runs before `running` drops again.
+    pub motion_duration: Duration,
+    /// How often the simulation samples enable and updates running. Motions and reaction times
+    /// are rounded up to whole ticks, so they can run up to one tick long.
+    pub tick: Duration,
+    /// Boot time before ready is raised. Enable edges during boot are ignored.
+    pub ready_after: Duration,
+    /// Reaction time between the enable edge and running going high. Dropping enable in the
+    /// meantime cancels the start.
+    pub running_assert_delay: Duration,
+    /// Varies `motion_duration` from one motion to the next, like a real arm's cycle times.
+    pub jitter: Option<MotionJitter>,
+    pub enable_mode: EnableMode,
+    /// How long enable has to hold a new level before the arm acts on it, like a controller
+    /// debouncing an input wired through a relay. Shorter glitches are ignored, in both
+    /// directions. Zero acts on every sample.
+    pub enable_debounce: Duration,
+    /// Slows the first motion after the arm sat idle, see [`WarmUp`].
+    pub warm_up: Option<WarmUp>,
+    /// How a motion progresses over its duration, reported in the progress register if there
+    /// is one.
+    pub motion_model: Arc<dyn MotionModel>,
+}
+
+impl ArmSimConfig {
+    pub const DEFAULT_MOTION_DURATION: Duration = Duration::from_secs(2);
+    pub const DEFAULT_TICK: Duration = Duration::from_millis(1);
+    /// How long running stays low between two motions of a held enable, like the end of a
+    /// controller's program scan. Long enough for a polling master to see the motion end.
+    pub const HELD_RESTART_GAP: Duration = Duration::from_millis(20);
+}
+
+impl Default for ArmSimConfig {
+    fn default() -> Self {
+        Self {
+            motion_duration: Self::DEFAULT_MOTION_DURATION,
+            tick: Self::DEFAULT_TICK,
+            ready_after: Duration::ZERO,
+            running_assert_delay: Duration::ZERO,
+            jitter: None,
+            enable_mode: EnableMode::default(),
+            enable_debounce: Duration::ZERO,
+            warm_up: None,
+            motion_model: Arc::new(Linear),
+        }
+    }
+}
+
+/// How a controller reads the enable coil.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum EnableMode {
+    /// A sub routine runs once per rising edge, holding enable high doesn't repeat it.
+    #[default]
+    Edge,
+    /// Sub routines keep running back to back for as long as enable is high. The master has to
+    /// drop enable when running falls, or the sub routine starts over.
+    Held,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JitterDistribution {
+    Uniform,
+    /// Normal with a standard deviation of a third of the magnitude, cut off at the magnitude.
+    Gaussian,
+}
+
+/// Random variation of each motion's duration, within `magnitude` either side of the configured
+/// duration. The RNG is seeded so a run can be reproduced with the same `seed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MotionJitter {
+    pub distribution: JitterDistribution,
+    pub magnitude: Duration,
+    pub seed: u64,
+}
+
+impl MotionJitter {
+    pub fn rng(&self) -> StdRng {
+        StdRng::seed_from_u64(self.seed)
+    }
+
+    /// Draws one motion duration around `base`.
+    pub fn sample(&self, base: Duration, rng: &mut StdRng) -> Duration {
+        self.distribution.sample(base, self.magnitude, rng)
+    }
+}
+
+impl JitterDistribution {
+    /// Draws one duration within `magnitude` either side of `base`. Never negative, so with a
+    /// magnitude larger than `base` short durations pile up at zero.
+    pub fn sample(self, base: Duration, magnitude: Duration, rng: &mut StdRng) -> Duration {
+        let magnitude = magnitude.as_secs_f64();
+        let offset = match self {
+            JitterDistribution::Uniform => rng.random_range(-magnitude..=magnitude),
+            JitterDistribution::Gaussian => {
+                // Box-Muller, 1 - u keeps the logarithm finite
+                let u1: f64 = 1.0 - rng.random::<f64>();
+                let u2: f64 = rng.random();
+                let normal = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+                (normal * magnitude / 3.0).clamp(-magnitude, magnitude)
+            }
+        };
+        Duration::from_secs_f64((base.as_secs_f64() + offset).max(0.0))
+    }
+}
+
+impl fmt::Display for MotionJitter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ±{:?}, seed {}", self.distribution, self.magnitude, self.seed)
+    }
+}
+
+/// Maps the time into a motion to how far along it is, shaping the progress register.
+pub trait MotionModel: fmt::Debug + Send + Sync {
+    /// Progress from 0 to 1 after `elapsed` of a motion taking `duration`.
+    fn progress(&self, elapsed: Duration, duration: Duration) -> f64;
+
+    /// Whether the motion has arrived. By default once its duration is up.
+    fn is_complete(&self, elapsed: Duration, duration: Duration) -> bool {
+        elapsed >= duration
+    }
+}
+
+/// The fraction of the motion's duration that has passed, clamped to 0..=1.
+fn time_fraction(elapsed: Duration, duration: Duration) -> f64 {
+    if duration.is_zero() {
+        return 1.0;
+    }
+    (elapsed.as_secs_f64() / duration.as_secs_f64()).clamp(0.0, 1.0)
+}
+
+/// Constant speed from start to end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Linear;
+
+impl MotionModel for Linear {
+    fn progress(&self, elapsed: Duration, duration: Duration) -> f64 {
+        time_fraction(elapsed, duration)
+    }
+}
+
+/// Trapezoidal velocity profile: constant acceleration for `ramp` of the duration, cruising, then
+/// decelerating for the same fraction at the end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trapezoidal {
+    /// Fraction of the duration spent accelerating, and again decelerating. At most 0.5, which
+    /// leaves no cruise and makes a triangular profile.
+    pub ramp: f64,
+}
+
+impl Trapezoidal {
+    pub const DEFAULT: Trapezoidal = Trapezoidal { ramp: 0.25 };
+}
+
+impl MotionModel for Trapezoidal {
+    fn progress(&self, elapsed: Duration, duration: Duration) -> f64 {
+        let t = time_fraction(elapsed, duration);
+        let ramp = self.ramp.clamp(0.0, 0.5);
+        if ramp == 0.0 {
+            return t;
+        }
+        // The cruise speed that covers the whole distance in the time given
+        let cruise = 1.0 / (1.0 - ramp);
+        if t < ramp {
+            cruise * t * t / (2.0 * ramp)
+        } else if t <= 1.0 - ramp {
+            cruise * (t - ramp / 2.0)
+        } else {
+            1.0 - cruise * (1.0 - t) * (1.0 - t) / (2.0 * ramp)
+        }
+    }
+}
+
+/// Smooth S-curve, speeding up and slowing down without a jump in acceleration at either end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SCurve;
+
+impl MotionModel for SCurve {
+    fn progress(&self, elapsed: Duration, duration: Duration) -> f64 {
+        let t = time_fraction(elapsed, duration);
+        // Smootherstep, zero speed and acceleration at both ends
+        t * t * t * (t * (6.0 * t - 15.0) + 10.0)
+    }
+}
+
+/// Progress register value of a finished motion, the register holds progress in hundredths of a
+/// percent.
+pub const PROGRESS_FULL_SCALE: u16 = 10_000;
+
+/// A cold arm: the first motion after `idle_after` without moving takes `multiplier` times as
+/// long, later ones are nominal again. The first motion since boot is always cold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WarmUp {
+    pub multiplier: f64,
+    pub idle_after: Duration,
+}
+
+/// A fault the arm starts in, to exercise the master's clear-fault-then-run sequence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InitialFault {
+    /// Fault code seeded into the fault register.
+    pub code: u16,
+    /// Coil the master sets to clear the fault. The arm drops it again once the fault is cleared.
+    pub reset_coil: u16,
+}
+
+/// Lets the arm queue sub routines commanded mid-motion instead of rejecting them busy, like
+/// controllers that pipeline their programs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommandQueue {
+    /// Most sub routines waiting behind the running one. Commands past that are rejected busy.
+    pub depth: u16,
+    /// Input register where the arm reports how many sub routines are waiting.
+    pub pending_ireg: u16,
+}
+
+/// Fault injection knobs for the simulated arm, toggled through [`SharedModbusState`].
+#[derive(Default, Debug)]
+pub struct SimControls {
+    pub(crate) jam_next: bool,
+    pub(crate) jammed: bool,
+    pub(crate) mislatch_next: bool,
+    pub(crate) heartbeat_paused: bool,
+    pub(crate) motion_paused: bool,
+    /// Replaces [`ArmSimConfig::motion_duration`] from the next motion on.
+    pub(crate) motion_duration: Option<Duration>,
+    /// Motion durations of single indices, over the default.
+    pub(crate) index_motion_durations: HashMap<u32, Duration>,
+}
+
+/// Plays the arm's side of the handshake against `state`, like a real arm polling over Modbus would.
+///
+/// Ready is raised once `ready_after` has passed; until then enable is ignored. After that, a
+/// rising edge on enable is answered `running_assert_delay` later by raising running for
+/// `motion_duration`, running the index that was in the index registers at the edge. Dropping enable mid-motion stops it early, while a new rising edge
+/// mid-motion is rejected with [`FAULT_BUSY`] and leaves the motion alone; the busy fault clears
+/// once that motion ends. A jammed motion keeps running high, ignoring enable, until
+/// [`SharedModbusState::clear_jam`] is called.
+///
+/// [`SharedModbusState::pause_motion`] freezes the motion's timeline with running left high, like
+/// a feed hold; after [`SharedModbusState::resume_motion`] it runs for whatever time it had left.
+/// Enable is still watched while paused.
+///
+/// In [`EnableMode::Held`] an idle arm starts whenever enable is high, not only on a rising
+/// edge, so a completed motion restarts [`ArmSimConfig::HELD_RESTART_GAP`] (plus
+/// `running_assert_delay`) later.
+///
+/// With a [`CommandQueue`] configured, a rising edge mid-motion queues the index in the index
+/// register instead, while there's room. Queued sub routines run one after the other once the
+/// motion ends, each after the same gap as a held restart, so running visibly drops in between.
+/// Dropping enable mid-motion abandons the queue along with the motion.
+///
+/// With a [`WarmUp`] configured, the first motion after a long enough idle runs longer.
+///
+/// With an `enable_debounce`, enable is only acted on once a sampled level held for the debounce
+/// time, so the rising edge the arm answers comes that much later and a shorter glitch, high or
+/// low, is ignored.
+///
+/// With a progress register, each tick of a motion writes how far along it is according to the
+/// [`MotionModel`], up to [`PROGRESS_FULL_SCALE`] once it completes. A motion stopped early leaves
+/// the progress where it stopped.
+///
+/// [`SharedModbusState::set_motion_duration`] and [`SharedModbusState::set_index_motion_duration`]
+/// change how long motions take while the arm runs, from the next motion on.
+///
+/// While idle with a fault set, rising edges are refused. With an [`InitialFault`] configured,
+/// setting its reset coil clears the fault.
+pub async fn run_arm_sim(state: SharedModbusState, config: ArmSimConfig) {
+    info!("Simulated arm started: motion takes {:?}, tick {:?}, ready after {:?}, running asserted after {:?}, {:?} enable",
+        config.motion_duration, config.tick, config.ready_after, config.running_assert_delay, config.enable_mode);
+    if let Some(jitter) = &config.jitter {
+        // Logged so a run can be reproduced with `--seed`
+        info!("Simulated arm: motion jitter {jitter}");
+    }
+    if !config.enable_debounce.is_zero() {
+        info!("Simulated arm: enable debounced for {:?}", config.enable_debounce);
+    }
+    if let Some(warm_up) = &config.warm_up {
+        info!("Simulated arm: motions after {:?} idle take {}x as long", warm_up.idle_after, warm_up.multiplier);
+    }
+    let mut jitter_rng = config.jitter.map(|jitter| jitter.rng());
+    let mut motion_duration = config.motion_duration;
+    let booted_at = Instant::now() + config.ready_after;
+    let mut ready = false;
+    let mut last_edges = state.enable_rising_edges();
+    let mut debounce = (!config.enable_debounce.is_zero()).then(|| EnableDebounce::new(config.enable_debounce));
+    let mut motion_started: Option<Instant> = None;
+    let mut start_due: Option<Instant> = None;
+    // The index latched at the edge a delayed start answers, `None` for held restarts
+    let mut due_idx: Option<u32> = None;
+    let mut jammed = false;
+    let mut paused_since: Option<Instant> = None;
+    let mut queued: VecDeque<u32> = VecDeque::new();
+    let mut queued_due: Option<Instant> = None;
+    // When the arm last moved, for the warm-up. `None` until the first motion
+    let mut last_moved: Option<Instant> = None;
+    // An interval instead of a sleep per loop, so the time spent in the loop doesn't add up
+    let mut interval = time::interval(config.tick);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        // Re-read every tick so a register map change takes effect immediately
+        let map = state.register_map();
+        let edges = state.enable_rising_edges();
+        let (enable, rising_edge) = match debounce.as_mut() {
+            // Edges between samples are glitches to a debounced input, only the sampled level counts
+            Some(debounce) => debounce.sample(state.read_coil(map.enable_coil)),
+            None => (state.read_coil(map.enable_coil), edges != last_edges),
+        };
+        last_edges = edges;
+        let start_requested = match config.enable_mode {
+            EnableMode::Edge => rising_edge,
+            EnableMode::Held => enable,
+        };
+        match (state.motion_paused(), paused_since) {
+            (true, None) => {
+                info!("Simulated arm: motion paused");
+                paused_since = Some(Instant::now());
+            }
+            (false, Some(since)) => {
+                info!("Simulated arm: motion resumed");
+                // Shifting the start keeps the time already spent moving
+                motion_started = motion_started.map(|started| started + since.elapsed());
+                paused_since = None;
+            }
+            _ => {}
+        }
+
+        if !ready {
+            if Instant::now() < booted_at {
+                if rising_edge {
+                    debug!("Simulated arm: ignoring enable while booting");
+                }
+                continue;
+            }
+            info!("Simulated arm: ready");
+            ready = true;
+            state.write_coil(map.ready_coil, true);
+        } else if !state.read_coil(map.ready_coil) {
+            // A real arm rewrites its status every cycle, so ready comes back after a state reset
+            state.write_coil(map.ready_coil, true);
+        }
+
+        if let Some(fault) = state.initial_fault()
+            && state.read_coil(fault.reset_coil) {
+            info!("Simulated arm: fault reset");
+            state.write_holding_register(map.fault_hreg, 0);
+            state.write_coil(fault.reset_coil, false);
+        }
+
+        if let Some(due) = start_due {
+            if !enable {
+                debug!("Simulated arm: enable dropped before running was asserted");
+                start_due = None;
+                due_idx = None;
+            } else if Instant::now() >= due {
+                start_due = None;
+                let idx = due_idx.take().unwrap_or_else(|| state.read_index());
+                motion_duration = next_motion_duration(&state, &config, idx, jitter_rng.as_mut(), last_moved);
+                jammed = start_motion(&state, idx);
+                motion_started = Some(Instant::now());
+            }
+            continue;
+        }
+
+        let room_in_queue = state.command_queue().is_some_and(|queue| queued.len() < usize::from(queue.depth));
+        match motion_started {
+            None if queued_due.is_some_and(|due| Instant::now() >= due) => {
+                queued_due = None;
+                if let Some(idx) = queued.pop_front() {
+                    update_pending(&state, &queued);
+                    motion_duration = next_motion_duration(&state, &config, idx, jitter_rng.as_mut(), last_moved);
+                    jammed = start_motion(&state, idx);
+                    motion_started = Some(Instant::now());
+                }
+            }
+            // Still between queued sub routines, so a new command joins the queue
+            None if rising_edge && queued_due.is_some() && room_in_queue => {
+                enqueue(&state, &mut queued);
+            }
+            None if queued_due.is_some() => {}
+            None if rising_edge && state.read_holding_registers(map.fault_hreg, 1)[0] != 0 => {
+                warn!("Simulated arm: commanded while faulted, refusing");
+            }
+            // Refused just the same, without repeating the warning every tick of a held enable
+            None if start_requested && state.read_holding_registers(map.fault_hreg, 1)[0] != 0 => {}
+            None if start_requested && config.running_assert_delay.is_zero() => {
+                let idx = commanded_index(&state, rising_edge);
+                motion_duration = next_motion_duration(&state, &config, idx, jitter_rng.as_mut(), last_moved);
+                jammed = start_motion(&state, idx);
+                motion_started = Some(Instant::now());
+            }
+            None if start_requested => {
+                start_due = Some(Instant::now() + config.running_assert_delay);
+                due_idx = Some(commanded_index(&state, rising_edge));
+            }
+            None => {}
+            Some(_) if rising_edge && room_in_queue => {
+                enqueue(&state, &mut queued);
+            }
+            Some(_) if rising_edge => {
+                warn!("Simulated arm: commanded while busy, rejecting");
+                state.write_holding_register(map.fault_hreg, FAULT_BUSY);
+            }
+            Some(_) if jammed && !state.is_jammed() => {
+                info!("Simulated arm: jam cleared, motion stopped");
+                jammed = false;
+                motion_started = None;
+                end_motion(&state);
+                abandon_queue(&state, &mut queued);
+            }
+            Some(_) if jammed => {}
+            Some(_) if !enable => {
+                debug!("Simulated arm: enable dropped, stopping early");
+                motion_started = None;
+                end_motion(&state);
+                abandon_queue(&state, &mut queued);
+            }
+            Some(_) if paused_since.is_some() => {}
+            Some(started) if config.motion_model.is_complete(started.elapsed(), motion_duration) => {
+                debug!("Simulated arm: motion complete");
+                motion_started = None;
+                write_progress(&state, 1.0);
+                end_motion(&state);
+                if !queued.is_empty() {
+                    queued_due = Some(Instant::now() + ArmSimConfig::HELD_RESTART_GAP);
+                } else if config.enable_mode == EnableMode::Held && enable {
+                    start_due = Some(Instant::now() + ArmSimConfig::HELD_RESTART_GAP + config.running_assert_delay);
+                }
+            }
+            Some(_) => {}
+        }
+        if let Some(started) = motion_started {
+            last_moved = Some(Instant::now());
+            if paused_since.is_none() {
+                write_progress(&state, config.motion_model.progress(started.elapsed(), motion_duration));
+            }
+        }
+    }
+}
+
+/// The enable level as a debounced input sees it: a new sampled level only counts once it held
+/// for the debounce time.
+struct EnableDebounce {
+    debounce: Duration,
+    level: bool,
+    /// When the samples started disagreeing with `level`.
+    changing_since: Option<Instant>,
+}
+
+impl EnableDebounce {
+    fn new(debounce: Duration) -> Self {
+        Self { debounce, level: false, changing_since: None }
+    }
+
+    /// Returns the debounced level and whether it just rose.
+    fn sample(&mut self, raw: bool) -> (bool, bool) {
+        if raw == self.level {
+            if self.changing_since.take().is_some() {
+                debug!("Simulated arm: enable glitch shorter than the debounce ignored");
+            }
+            return (self.level, false);
+        }
+        let since = *self.changing_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < self.debounce {
+            return (self.level, false);
+        }
+        self.level = raw;
+        self.changing_since = None;
+        (raw, raw)
+    }
+}
+
+fn next_motion_duration(state: &SharedModbusState, config: &ArmSimConfig, idx: u32, rng: Option<&mut StdRng>,
+    last_moved: Option<Instant>) -> Duration {
+    let base = state.motion_duration_for(idx).unwrap_or(config.motion_duration);
+    let duration = match (config.jitter, rng) {
+        (Some(jitter), Some(rng)) => {
+            let duration = jitter.sample(base, rng);
+            debug!("Simulated arm: this motion takes {duration:?}");
+            duration
+        }
+        _ => base,
+    };
+    match config.warm_up {
+        Some(warm_up) if last_moved.is_none_or(|moved| moved.elapsed() >= warm_up.idle_after) => {
+            let cold = duration.mul_f64(warm_up.multiplier);
+            info!("Simulated arm: cold, this motion takes {cold:?}");
+            cold
+        }
+        _ => duration,
+    }
+}
+
+/// Latches the commanded index, echoing it if configured, and raises running. Returns whether
+/// the motion jams.
+fn start_motion(state: &SharedModbusState, idx: u32) -> bool {
+    let map = state.register_map();
+    if state.index_echo().is_some() {
+        let latched = if state.take_mislatch_request() {
+            let wrong = state.index_width().next(idx);
+            warn!("Simulated arm: latching #{wrong} instead of #{idx}");
+            wrong
+        } else {
+            idx
+        };
+        state.write_index_echo(latched);
+    }
+    let jammed = state.take_jam_request();
+    if jammed {
+        warn!("Simulated arm: sub routine #{idx} jammed");
+    } else {
+        debug!("Simulated arm: starting sub routine #{idx}");
+    }
+    state.write_coil(map.running_coil, true);
+    jammed
+}
+
+/// The index a start runs: the one latched at the rising edge, or for a held restart without an
+/// edge, the one in the index registers now.
+fn commanded_index(state: &SharedModbusState, rising_edge: bool) -> u32 {
+    if rising_edge { state.index_at_last_edge() } else { state.read_index() }
+}
+
+/// Queues the index latched at the rising edge behind the running motion.
+fn enqueue(state: &SharedModbusState, queued: &mut VecDeque<u32>) {
+    let idx = state.index_at_last_edge();
+    info!("Simulated arm: queued sub routine #{idx}, {} waiting", queued.len() + 1);
+    queued.push_back(idx);
+    update_pending(state, queued);
+}
+
+fn abandon_queue(state: &SharedModbusState, queued: &mut VecDeque<u32>) {
+    if !queued.is_empty() {
+        warn!("Simulated arm: abandoning {} queued sub routines", queued.len());
+        queued.clear();
+        update_pending(state, queued);
+    }
+}
+
+fn update_pending(state: &SharedModbusState, queued: &VecDeque<u32>) {
+    if let Some(queue) = state.command_queue() {
+        // Can't exceed the depth, which fits a register
+        state.write_input_register(queue.pending_ireg, queued.len() as u16);
+    }
+}
+
+fn write_progress(state: &SharedModbusState, progress: f64) {
+    if let Some(progress_ireg) = state.progress_register() {
+        state.write_input_register(progress_ireg, (progress.clamp(0.0, 1.0) * f64::from(PROGRESS_FULL_SCALE)).round() as u16);
+    }
+}
+
+fn end_motion(state: &SharedModbusState) {
+    let map = state.register_map();
+    state.write_coil(map.running_coil, false);
+    // Busy only describes the motion that just ended
+    if state.read_holding_registers(map.fault_hreg, 1)[0] == FAULT_BUSY {
+        state.write_holding_register(map.fault_hreg, 0);
+    }
+}
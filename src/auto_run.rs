@@ -0,0 +1,19 @@
+use std::sync::Arc;
+use log::{info, warn};
+use crate::connections::ConnectionTracker;
+use crate::mb_stuff::SharedModbusState;
+use crate::test_cases::{RunOutcome, SubroutineRun, TestConfig};
+
+/// For demos without the TUI: once the first master connects, runs sub routine `idx` once the way
+/// a single sub routine test does, latching the index and holding enable until the motion
+/// completes. Later connections, reconnects after a power cycle included, don't run it again.
+pub async fn auto_run(state: SharedModbusState, connections: Arc<ConnectionTracker>, config: TestConfig, idx: u32) -> RunOutcome {
+    connections.first_connection().await;
+    info!("Master connected, auto-running sub routine #{idx}");
+    let outcome = SubroutineRun::new(idx).with_config(&config).execute(&state).await;
+    match outcome.clone().into_result(idx, &state.register_map()) {
+        Ok(()) => info!("Auto-run of sub routine #{idx} completed"),
+        Err(err) => warn!("Auto-run of sub routine #{idx} failed: {err}"),
+    }
+    outcome
+}
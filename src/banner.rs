@@ -0,0 +1,44 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use crate::mb_stuff::{BROADCAST_UNIT_ID, DEFAULT_SERVER_ID};
+use crate::register_map::RegisterMap;
+
+/// What a master needs to know to talk to this simulator, shown before anything else so it
+/// doesn't have to be dug out of the logs.
+#[derive(Clone, Copy, Debug)]
+pub struct Banner {
+    pub addr: SocketAddr,
+    pub register_map: RegisterMap,
+    /// The register map is picked in the TUI, `register_map` only holds until then.
+    pub map_pending: bool,
+    /// Unit IDs 1 to n from `--units`, `None` when every unit ID is answered the same.
+    pub units: Option<u8>,
+}
+
+impl Banner {
+    pub fn render(&self) -> String {
+        let map = &self.register_map;
+        let mut banner = format!("{} listening on {}\n", DEFAULT_SERVER_ID, self.addr);
+        let mut line = |label: &str, value: &dyn std::fmt::Display| {
+            // Writing to a String can't fail
+            let _ = writeln!(banner, "  {:<16}{value}", format!("{label}:"));
+        };
+        line("Address", &self.addr.ip());
+        line("Port", &self.addr.port());
+        match self.units {
+            Some(1) => line("Unit IDs", &format!("1, {BROADCAST_UNIT_ID} is broadcast")),
+            Some(units) => line("Unit IDs", &format!("1-{units}, {BROADCAST_UNIT_ID} is broadcast")),
+            // The service answers every unit ID the same, apart from broadcasts
+            None => line("Unit IDs", &format!("any, {BROADCAST_UNIT_ID} is broadcast")),
+        }
+        if self.map_pending {
+            line("Register map", &"picked in the TUI, until then:");
+        }
+        line("Enable coil", &map.enable_coil);
+        line("Running coil", &map.running_coil);
+        line("Ready coil", &map.ready_coil);
+        line("Index register", &map.index_hreg);
+        line("Fault register", &map.fault_hreg);
+        banner
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tokio_modbus::client::{tcp, Reader, Writer};
+use crate::connections::ConnectionTracker;
+use crate::mb_stuff::{ExampleService, SharedModbusState};
+use crate::server_context;
+
+/// How long each phase of `--bench` and `cargo bench` runs unless told otherwise.
+pub const DEFAULT_BENCH_DURATION: Duration = Duration::from_secs(5);
+/// The concurrent connections measured next to a single one.
+pub const DEFAULT_BENCH_CONNECTIONS: usize = 4;
+
+/// Requests per second the simulator sustained, each connection sending its next request as soon
+/// as the previous one was answered, like an aggressive polling master.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Throughput {
+    pub connections: usize,
+    /// Reads of the running coil.
+    pub reads_per_sec: f64,
+    /// Writes of the index register.
+    pub writes_per_sec: f64,
+}
+
+impl Display for Throughput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} connection{}: {:.0} reads/s, {:.0} writes/s", self.connections,
+            if self.connections == 1 { "" } else { "s" }, self.reads_per_sec, self.writes_per_sec)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Read,
+    Write,
+}
+
+/// Serves a fresh state on a loopback port and drives it with `connections` clients for
+/// `duration` of reads, then `duration` of writes.
+///
+/// The whole path a master's requests take is measured, TCP and framing included, so loopback
+/// latency caps a single connection's rate well before the service does.
+pub async fn measure_throughput(connections: usize, duration: Duration) -> anyhow::Result<Throughput> {
+    let state = SharedModbusState::new();
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await?;
+    let addr = listener.local_addr()?;
+    let service_state = state.clone();
+    let server = tokio::spawn(server_context(listener, None, Arc::new(ConnectionTracker::new(None)),
+        move |peer| ExampleService::with_shared_state(service_state.clone(), peer)));
+    let result = async {
+        Ok(Throughput {
+            connections,
+            reads_per_sec: drive(addr, &state, connections, duration, Op::Read).await?,
+            writes_per_sec: drive(addr, &state, connections, duration, Op::Write).await?,
+        })
+    }.await;
+    server.abort();
+    result
+}
+
+/// Returns the requests per second all `connections` together got answered.
+async fn drive(addr: SocketAddr, state: &SharedModbusState, connections: usize, duration: Duration, op: Op) -> anyhow::Result<f64> {
+    let map = state.register_map();
+    let mut clients = Vec::with_capacity(connections);
+    for _ in 0..connections {
+        clients.push(tcp::connect(addr).await?);
+    }
+    let started = Instant::now();
+    let deadline = started + duration;
+    let tasks: Vec<_> = clients.into_iter().map(|mut ctx| tokio::spawn(async move {
+        let mut answered: u64 = 0;
+        while Instant::now() < deadline {
+            match op {
+                Op::Read => { ctx.read_coils(map.running_coil, 1).await??; }
+                Op::Write => { ctx.write_single_register(map.index_hreg, (answered % 100) as u16).await??; }
+            }
+            answered += 1;
+        }
+        anyhow::Ok(answered)
+    })).collect();
+    let mut answered = 0;
+    for task in tasks {
+        answered += task.await??;
+    }
+    Ok(answered as f64 / started.elapsed().as_secs_f64())
+}
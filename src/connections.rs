@@ -0,0 +1,208 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use log::error;
+use tokio::sync::{oneshot, Notify};
+use tokio::time;
+
+/// Counts open Modbus connections and optionally caps them, like a device that only accepts a
+/// few masters at a time.
+pub struct ConnectionTracker {
+    active: AtomicUsize,
+    max: Option<usize>,
+    client_seen: AtomicBool,
+    /// Unlike `client_seen`, only set by a real connection.
+    connected: AtomicBool,
+    powered: AtomicBool,
+    /// Wakes every [`TrackedStream`] to close when the power goes off.
+    power_cut: Arc<Notify>,
+}
+
+impl ConnectionTracker {
+    const CLIENT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// `None` accepts any number of connections.
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            max,
+            client_seen: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+            powered: AtomicBool::new(true),
+            power_cut: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Counts a new connection, or returns `None` if the limit is already reached or the power is
+    /// off.
+    ///
+    /// The connection stays counted until the returned guard is dropped.
+    pub fn try_open(self: &Arc<Self>) -> Option<ConnectionGuard> {
+        if !self.powered() {
+            return None;
+        }
+        self.active.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+            match self.max {
+                Some(max) if active >= max => None,
+                _ => Some(active + 1),
+            }
+        }).ok()?;
+        self.client_seen.store(true, Ordering::Relaxed);
+        self.connected.store(true, Ordering::Relaxed);
+        Some(ConnectionGuard(self.clone()))
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        self.max
+    }
+
+    /// Whether a connection was ever accepted, or something standing in for a client marked it so.
+    pub fn client_seen(&self) -> bool {
+        self.client_seen.load(Ordering::Relaxed)
+    }
+
+    /// For stand-ins like the simulated arm, which play the client without connecting.
+    pub fn mark_client_seen(&self) {
+        self.client_seen.store(true, Ordering::Relaxed);
+    }
+
+    pub fn powered(&self) -> bool {
+        self.powered.load(Ordering::Relaxed)
+    }
+
+    /// Closes every open connection and refuses new ones until [`Self::power_on`].
+    pub fn power_off(&self) {
+        self.powered.store(false, Ordering::Relaxed);
+        self.power_cut.notify_waiters();
+    }
+
+    pub fn power_on(&self) {
+        self.powered.store(true, Ordering::Relaxed);
+    }
+
+    /// Resolves once a client was seen, or fails if none was within `timeout`.
+    pub async fn require_client(&self, timeout: Duration) -> Result<(), NoClientError> {
+        time::timeout(timeout, async {
+            while !self.client_seen() {
+                time::sleep(Self::CLIENT_POLL_INTERVAL).await;
+            }
+        }).await.map_err(|_| NoClientError { waited: timeout })
+    }
+
+    /// Resolves once a connection was accepted. Stand-ins marking a client seen don't count.
+    pub async fn first_connection(&self) {
+        while !self.connected.load(Ordering::Relaxed) {
+            time::sleep(Self::CLIENT_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// No master connected within the time allowed, which in a test fixture usually means it's wired
+/// to the wrong address or port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NoClientError {
+    pub waited: Duration,
+}
+
+impl Display for NoClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No client connected within {} ms", self.waited.as_millis())
+    }
+}
+
+impl std::error::Error for NoClientError {}
+
+/// Why a session ended, see [`wait_for_shutdown`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShutdownReason {
+    TuiFinished,
+    Interrupted,
+    /// `--require-client` ran out before a master connected.
+    NoClient(NoClientError),
+}
+
+/// Waits until either the TUI thread reports it is done, `interrupt` resolves or `client_required`
+/// fails.
+///
+/// A TUI thread that panics drops its sender, which also counts as finished. If the interrupt
+/// listener itself fails, this falls back to waiting on the TUI alone.
+pub async fn wait_for_shutdown(
+    mut tui_done: oneshot::Receiver<()>,
+    interrupt: impl Future<Output = std::io::Result<()>>,
+    client_required: impl Future<Output = Result<(), NoClientError>>,
+) -> ShutdownReason {
+    tokio::select! {
+        _ = &mut tui_done => ShutdownReason::TuiFinished,
+        Err(err) = client_required => ShutdownReason::NoClient(err),
+        res = interrupt => match res {
+            Ok(()) => ShutdownReason::Interrupted,
+            Err(err) => {
+                error!("Failed to listen for Ctrl-C, only the TUI can end the session: {err}");
+                let _ = tui_done.await;
+                ShutdownReason::TuiFinished
+            }
+        }
+    }
+}
+
+/// Keeps one connection counted in its [`ConnectionTracker`].
+pub struct ConnectionGuard(Arc<ConnectionTracker>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wraps a connection's transport so it stays counted exactly as long as the transport lives.
+///
+/// tokio-modbus drops the transport once the connection ends, for whatever reason. Reads fail
+/// with `ConnectionAborted` once the tracker's power goes off, which ends the connection the same
+/// way.
+pub struct TrackedStream<S> {
+    inner: S,
+    power_cut: Pin<Box<dyn Future<Output = ()> + Send>>,
+    guard: ConnectionGuard,
+}
+
+impl<S> TrackedStream<S> {
+    pub fn new(inner: S, guard: ConnectionGuard) -> Self {
+        let notify = guard.0.power_cut.clone();
+        let power_cut = Box::pin(async move { notify.notified().await });
+        Self { inner, power_cut, guard }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TrackedStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        // The flag covers a power cut before the first poll registered the waiter
+        if self.power_cut.as_mut().poll(cx).is_ready() || !self.guard.0.powered() {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "simulated power cycle")));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TrackedStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,124 @@
+use std::io;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use log::{debug, info, warn};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio_modbus::client::{tcp, Context};
+use tokio_modbus::slave::{Slave, SlaveContext};
+
+/// A fixed number of client connections to one server, handed out to callers running tests
+/// against it concurrently, e.g. one per unit ID.
+///
+/// Connections the server dropped are replaced: [`ContextPool::with_context`] retries a request
+/// that failed on the transport once on a new connection, and a connection given back with
+/// [`PooledContext::discard`] is reopened on its next checkout.
+pub struct ContextPool {
+    addr: SocketAddr,
+    size: usize,
+    idle: Mutex<Vec<Context>>,
+    permits: Semaphore,
+}
+
+impl ContextPool {
+    /// Opens all `size` connections to `addr` up front, failing if any of them can't be opened.
+    pub async fn connect(addr: SocketAddr, size: usize) -> io::Result<Self> {
+        let mut idle = Vec::with_capacity(size);
+        for _ in 0..size {
+            idle.push(tcp::connect(addr).await?);
+        }
+        info!("Opened a pool of {size} connections to {addr}");
+        Ok(Self { addr, size, idle: Mutex::new(idle), permits: Semaphore::new(size) })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Connections open and not checked out at the moment.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    /// Waits for a connection to be free and checks it out, reopening it if it was discarded.
+    pub async fn get(&self) -> io::Result<PooledContext<'_>> {
+        let permit = self.permits.acquire().await.expect("the pool never closes its semaphore");
+        let idle = self.idle.lock().unwrap().pop();
+        let ctx = match idle {
+            Some(ctx) => ctx,
+            None => self.reconnect().await?,
+        };
+        Ok(PooledContext { ctx: Some(ctx), pool: self, _permit: permit })
+    }
+
+    /// Runs `request` on a pooled connection. A connection the request fails on is discarded, and
+    /// after a transport failure, likely the server having dropped the connection while it sat in
+    /// the pool, `request` is run once more on a new one.
+    ///
+    /// The retry can repeat a request the server did get, so `request` should be safe to repeat,
+    /// as reads and single writes are.
+    pub async fn with_context<T>(&self, mut request: impl AsyncFnMut(&mut Context) -> tokio_modbus::Result<T>)
+        -> tokio_modbus::Result<T> {
+        let mut ctx = self.get().await?;
+        match request(&mut ctx).await {
+            Ok(response) => Ok(response),
+            Err(tokio_modbus::Error::Transport(err)) => {
+                warn!("Pooled connection to {} failed, retrying on a new one: {err}", self.addr);
+                ctx.ctx = Some(self.reconnect().await?);
+                let result = request(&mut ctx).await;
+                if result.is_err() {
+                    ctx.discard();
+                }
+                result
+            }
+            Err(err) => {
+                ctx.discard();
+                Err(err)
+            }
+        }
+    }
+
+    async fn reconnect(&self) -> io::Result<Context> {
+        debug!("Reopening a pooled connection to {}", self.addr);
+        tcp::connect(self.addr).await
+    }
+}
+
+/// A connection checked out of a [`ContextPool`], given back when dropped.
+pub struct PooledContext<'a> {
+    ctx: Option<Context>,
+    pool: &'a ContextPool,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl PooledContext<'_> {
+    /// Gives the connection back as dead, e.g. after a request failed on it, so the next checkout
+    /// opens a new one instead.
+    pub fn discard(mut self) {
+        self.ctx = None;
+    }
+}
+
+impl Deref for PooledContext<'_> {
+    type Target = Context;
+
+    fn deref(&self) -> &Context {
+        self.ctx.as_ref().expect("only dropping or discarding takes the context")
+    }
+}
+
+impl DerefMut for PooledContext<'_> {
+    fn deref_mut(&mut self) -> &mut Context {
+        self.ctx.as_mut().expect("only dropping or discarding takes the context")
+    }
+}
+
+impl Drop for PooledContext<'_> {
+    fn drop(&mut self) {
+        if let Some(mut ctx) = self.ctx.take() {
+            // The next caller may talk to another unit, it sets its own
+            ctx.set_slave(Slave::tcp_device());
+            self.pool.idle.lock().unwrap().push(ctx);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::Duration;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio_modbus::FunctionCode;
+use crate::arm_sim::JitterDistribution;
+
+/// Response latency of one function code, varied by up to the jitter's magnitude either side when
+/// there is one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FunctionLatency {
+    pub base: Duration,
+    pub jitter: Option<(JitterDistribution, Duration)>,
+}
+
+impl Display for FunctionLatency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.base)?;
+        match self.jitter {
+            Some((distribution, magnitude)) => write!(f, " {distribution:?} ±{magnitude:?}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Simulates a lossy, slow link between the client and the server.
+///
+/// Dropped requests are never answered, forcing the client to time out, unlike an exception
+/// response. Ignored coil writes are answered as usual but never applied, so only a client that
+/// reads back notices. The RNG is seeded so a run can be reproduced with the same `seed`.
+pub struct DegradedLink {
+    drop_fraction: f64,
+    ignore_write_fraction: f64,
+    latency: Duration,
+    /// Overrides `latency` for the function codes it has.
+    function_latencies: BTreeMap<u8, FunctionLatency>,
+    seed: u64,
+    rng: Mutex<StdRng>,
+}
+
+impl DegradedLink {
+    /// `drop_fraction` is clamped to `0.0..=1.0`.
+    pub fn new(drop_fraction: f64, latency: Duration, seed: u64) -> Self {
+        Self {
+            drop_fraction: drop_fraction.clamp(0.0, 1.0),
+            ignore_write_fraction: 0.0,
+            latency,
+            function_latencies: BTreeMap::new(),
+            seed,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    /// Silently ignores `fraction` of WriteSingleCoil requests, clamped to `0.0..=1.0`.
+    pub fn with_ignored_writes(mut self, fraction: f64) -> Self {
+        self.ignore_write_fraction = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Gives the function codes (keys) their own latency, e.g. reads slower than writes.
+    pub fn with_function_latencies(mut self, function_latencies: BTreeMap<u8, FunctionLatency>) -> Self {
+        self.function_latencies = function_latencies;
+        self
+    }
+
+    /// Rolls whether the next WriteSingleCoil is acknowledged without being applied.
+    pub fn should_ignore_write(&self) -> bool {
+        self.rng.lock().unwrap().random_bool(self.ignore_write_fraction)
+    }
+
+    /// Rolls whether the next request is lost.
+    pub fn should_drop(&self) -> bool {
+        self.rng.lock().unwrap().random_bool(self.drop_fraction)
+    }
+
+    /// How long each response is held back before being sent, unless its function code has its
+    /// own latency.
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// Draws how long to hold back the response to a `function` request.
+    pub fn latency_for(&self, function: FunctionCode) -> Duration {
+        match self.function_latencies.get(&function.value()) {
+            Some(FunctionLatency { base, jitter: Some((distribution, magnitude)) }) =>
+                distribution.sample(*base, *magnitude, &mut self.rng.lock().unwrap()),
+            Some(FunctionLatency { base, jitter: None }) => *base,
+            None => self.latency,
+        }
+    }
+}
+
+impl Display for DegradedLink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "dropping {}% of requests, ignoring {}% of coil writes, {:?} latency, seed {}",
+            self.drop_fraction * 100.0, self.ignore_write_fraction * 100.0, self.latency, self.seed)?;
+        for (function, latency) in &self.function_latencies {
+            write!(f, ", FC {function:02} {latency}")?;
+        }
+        Ok(())
+    }
+}
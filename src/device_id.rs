@@ -0,0 +1,94 @@
+use log::warn;
+use tokio_modbus::{ExceptionCode, Response};
+
+/// Encapsulated Interface Transport (FC 43); tokio-modbus has no dedicated request type for it.
+pub const ENCAPSULATED_INTERFACE_FUNCTION_CODE: u8 = 0x2B;
+/// The MEI type of Read Device Identification, the only one implemented.
+pub const READ_DEVICE_ID_MEI_TYPE: u8 = 0x0E;
+/// Read Device ID code asking for one object by its ID.
+const READ_DEVICE_ID_INDIVIDUAL: u8 = 0x04;
+/// Basic identification, readable as a stream and individually.
+const CONFORMITY_BASIC: u8 = 0x81;
+/// Longest the three objects may be together and still fit a response PDU.
+pub const MAX_DEVICE_ID_LEN: usize = 240;
+
+/// The basic device identification objects answered to Read Device Identification (FC 43 / MEI
+/// type 14), as scanners like modpoll ask for them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceIdentification {
+    /// Object 0x00.
+    pub vendor_name: String,
+    /// Object 0x01.
+    pub product_code: String,
+    /// Object 0x02.
+    pub revision: String,
+}
+
+impl Default for DeviceIdentification {
+    fn default() -> Self {
+        Self {
+            vendor_name: "rtu-sim".to_string(),
+            product_code: "rtu-sim".to_string(),
+            revision: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+impl DeviceIdentification {
+    fn objects(&self) -> [&str; 3] {
+        [&self.vendor_name, &self.product_code, &self.revision]
+    }
+
+    /// Total length of the objects, at most [`MAX_DEVICE_ID_LEN`].
+    pub fn len(&self) -> usize {
+        self.objects().iter().map(|object| object.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Answers an Encapsulated Interface Transport request. `data` is the PDU after the function
+    /// code: the MEI type, the Read Device ID code and the object ID.
+    ///
+    /// Only basic identification exists, so the stream codes for regular and extended
+    /// identification get the basic objects too. Everything fits one response, so more follows is
+    /// never set.
+    pub fn respond(&self, data: &[u8], tag: &str) -> Result<Response, ExceptionCode> {
+        let &[mei_type, read_code, object_id] = data else {
+            if data.first() == Some(&READ_DEVICE_ID_MEI_TYPE) {
+                warn!("{tag} Exception::IllegalDataValue - Read Device Identification request with {} data bytes", data.len());
+                return Err(ExceptionCode::IllegalDataValue);
+            }
+            warn!("{tag} Exception::IllegalFunction - Unsupported MEI type in {data:02X?}");
+            return Err(ExceptionCode::IllegalFunction);
+        };
+        if mei_type != READ_DEVICE_ID_MEI_TYPE {
+            warn!("{tag} Exception::IllegalFunction - Unsupported MEI type {mei_type:#04X}");
+            return Err(ExceptionCode::IllegalFunction);
+        }
+        let objects = self.objects();
+        let ids = match read_code {
+            1..=3 if usize::from(object_id) < objects.len() => usize::from(object_id)..objects.len(),
+            // A stream starting at an object that doesn't exist starts over at the first
+            1..=3 => 0..objects.len(),
+            READ_DEVICE_ID_INDIVIDUAL if usize::from(object_id) < objects.len() =>
+                usize::from(object_id)..usize::from(object_id) + 1,
+            READ_DEVICE_ID_INDIVIDUAL => {
+                warn!("{tag} Exception::IllegalDataAddress - No device identification object {object_id:#04X}");
+                return Err(ExceptionCode::IllegalDataAddress);
+            }
+            other => {
+                warn!("{tag} Exception::IllegalDataValue - Unsupported Read Device ID code {other:#04X}");
+                return Err(ExceptionCode::IllegalDataValue);
+            }
+        };
+        let mut response = vec![mei_type, read_code, CONFORMITY_BASIC, 0x00, 0x00, ids.len() as u8];
+        for id in ids {
+            response.push(id as u8);
+            response.push(objects[id].len() as u8);
+            response.extend_from_slice(objects[id].as_bytes());
+        }
+        Ok(Response::Custom(ENCAPSULATED_INTERFACE_FUNCTION_CODE, response.into()))
+    }
+}
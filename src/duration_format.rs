@@ -0,0 +1,21 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Formats a duration for logs and reports in one unit at every magnitude, milliseconds with
+/// microsecond precision, e.g. `0.004 ms` or `1500.000 ms`, where `{:?}` would switch between
+/// `4.1µs` and `1.5s`.
+pub fn format_duration(duration: Duration) -> String {
+    format!("{} ms", Millis(duration))
+}
+
+/// Just the number of [`format_duration`], for fields already named in milliseconds like the
+/// sweep CSV's `delay_ms`. Rounds to the nearest microsecond, half up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Millis(pub Duration);
+
+impl Display for Millis {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let micros = (self.0.as_nanos() + 500) / 1000;
+        write!(f, "{}.{:03}", micros / 1000, micros % 1000)
+    }
+}
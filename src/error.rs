@@ -0,0 +1,64 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use log::warn;
+use tokio::net::TcpListener;
+
+/// The ways setting up the simulator fails, for callers that need to tell them apart. Failures
+/// while serving stay `anyhow` errors, as they are only ever logged.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A command line argument that does not parse or is out of range.
+    #[error("Invalid {arg}: {value}")]
+    BadArg { arg: &'static str, value: String },
+    /// The address to serve on is taken or not ours.
+    #[error("Failed to bind {addr}: {source}")]
+    Bind { addr: SocketAddr, source: std::io::Error },
+    /// No IPv4 interface to serve on was found.
+    #[error("No local IPv4 address: {0}")]
+    NoLocalIp(#[source] local_ip_address::Error),
+    /// A file read at startup, like a golden sequence or a replay log, is missing or malformed.
+    #[error("Failed to load {}: {source:#}", path.display())]
+    Config { path: PathBuf, source: anyhow::Error },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// A [`Error::BadArg`] for `value`, which may carry a hint after the value itself, e.g.
+    /// `"7 (expected 0 or 1)"`.
+    pub fn bad_arg(arg: &'static str, value: impl Into<String>) -> Self {
+        Error::BadArg { arg, value: value.into() }
+    }
+}
+
+/// Parses a port to serve on. Port 0 is refused, as the port a master polls has to be known.
+pub fn parse_port(value: &str) -> Result<u16> {
+    match value.parse() {
+        Ok(0) | Err(_) => Err(Error::bad_arg("port number", value)),
+        Ok(port) => Ok(port),
+    }
+}
+
+/// Binds `addr` to serve Modbus on.
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(addr).await.map_err(|source| Error::Bind { addr, source })
+}
+
+/// The IPv4 address of the interface the default route goes out of.
+pub fn local_ipv4() -> Result<Ipv4Addr> {
+    match local_ip_address::local_ip() {
+        Ok(std::net::IpAddr::V4(v4)) => Ok(v4),
+        // Only a broken lookup answers with IPv6, `local_ip` looks for IPv4 interfaces
+        Ok(std::net::IpAddr::V6(_)) => Err(Error::NoLocalIp(local_ip_address::Error::LocalIpAddressNotFound)),
+        Err(err) => Err(Error::NoLocalIp(err)),
+    }
+}
+
+/// Picks the address to serve on from the local IP lookup, falling back to loopback when there
+/// is no usable IPv4 interface, e.g. in a minimal container.
+pub fn resolve_local_ipv4(local_ip: Result<Ipv4Addr>) -> Ipv4Addr {
+    local_ip.unwrap_or_else(|err| {
+        warn!("{err}. Serving on {} instead, use --bind to choose", Ipv4Addr::LOCALHOST);
+        Ipv4Addr::LOCALHOST
+    })
+}
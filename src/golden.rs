@@ -0,0 +1,135 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::Mutex;
+use anyhow::Context;
+use log::{error, info};
+use tokio_modbus::Request;
+use crate::error::Error;
+use crate::traffic_log::{format_request, parse_request};
+
+// Expected request sequence, one request per line in the traffic log's request format:
+//
+//     WriteSingleRegister 8 3
+//     WriteSingleCoil 8 1
+//     ReadCoils 9 1
+//     WriteSingleCoil 8 0
+//
+// Capture lines are accepted too, their timestamp and response are ignored, so a capture of a
+// known good session can serve as the golden sequence of the next one. Blank lines and lines
+// starting with `#` are ignored.
+
+/// The request sequence a master is expected to send, checked request by request as they arrive.
+///
+/// A read repeating the request matched just before is taken as polling and skipped, so a master
+/// may poll running any number of times where the sequence lists one read.
+pub struct GoldenSequence {
+    expected: Vec<String>,
+    progress: Mutex<Progress>,
+}
+
+#[derive(Default)]
+struct Progress {
+    matched: usize,
+    divergence: Option<Divergence>,
+}
+
+/// Where the requests first strayed from the golden sequence.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Position in the golden sequence, from 0.
+    pub position: usize,
+    /// `None` if the master sent more requests than the sequence has.
+    pub expected: Option<String>,
+    /// `None` if the master stopped before the end of the sequence.
+    pub actual: Option<String>,
+}
+
+impl GoldenSequence {
+    pub fn load(path: &Path) -> crate::error::Result<Self> {
+        let sequence = std::fs::read_to_string(path).map_err(anyhow::Error::from)
+            .and_then(|contents| Self::parse(&contents))
+            .map_err(|source| Error::Config { path: path.to_path_buf(), source })?;
+        info!("Loaded a golden sequence of {} requests from {}", sequence.expected.len(), path.display());
+        Ok(sequence)
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut expected = Vec::new();
+        for (line_no, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let request = line.split_once(" => ").map_or(line, |(request, _)| request);
+            // The leading timestamp of a capture line
+            let request = match request.split_once(' ') {
+                Some((first, rest)) if first.chars().all(|c| c.is_ascii_digit()) => rest,
+                _ => request,
+            };
+            let request = parse_request(request).with_context(|| format!("line {}", line_no + 1))?;
+            expected.push(format_request(&request).expect("parsed requests are always formattable"));
+        }
+        Ok(Self { expected, progress: Mutex::new(Progress::default()) })
+    }
+
+    /// Checks the next incoming request. Only the first divergence is reported, later requests are
+    /// no longer checked.
+    pub fn check(&self, request: &Request<'_>) {
+        let Some(actual) = format_request(request) else {
+            // Requests the traffic log can't express can't be in the sequence either
+            return;
+        };
+        let mut progress = self.progress.lock().unwrap();
+        if progress.divergence.is_some() {
+            return;
+        }
+        let position = progress.matched;
+        if self.expected.get(position) == Some(&actual) {
+            progress.matched += 1;
+            if progress.matched == self.expected.len() {
+                info!("Golden sequence: all {} requests matched", self.expected.len());
+            }
+            return;
+        }
+        let polling = is_read(request) && position > 0 && self.expected[position - 1] == actual;
+        if polling {
+            return;
+        }
+        let divergence = Divergence { position, expected: self.expected.get(position).cloned(), actual: Some(actual) };
+        error!("Golden sequence {divergence}");
+        progress.divergence = Some(divergence);
+    }
+
+    /// The first divergence, including stopping short of the end of the sequence.
+    pub fn result(&self) -> Result<(), Divergence> {
+        let progress = self.progress.lock().unwrap();
+        if let Some(divergence) = &progress.divergence {
+            return Err(divergence.clone());
+        }
+        match self.expected.get(progress.matched) {
+            Some(expected) => Err(Divergence { position: progress.matched, expected: Some(expected.clone()), actual: None }),
+            None => Ok(()),
+        }
+    }
+}
+
+fn is_read(request: &Request<'_>) -> bool {
+    matches!(request, Request::ReadCoils(..) | Request::ReadDiscreteInputs(..)
+        | Request::ReadHoldingRegisters(..) | Request::ReadInputRegisters(..))
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "diverged at request #{}: expected ", self.position + 1)?;
+        match &self.expected {
+            Some(expected) => write!(f, "`{expected}`")?,
+            None => write!(f, "no more requests")?,
+        }
+        match &self.actual {
+            Some(actual) => write!(f, ", got `{actual}`"),
+            None => write!(f, ", got none"),
+        }
+    }
+}
+
+impl std::error::Error for Divergence {}
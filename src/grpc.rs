@@ -0,0 +1,364 @@
+// Handlers answer with tonic's `Status`, large as it is, like any tonic service
+#![allow(clippy::result_large_err)]
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use log::{debug, info, warn};
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::BroadcastStream;
+use tonic::body::BoxBody;
+use tonic::client::Grpc as GrpcClient;
+use tonic::codec::{ProstCodec, Streaming};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::codegen::{http, BoxFuture, BoxStream, Future, Service};
+use tonic::server::{Grpc, NamedService};
+use tonic::transport::{Channel, Endpoint, Server};
+use tonic::{IntoRequest, Status};
+use crate::mb_stuff::{addresses, ChangeKind, ReadOnlyPolicy, SharedModbusState, StateChange};
+use crate::test_cases::{run_test_case_shared, run_within_budget, TestCases, TestConfig};
+
+// The messages and service of proto/rtu_sim.proto, written out by hand so building needs no
+// protoc. Keep the two in step: field tags and method paths are the wire format.
+
+/// A range of coils or registers to read.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ReadRequest {
+    #[prost(uint32, tag = "1")]
+    pub addr: u32,
+    #[prost(uint32, tag = "2")]
+    pub count: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CoilValues {
+    #[prost(bool, repeated, tag = "1")]
+    pub values: Vec<bool>,
+}
+
+/// Register values, each 0 to 65535.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RegisterValues {
+    #[prost(uint32, repeated, tag = "1")]
+    pub values: Vec<u32>,
+}
+
+/// Coils to write, starting at `addr`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteCoilsRequest {
+    #[prost(uint32, tag = "1")]
+    pub addr: u32,
+    #[prost(bool, repeated, tag = "2")]
+    pub values: Vec<bool>,
+}
+
+/// Holding registers to write, starting at `addr`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteRegistersRequest {
+    #[prost(uint32, tag = "1")]
+    pub addr: u32,
+    #[prost(uint32, repeated, tag = "2")]
+    pub values: Vec<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteReply {}
+
+/// A test case as the test history keeps it, e.g. `{"SrSingle":3}`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RunTestCaseRequest {
+    #[prost(string, tag = "1")]
+    pub test_case: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RunTestCaseReply {
+    #[prost(bool, tag = "1")]
+    pub passed: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WatchChangesRequest {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ChangeSpace {
+    Coil = 0,
+    HoldingRegister = 1,
+}
+
+/// A [`StateChange`], coils as 0/1.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ChangeEvent {
+    #[prost(enumeration = "ChangeSpace", tag = "1")]
+    pub space: i32,
+    #[prost(uint32, tag = "2")]
+    pub addr: u32,
+    #[prost(uint32, tag = "3")]
+    pub old: u32,
+    #[prost(uint32, tag = "4")]
+    pub new: u32,
+}
+
+impl From<StateChange> for ChangeEvent {
+    fn from(change: StateChange) -> Self {
+        let space = match change.kind {
+            ChangeKind::Coil => ChangeSpace::Coil,
+            ChangeKind::HoldingRegister => ChangeSpace::HoldingRegister,
+        };
+        Self { space: space.into(), addr: change.address.into(), old: change.old.into(), new: change.new.into() }
+    }
+}
+
+const READ_COILS: &str = "/rtu_sim.Control/ReadCoils";
+const WRITE_COILS: &str = "/rtu_sim.Control/WriteCoils";
+const READ_HOLDING_REGISTERS: &str = "/rtu_sim.Control/ReadHoldingRegisters";
+const WRITE_HOLDING_REGISTERS: &str = "/rtu_sim.Control/WriteHoldingRegisters";
+const READ_INPUT_REGISTERS: &str = "/rtu_sim.Control/ReadInputRegisters";
+const RUN_TEST_CASE: &str = "/rtu_sim.Control/RunTestCase";
+const WATCH_CHANGES: &str = "/rtu_sim.Control/WatchChanges";
+
+/// gRPC alternative to `--json` for orchestrating simulators from another process: reads and
+/// writes the state, runs test cases through the same executor as the TUI and streams every
+/// change.
+#[derive(Clone)]
+pub struct ControlServer {
+    state: SharedModbusState,
+    test_config: TestConfig,
+    /// Held while a test case runs, as two would fight over the one handshake.
+    test_case_running: Arc<Mutex<()>>,
+}
+
+impl ControlServer {
+    pub fn new(state: SharedModbusState, test_config: TestConfig) -> Self {
+        Self { state, test_config, test_case_running: Arc::new(Mutex::new(())) }
+    }
+
+    async fn read_coils(self, request: ReadRequest) -> Result<CoilValues, Status> {
+        let (addr, count) = read_range(&request)?;
+        Ok(CoilValues { values: self.state.read_coils(addr, count) })
+    }
+
+    /// Honors read-only coils the way the service does: refused outright under
+    /// [`ReadOnlyPolicy::Reject`], skipped under [`ReadOnlyPolicy::Ignore`].
+    async fn write_coils(self, request: WriteCoilsRequest) -> Result<WriteReply, Status> {
+        let addr = write_range(request.addr, request.values.len())?;
+        let protected: Vec<u16> = addresses(addr, request.values.len())
+            .filter(|&coil_addr| self.state.is_read_only_coil(coil_addr))
+            .collect();
+        if protected.is_empty() {
+            self.state.write_coils(addr, &request.values);
+        } else if self.state.read_only_policy() == ReadOnlyPolicy::Reject {
+            return Err(Status::permission_denied(format!("Coils {protected:?} are read-only")));
+        } else {
+            debug!("Ignoring gRPC write to read-only coils {protected:?}");
+            self.state.write_writable_coils(addr, &request.values);
+        }
+        Ok(WriteReply {})
+    }
+
+    async fn read_holding_registers(self, request: ReadRequest) -> Result<RegisterValues, Status> {
+        let (addr, count) = read_range(&request)?;
+        Ok(RegisterValues { values: self.state.read_holding_registers(addr, count).into_iter().map(u32::from).collect() })
+    }
+
+    async fn write_holding_registers(self, request: WriteRegistersRequest) -> Result<WriteReply, Status> {
+        let addr = write_range(request.addr, request.values.len())?;
+        let values = request.values.iter()
+            .map(|&value| u16::try_from(value).map_err(|_| Status::invalid_argument(format!("Register value {value} is over 65535"))))
+            .collect::<Result<Vec<u16>, Status>>()?;
+        self.state.write_holding_registers(addr, &values);
+        Ok(WriteReply {})
+    }
+
+    async fn read_input_registers(self, request: ReadRequest) -> Result<RegisterValues, Status> {
+        let (addr, count) = read_range(&request)?;
+        Ok(RegisterValues { values: self.state.read_input_registers(addr, count).into_iter().map(u32::from).collect() })
+    }
+
+    /// Runs the test case like the TUI does: on a reset state unless `--no-reset`, within the
+    /// test budget, and failed if the arm isn't back at rest afterwards.
+    async fn run_test_case(self, request: RunTestCaseRequest) -> Result<RunTestCaseReply, Status> {
+        let test_case: TestCases = serde_json::from_str(&request.test_case)
+            .map_err(|err| Status::invalid_argument(format!("Invalid test case {:?}: {err}", request.test_case)))?;
+        let _running = self.test_case_running.lock().await;
+        info!("gRPC test case: {test_case:?}");
+        if self.test_config.reset_between_tests {
+            self.state.reset();
+        }
+        // Sweeps run over gRPC only log their results, there is no --csv for them
+        let mut sweep_csv = None;
+        let run = run_test_case_shared(&self.state, &[], &self.test_config, &test_case, &mut sweep_csv);
+        let passed = run_within_budget(&self.state, &self.test_config, run).await;
+        info!("gRPC test case {test_case:?} {}", if passed { "passed" } else { "failed" });
+        Ok(RunTestCaseReply { passed })
+    }
+
+    /// Streams every change from the call on. A watcher too slow to keep up misses the oldest.
+    async fn watch_changes(self, _request: WatchChangesRequest) -> Result<BoxStream<ChangeEvent>, Status> {
+        let changes = BroadcastStream::new(self.state.subscribe_changes()).filter_map(|change| match change {
+            Ok(change) => Some(Ok(ChangeEvent::from(change))),
+            Err(err) => {
+                warn!("gRPC change watcher fell behind: {err}");
+                None
+            }
+        });
+        Ok(Box::pin(changes))
+    }
+}
+
+/// Checks a read fits the 16-bit address space, as a Modbus read has to.
+fn read_range(request: &ReadRequest) -> Result<(u16, u16), Status> {
+    match (u16::try_from(request.addr), u16::try_from(request.count)) {
+        (Ok(addr), Ok(count)) if usize::from(addr) + usize::from(count) <= usize::from(u16::MAX) + 1 => Ok((addr, count)),
+        _ => Err(Status::out_of_range(format!("{} addresses from {} run past {}", request.count, request.addr, u16::MAX))),
+    }
+}
+
+/// Checks a write of `len` values fits the 16-bit address space, as a Modbus write has to.
+fn write_range(addr: u32, len: usize) -> Result<u16, Status> {
+    if len == 0 {
+        return Err(Status::invalid_argument(format!("Write to {addr} has no values")));
+    }
+    match u16::try_from(addr) {
+        Ok(start) if usize::from(start) + len <= usize::from(u16::MAX) + 1 => Ok(start),
+        _ => Err(Status::out_of_range(format!("{len} addresses from {addr} run past {}", u16::MAX))),
+    }
+}
+
+/// Answers a call with one of the [`ControlServer`] methods, unary or streaming, whichever
+/// `Res` is.
+struct Handler<F>(F);
+
+impl<Req, Res, F, Fut> Service<tonic::Request<Req>> for Handler<F>
+where
+    F: FnMut(Req) -> Fut,
+    Fut: Future<Output = Result<Res, Status>> + Send + 'static,
+    Res: Send + 'static,
+{
+    type Response = tonic::Response<Res>;
+    type Error = Status;
+    type Future = BoxFuture<tonic::Response<Res>, Status>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: tonic::Request<Req>) -> Self::Future {
+        let reply = (self.0)(request.into_inner());
+        Box::pin(async move { reply.await.map(tonic::Response::new) })
+    }
+}
+
+impl NamedService for ControlServer {
+    const NAME: &'static str = "rtu_sim.Control";
+}
+
+impl Service<http::Request<BoxBody>> for ControlServer {
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Infallible>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        let server = self.clone();
+        Box::pin(async move {
+            let path = request.uri().path().to_string();
+            debug!("gRPC call {path}");
+            let response = match path.as_str() {
+                READ_COILS => Grpc::new(ProstCodec::default())
+                    .unary(Handler(|req| server.clone().read_coils(req)), request).await,
+                WRITE_COILS => Grpc::new(ProstCodec::default())
+                    .unary(Handler(|req| server.clone().write_coils(req)), request).await,
+                READ_HOLDING_REGISTERS => Grpc::new(ProstCodec::default())
+                    .unary(Handler(|req| server.clone().read_holding_registers(req)), request).await,
+                WRITE_HOLDING_REGISTERS => Grpc::new(ProstCodec::default())
+                    .unary(Handler(|req| server.clone().write_holding_registers(req)), request).await,
+                READ_INPUT_REGISTERS => Grpc::new(ProstCodec::default())
+                    .unary(Handler(|req| server.clone().read_input_registers(req)), request).await,
+                RUN_TEST_CASE => Grpc::new(ProstCodec::default())
+                    .unary(Handler(|req| server.clone().run_test_case(req)), request).await,
+                WATCH_CHANGES => Grpc::new(ProstCodec::default())
+                    .server_streaming(Handler(|req| server.clone().watch_changes(req)), request).await,
+                _ => {
+                    warn!("gRPC call to unknown method {path}");
+                    Status::unimplemented(format!("No method {path}")).into_http()
+                }
+            };
+            Ok(response)
+        })
+    }
+}
+
+/// Serves a [`ControlServer`] on `addr` for `--grpc`.
+pub async fn serve_grpc(addr: SocketAddr, server: ControlServer) -> anyhow::Result<()> {
+    info!("Serving gRPC control on {addr}");
+    Server::builder().add_service(server).serve(addr).await?;
+    Ok(())
+}
+
+/// Client for a [`ControlServer`], in Rust test harnesses. Other languages generate theirs from
+/// proto/rtu_sim.proto.
+#[derive(Clone)]
+pub struct ControlClient {
+    inner: GrpcClient<Channel>,
+}
+
+impl ControlClient {
+    pub async fn connect(addr: SocketAddr) -> Result<Self, tonic::transport::Error> {
+        let channel = Endpoint::from_shared(format!("http://{addr}"))?.connect().await?;
+        Ok(Self { inner: GrpcClient::new(channel) })
+    }
+
+    async fn ready(&mut self) -> Result<(), Status> {
+        self.inner.ready().await.map_err(|err| Status::unavailable(format!("gRPC control not ready: {err}")))
+    }
+
+    async fn unary<Req, Res>(&mut self, path: &'static str, request: impl IntoRequest<Req>) -> Result<tonic::Response<Res>, Status>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Res: prost::Message + Default + Send + Sync + 'static,
+    {
+        self.ready().await?;
+        self.inner.unary(request.into_request(), PathAndQuery::from_static(path), ProstCodec::default()).await
+    }
+
+    pub async fn read_coils(&mut self, request: impl IntoRequest<ReadRequest>) -> Result<tonic::Response<CoilValues>, Status> {
+        self.unary(READ_COILS, request).await
+    }
+
+    pub async fn write_coils(&mut self, request: impl IntoRequest<WriteCoilsRequest>) -> Result<tonic::Response<WriteReply>, Status> {
+        self.unary(WRITE_COILS, request).await
+    }
+
+    pub async fn read_holding_registers(&mut self, request: impl IntoRequest<ReadRequest>)
+        -> Result<tonic::Response<RegisterValues>, Status> {
+        self.unary(READ_HOLDING_REGISTERS, request).await
+    }
+
+    pub async fn write_holding_registers(&mut self, request: impl IntoRequest<WriteRegistersRequest>)
+        -> Result<tonic::Response<WriteReply>, Status> {
+        self.unary(WRITE_HOLDING_REGISTERS, request).await
+    }
+
+    pub async fn read_input_registers(&mut self, request: impl IntoRequest<ReadRequest>)
+        -> Result<tonic::Response<RegisterValues>, Status> {
+        self.unary(READ_INPUT_REGISTERS, request).await
+    }
+
+    pub async fn run_test_case(&mut self, request: impl IntoRequest<RunTestCaseRequest>)
+        -> Result<tonic::Response<RunTestCaseReply>, Status> {
+        self.unary(RUN_TEST_CASE, request).await
+    }
+
+    pub async fn watch_changes(&mut self, request: impl IntoRequest<WatchChangesRequest>)
+        -> Result<tonic::Response<Streaming<ChangeEvent>>, Status> {
+        self.ready().await?;
+        self.inner.server_streaming(request.into_request(), PathAndQuery::from_static(WATCH_CHANGES), ProstCodec::default()).await
+    }
+}
@@ -0,0 +1,68 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Wraps a connection so reads fail with `TimedOut` once the peer has been silent for `timeout`.
+///
+/// Any received data resets the deadline, so a master that keeps polling never trips it.
+/// The error ends tokio-modbus' request loop, which drops (and closes) the connection.
+/// A `None` timeout never expires.
+pub struct IdleTimeoutStream<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> IdleTimeoutStream<S> {
+    pub fn new(inner: S, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            deadline: timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeoutStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(res) => {
+                if buf.filled().len() > filled_before
+                    && let Some(timeout) = self.timeout
+                    && let Some(deadline) = self.deadline.as_mut() {
+                    deadline.as_mut().reset(Instant::now() + timeout);
+                }
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                let timeout = self.timeout;
+                match self.deadline.as_mut().map(|deadline| deadline.as_mut().poll(cx)) {
+                    Some(Poll::Ready(())) => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no request received for {:?}", timeout.unwrap_or_default()),
+                    ))),
+                    _ => Poll::Pending,
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeoutStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
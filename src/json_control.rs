@@ -0,0 +1,89 @@
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use crate::mb_stuff::SharedModbusState;
+
+/// One line of input in `--json` mode, e.g. `{"op":"write_coil","addr":3,"value":true}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Command {
+    ReadCoils { addr: u16, count: u16 },
+    WriteCoil { addr: u16, value: bool },
+    ReadHoldingRegisters { addr: u16, count: u16 },
+    WriteHoldingRegister { addr: u16, value: u16 },
+    ReadInputRegisters { addr: u16, count: u16 },
+    PulseRunning { ms: u64 },
+    Freeze,
+    Unfreeze,
+    Reset,
+}
+
+/// The line written back for each command, e.g. `{"result":"coils","values":[true]}`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Reply {
+    Done,
+    Coils { values: Vec<bool> },
+    Registers { values: Vec<u16> },
+    Error { message: String },
+}
+
+impl Command {
+    fn apply(self, state: &SharedModbusState) -> Reply {
+        match self {
+            Command::ReadCoils { addr, count } => Reply::Coils { values: state.read_coils(addr, count) },
+            Command::WriteCoil { addr, value } => {
+                state.write_coil(addr, value);
+                Reply::Done
+            }
+            Command::ReadHoldingRegisters { addr, count } =>
+                Reply::Registers { values: state.read_holding_registers(addr, count) },
+            Command::WriteHoldingRegister { addr, value } => {
+                state.write_holding_register(addr, value);
+                Reply::Done
+            }
+            Command::ReadInputRegisters { addr, count } =>
+                Reply::Registers { values: state.read_input_registers(addr, count) },
+            Command::PulseRunning { ms } => {
+                state.pulse_running(Duration::from_millis(ms));
+                Reply::Done
+            }
+            Command::Freeze => {
+                state.freeze();
+                Reply::Done
+            }
+            Command::Unfreeze => {
+                state.unfreeze();
+                Reply::Done
+            }
+            Command::Reset => {
+                state.reset();
+                Reply::Done
+            }
+        }
+    }
+}
+
+/// Scripted alternative to the TUI: applies newline-delimited JSON [`Command`]s from `input` to
+/// `state`, answering each with one [`Reply`] line on `output`, until `input` ends.
+///
+/// A line that doesn't parse gets an error reply; it doesn't end the session. Must be called
+/// from within a tokio runtime, for `pulse_running`.
+pub fn run_json_control(state: &SharedModbusState, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        debug!("JSON command: {line}");
+        let reply = match serde_json::from_str::<Command>(&line) {
+            Ok(command) => command.apply(state),
+            Err(err) => Reply::Error { message: err.to_string() },
+        };
+        serde_json::to_writer(&mut output, &reply)?;
+        writeln!(output)?;
+        output.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! Modbus TCP simulator for the enable/running handshake robot arms use to run sub routines.
+//!
+//! The binary wraps this in a CLI and an interactive test runner. The pieces are usable on their
+//! own, e.g. to serve a [`mb_stuff::SharedModbusState`] from another test harness:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use tokio::net::TcpListener;
+//! use tokio_modbus::client::{tcp, Reader};
+//! use rtu_sim::connections::ConnectionTracker;
+//! use rtu_sim::mb_stuff::{ExampleService, SharedModbusState};
+//! use rtu_sim::server_context;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! let state = SharedModbusState::new();
+//! let listener = TcpListener::bind("127.0.0.1:0").await?;
+//! let addr = listener.local_addr()?;
+//! let service_state = state.clone();
+//! tokio::spawn(server_context(listener, None, Arc::new(ConnectionTracker::new(None)),
+//!     move |peer| ExampleService::with_shared_state(service_state.clone(), peer)));
+//!
+//! let ready_coil = state.register_map().ready_coil;
+//! state.write_coil(ready_coil, true);
+//! let mut ctx = tcp::connect(addr).await?;
+//! assert_eq!(ctx.read_coils(ready_coil, 1).await??, vec![true]);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod mb_stuff;
+pub mod test_cases;
+pub mod sweep_csv;
+pub mod idle_timeout;
+pub mod traffic_log;
+pub mod arm_sim;
+pub mod metrics;
+pub mod prometheus;
+pub mod degraded_link;
+pub mod register_map;
+pub mod ramp;
+pub mod connections;
+pub mod json_control;
+pub mod test_history;
+pub mod watchdog;
+pub mod banner;
+pub mod log_file;
+pub mod golden;
+pub mod power_cycle;
+pub mod regions;
+pub mod device_id;
+pub mod rate_limit;
+pub mod remote;
+pub mod context_pool;
+pub mod auto_run;
+pub mod duration_format;
+pub mod bench;
+pub mod error;
+pub mod grpc;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use tokio::net::TcpListener;
+use tokio_modbus::server::tcp::{accept_tcp_connection, Server};
+use crate::connections::{ConnectionTracker, TrackedStream};
+use crate::idle_timeout::IdleTimeoutStream;
+use crate::mb_stuff::ExampleService;
+
+/// Fault code set when a new sub routine is commanded while one is still running.
+pub const FAULT_BUSY: u16 = 1;
+/// Fault code set when the master stopped toggling the watchdog coil.
+pub const FAULT_WATCHDOG: u16 = 2;
+
+/// Serves Modbus TCP on `listener`, building each connection's service with `new_service`.
+pub async fn server_context(
+    listener: TcpListener,
+    idle_timeout: Option<Duration>,
+    connections: Arc<ConnectionTracker>,
+    new_service: impl Fn(SocketAddr) -> ExampleService + Clone + Send + Sync + 'static,
+) -> anyhow::Result<()> {
+    info!("Starting up local server on {}", listener.local_addr()?);
+    let server = Server::new(listener);
+
+    let on_connected = move |stream, socket_addr| {
+        let new_service = new_service.clone();
+        let guard = connections.try_open();
+        let (active, max, powered) = (connections.active(), connections.max(), connections.powered());
+        let new_service = move |socket_addr| Ok(Some(new_service(socket_addr)));
+        async move {
+            let Some(guard) = guard else {
+                // Dropping the stream closes it, which is all a busy or rebooting device does
+                if !powered {
+                    info!("Refused connection from {socket_addr}: powered off");
+                } else {
+                    warn!("Refused connection from {socket_addr}: already at the limit of {} connections",
+                        max.unwrap_or_default());
+                }
+                return Ok(None);
+            };
+            info!("New connection from {socket_addr} ({active} open)");
+            let connection = accept_tcp_connection(stream, socket_addr, new_service)?;
+            Ok(connection.map(|(service, stream)| {
+                (service, TrackedStream::new(IdleTimeoutStream::new(stream, idle_timeout), guard))
+            }))
+        }
+    };
+
+    let on_process_error = |err: std::io::Error| {
+        match err.kind() {
+            std::io::ErrorKind::TimedOut => info!("Closed idle connection: {err}"),
+            std::io::ErrorKind::ConnectionAborted => info!("Closed connection: {err}"),
+            // The client hung up before its response was sent, e.g. while it was held back by latency
+            std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset =>
+                debug!("Client disconnected mid-request: {err}"),
+            // Raised by tokio-modbus' decoder, e.g. a WriteSingleCoil value other than 0xFF00/0x0000
+            std::io::ErrorKind::InvalidData => warn!("Closed connection after a malformed request: {err}"),
+            _ => error!("{err}"),
+        }
+    };
+    server.serve(&on_connected, on_process_error).await?;
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Sends every record to the console logger and to a copy of the log in a file.
+///
+/// Both are plain `env_logger`s, so the file gets the same timestamped lines as the console, just
+/// without colors.
+pub struct TeeLogger {
+    console: env_logger::Logger,
+    file: env_logger::Logger,
+}
+
+impl TeeLogger {
+    pub fn new(console: env_logger::Logger, file: env_logger::Logger) -> Self {
+        Self { console, file }
+    }
+
+    /// The most verbose level either side wants, for [`log::set_max_level`].
+    pub fn filter(&self) -> LevelFilter {
+        self.console.filter().max(self.file.filter())
+    }
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata) || self.file.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        // Each side applies its own filter
+        self.console.log(record);
+        self.file.log(record);
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        self.file.flush();
+    }
+}
+
+/// Configures `builder` to append to the log file at `path`, creating it if needed.
+pub fn log_to_file(builder: &mut env_logger::Builder, path: &Path) -> io::Result<()> {
+    let file: File = OpenOptions::new().create(true).append(true).open(path)?;
+    builder
+        .target(env_logger::Target::Pipe(Box::new(file)))
+        .write_style(env_logger::WriteStyle::Never);
+    Ok(())
+}
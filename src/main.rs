@@ -1,347 +1,2251 @@
-mod mb_stuff;
-mod test_cases;
+mod selftest;
 
-use log::{info, warn, error, debug};
+use anyhow::Context;
+use log::{info, warn, error, LevelFilter};
 use std::{
     net::SocketAddr,
-    sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use std::fmt::{Debug, Formatter};
-use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::net::TcpListener;
-use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
-use local_ip_address::local_ip;
-use tokio::time::Instant;
-use tokio_modbus::{
-    prelude::*,
-    server::tcp::{accept_tcp_connection, Server},
-};
-use crate::mb_stuff::{ExampleService, SharedModbusState};
-use crate::test_cases::{EarlyStopResult, sr_single_shared, sr_single_early_stop_shared};
+use tokio::sync::oneshot;
+use tokio_modbus::SlaveId;
+use tokio_modbus::client::tcp;
+use tracing::{field, info_span, Instrument};
+use dialoguer::{console::Term, theme::ColorfulTheme, Confirm, Input, Select};
+use rtu_sim::mb_stuff::{ChangeKind, ExampleService, ExceptionStatusBits, FloorPolicy, IndexWidth, ReadOnlyPolicy, RequestFloor, SeedRange, SharedModbusState, SwapMode, UndeclaredDefaults, UnknownFunctionPolicy, WordOrder, DEFAULT_SERVER_ID, MAX_SERVER_ID_LEN, MAX_UNIT_ID};
+use rtu_sim::banner::Banner;
+use rtu_sim::log_file::{log_to_file, TeeLogger};
+use rtu_sim::auto_run::auto_run;
+use rtu_sim::bench::{measure_throughput, DEFAULT_BENCH_CONNECTIONS, DEFAULT_BENCH_DURATION};
+use rtu_sim::arm_sim::{run_arm_sim, ArmSimConfig, CommandQueue, EnableMode, InitialFault, JitterDistribution, Linear, MotionJitter, MotionModel, SCurve, Trapezoidal, WarmUp};
+use rtu_sim::connections::{wait_for_shutdown, ConnectionTracker, ShutdownReason};
+use rtu_sim::error::{bind, local_ipv4, parse_port, resolve_local_ipv4, Error};
+use rtu_sim::degraded_link::{DegradedLink, FunctionLatency};
+use rtu_sim::device_id::{DeviceIdentification, MAX_DEVICE_ID_LEN};
+use rtu_sim::golden::GoldenSequence;
+use rtu_sim::regions::{AddressSpace, Region, Regions};
+use rtu_sim::remote::run_remote_test_case;
+use rtu_sim::power_cycle::{simulate_power_cycle, DEFAULT_BOOT_DELAY};
+use rtu_sim::json_control::run_json_control;
+use rtu_sim::metrics::Metrics;
+use rtu_sim::prometheus::serve_metrics;
+use rtu_sim::grpc::{serve_grpc, ControlServer};
+use rtu_sim::ramp::{run_ramp, RampConfig};
+use rtu_sim::register_map::RegisterMap;
+use crate::selftest::run_selftest;
+use rtu_sim::sweep_csv::SweepCsv;
+use rtu_sim::test_history::{SessionTally, TestHistory};
+use rtu_sim::traffic_log::{CaptureLog, ReplayLog};
+use rtu_sim::watchdog::{run_heartbeat, run_watchdog, WatchdogConfig};
+use rtu_sim::server_context;
+use rtu_sim::test_cases::{TestCases, SweepSchedule, TestConfig, run_test_case_shared, run_within_budget, plan_test_case};
 
-pub const ENABLE_COIL_OFFSET: u16 = 8;
-pub const RUNNING_COIL_OFFSET: u16 = 9;
-pub const INDEX_HREG_OFFSET: u16 = 8;
-static CLIENT_CONNECTED: AtomicBool = AtomicBool::new(false);
 const DEFAULT_PORT: u16 = 502; // Default Modbus TCP port
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const FAILURE_HISTORY_DUMP_LEN: usize = 32;
 
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> anyhow::Result<()> {
 
     let args: Vec<String> = std::env::args().collect();
     let port = parse_port_arg(&args)?;
-    
-    let ip = local_ip().unwrap();
-    let ipv4 = match ip{
-        IpAddr::V4(v4) => v4,
-        IpAddr::V6(_) => panic!("Local IP says IPv6. This is not supported and highly unlikely for a local ip")
+    let bind_ip = parse_bind_arg(&args)?;
+    let log_level = parse_log_level_arg(&args)?;
+    let log_file = parse_log_file_arg(&args)?;
+    let csv_path = parse_csv_arg(&args)?;
+    let test_config = TestConfig {
+        poll_interval: parse_poll_interval_arg(&args)?,
+        stable_reads: parse_stable_reads_arg(&args)?,
+        ready_timeout: parse_wait_for_ready_arg(&args)?,
+        reset_between_tests: !args.iter().any(|arg| arg == "--no-reset"),
+        test_budget: parse_test_budget_arg(&args)?,
+        dry_run: args.iter().any(|arg| arg == "--dry-run"),
+        enable_mode: parse_enable_mode_arg(&args)?,
+        early_stop_margin: parse_early_stop_margin_arg(&args)?,
+        parallel_runs: parse_parallel_arg(&args)?,
+    };
+    let idle_timeout = parse_idle_timeout_arg(&args)?;
+    let replay_path = parse_replay_arg(&args)?;
+    let golden_path = parse_golden_arg(&args)?;
+    let capture_path = parse_capture_arg(&args)?;
+    let history_size = parse_history_size_arg(&args)?;
+    let server_id = parse_server_id_arg(&args)?;
+    let device_identification = Arc::new(parse_device_identification_args(&args)?);
+    let (read_only_coils, read_only_policy) = parse_read_only_coils_args(&args)?;
+    let prometheus_port = parse_prometheus_arg(&args)?;
+    let grpc_port = parse_grpc_arg(&args)?;
+    let degraded_link = parse_degraded_link_args(&args)?;
+    let arm_sim_config = parse_arm_sim_args(&args)?;
+    let register_map = parse_register_map_arg(&args)?;
+    let ramp_config = parse_ramp_args(&args)?;
+    let initial_fault = parse_initial_fault_arg(&args)?;
+    let command_queue = parse_command_queue_arg(&args)?;
+    let index_echo = parse_index_echo_arg(&args)?;
+    let progress_ireg = parse_progress_ireg_arg(&args)?;
+    let index_width = parse_index_width_arg(&args)?;
+    let write_mirror = parse_mirror_writes_arg(&args)?;
+    let watchdog = parse_watchdog_arg(&args)?;
+    let auto_run_index = parse_auto_run_arg(&args)?;
+    check_index_echo_clash(index_echo, index_width, ramp_config)?;
+    let max_connections = parse_max_connections_arg(&args)?;
+    let unit_count = parse_units_arg(&args)?;
+    let require_client = parse_require_client_arg(&args)?;
+    let swap_mode = parse_swap_arg(&args)?;
+    let unknown_function_policy = parse_unknown_function_arg(&args)?;
+    let allow_zero_count = args.iter().any(|arg| arg == "--allow-zero-count");
+    let disabled_functions = Arc::new(parse_disable_fc_arg(&args)?);
+    let request_floor = parse_request_floor_arg(&args)?;
+    let exception_status_bits = parse_exception_status_bits_arg(&args)?;
+    let coil_aliases = parse_alias_arg(&args, "--coil-alias")?;
+    let register_aliases = parse_alias_arg(&args, "--register-alias")?;
+    let undeclared_defaults = parse_undeclared_defaults_args(&args)?;
+    let regions = parse_region_arg(&args)?;
+    let seed_ranges = parse_seed_range_arg(&args)?;
+    let control_mode = parse_control_mode(&args)?;
+    let remote = parse_connect_arg(&args)?;
+    init_logger(log_level, log_file.as_deref())
+        .context("Failed to open log file")?;
+    if args.iter().any(|arg| arg == "--selftest") {
+        run_selftest().await?;
+        info!("Self-test passed");
+        return Ok(());
+    }
+    if args.iter().any(|arg| arg == "--bench") {
+        return run_bench(&args).await;
+    }
+    if let Some(remote) = remote {
+        return remote_tui(remote, test_config, register_map, csv_path).await;
+    }
+    if let Some(link) = &degraded_link {
+        // Logged so a flaky run can be reproduced with `--seed`
+        info!("Degraded link: {link}");
+    }
+
+    let replay = match replay_path {
+        Some(path) => Some(Arc::new(ReplayLog::load(&path)?)),
+        None => None,
     };
+    let capture = match capture_path {
+        Some(path) => Some(Arc::new(CaptureLog::open(&path)?)),
+        None => None,
+    };
+    let golden = match golden_path {
+        Some(path) => Some(Arc::new(GoldenSequence::load(&path)?)),
+        None => None,
+    };
+
+    let ipv4 = bind_ip.unwrap_or_else(|| resolve_local_ipv4(local_ipv4()));
     let sock_addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(ipv4, port));
-    env_logger::builder().filter_level(log::LevelFilter::Info).init();
-    
+
     // Create shared state
-    let shared_state = SharedModbusState::new();
+    let shared_state = SharedModbusState::new()
+        .with_history_capacity(history_size)
+        .with_read_only_coils(read_only_coils, read_only_policy)
+        .with_aliases(coil_aliases, register_aliases)
+        .with_undeclared_defaults(undeclared_defaults)
+        .with_regions(regions)
+        .with_seed_ranges(seed_ranges)
+        .with_ramp(ramp_config)
+        .with_index_echo(index_echo)
+        .with_progress_register(progress_ireg)
+        .with_index_width(index_width)
+        .with_watchdog(watchdog)
+        .with_initial_fault(initial_fault)
+        .with_command_queue(command_queue)
+        .with_write_mirror(write_mirror)
+        .with_unit_id(unit_count.map(|_| 1));
+    if let Some(offset) = write_mirror {
+        info!("Holding register writes are mirrored into the input registers {offset} addresses up");
+    }
+    for region in shared_state.regions().iter() {
+        info!("Region {region}");
+    }
+    if let Some(map) = register_map {
+        if !index_width.fits(&map) {
+            return Err(Error::bad_arg("index width", format!("{} (overlaps the fault register in register map {map})",
+                index_width.registers())).into());
+        }
+        shared_state.set_register_map(map);
+        info!("Register map: {map}");
+    }
     let shared_state_clone = shared_state.clone();
+    // Unit 1 is the state the TUI and JSON control drive, the others only have their simulated arm
+    let units: Option<Arc<HashMap<SlaveId, SharedModbusState>>> = unit_count.map(|count| Arc::new((1..=count)
+        .map(|unit| (unit, if unit == 1 { shared_state.clone() } else { shared_state.independent_copy().with_unit_id(Some(unit)) }))
+        .collect()));
+
+    let metrics = Arc::new(Metrics::new());
+    let new_service = {
+        let shared_state = shared_state.clone();
+        let metrics = metrics.clone();
+        let golden = golden.clone();
+        let units = units.clone();
+        move |peer| ExampleService::with_shared_state(shared_state.clone(), peer)
+            .with_replay(replay.clone())
+            .with_capture(capture.clone())
+            .with_golden_sequence(golden.clone())
+            .with_server_id(server_id.clone())
+            .with_device_identification(device_identification.clone())
+            .with_metrics(Some(metrics.clone()))
+            .with_degraded_link(degraded_link.clone())
+            .with_swap_mode(swap_mode)
+            .with_unknown_function_policy(unknown_function_policy)
+            .with_zero_count_reads(allow_zero_count)
+            .with_disabled_functions(disabled_functions.clone())
+            .with_request_floor(request_floor)
+            .with_exception_status_bits(exception_status_bits)
+            .with_units(units.clone())
+    };
+    let connections = Arc::new(ConnectionTracker::new(max_connections));
+    let listener = bind(sock_addr).await?;
+    // stderr, as stdout carries the replies in JSON mode
+    eprint!("{}", Banner {
+        addr: listener.local_addr()?,
+        register_map: shared_state.register_map(),
+        map_pending: register_map.is_none() && control_mode == ControlMode::Tui,
+        units: unit_count,
+    }.render());
+    let server_handle = tokio::spawn(server_context(listener, idle_timeout, connections.clone(), new_service));
+    // Never fails without `--require-client`
+    let client_required = {
+        let connections = connections.clone();
+        async move {
+            match require_client {
+                Some(timeout) => connections.require_client(timeout).await,
+                None => Ok(()),
+            }
+        }
+    };
+
+    if let Some(port) = prometheus_port {
+        let metrics_listener = TcpListener::bind(SocketAddrV4::new(ipv4, port)).await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_metrics(metrics_listener, metrics).await {
+                error!("Prometheus endpoint stopped: {err}");
+            }
+        });
+    }
+    if let Some(port) = grpc_port {
+        let grpc_addr = SocketAddr::V4(SocketAddrV4::new(ipv4, port));
+        let server = ControlServer::new(shared_state.clone(), test_config.clone());
+        tokio::spawn(async move {
+            if let Err(err) = serve_grpc(grpc_addr, server).await {
+                error!("gRPC control stopped: {err}");
+            }
+        });
+    }
+
+    let tui_config = TuiConfig {
+        csv_path,
+        history_path: parse_test_history_arg(&args)?,
+        sim_enabled: arm_sim_config.is_some(),
+        prompt_register_map: register_map.is_none(),
+        // In unit ID order, the order parallel runs hand out sub routines in
+        other_units: units.iter()
+            .flat_map(|units| (2..=units.len() as SlaveId).filter_map(|unit| units.get(&unit).cloned()))
+            .collect(),
+    };
+    if let Some(config) = arm_sim_config {
+        // Each unit moves on its own
+        let unit_states: Vec<SharedModbusState> = match &units {
+            Some(units) => units.values().cloned().collect(),
+            None => vec![shared_state.clone()],
+        };
+        for state in unit_states {
+            tokio::spawn(run_arm_sim(state, config.clone()));
+        }
+        // The simulated arm stands in for the Modbus client the TUI would otherwise wait for
+        connections.mark_client_seen();
+    }
+
+    if let Some(idx) = auto_run_index {
+        info!("Sub routine #{idx} runs once the first master connects");
+        tokio::spawn(auto_run(shared_state.clone(), connections.clone(), test_config.clone(), idx));
+    }
+    if let Some(config) = ramp_config {
+        tokio::spawn(run_ramp(shared_state.clone(), config));
+    }
+    if let Some(config) = watchdog {
+        tokio::spawn(run_watchdog(shared_state.clone(), config));
+        // Without the TUI the controlling process is the master and keeps the heartbeat itself
+        if control_mode == ControlMode::Tui {
+            tokio::spawn(run_heartbeat(shared_state.clone(), config));
+        }
+    }
 
-    let server_handle = tokio::spawn(server_context(sock_addr, shared_state));
+    if control_mode == ControlMode::Headless {
+        info!("Running headless, press Ctrl-C to stop");
+        let result = tokio::select! {
+            res = tokio::signal::ctrl_c() => res.map(|()| info!("Interrupted, shutting down")).map_err(Into::into),
+            Err(err) = client_required => Err(err.into()),
+        };
+        shared_state.write_coil(shared_state.register_map().enable_coil, false);
+        server_handle.abort();
+        return result.and(golden_result(golden.as_deref()));
+    }
 
     // Run client (with blocking TUI) in a separate thread
+    let (tui_done_tx, tui_done_rx) = oneshot::channel();
     let client_handle = std::thread::spawn(move || {
         // Use a runtime in this thread for the async parts
         let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(tui_thread(shared_state_clone))
+        if control_mode == ControlMode::Json {
+            // stdin belongs to the controlling process instead of the TUI
+            let _guard = rt.enter();
+            if let Err(err) = run_json_control(&shared_state_clone, std::io::stdin().lock(), std::io::stdout().lock()) {
+                error!("JSON control stopped: {err}");
+            }
+        } else {
+            rt.block_on(tui_thread(shared_state_clone, test_config, tui_config, metrics, connections));
+        }
+        let _ = tui_done_tx.send(());
     });
 
-    // Wait for client to finish
-    client_handle.join().unwrap();
-    // Optionally abort the server when client is done
+    // Wait for client to finish or for the user to hit Ctrl-C
+    let result = match wait_for_shutdown(tui_done_rx, tokio::signal::ctrl_c(), client_required).await {
+        ShutdownReason::TuiFinished => {
+            if client_handle.join().is_err() {
+                error!("TUI thread panicked");
+            }
+            Ok(())
+        }
+        ShutdownReason::Interrupted => {
+            // The TUI thread may be blocked on a prompt, so it is left to die with the process
+            warn!("Interrupted, shutting down");
+            shared_state.write_coil(shared_state.register_map().enable_coil, false);
+            let _ = Term::stderr().show_cursor();
+            Ok(())
+        }
+        ShutdownReason::NoClient(err) => {
+            // Left to die with the process like on Ctrl-C
+            shared_state.write_coil(shared_state.register_map().enable_coil, false);
+            let _ = Term::stderr().show_cursor();
+            Err(err.into())
+        }
+    };
     server_handle.abort();
 
-    Ok(())
+    result.and(golden_result(golden.as_deref()))
+}
+
+/// Reports how the session went against `--golden`, failing on a divergence so CI notices.
+fn golden_result(golden: Option<&GoldenSequence>) -> anyhow::Result<()> {
+    let Some(golden) = golden else {
+        return Ok(());
+    };
+    match golden.result() {
+        Ok(()) => {
+            info!("Golden sequence matched");
+            Ok(())
+        }
+        Err(divergence) => Err(anyhow::anyhow!("Golden sequence {divergence}")),
+    }
 }
 
 
-fn parse_port_arg(args: &[String]) -> Result<u16, Box<dyn std::error::Error>> {
+/// What drives the handshake while the server runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ControlMode {
+    /// The interactive test runner.
+    Tui,
+    /// JSON commands on stdin, see [`run_json_control`].
+    Json,
+    /// Nothing, the server just serves until Ctrl-C, e.g. as a fixture for another harness.
+    Headless,
+}
+
+/// Parses `--json` and `--headless`, at most one of which may be given.
+fn parse_control_mode(args: &[String]) -> Result<ControlMode, Error> {
+    let json = args.iter().any(|arg| arg == "--json");
+    let headless = args.iter().any(|arg| arg == "--headless");
+    match (json, headless) {
+        (true, true) => Err(Error::bad_arg("control mode", "--json with --headless (pick one)")),
+        (true, false) => Ok(ControlMode::Json),
+        (false, true) => Ok(ControlMode::Headless),
+        (false, false) => Ok(ControlMode::Tui),
+    }
+}
+
+/// Finds the value following `long` or `short` in the argument list.
+fn arg_value<'a>(args: &'a [String], long: &'static str, short: Option<&str>) -> Result<Option<&'a str>, Error> {
     for i in 0..args.len() {
-        if args[i] == "--port" || args[i] == "-p" {
+        if args[i] == long || Some(args[i].as_str()) == short {
             if i + 1 >= args.len() {
-                return Err("Port argument requires a value".into());
+                return Err(Error::bad_arg(long, "nothing (a value is required)"));
+            }
+            return Ok(Some(&args[i + 1]));
+        }
+    }
+    Ok(None)
+}
+
+/// Parses `--bind <ipv4>`, the address to serve on instead of the detected local IP.
+fn parse_bind_arg(args: &[String]) -> Result<Option<Ipv4Addr>, Error> {
+    match arg_value(args, "--bind", None)? {
+        Some(ip) => Ok(Some(ip.parse().map_err(|_| Error::bad_arg("IPv4 address", ip))?)),
+        None => Ok(None),
+    }
+}
+
+/// Parses `--connect <addr:port>`, a remote arm or simulator the TUI runs its test cases
+/// against instead of serving the embedded one.
+fn parse_connect_arg(args: &[String]) -> Result<Option<SocketAddr>, Error> {
+    match arg_value(args, "--connect", None)? {
+        Some(addr) => Ok(Some(addr.parse().map_err(|_| Error::bad_arg("address to connect to", addr))?)),
+        None => Ok(None),
+    }
+}
+
+fn parse_port_arg(args: &[String]) -> Result<u16, Error> {
+    let Some(port_str) = arg_value(args, "--port", Some("-p"))? else {
+        return Ok(DEFAULT_PORT);
+    };
+    parse_port(port_str)
+}
+
+
+/// Parses `--log-level <level>` / `-l <level>`.
+///
+/// Returns `None` when the flag is absent so `RUST_LOG` (or the Info default) can apply.
+fn parse_log_level_arg(args: &[String]) -> Result<Option<LevelFilter>, Error> {
+    let Some(level_str) = arg_value(args, "--log-level", Some("-l"))? else {
+        return Ok(None);
+    };
+    let level: LevelFilter = level_str.parse()
+        .map_err(|_| Error::bad_arg("log level", format!("{} (expected one of off, error, warn, info, debug, trace)", level_str)))?;
+    Ok(Some(level))
+}
+
+
+/// Parses `--csv <path>`, the file early-stop sweep results are appended to.
+fn parse_csv_arg(args: &[String]) -> Result<Option<PathBuf>, Error> {
+    Ok(arg_value(args, "--csv", None)?.map(PathBuf::from))
+}
+
+
+/// Parses `--poll-interval <ms>`, how often the running coil is sampled while waiting on the arm.
+fn parse_poll_interval_arg(args: &[String]) -> Result<Duration, Error> {
+    let Some(interval_str) = arg_value(args, "--poll-interval", None)? else {
+        return Ok(TestConfig::DEFAULT_POLL_INTERVAL);
+    };
+    let interval_ms: u64 = interval_str.parse()
+        .map_err(|_| Error::bad_arg("poll interval", interval_str))?;
+    if interval_ms == 0 {
+        return Err(Error::bad_arg("poll interval", "0 (must be greater than 0)"));
+    }
+    Ok(Duration::from_millis(interval_ms))
+}
+
+/// Parses `--parallel <n>`, how many units from `--units` run the sub routines of a test case at
+/// once. One at a time by default.
+fn parse_parallel_arg(args: &[String]) -> Result<usize, Error> {
+    let Some(parallel_str) = arg_value(args, "--parallel", None)? else {
+        return Ok(1);
+    };
+    match parallel_str.parse() {
+        Ok(parallel) if parallel > 0 => Ok(parallel),
+        _ => Err(Error::bad_arg("parallel run count", format!("{parallel_str} (must be greater than 0)"))),
+    }
+}
+
+/// Parses `--stable-reads <n>`, the number of consecutive agreeing reads needed to accept a
+/// running coil transition.
+fn parse_stable_reads_arg(args: &[String]) -> Result<u32, Error> {
+    let Some(reads_str) = arg_value(args, "--stable-reads", None)? else {
+        return Ok(TestConfig::DEFAULT_STABLE_READS);
+    };
+    let reads: u32 = reads_str.parse()
+        .map_err(|_| Error::bad_arg("stable read count", reads_str))?;
+    if reads == 0 {
+        return Err(Error::bad_arg("stable read count", "0 (must be greater than 0)"));
+    }
+    Ok(reads)
+}
+
+
+/// Parses `--idle-timeout <secs>`, after which a silent connection is closed. `0` disables it.
+fn parse_idle_timeout_arg(args: &[String]) -> Result<Option<Duration>, Error> {
+    let Some(timeout_str) = arg_value(args, "--idle-timeout", None)? else {
+        return Ok(Some(DEFAULT_IDLE_TIMEOUT));
+    };
+    let timeout_secs: u64 = timeout_str.parse()
+        .map_err(|_| Error::bad_arg("idle timeout", timeout_str))?;
+    Ok((timeout_secs > 0).then(|| Duration::from_secs(timeout_secs)))
+}
+
+
+/// Parses `--request-floor <ms>[:flag|reject]`, the shortest spacing allowed between two
+/// requests on one connection. Faster requests are logged and counted, and with `reject` also
+/// answered busy. Off unless given.
+fn parse_request_floor_arg(args: &[String]) -> Result<Option<RequestFloor>, Error> {
+    let Some(floor_str) = arg_value(args, "--request-floor", None)? else {
+        return Ok(None);
+    };
+    let (spacing_str, policy_str) = floor_str.split_once(':').unwrap_or((floor_str, "flag"));
+    let policy = match policy_str {
+        "flag" => FloorPolicy::Flag,
+        "reject" => FloorPolicy::Reject,
+        _ => return Err(Error::bad_arg("request floor policy", format!("{} (expected flag or reject)", policy_str))),
+    };
+    match spacing_str.parse() {
+        Ok(spacing_ms) if spacing_ms > 0 => Ok(Some(RequestFloor { min_spacing: Duration::from_millis(spacing_ms), policy })),
+        _ => Err(Error::bad_arg("request floor", spacing_str)),
+    }
+}
+
+/// Parses `--require-client <secs>`, how long a master has to connect before the process gives up
+/// and exits non-zero, to catch test fixtures wired to the wrong address. Off unless given.
+fn parse_require_client_arg(args: &[String]) -> Result<Option<Duration>, Error> {
+    let Some(timeout_str) = arg_value(args, "--require-client", None)? else {
+        return Ok(None);
+    };
+    match timeout_str.parse() {
+        Ok(timeout_secs) if timeout_secs > 0 => Ok(Some(Duration::from_secs(timeout_secs))),
+        _ => Err(Error::bad_arg("client timeout", timeout_str)),
+    }
+}
+
+/// Parses `--replay <path>`, a recorded traffic log whose responses are served back verbatim.
+fn parse_replay_arg(args: &[String]) -> Result<Option<PathBuf>, Error> {
+    Ok(arg_value(args, "--replay", None)?.map(PathBuf::from))
+}
+
+
+/// Parses `--golden <path>`, the request sequence the master is expected to send, checked as
+/// requests arrive and reported at shutdown.
+fn parse_golden_arg(args: &[String]) -> Result<Option<PathBuf>, Error> {
+    Ok(arg_value(args, "--golden", None)?.map(PathBuf::from))
+}
+
+/// Parses `--capture <path>`, a file every handled exchange is appended to in `--replay` format.
+fn parse_capture_arg(args: &[String]) -> Result<Option<PathBuf>, Error> {
+    Ok(arg_value(args, "--capture", None)?.map(PathBuf::from))
+}
+
+/// Parses `--log-file <path>`, where everything logged is appended as well as shown.
+fn parse_log_file_arg(args: &[String]) -> Result<Option<PathBuf>, Error> {
+    Ok(arg_value(args, "--log-file", None)?.map(PathBuf::from))
+}
+
+/// Parses `--test-history <path>`, a file keeping run test cases so they can be rerun in later sessions.
+fn parse_test_history_arg(args: &[String]) -> Result<Option<PathBuf>, Error> {
+    Ok(arg_value(args, "--test-history", None)?.map(PathBuf::from))
+}
+
+/// Parses `--server-id <text>`, the identity reported to ReportServerId (FC 17) requests.
+fn parse_server_id_arg(args: &[String]) -> Result<Arc<str>, Error> {
+    let Some(server_id) = arg_value(args, "--server-id", None)? else {
+        return Ok(DEFAULT_SERVER_ID.into());
+    };
+    if server_id.len() > MAX_SERVER_ID_LEN {
+        return Err(Error::bad_arg("server ID", format!("{server_id} ({} bytes, max is {MAX_SERVER_ID_LEN})", server_id.len())));
+    }
+    Ok(server_id.into())
+}
+
+/// Parses `--vendor-name <text>`, `--product-code <text>` and `--revision <text>`, the basic
+/// device identification reported to Read Device Identification (FC 43) requests.
+fn parse_device_identification_args(args: &[String]) -> Result<DeviceIdentification, Error> {
+    let mut identification = DeviceIdentification::default();
+    if let Some(vendor_name) = arg_value(args, "--vendor-name", None)? {
+        identification.vendor_name = vendor_name.to_string();
+    }
+    if let Some(product_code) = arg_value(args, "--product-code", None)? {
+        identification.product_code = product_code.to_string();
+    }
+    if let Some(revision) = arg_value(args, "--revision", None)? {
+        identification.revision = revision.to_string();
+    }
+    if identification.len() > MAX_DEVICE_ID_LEN {
+        return Err(Error::bad_arg("device identification",
+            format!("{} bytes (max is {MAX_DEVICE_ID_LEN})", identification.len())));
+    }
+    Ok(identification)
+}
+
+/// Parses `--read-only-coils <addr,addr,..>` and `--read-only-policy <reject|ignore>`.
+fn parse_read_only_coils_args(args: &[String]) -> Result<(HashSet<u16>, ReadOnlyPolicy), Error> {
+    let coils = match arg_value(args, "--read-only-coils", None)? {
+        Some(list) => list.split(',')
+            .map(|addr| addr.trim().parse().map_err(|_| Error::bad_arg("coil address", addr)))
+            .collect::<Result<HashSet<u16>, _>>()?,
+        None => HashSet::new(),
+    };
+    let policy = match arg_value(args, "--read-only-policy", None)? {
+        Some("reject") | None => ReadOnlyPolicy::Reject,
+        Some("ignore") => ReadOnlyPolicy::Ignore,
+        Some(other) => return Err(Error::bad_arg("read-only policy", format!("{} (expected reject or ignore)", other))),
+    };
+    Ok((coils, policy))
+}
+
+/// Parses `--region <coil|hreg|ireg>:<name>=<start>..<end>[,...]`, e.g.
+/// `--region coil:control_coils=0..16,ireg:status_inputs=0..8`. The end is exclusive.
+fn parse_region_arg(args: &[String]) -> Result<Regions, Error> {
+    let Some(list) = arg_value(args, "--region", None)? else {
+        return Ok(Regions::default());
+    };
+    let regions = list.split(',')
+        .map(|entry| {
+            let invalid = || Error::bad_arg("--region entry", format!("{entry} (expected <coil|hreg|ireg>:<name>=<start>..<end>)"));
+            let (space, rest) = entry.trim().split_once(':').ok_or_else(invalid)?;
+            let (name, range) = rest.split_once('=').ok_or_else(invalid)?;
+            let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+            let space = AddressSpace::from_short_name(space).ok_or_else(invalid)?;
+            let (start, end): (u16, u16) = (start.parse().map_err(|_| invalid())?, end.parse().map_err(|_| invalid())?);
+            if name.is_empty() || start >= end {
+                return Err(invalid());
             }
-            let port_str = &args[i + 1];
-            let port: u16 = port_str.parse()
-                .map_err(|_| format!("Invalid port number: {}", port_str))?;
-            if port == 0 {
-                return Err("Port number must be greater than 0".into());
+            Ok(Region { name: name.to_string(), space, range: start..end })
+        })
+        .collect::<Result<Vec<Region>, Error>>()?;
+    Ok(Regions::new(regions))
+}
+
+/// Parses `--seed-range <coil|hreg|ireg>:<start>..=<end>[:<value>][,...]`, e.g.
+/// `--seed-range coil:0..=31,hreg:0..=99:0`, declaring every address in each range with the value,
+/// 0 (false) unless given.
+fn parse_seed_range_arg(args: &[String]) -> Result<Vec<SeedRange>, Error> {
+    let Some(list) = arg_value(args, "--seed-range", None)? else {
+        return Ok(Vec::new());
+    };
+    let seed_ranges = list.split(',')
+        .map(|entry| {
+            let invalid = || Error::bad_arg("--seed-range entry", format!("{entry} (expected <coil|hreg|ireg>:<start>..=<end>[:<value>])"));
+            let (space, rest) = entry.trim().split_once(':').ok_or_else(invalid)?;
+            let (range, value) = rest.split_once(':').unwrap_or((rest, "0"));
+            let (start, end) = range.split_once("..=").ok_or_else(invalid)?;
+            let space = AddressSpace::from_short_name(space).ok_or_else(invalid)?;
+            let (start, end): (u16, u16) = (start.parse().map_err(|_| invalid())?, end.parse().map_err(|_| invalid())?);
+            let value: u16 = value.parse().map_err(|_| invalid())?;
+            if start > end || (space == AddressSpace::Coil && value > 1) {
+                return Err(invalid());
             }
-            return Ok(port);
+            Ok(SeedRange::new(space, start..=end, value))
+        })
+        .collect::<Result<Vec<SeedRange>, Error>>()?;
+    Ok(seed_ranges)
+}
+
+/// Parses `<flag> <alias>=<canonical>[,...]`, e.g. `--coil-alias 110=10,111=11`. Off unless given.
+fn parse_alias_arg(args: &[String], flag: &'static str) -> Result<HashMap<u16, u16>, Error> {
+    let Some(list) = arg_value(args, flag, None)? else {
+        return Ok(HashMap::new());
+    };
+    let aliases = list.split(',')
+        .map(|pair| {
+            let invalid = || Error::bad_arg(flag, format!("{pair} (expected <alias>=<canonical>)"));
+            let (alias, canonical) = pair.split_once('=').ok_or_else(invalid)?;
+            Ok((alias.trim().parse().map_err(|_| invalid())?, canonical.trim().parse().map_err(|_| invalid())?))
+        })
+        .collect::<Result<HashMap<u16, u16>, Error>>()?;
+    Ok(aliases)
+}
+
+/// Parses `--disable-fc <code>,...`, function codes answered with `IllegalFunction` even though
+/// the simulator implements them.
+fn parse_disable_fc_arg(args: &[String]) -> Result<HashSet<u8>, Error> {
+    let Some(list) = arg_value(args, "--disable-fc", None)? else {
+        return Ok(HashSet::new());
+    };
+    let codes = list.split(',')
+        .map(|code| code.trim().parse().map_err(|_| Error::bad_arg("function code", code)))
+        .collect::<Result<HashSet<u8>, Error>>()?;
+    Ok(codes)
+}
+
+/// Parses `--swap <none|byte|word|byte-word>`, how register payloads are rearranged on the wire.
+fn parse_swap_arg(args: &[String]) -> Result<SwapMode, Error> {
+    match arg_value(args, "--swap", None)? {
+        Some("none") | None => Ok(SwapMode::None),
+        Some("byte") => Ok(SwapMode::Byte),
+        Some("word") => Ok(SwapMode::Word),
+        Some("byte-word") => Ok(SwapMode::ByteAndWord),
+        Some(other) => Err(Error::bad_arg("swap mode", format!("{} (expected none, byte, word or byte-word)", other))),
+    }
+}
+
+/// Parses `--enable-mode <edge|held>`, how the arm reads enable. It applies to both the
+/// simulated arm and the test cases' expectations of the real one.
+fn parse_enable_mode_arg(args: &[String]) -> Result<EnableMode, Error> {
+    match arg_value(args, "--enable-mode", None)? {
+        Some("edge") | None => Ok(EnableMode::Edge),
+        Some("held") => Ok(EnableMode::Held),
+        Some(other) => Err(Error::bad_arg("enable mode", format!("{} (expected edge or held)", other))),
+    }
+}
+
+/// Parses `--unknown-function <illegal-function|device-failure|drop>`, how requests with function
+/// codes the simulator doesn't implement are answered.
+fn parse_unknown_function_arg(args: &[String]) -> Result<UnknownFunctionPolicy, Error> {
+    match arg_value(args, "--unknown-function", None)? {
+        Some("illegal-function") | None => Ok(UnknownFunctionPolicy::IllegalFunction),
+        Some("device-failure") => Ok(UnknownFunctionPolicy::DeviceFailure),
+        Some("drop") => Ok(UnknownFunctionPolicy::Drop),
+        Some(other) => Err(Error::bad_arg("unknown function policy", format!("{} (expected illegal-function, device-failure or drop)", other))),
+    }
+}
+
+/// Parses `--exception-status-bits <enable>,<running>,<ready>,<fault>`, the bit each signal takes
+/// in the FC 07 status byte.
+fn parse_exception_status_bits_arg(args: &[String]) -> Result<ExceptionStatusBits, Error> {
+    let Some(bits_str) = arg_value(args, "--exception-status-bits", None)? else {
+        return Ok(ExceptionStatusBits::DEFAULT);
+    };
+    let bits = bits_str.split(',')
+        .map(|bit| match bit.trim().parse() {
+            Ok(bit) if bit < 8 => Ok(bit),
+            _ => Err(Error::bad_arg("exception status bit", bit)),
+        })
+        .collect::<Result<Vec<u8>, _>>()?;
+    let &[enable, running, ready, fault] = bits.as_slice() else {
+        return Err(Error::bad_arg("exception status bits", format!("{bits_str} (expected 4: enable,running,ready,fault)")));
+    };
+    if bits.iter().collect::<HashSet<_>>().len() != bits.len() {
+        return Err(Error::bad_arg("exception status bits", format!("{bits_str} (must be distinct)")));
+    }
+    Ok(ExceptionStatusBits { enable, running, ready, fault })
+}
+
+/// Parses `--prometheus <port>`, where to serve `/metrics` for scraping. Off unless given.
+fn parse_prometheus_arg(args: &[String]) -> Result<Option<u16>, Error> {
+    let Some(port_str) = arg_value(args, "--prometheus", None)? else {
+        return Ok(None);
+    };
+    Ok(Some(port_str.parse().map_err(|_| Error::bad_arg("Prometheus port", port_str))?))
+}
+
+/// Parses `--grpc <port>`, where to serve the gRPC control interface. Off unless given.
+fn parse_grpc_arg(args: &[String]) -> Result<Option<u16>, Error> {
+    let Some(port_str) = arg_value(args, "--grpc", None)? else {
+        return Ok(None);
+    };
+    Ok(Some(parse_port(port_str).map_err(|_| Error::bad_arg("gRPC port", port_str))?))
+}
+
+/// Parses a register value, decimal or `0x` prefixed hex.
+fn parse_register_value(value_str: &str) -> Option<u16> {
+    match value_str.strip_prefix("0x").or_else(|| value_str.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => value_str.parse().ok(),
+    }
+}
+
+/// Parses `--undeclared-coil <0|1>`, `--undeclared-hreg <value>` and `--undeclared-ireg <value>`,
+/// what reads of addresses the simulator doesn't have return. All 0 unless given.
+fn parse_undeclared_defaults_args(args: &[String]) -> Result<UndeclaredDefaults, Error> {
+    let mut defaults = UndeclaredDefaults::default();
+    if let Some(coil_str) = arg_value(args, "--undeclared-coil", None)? {
+        defaults.coil = match coil_str {
+            "0" => false,
+            "1" => true,
+            _ => return Err(Error::bad_arg("undeclared coil value", format!("{} (expected 0 or 1)", coil_str))),
+        };
+    }
+    if let Some(value_str) = arg_value(args, "--undeclared-hreg", None)? {
+        defaults.holding_register = parse_register_value(value_str)
+            .ok_or_else(|| Error::bad_arg("undeclared holding register value", value_str))?;
+    }
+    if let Some(value_str) = arg_value(args, "--undeclared-ireg", None)? {
+        defaults.input_register = parse_register_value(value_str)
+            .ok_or_else(|| Error::bad_arg("undeclared input register value", value_str))?;
+    }
+    Ok(defaults)
+}
+
+/// Parses `--index-echo <input register>`, where the simulated arm echoes the index it latched.
+/// Off unless given.
+fn parse_index_echo_arg(args: &[String]) -> Result<Option<u16>, Error> {
+    match arg_value(args, "--index-echo", None)? {
+        Some(addr) => Ok(Some(addr.parse().map_err(|_| Error::bad_arg("input register address", addr))?)),
+        None => Ok(None),
+    }
+}
+
+/// Refuses an index echo whose registers overlap the ramp's actual input register, as each would
+/// overwrite the other.
+fn check_index_echo_clash(index_echo: Option<u16>, index_width: IndexWidth, ramp: Option<RampConfig>) -> Result<(), Error> {
+    let (Some(echo), Some(ramp)) = (index_echo, ramp) else {
+        return Ok(());
+    };
+    // The echo is cut short at 0xFFFF rather than wrapping, see `SharedModbusState::write_index_echo`
+    let echo_end = (u32::from(echo) + u32::from(index_width.registers())).min(u32::from(u16::MAX) + 1);
+    let actual = u32::from(ramp.actual_ireg);
+    if u32::from(echo) <= actual && actual < echo_end {
+        return Err(Error::bad_arg("index echo register", format!("{echo} (clashes with the ramp's actual input register)")));
+    }
+    Ok(())
+}
+
+/// Parses `--progress-ireg <input register>`, where the simulated arm reports the progress of its
+/// motion. Off unless given.
+fn parse_progress_ireg_arg(args: &[String]) -> Result<Option<u16>, Error> {
+    match arg_value(args, "--progress-ireg", None)? {
+        Some(addr) => Ok(Some(addr.parse().map_err(|_| Error::bad_arg("input register address", addr))?)),
+        None => Ok(None),
+    }
+}
+
+/// Parses `--mirror-writes <offset>`, where every holding register write is mirrored into the
+/// input register `offset` addresses up. Off unless given.
+fn parse_mirror_writes_arg(args: &[String]) -> Result<Option<u16>, Error> {
+    match arg_value(args, "--mirror-writes", None)? {
+        Some(offset) => Ok(Some(offset.parse().map_err(|_| Error::bad_arg("mirror offset", offset))?)),
+        None => Ok(None),
+    }
+}
+
+/// Parses `--index-width <1|2>[:big|little]`, how many holding registers the sub routine index,
+/// and its echo, take. Two registers hold a 32-bit index, the high word first unless `little`.
+fn parse_index_width_arg(args: &[String]) -> Result<IndexWidth, Error> {
+    let Some(width_str) = arg_value(args, "--index-width", None)? else {
+        return Ok(IndexWidth::Single);
+    };
+    let (registers_str, order_str) = width_str.split_once(':').unwrap_or((width_str, "big"));
+    let word_order = match order_str {
+        "big" => WordOrder::BigEndian,
+        "little" => WordOrder::LittleEndian,
+        _ => return Err(Error::bad_arg("index word order", format!("{} (expected big or little)", order_str))),
+    };
+    match registers_str {
+        "1" => Ok(IndexWidth::Single),
+        "2" => Ok(IndexWidth::Double(word_order)),
+        _ => Err(Error::bad_arg("index width", format!("{} (expected 1 or 2 registers)", registers_str))),
+    }
+}
+
+/// Parses `--auto-run <idx>`, a sub routine run once as soon as the first master connects. Off
+/// unless given.
+fn parse_auto_run_arg(args: &[String]) -> Result<Option<u32>, Error> {
+    match arg_value(args, "--auto-run", None)? {
+        Some(idx) => Ok(Some(idx.parse().map_err(|_| Error::bad_arg("auto-run index", idx))?)),
+        None => Ok(None),
+    }
+}
+
+/// Parses `--watchdog <coil>:<timeout ms>`, a heartbeat coil that must toggle at least every
+/// timeout for the arm to stay enabled. Off unless given.
+fn parse_watchdog_arg(args: &[String]) -> Result<Option<WatchdogConfig>, Error> {
+    let Some(watchdog_str) = arg_value(args, "--watchdog", None)? else {
+        return Ok(None);
+    };
+    let (coil_str, timeout_str) = watchdog_str.split_once(':')
+        .ok_or_else(|| Error::bad_arg("watchdog", format!("{} (expected <coil>:<timeout ms>)", watchdog_str)))?;
+    let coil: u16 = coil_str.parse().map_err(|_| Error::bad_arg("watchdog coil", coil_str))?;
+    let timeout_ms: u64 = match timeout_str.parse() {
+        Ok(timeout_ms) if timeout_ms > 0 => timeout_ms,
+        _ => return Err(Error::bad_arg("watchdog timeout", timeout_str)),
+    };
+    Ok(Some(WatchdogConfig { coil, timeout: Duration::from_millis(timeout_ms) }))
+}
+
+/// Parses `--initial-fault <code>:<reset coil>`, a fault the arm starts in after every reset and
+/// the coil that clears it. Off unless given.
+fn parse_initial_fault_arg(args: &[String]) -> Result<Option<InitialFault>, Error> {
+    let Some(fault_str) = arg_value(args, "--initial-fault", None)? else {
+        return Ok(None);
+    };
+    let (code_str, coil_str) = fault_str.split_once(':')
+        .ok_or_else(|| Error::bad_arg("initial fault", format!("{} (expected <code>:<reset coil>)", fault_str)))?;
+    let code: u16 = match code_str.parse() {
+        Ok(code) if code != 0 => code,
+        _ => return Err(Error::bad_arg("fault code", code_str)),
+    };
+    let reset_coil: u16 = coil_str.parse().map_err(|_| Error::bad_arg("fault reset coil", coil_str))?;
+    Ok(Some(InitialFault { code, reset_coil }))
+}
+
+/// Parses `--command-queue <depth>:<pending input register>`, how many sub routines the simulated
+/// arm queues when commanded mid-motion and where it reports how many are waiting. Off unless
+/// given, commands mid-motion are then rejected busy.
+fn parse_command_queue_arg(args: &[String]) -> Result<Option<CommandQueue>, Error> {
+    let Some(queue_str) = arg_value(args, "--command-queue", None)? else {
+        return Ok(None);
+    };
+    let (depth_str, ireg_str) = queue_str.split_once(':')
+        .ok_or_else(|| Error::bad_arg("command queue", format!("{} (expected <depth>:<pending input register>)", queue_str)))?;
+    let depth: u16 = match depth_str.parse() {
+        Ok(depth) if depth > 0 => depth,
+        _ => return Err(Error::bad_arg("command queue depth", depth_str)),
+    };
+    let pending_ireg: u16 = ireg_str.parse().map_err(|_| Error::bad_arg("input register address", ireg_str))?;
+    Ok(Some(CommandQueue { depth, pending_ireg }))
+}
+
+/// Parses `--units <n>`, how many arms answer, as unit IDs 1 to n, each with its own state and
+/// simulated arm. Without it, every unit ID shares one state.
+fn parse_units_arg(args: &[String]) -> Result<Option<u8>, Error> {
+    let Some(units_str) = arg_value(args, "--units", None)? else {
+        return Ok(None);
+    };
+    match units_str.parse() {
+        Ok(units) if (1..=MAX_UNIT_ID).contains(&units) => Ok(Some(units)),
+        _ => Err(Error::bad_arg("unit count", format!("{} (expected 1 to {MAX_UNIT_ID})", units_str))),
+    }
+}
+
+/// Parses `--max-connections <n>`, how many masters may be connected at once. Unlimited by default.
+fn parse_max_connections_arg(args: &[String]) -> Result<Option<usize>, Error> {
+    let Some(max_str) = arg_value(args, "--max-connections", None)? else {
+        return Ok(None);
+    };
+    match max_str.parse() {
+        Ok(max) if max > 0 => Ok(Some(max)),
+        _ => Err(Error::bad_arg("max connections", max_str)),
+    }
+}
+
+/// Parses `--drop-percent <0-100>`, `--ignore-write-percent <0-100>`, `--latency-ms <ms>`,
+/// `--fc-latency` and `--seed <n>` into a degraded link. `None` when neither drops, ignored writes
+/// nor latency are asked for.
+fn parse_degraded_link_args(args: &[String]) -> Result<Option<Arc<DegradedLink>>, Error> {
+    let percent_arg = |long: &'static str, what: &'static str| -> Result<f64, Error> {
+        match arg_value(args, long, None)? {
+            Some(percent_str) => Ok(percent_str.parse().ok()
+                .filter(|percent| (0.0..=100.0).contains(percent))
+                .ok_or_else(|| Error::bad_arg(what, format!("{percent_str} (expected 0 to 100)")))?),
+            None => Ok(0.0),
         }
+    };
+    let drop_percent = percent_arg("--drop-percent", "drop percentage")?;
+    let ignore_write_percent = percent_arg("--ignore-write-percent", "ignored write percentage")?;
+    let latency = match arg_value(args, "--latency-ms", None)? {
+        Some(ms_str) => Duration::from_millis(ms_str.parse().map_err(|_| Error::bad_arg("latency", ms_str))?),
+        None => Duration::ZERO,
+    };
+    let function_latencies = parse_fc_latency_arg(args)?;
+    let seed = parse_seed_arg(args)?;
+    if drop_percent == 0.0 && ignore_write_percent == 0.0 && latency.is_zero() && function_latencies.is_empty() {
+        return Ok(None);
     }
-    Ok(DEFAULT_PORT)
+    Ok(Some(Arc::new(DegradedLink::new(drop_percent / 100.0, latency, seed)
+        .with_ignored_writes(ignore_write_percent / 100.0)
+        .with_function_latencies(function_latencies))))
 }
 
+fn parse_jitter_distribution(name: &str) -> Option<JitterDistribution> {
+    match name {
+        "uniform" => Some(JitterDistribution::Uniform),
+        "gaussian" => Some(JitterDistribution::Gaussian),
+        _ => None,
+    }
+}
 
-async fn server_context(socket_addr: SocketAddr, shared_state: SharedModbusState) -> anyhow::Result<()> {
-    info!("Starting up local server on {socket_addr}");
-    let listener = TcpListener::bind(socket_addr).await?;
-    let server = Server::new(listener);
+/// Parses `--fc-latency <fc>=<ms>[~<jitter ms>[:uniform|gaussian]][,...]`, e.g.
+/// `--fc-latency 3=40~10:gaussian,5=5` for ReadHoldingRegisters around 40 ms and WriteSingleCoil
+/// at 5 ms. Function codes not listed keep the `--latency-ms` latency.
+fn parse_fc_latency_arg(args: &[String]) -> Result<BTreeMap<u8, FunctionLatency>, Error> {
+    let Some(list) = arg_value(args, "--fc-latency", None)? else {
+        return Ok(BTreeMap::new());
+    };
+    let latencies = list.split(',')
+        .map(|entry| {
+            let invalid = || Error::bad_arg("--fc-latency entry", format!("{entry} (expected <fc>=<ms>[~<jitter ms>[:uniform|gaussian]])"));
+            let (function, latency) = entry.trim().split_once('=').ok_or_else(invalid)?;
+            let function: u8 = function.parse().map_err(|_| invalid())?;
+            let (base, jitter) = match latency.split_once('~') {
+                Some((base, jitter)) => (base, Some(jitter)),
+                None => (latency, None),
+            };
+            let base = Duration::from_millis(base.parse().map_err(|_| invalid())?);
+            let jitter = match jitter {
+                Some(jitter) => {
+                    let (magnitude, distribution) = jitter.split_once(':').unwrap_or((jitter, "uniform"));
+                    let distribution = parse_jitter_distribution(distribution).ok_or_else(invalid)?;
+                    Some((distribution, Duration::from_millis(magnitude.parse().map_err(|_| invalid())?)))
+                }
+                None => None,
+            };
+            Ok((function, FunctionLatency { base, jitter }))
+        })
+        .collect::<Result<BTreeMap<u8, FunctionLatency>, Error>>()?;
+    Ok(latencies)
+}
 
-    let on_connected = move |stream, socket_addr| {
-        let shared_state = shared_state.clone();
-        CLIENT_CONNECTED.store(true, Ordering::Relaxed);
-        let new_service = move |_socket_addr| {
-            let state = shared_state.clone();
-            Ok(Some(ExampleService::with_shared_state(state)))
+/// Parses `--seed <n>`, shared by everything random so one seed reproduces a run. Without it
+/// the seed comes from the clock.
+fn parse_seed_arg(args: &[String]) -> Result<u64, Error> {
+    Ok(match arg_value(args, "--seed", None)? {
+        Some(seed_str) => seed_str.parse().map_err(|_| Error::bad_arg("seed", seed_str))?,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_nanos() as u64).unwrap_or_default(),
+    })
+}
+
+/// Parses `--test-budget <secs>`, the most time any one test case may take before it's aborted.
+fn parse_test_budget_arg(args: &[String]) -> Result<Option<Duration>, Error> {
+    let Some(secs_str) = arg_value(args, "--test-budget", None)? else {
+        return Ok(None);
+    };
+    let secs: u64 = secs_str.parse().map_err(|_| Error::bad_arg("test budget", secs_str))?;
+    Ok(Some(Duration::from_secs(secs)))
+}
+
+/// Parses `--early-stop-margin <ms>`, how close to the motion's end a completed early stop counts
+/// as marginal instead of too late. Off unless given.
+fn parse_early_stop_margin_arg(args: &[String]) -> Result<Option<Duration>, Error> {
+    let Some(margin_str) = arg_value(args, "--early-stop-margin", None)? else {
+        return Ok(None);
+    };
+    let margin_ms: u64 = margin_str.parse().map_err(|_| Error::bad_arg("early stop margin", margin_str))?;
+    Ok(Some(Duration::from_millis(margin_ms)))
+}
+
+/// Parses `--register-map <name>`, picking one of [`RegisterMap::PRESETS`] instead of asking at
+/// startup.
+fn parse_register_map_arg(args: &[String]) -> Result<Option<RegisterMap>, Error> {
+    let Some(name) = arg_value(args, "--register-map", None)? else {
+        return Ok(None);
+    };
+    let map = RegisterMap::preset(name).ok_or_else(|| {
+        let names: Vec<_> = RegisterMap::PRESETS.iter().map(|(name, _)| *name).collect();
+        Error::bad_arg("register map", format!("{} (expected one of: {})", name, names.join(", ")))
+    })?;
+    Ok(Some(map))
+}
+
+/// Parses `--history-size <n>`, how many coil/register changes are kept for failure dumps.
+fn parse_history_size_arg(args: &[String]) -> Result<usize, Error> {
+    let Some(size_str) = arg_value(args, "--history-size", None)? else {
+        return Ok(SharedModbusState::DEFAULT_HISTORY_CAPACITY);
+    };
+    size_str.parse().map_err(|_| Error::bad_arg("history size", size_str))
+}
+
+
+/// Parses `--wait-for-ready <secs>`: when given, each sub routine first waits up to that long for
+/// the arm's ready coil.
+fn parse_wait_for_ready_arg(args: &[String]) -> Result<Option<Duration>, Error> {
+    let Some(timeout_str) = arg_value(args, "--wait-for-ready", None)? else {
+        return Ok(None);
+    };
+    let timeout_secs: u64 = timeout_str.parse()
+        .map_err(|_| Error::bad_arg("ready timeout", timeout_str))?;
+    Ok(Some(Duration::from_secs(timeout_secs)))
+}
+
+
+/// Parses `--simulate-arm` and its `--sim-motion-ms <ms>`, `--sim-ready-after-ms <ms>`,
+/// `--sim-tick-ms <ms>`, `--sim-running-delay-ms <ms>` and
+/// `--sim-jitter-ms <ms>[:uniform|gaussian]`, `--sim-warm-up <multiplier>:<idle secs>`,
+/// `--sim-motion-model <linear|trapezoidal[:<ramp fraction>]|s-curve>` and
+/// `--sim-enable-debounce-ms <ms>` settings. The jitter is seeded by `--seed`.
+///
+/// Returns `None` unless `--simulate-arm` is given, in which case no real arm needs to connect.
+fn parse_arm_sim_args(args: &[String]) -> Result<Option<ArmSimConfig>, Error> {
+    if !args.iter().any(|arg| arg == "--simulate-arm") {
+        return Ok(None);
+    }
+    let mut config = ArmSimConfig {
+        enable_mode: parse_enable_mode_arg(args)?,
+        ..ArmSimConfig::default()
+    };
+    if let Some(motion_str) = arg_value(args, "--sim-motion-ms", None)? {
+        let motion_ms: u64 = motion_str.parse()
+            .map_err(|_| Error::bad_arg("simulated motion duration", motion_str))?;
+        config.motion_duration = Duration::from_millis(motion_ms);
+    }
+    if let Some(ready_str) = arg_value(args, "--sim-ready-after-ms", None)? {
+        let ready_ms: u64 = ready_str.parse()
+            .map_err(|_| Error::bad_arg("simulated startup delay", ready_str))?;
+        config.ready_after = Duration::from_millis(ready_ms);
+    }
+    if let Some(tick_str) = arg_value(args, "--sim-tick-ms", None)? {
+        config.tick = match tick_str.parse() {
+            Ok(tick_ms) if tick_ms > 0 => Duration::from_millis(tick_ms),
+            _ => return Err(Error::bad_arg("simulation tick", tick_str)),
         };
-        async move {
-            info!("New connection from {socket_addr}");
-            accept_tcp_connection(stream, socket_addr, new_service)
+    }
+    if let Some(delay_str) = arg_value(args, "--sim-running-delay-ms", None)? {
+        let delay_ms: u64 = delay_str.parse()
+            .map_err(|_| Error::bad_arg("simulated running delay", delay_str))?;
+        config.running_assert_delay = Duration::from_millis(delay_ms);
+    }
+    if let Some(jitter_str) = arg_value(args, "--sim-jitter-ms", None)? {
+        let (magnitude_str, distribution_str) = jitter_str.split_once(':').unwrap_or((jitter_str, "uniform"));
+        let distribution = parse_jitter_distribution(distribution_str)
+            .ok_or_else(|| Error::bad_arg("jitter distribution", format!("{} (expected uniform or gaussian)", distribution_str)))?;
+        let magnitude_ms: u64 = magnitude_str.parse()
+            .map_err(|_| Error::bad_arg("simulated motion jitter", magnitude_str))?;
+        config.jitter = Some(MotionJitter {
+            distribution,
+            magnitude: Duration::from_millis(magnitude_ms),
+            seed: parse_seed_arg(args)?,
+        });
+    }
+    if let Some(warm_up_str) = arg_value(args, "--sim-warm-up", None)? {
+        let invalid = || Error::bad_arg("simulated warm-up", format!("{} (expected <multiplier>:<idle secs>)", warm_up_str));
+        let (multiplier_str, idle_str) = warm_up_str.split_once(':').ok_or_else(invalid)?;
+        let multiplier: f64 = multiplier_str.parse().ok().filter(|multiplier| *multiplier >= 1.0).ok_or_else(invalid)?;
+        let idle_secs: u64 = idle_str.parse().map_err(|_| invalid())?;
+        config.warm_up = Some(WarmUp { multiplier, idle_after: Duration::from_secs(idle_secs) });
+    }
+    if let Some(debounce_str) = arg_value(args, "--sim-enable-debounce-ms", None)? {
+        let debounce_ms: u64 = debounce_str.parse()
+            .map_err(|_| Error::bad_arg("simulated enable debounce", debounce_str))?;
+        config.enable_debounce = Duration::from_millis(debounce_ms);
+    }
+    if let Some(model_str) = arg_value(args, "--sim-motion-model", None)? {
+        config.motion_model = parse_motion_model(model_str).ok_or_else(|| {
+            Error::bad_arg("motion model", format!("{} (expected linear, trapezoidal[:<ramp fraction>] or s-curve)", model_str))
+        })?;
+    }
+    Ok(Some(config))
+}
+
+fn parse_motion_model(model_str: &str) -> Option<Arc<dyn MotionModel>> {
+    match model_str.split_once(':') {
+        None if model_str == "linear" => Some(Arc::new(Linear)),
+        None if model_str == "trapezoidal" => Some(Arc::new(Trapezoidal::DEFAULT)),
+        None if model_str == "s-curve" => Some(Arc::new(SCurve)),
+        Some(("trapezoidal", ramp_str)) => {
+            let ramp: f64 = ramp_str.parse().ok().filter(|ramp| (0.0..=0.5).contains(ramp))?;
+            Some(Arc::new(Trapezoidal { ramp }))
         }
+        _ => None,
+    }
+}
+
+/// Parses `--ramp <target register>:<actual input register>` and its `--ramp-rate <units>` /
+/// `--ramp-tick-ms <ms>` settings.
+fn parse_ramp_args(args: &[String]) -> Result<Option<RampConfig>, Error> {
+    let Some(ramp_str) = arg_value(args, "--ramp", None)? else {
+        return Ok(None);
     };
+    let (target_str, actual_str) = ramp_str.split_once(':')
+        .ok_or_else(|| Error::bad_arg("ramp registers", format!("{} (expected <target>:<actual>)", ramp_str)))?;
+    let target_hreg: u16 = target_str.parse().map_err(|_| Error::bad_arg("ramp target register", target_str))?;
+    let actual_ireg: u16 = actual_str.parse().map_err(|_| Error::bad_arg("ramp actual register", actual_str))?;
+    let mut config = RampConfig::new(target_hreg, actual_ireg);
+    if let Some(rate_str) = arg_value(args, "--ramp-rate", None)? {
+        config.rate = match rate_str.parse() {
+            Ok(rate) if rate > 0 => rate,
+            _ => return Err(Error::bad_arg("ramp rate", rate_str)),
+        };
+    }
+    if let Some(tick_str) = arg_value(args, "--ramp-tick-ms", None)? {
+        let tick_ms: u64 = match tick_str.parse() {
+            Ok(tick_ms) if tick_ms > 0 => tick_ms,
+            _ => return Err(Error::bad_arg("ramp tick", tick_str)),
+        };
+        config.tick = Duration::from_millis(tick_ms);
+    }
+    Ok(Some(config))
+}
 
-    let on_process_error = |err| {
-        error!("{err}");
+
+/// Sets up `env_logger`.
+///
+/// Precedence: `--log-level` sets the global level and overrides any global level in `RUST_LOG`,
+/// but module-specific `RUST_LOG` directives (e.g. `tokio_modbus=trace`) still apply.
+/// Without the flag, `RUST_LOG` is used as-is, falling back to Info when it is unset.
+/// Logs to the console and, with `log_file`, also appends the same records to that file.
+fn init_logger(level: Option<LevelFilter>, log_file: Option<&Path>) -> std::io::Result<()> {
+    let builder = || {
+        let mut builder = env_logger::Builder::from_env(
+            env_logger::Env::default().default_filter_or(DEFAULT_LOG_LEVEL.as_str())
+        );
+        if let Some(level) = level {
+            builder.filter_level(level);
+        }
+        builder
     };
-    server.serve(&on_connected, on_process_error).await?;
+    let Some(path) = log_file else {
+        builder().init();
+        return Ok(());
+    };
+    let mut file_builder = builder();
+    log_to_file(&mut file_builder, path)?;
+    let logger = TeeLogger::new(builder().build(), file_builder.build());
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(logger)).expect("the logger is only set once");
     Ok(())
 }
 
 
-enum TestCases {
-    SrSingle(u16),
-    SrUpTo(u16),
-    SrOutOfBounds,
-    SrEarlyStopWithDelay(u16, u16),
-    SrEarlyStopWithDelayOnAllUpTo(u16, u16),
-    SrEarlyStopAllDelays(u16),
-}
-
-impl Debug for TestCases {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            TestCases::SrSingle(index) =>
-                write!(f, "Test single sub routine: {}", index),
-            TestCases::SrUpTo(index) =>
-                write!(f, "Test all sub routines up to {}", index),
-            TestCases::SrOutOfBounds =>
-                write!(f, "Test out of bounds sub routine."),
-            TestCases::SrEarlyStopWithDelay(index, delay) =>
-                write!(f, "Test sub routine #{} early stop with delay: {}", index, delay),
-            TestCases::SrEarlyStopAllDelays(index) =>
-                write!(f, "Test sub routine #{} early stop with all delays", index),
-            TestCases::SrEarlyStopWithDelayOnAllUpTo(index, delay) => {
-                write!(f, "Test all sub routines up to #{} early stop with delay {}", index, delay)
+/// Walks the user through picking a test case and its parameters.
+fn prompt_test_case(color_theme: &ColorfulTheme, ramp_enabled: bool) -> dialoguer::Result<TestCases> {
+    let mut selections = vec![
+        "Execute SR",
+        "Early stop",
+        "Out of bounds",
+        "Stress",
+    ];
+    if ramp_enabled {
+        selections.push("Ramp");
+    }
+
+    let selection = Select::with_theme(color_theme)
+        .with_prompt("Select a test case")
+        .default(0)
+        .items(&selections[..])
+        .interact()?;
+
+    info!("Running test: {}!", selections[selection]);
+    let test_case = match selection {
+        0 => { // Execute SR
+            let selection = Select::with_theme(color_theme)
+                .with_prompt("What routines to test")
+                .default(0)
+                .items(&["Single manual index", "All indices up to user specified value", "Single index after a fault reset"])
+                .interact()?;
+            let index: u16 = Input::with_theme(color_theme)
+                .with_prompt("Sub routine index: ")
+                .interact_text()?;
+            match selection {
+                0 => TestCases::SrSingle(index),
+                1 => TestCases::SrUpTo(index),
+                _ => TestCases::FaultRecovery(index),
             }
+        },
+        1 => {
+            let selection = Select::with_theme(color_theme)
+                .with_prompt("How should early stop be tested? (How long to wait before early stop)")
+                .default(0)
+                .items(&[
+                    "All delays on specific sub routine",
+                    "Specific delay on specific sub routine",
+                    "Specific delay on all sub routines up to \'n\'"])
+                .interact()?;
+            if selection == 0 {
+                let index: u16 = Input::with_theme(color_theme)
+                    .with_prompt("Sub routine index: ")
+                    .interact_text()?;
+                let schedule = prompt_sweep_schedule(color_theme)?;
+                TestCases::SrEarlyStopAllDelays(index, schedule)
+            } else if selection == 1 {
+                let index: u16 = Input::with_theme(color_theme)
+                    .with_prompt("Sub routine index: ")
+                    .interact_text()?;
+                let delay: u16 = Input::with_theme(color_theme)
+                    .with_prompt("Delay after writing enable high to cancel op (ms)")
+                    .interact_text()?;
+                TestCases::SrEarlyStopWithDelay(index, delay)
+            } else {
+                let index: u16 = Input::with_theme(color_theme)
+                    .with_prompt("Test all sub routines up to index: ")
+                    .interact_text()?;
+                let delay: u16 = Input::with_theme(color_theme)
+                    .with_prompt("Delay after writing enable high to cancel op (ms)")
+                    .interact_text()?;
+                TestCases::SrEarlyStopWithDelayOnAllUpTo(index, delay)
+            }
+        }
+        2 => {
+            TestCases::SrOutOfBounds
+        }
+        3 => {
+            let selection = Select::with_theme(color_theme)
+                .with_prompt("Which stress test?")
+                .default(0)
+                .items(&["Rapid enable toggle", "Start while busy", "Soak"])
+                .interact()?;
+            if selection == 2 {
+                let idx: u16 = Input::with_theme(color_theme)
+                    .with_prompt("Sub routine index: ")
+                    .interact_text()?;
+                let iterations: u32 = Input::with_theme(color_theme)
+                    .with_prompt("Iterations")
+                    .interact_text()?;
+                TestCases::Soak { idx, iterations }
+            } else if selection == 0 {
+                let count: u16 = Input::with_theme(color_theme)
+                    .with_prompt("Number of enable rising edges")
+                    .interact_text()?;
+                let interval: u16 = Input::with_theme(color_theme)
+                    .with_prompt("Time to hold enable high, then low, per toggle (ms)")
+                    .interact_text()?;
+                TestCases::RapidEnableToggle(count, interval)
+            } else {
+                let index: u16 = Input::with_theme(color_theme)
+                    .with_prompt("Sub routine index: ")
+                    .interact_text()?;
+                TestCases::StartWhileBusy(index)
+            }
+        }
+        _ => {
+            let target: u16 = Input::with_theme(color_theme)
+                .with_prompt("Setpoint to ramp to")
+                .interact_text()?;
+            TestCases::RampConverges(target)
+        }
+    };
+    Ok(test_case)
+}
+
+/// Offers previously run test cases, newest first.
+fn prompt_history(color_theme: &ColorfulTheme, history: &TestHistory) -> dialoguer::Result<TestCases> {
+    let entries: Vec<&TestCases> = history.entries().iter().rev().collect();
+    let items: Vec<String> = entries.iter().map(|test_case| format!("{test_case:?}")).collect();
+    let selection = Select::with_theme(color_theme)
+        .with_prompt("Which test case?")
+        .default(0)
+        .items(&items)
+        .interact()?;
+    Ok(entries[selection].clone())
+}
+
+/// Manual controls for the simulated arm's fault injection.
+fn prompt_sim_controls(color_theme: &ColorfulTheme, shared_state: &SharedModbusState) -> dialoguer::Result<()> {
+    let mut items = vec!["Jam next motion", "Clear jam", "Mislatch next index", "Pulse running",
+        if shared_state.motion_paused() { "Resume motion" } else { "Pause motion" }, "Set motion duration",
+        if shared_state.frozen() { "Unfreeze input registers" } else { "Freeze input registers" }];
+    if shared_state.watchdog().is_some() {
+        items.push(if shared_state.heartbeat_paused() { "Resume heartbeat" } else { "Pause heartbeat" });
+    }
+    let selection = Select::with_theme(color_theme)
+        .with_prompt("Simulated arm controls")
+        .default(0)
+        .items(&items)
+        .interact()?;
+    match selection {
+        0 => {
+            shared_state.jam_next_motion();
+            info!("Next simulated motion will jam until cleared");
+        }
+        1 => {
+            shared_state.clear_jam();
+            info!("Simulated arm jam cleared");
+        }
+        2 => {
+            shared_state.mislatch_next_index();
+            info!("Next simulated motion will latch the wrong index");
+        }
+        3 => {
+            let pulse_ms: u64 = Input::with_theme(color_theme)
+                .with_prompt("Pulse width (ms)")
+                .interact_text()?;
+            shared_state.pulse_running(Duration::from_millis(pulse_ms));
+            info!("Pulsing running for {pulse_ms} ms");
+        }
+        4 if shared_state.motion_paused() => {
+            shared_state.resume_motion();
+            info!("Simulated motion resumed");
+        }
+        4 => {
+            shared_state.pause_motion();
+            info!("Simulated motion paused");
+        }
+        5 => prompt_motion_duration(color_theme, shared_state)?,
+        6 if shared_state.frozen() => {
+            shared_state.unfreeze();
+            info!("Input registers unfrozen");
+        }
+        6 => {
+            shared_state.freeze();
+            info!("Input registers frozen, reads return this snapshot until unfrozen");
+        }
+        _ => {
+            let paused = !shared_state.heartbeat_paused();
+            shared_state.pause_heartbeat(paused);
+            info!("Watchdog heartbeat {}", if paused { "paused" } else { "resumed" });
         }
     }
+    Ok(())
 }
 
-async fn tui_thread(shared_state: SharedModbusState) {
+/// Changes how long the simulated arm's motions take, for all indices or just one, from the next
+/// motion on.
+fn prompt_motion_duration(color_theme: &ColorfulTheme, shared_state: &SharedModbusState) -> dialoguer::Result<()> {
+    let for_one_index = Select::with_theme(color_theme)
+        .with_prompt("Motion duration of")
+        .default(0)
+        .items(&["All indices", "One index"])
+        .interact()? == 1;
+    let idx: Option<u32> = if for_one_index {
+        Some(Input::with_theme(color_theme)
+            .with_prompt("Index")
+            .interact_text()?)
+    } else {
+        None
+    };
+    let motion_ms: u64 = Input::with_theme(color_theme)
+        .with_prompt("Motion duration (ms)")
+        .interact_text()?;
+    let duration = Duration::from_millis(motion_ms);
+    match idx {
+        Some(idx) => {
+            shared_state.set_index_motion_duration(idx, Some(duration));
+            info!("Simulated motions of #{idx} take {duration:?} from the next one on");
+        }
+        None => {
+            shared_state.set_motion_duration(duration);
+            info!("Simulated motions take {duration:?} from the next one on");
+        }
+    }
+    Ok(())
+}
+
+fn prompt_boot_delay(color_theme: &ColorfulTheme) -> dialoguer::Result<Duration> {
+    let boot_ms: u64 = Input::with_theme(color_theme)
+        .with_prompt("Boot delay (ms)")
+        .default(DEFAULT_BOOT_DELAY.as_millis() as u64)
+        .interact_text()?;
+    Ok(Duration::from_millis(boot_ms))
+}
+
+/// Settings that only matter to the interactive TUI.
+struct TuiConfig {
+    /// Where early-stop sweep results are appended, if anywhere.
+    csv_path: Option<PathBuf>,
+    /// Where run test cases are kept for reruns across sessions, if anywhere.
+    history_path: Option<PathBuf>,
+    sim_enabled: bool,
+    /// Ask for the register map at startup, unless `--register-map` already picked one.
+    prompt_register_map: bool,
+    /// Units 2 and up from `--units`, which take the register map picked for unit 1 too.
+    other_units: Vec<SharedModbusState>,
+}
+
+const RUN_TEST_CASE: &str = "Run a test case";
+const RERUN_LAST: &str = "Rerun last test case";
+const RERUN_FROM_HISTORY: &str = "Rerun from history";
+const SERVER_METRICS: &str = "Server metrics";
+const SIM_CONTROLS: &str = "Simulated arm controls";
+const POWER_CYCLE: &str = "Simulate power cycle";
+
+async fn tui_thread(
+    shared_state: SharedModbusState,
+    test_config: TestConfig,
+    tui_config: TuiConfig,
+    metrics: Arc<Metrics>,
+    connections: Arc<ConnectionTracker>,
+) {
     let color_theme = ColorfulTheme::default();
 
+    if tui_config.prompt_register_map {
+        match prompt_for_register_map(&color_theme) {
+            Ok(map) => {
+                if !shared_state.index_width().fits(&map) {
+                    warn!("The {} register index overlaps the fault register, the index will corrupt it",
+                        shared_state.index_width().registers());
+                }
+                shared_state.set_register_map(map);
+                for unit in &tui_config.other_units {
+                    unit.set_register_map(map);
+                }
+                info!("Register map: {map}");
+            }
+            Err(err) => error!("Failed to choose a register map, keeping the default: {err}"),
+        }
+    }
+
+    let mut sweep_csv = match tui_config.csv_path.as_deref().map(SweepCsv::open).transpose() {
+        Ok(csv) => csv,
+        Err(err) => {
+            error!("Failed to open CSV file, sweep results will only be logged: {err}");
+            None
+        }
+    };
+    let mut history = match TestHistory::open(tui_config.history_path.as_deref()) {
+        Ok(history) => history,
+        Err(err) => {
+            error!("Failed to open test history, it will not be kept across sessions: {err}");
+            TestHistory::open(None).expect("in-memory history can't fail")
+        }
+    };
+
     // Give the server some time for starting up
     tokio::time::sleep(Duration::from_secs(1)).await;
-    if !CLIENT_CONNECTED.load(Ordering::Relaxed) {
+    // A dry run never talks to the arm, so there's nothing to wait for
+    if !test_config.dry_run && !connections.client_seen() {
         warn!("No client connected yet. Waiting for connection...");
-        while(!CLIENT_CONNECTED.load(Ordering::Relaxed)) {
+        while !connections.client_seen() {
             tokio::time::sleep(Duration::from_millis(10)).await;
         }
     }
     
-    info!("Client is connected - ready to run tests");
-    
-    let mut test_success;
+    if test_config.dry_run {
+        info!("Dry run - test cases will only be described");
+    } else {
+        info!("Client is connected - ready to run tests");
+    }
     
+    let mut tally = SessionTally::default();
     loop {
+        if test_config.reset_between_tests {
+            shared_state.reset();
+        }
+        if !tally.is_empty() {
+            info!("Session so far: {}", tally.render(&color_theme.values_style, &color_theme.error_style));
+        }
 
-        
-
-        test_success = true;
-        let selections = &[
-            "Execute SR",
-            "Early stop",
-            "Out of bounds",
-        ];
-
-        let selection = Select::with_theme(&color_theme)
-            .with_prompt("Select a test case")
-            .default(0)
-            .items(&selections[..])
-            .interact()
-            .unwrap();
-
-        info!("Running test: {}!", selections[selection]);
-        let test_case: TestCases = match selection {
-            0 => { // Execute SR
-                let selection = Select::with_theme(&color_theme)
-                    .with_prompt("What routines to test")
-                    .default(0)
-                    .items(&["Single manual index", "All indices up to user specified value"])
-                    .interact()
-                    .unwrap();
-                let index: u16 = Input::with_theme(&color_theme)
-                    .with_prompt("Sub routine index: ")
-                    .interact_text()
-                    .unwrap();
-                if selection == 0 {
-                    TestCases::SrSingle(index)
-                } else {
-                    TestCases::SrUpTo(index)
-                }
-            },
-            1 => {
-                let selection = Select::with_theme(&color_theme)
-                    .with_prompt("How should early stop be tested? (How long to wait before early stop)")
-                    .default(0)
-                    .items(&[
-                        "All delays on specific sub routine",
-                        "Specific delay on specific sub routine",
-                        "Specific delay on all sub routines up to \'n\'"])
-                    .interact()
-                    .unwrap();
-                if selection == 0 {
-                    let index: u16 = Input::with_theme(&color_theme)
-                        .with_prompt("Sub routine index: ")
-                        .interact_text()
-                        .unwrap();
-                    TestCases::SrEarlyStopAllDelays(index)
-                } else if selection == 1 {
-                    let index: u16 = Input::with_theme(&color_theme)
-                        .with_prompt("Sub routine index: ")
-                        .interact_text()
-                        .unwrap();
-                    let delay: u16 = Input::with_theme(&color_theme)
-                        .with_prompt("Delay after writing enable high to cancel op (ms)")
-                        .interact_text()
-                        .unwrap();
-                    TestCases::SrEarlyStopWithDelay(index, delay)
-                } else {
-                    let index: u16 = Input::with_theme(&color_theme)
-                        .with_prompt("Test all sub routines up to index: ")
-                        .interact_text()
-                        .unwrap();
-                    let delay: u16 = Input::with_theme(&color_theme)
-                        .with_prompt("Delay after writing enable high to cancel op (ms)")
-                        .interact_text()
-                        .unwrap();
-                    TestCases::SrEarlyStopWithDelayOnAllUpTo(index, delay)
+        let mut next_steps = vec![RUN_TEST_CASE];
+        if !history.entries().is_empty() {
+            next_steps.extend([RERUN_LAST, RERUN_FROM_HISTORY]);
+        }
+        next_steps.push(SERVER_METRICS);
+        if tui_config.sim_enabled {
+            next_steps.push(SIM_CONTROLS);
+        }
+        next_steps.push(POWER_CYCLE);
+        // Only running a test starts a new iteration, so e.g. a jam set up from the menu survives
+        // until the test it was meant for
+        let test_case = loop {
+            let selection = match Select::with_theme(&color_theme)
+                .with_prompt("What next?")
+                .default(0)
+                .items(&next_steps)
+                .interact() {
+                Ok(selection) => selection,
+                Err(err) => {
+                    warn!("Selection aborted: {err}");
+                    return;
                 }
-            }
-            _ => {
-                TestCases::SrOutOfBounds
-            }
-        };
-
-        info!("Test selected: \n\t{test_case:?}");
-
-        match &test_case {
-            TestCases::SrSingle(index) => {
-                info!("Arm should execute sub routine: {index} and then stop.");
-                match sr_single_shared(&shared_state, *index).await {
-                    Ok(_) => info!("Subroutine {index} completed successfully"),
+            };
+            match next_steps[selection] {
+                RUN_TEST_CASE => match prompt_test_case(&color_theme, shared_state.ramp().is_some()) {
+                    Ok(test_case) => break test_case,
                     Err(err) => {
-                        error!("Subroutine failed: {err}");
-                        test_success = false;
-                        shared_state.write_coil(ENABLE_COIL_OFFSET, false);
+                        warn!("Test selection aborted: {err}");
+                        return;
                     }
-                };
-            },
-            TestCases::SrUpTo(index) => {
-                info!("Arm should fully execute all sub routines from 0 up to {index} and then stop.");
-                for i in 0..=*index {
-                    match sr_single_shared(&shared_state, i).await {
-                        Ok(_) => {
-                            info!("Subroutine {i}/{index} completed successfully.");
-                        },
-                        Err(err) => {
-                            error!("Subroutine failed: {err}");
-                            test_success = false;
-                            shared_state.write_coil(ENABLE_COIL_OFFSET, false);
-                            break;
-                        }
-                    }
-                }
-            },
-            TestCases::SrOutOfBounds => {
-                info!("Arm should execute sub routine 65535 (assumed this does not exist). \
-                Just make sure nothing breaks. Could just run a default sr or do nothing \
-                as long as running is blipped for enough time to be read true");
-                match sr_single_shared(&shared_state, 65535).await {
-                    Ok(_) => info!("Subroutine 65535 completed successfully"),
-                    Err(err) => {
-                        test_success = false;
-                        error!("Subroutine 65535 failed: {err}")
+                },
+                RERUN_LAST => {
+                    if let Some(test_case) = history.last() {
+                        break test_case.clone();
                     }
                 }
-            },
-            TestCases::SrEarlyStopWithDelay(idx, delay) => {
-                info!("Arm should start execution of sub routine {idx} and then stop after {delay} ms.");
-                match sr_single_early_stop_shared(&shared_state, *idx, Duration::from_millis(*delay as u64)).await {
-                    Ok(EarlyStopResult::Success) => info!("Subroutine {idx} was stopped early successfully"),
-                    Ok(EarlyStopResult::TooLate) => warn!("Subroutine {idx} completed before it could be stopped early"),
+                RERUN_FROM_HISTORY => match prompt_history(&color_theme, &history) {
+                    Ok(test_case) => break test_case,
                     Err(err) => {
-                        test_success = false;
-                        error!("Subroutine {idx} failed stopping early: {err}");
-                        shared_state.write_coil(ENABLE_COIL_OFFSET, false);
+                        warn!("History selection aborted: {err}");
+                        return;
                     }
-                }
-            },
-            TestCases::SrEarlyStopWithDelayOnAllUpTo(idx, delay) => {
-                info!("Arm should start execution of each sub routine [0..={idx}] and stop each one after {delay} ms.");
-                for i in 0..=*idx {
-                    match sr_single_early_stop_shared(&shared_state, i, Duration::from_millis(*delay as u64)).await {
-                        Ok(EarlyStopResult::Success) => info!("Subroutine {i} was stopped early successfully"),
-                        Ok(EarlyStopResult::TooLate) => warn!("Subroutine {i} completed before it could be stopped early"),
-                        Err(err) => {
-                            test_success = false;
-                            error!("Subroutine {i} failed stopping early: {err}");
-                            shared_state.write_coil(ENABLE_COIL_OFFSET, false);
-                            break;
-                        }
+                },
+                SERVER_METRICS => {
+                    let snapshot = metrics.snapshot();
+                    info!("Server metrics: {} requests, {} exceptions, {} under the request floor, {} failed broadcasts, \
+                        {} open connections", snapshot.requests_total(), snapshot.exceptions_total(), snapshot.too_fast,
+                        snapshot.broadcast_failures, connections.active());
+                    for function in &snapshot.functions {
+                        info!("    {function}");
                     }
                 }
-            },
-            TestCases::SrEarlyStopAllDelays(idx) => {
-                info!("Arm should be given longer and longer periods of time to complete sub routine {idx} until it fully completes");
-                let mut delay = Duration::from_millis(0);
-                let mut increment = Duration::from_micros(1);
-                let max_inc = Duration::from_secs(2);
-                loop {
-                    delay += increment;
-                    if increment < max_inc {
-                        increment *= 4;
+                POWER_CYCLE => match prompt_boot_delay(&color_theme) {
+                    Ok(boot_delay) => simulate_power_cycle(&shared_state, &connections, boot_delay).await,
+                    Err(err) => {
+                        warn!("Power cycle aborted: {err}");
+                        return;
                     }
-                    debug!("Testing with delay: {:?}", delay);
-                    match sr_single_early_stop_shared(&shared_state, *idx, delay).await {
-                        Ok(EarlyStopResult::Success) => info!("Subroutine {idx} was stopped early at {:?} successfully", delay),
-                        Ok(EarlyStopResult::TooLate) => {
-                            warn!("Subroutine {idx} completed before it could be stopped early at {:?}", delay);
-                            break;
-                        },
-                        Err(err) => {
-                            test_success = false;
-                            error!("Subroutine {idx} failed stopping early at {:?}: {err}", delay);
-                            shared_state.write_coil(ENABLE_COIL_OFFSET, false);
-                            break;
-                        }
+                },
+                _ => {
+                    if let Err(err) = prompt_sim_controls(&color_theme, &shared_state) {
+                        warn!("Simulated arm controls aborted: {err}");
+                        return;
                     }
                 }
             }
+        };
+
+        info!("Test selected: \n\t{test_case:?}");
+        if let Err(err) = history.record(&test_case) {
+            error!("Failed to save test history: {err}");
+        }
+
+        if test_config.dry_run {
+            info!("Dry run, nothing will be written. The test would:");
+            for step in plan_test_case(&test_case, &test_config, &shared_state) {
+                info!("    {step}");
+            }
+            continue;
         }
+
+        // Every sub routine run, wait and early stop of the test case nests under this span
+        let span = info_span!("test_case", test_case = ?test_case, passed = field::Empty);
+        let run = run_test_case_shared(&shared_state, &tui_config.other_units, &test_config, &test_case, &mut sweep_csv)
+            .instrument(span.clone());
+        let test_success = run_within_budget(&shared_state, &test_config, run).await;
+        span.record("passed", test_success);
+        tally.record(&test_case, test_success);
         info!("Finished test: {:?}", &test_case);
         if test_success {
             info!("✅ Test was successful!");
         } else {
-            error!("❌ Test failed!")
+            error!("❌ Test failed!");
+            let changes = shared_state.recent_changes(FAILURE_HISTORY_DUMP_LEN);
+            error!("Last {} state changes before the failure (oldest first):", changes.len());
+            for change in changes {
+                match shared_state.regions().find(change.kind.into(), change.address) {
+                    Some(region) => error!("    {change}  ({})", region.name),
+                    None => error!("    {change}"),
+                }
+            }
+            let map = shared_state.register_map();
+            for (name, addr) in [("enable", map.enable_coil), ("running", map.running_coil)] {
+                match shared_state.last_written(ChangeKind::Coil, addr) {
+                    Some(at) => error!("The {name} coil was last written {:?} ago", at.elapsed()),
+                    None => error!("The {name} coil hasn't been written since the reset"),
+                }
+            }
+        }
+
+        if !Confirm::with_theme(&color_theme)
+            .with_prompt("Do you want to continue?")
+            .default(true)
+            .interact()
+            .unwrap_or(false)
+        {
+            let mut summary = tally.summary(&color_theme.values_style, &color_theme.error_style).into_iter();
+            if let Some(counts) = summary.next() {
+                info!("Session summary: {counts}");
+            }
+            for failure in summary {
+                info!("{failure}");
+            }
+            return
+        }
+    }
+}
+
+/// `--bench [--bench-secs <secs>] [--bench-connections <n>]`: measures the request rate the
+/// simulator sustains for a single connection and for several concurrent ones, then exits.
+async fn run_bench(args: &[String]) -> anyhow::Result<()> {
+    let duration = match arg_value(args, "--bench-secs", None)? {
+        Some(secs) => Duration::from_secs(secs.parse().map_err(|_| Error::bad_arg("benchmark duration", secs))?),
+        None => DEFAULT_BENCH_DURATION,
+    };
+    let connections = match arg_value(args, "--bench-connections", None)? {
+        Some(count) => match count.parse() {
+            Ok(count) if count > 0 => count,
+            _ => return Err(Error::bad_arg("benchmark connection count", count).into()),
+        },
+        None => DEFAULT_BENCH_CONNECTIONS,
+    };
+    info!("Measuring {duration:?} of reads and of writes each, over 1 and {connections} connections");
+    for count in [1, connections] {
+        let throughput = measure_throughput(count, duration).await.context("Benchmark failed")?;
+        info!("{throughput}");
+    }
+    Ok(())
+}
+
+/// The TUI for `--connect`: runs test cases over Modbus against the arm at `remote`. Nothing is
+/// served, so only the test cases that need just the handshake are available.
+async fn remote_tui(
+    remote: SocketAddr,
+    test_config: TestConfig,
+    register_map: Option<RegisterMap>,
+    csv_path: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let color_theme = ColorfulTheme::default();
+    let map = match register_map {
+        Some(map) => map,
+        None => prompt_for_register_map(&color_theme)?,
+    };
+    info!("Register map: {map}");
+    let mut ctx = tcp::connect(remote).await
+        .with_context(|| format!("Failed to connect to {remote}"))?;
+    info!("Connected to {remote} - ready to run tests");
+    let mut sweep_csv = match csv_path.as_deref().map(SweepCsv::open).transpose() {
+        Ok(csv) => csv,
+        Err(err) => {
+            error!("Failed to open CSV file, sweep results will only be logged: {err}");
+            None
+        }
+    };
+
+    let mut tally = SessionTally::default();
+    loop {
+        let test_case = prompt_test_case(&color_theme, false)?;
+        info!("Test selected: \n\t{test_case:?}");
+        let test_success = run_remote_test_case(&mut ctx, &map, &test_config, &test_case, &mut sweep_csv).await;
+        tally.record(&test_case, test_success);
+        if test_success {
+            info!("✅ Test was successful!");
+        } else {
+            error!("❌ Test failed!");
         }
+        info!("Session so far: {}", tally.render(&color_theme.values_style, &color_theme.error_style));
 
         if !Confirm::with_theme(&color_theme)
             .with_prompt("Do you want to continue?")
             .default(true)
             .interact()
-            .unwrap()
-        { return }
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Offers the built-in register map presets, or entering every address by hand.
+fn prompt_for_register_map(color_theme: &ColorfulTheme) -> dialoguer::Result<RegisterMap> {
+    let mut items: Vec<String> = RegisterMap::PRESETS.iter()
+        .map(|(name, map)| format!("{name} ({map})"))
+        .collect();
+    items.push("Custom".to_string());
+    let selection = Select::with_theme(color_theme)
+        .with_prompt("Register map")
+        .default(0)
+        .items(&items)
+        .interact()?;
+    if let Some((_, map)) = RegisterMap::PRESETS.get(selection) {
+        return Ok(*map);
+    }
+    let default = RegisterMap::DEFAULT;
+    let address = |prompt: &str, default: u16| Input::with_theme(color_theme)
+        .with_prompt(prompt)
+        .default(default)
+        .interact_text();
+    Ok(RegisterMap {
+        enable_coil: address("Enable coil", default.enable_coil)?,
+        running_coil: address("Running coil", default.running_coil)?,
+        ready_coil: address("Ready coil", default.ready_coil)?,
+        index_hreg: address("Index holding register", default.index_hreg)?,
+        fault_hreg: address("Fault holding register", default.fault_hreg)?,
+    })
+}
+
+fn prompt_sweep_schedule(color_theme: &ColorfulTheme) -> dialoguer::Result<SweepSchedule> {
+    let selection = Select::with_theme(color_theme)
+        .with_prompt("How should the delay be increased between attempts?")
+        .default(0)
+        .items(&[
+            "Geometric (multiply the increment each attempt)",
+            "Linear (fixed step each attempt)",
+            "Binary search between two known delays"])
+        .interact()?;
+    let schedule = match selection {
+        0 => {
+            let factor: u32 = Input::with_theme(color_theme)
+                .with_prompt("Increment growth factor")
+                .default(4)
+                .validate_with(|factor: &u32| if *factor >= SweepSchedule::MIN_GEOMETRIC_FACTOR {
+                    Ok(())
+                } else {
+                    Err(format!("Factor must be at least {}", SweepSchedule::MIN_GEOMETRIC_FACTOR))
+                })
+                .interact_text()?;
+            SweepSchedule::Geometric { factor }
+        }
+        1 => {
+            let step: u64 = Input::with_theme(color_theme)
+                .with_prompt("Delay step (ms)")
+                .validate_with(|step: &u64| if *step > 0 { Ok(()) } else { Err("Step must be greater than 0") })
+                .interact_text()?;
+            SweepSchedule::Linear { step: Duration::from_millis(step) }
+        }
+        _ => {
+            let too_short: u64 = Input::with_theme(color_theme)
+                .with_prompt("Delay known to stop the arm early (ms)")
+                .default(0)
+                .interact_text()?;
+            let long_enough: u64 = Input::with_theme(color_theme)
+                .with_prompt("Delay known to let the sub routine complete (ms)")
+                .validate_with(|long_enough: &u64| if *long_enough > too_short {
+                    Ok(())
+                } else {
+                    Err(format!("Must be longer than the delay known to stop early ({too_short} ms)"))
+                })
+                .interact_text()?;
+            let resolution: u64 = Input::with_theme(color_theme)
+                .with_prompt("Stop once the bracket is this narrow (ms)")
+                .default(1)
+                .validate_with(|resolution: &u64| if *resolution > 0 {
+                    Ok(())
+                } else {
+                    Err("Resolution must be greater than 0")
+                })
+                .interact_text()?;
+            SweepSchedule::BinarySearch {
+                too_short: Duration::from_millis(too_short),
+                long_enough: Duration::from_millis(long_enough),
+                resolution: Duration::from_millis(resolution),
+            }
+        }
+    };
+    Ok(schedule)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Parser<T> = fn(&[String]) -> Result<T, Error>;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// The message a parser refused `given` with, `None` if it accepted it.
+    fn refusal<T>(parse: impl Fn(&[String]) -> Result<T, Error>, given: &[&str]) -> Option<String> {
+        parse(&args(given)).err().map(|err| err.to_string())
+    }
+
+    #[test]
+    fn control_mode() {
+        assert_eq!(parse_control_mode(&args(&[])).ok(), Some(ControlMode::Tui));
+        assert_eq!(parse_control_mode(&args(&["--json"])).ok(), Some(ControlMode::Json));
+        assert_eq!(parse_control_mode(&args(&["--headless"])).ok(), Some(ControlMode::Headless));
+        assert_eq!(refusal(parse_control_mode, &["--json", "--headless"]).as_deref(),
+            Some("Invalid control mode: --json with --headless (pick one)"));
+    }
+
+    #[test]
+    fn missing_value() {
+        assert_eq!(refusal(parse_port_arg, &["--port"]).as_deref(), Some("Invalid --port: nothing (a value is required)"));
+        assert_eq!(refusal(parse_port_arg, &["-p"]).as_deref(), Some("Invalid --port: nothing (a value is required)"),
+            "The short flag is reported by its long name");
+    }
+
+    #[test]
+    fn bind_and_connect() {
+        assert_eq!(parse_bind_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_bind_arg(&args(&["--bind", "10.0.0.5"])).ok(), Some(Some(Ipv4Addr::new(10, 0, 0, 5))));
+        assert_eq!(refusal(parse_bind_arg, &["--bind", "localhost"]).as_deref(), Some("Invalid IPv4 address: localhost"));
+        assert_eq!(parse_connect_arg(&args(&["--connect", "127.0.0.1:1502"])).ok(),
+            Some(Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 1502)))));
+        assert_eq!(refusal(parse_connect_arg, &["--connect", "arm:502"]).as_deref(), Some("Invalid address to connect to: arm:502"));
+    }
+
+    #[test]
+    fn port() {
+        assert_eq!(parse_port_arg(&args(&[])).ok(), Some(DEFAULT_PORT));
+        assert_eq!(parse_port_arg(&args(&["--port", "1502"])).ok(), Some(1502));
+        assert_eq!(parse_port_arg(&args(&["-p", "1502"])).ok(), Some(1502));
+        for port in ["0", "70000", "modbus"] {
+            assert_eq!(refusal(parse_port_arg, &["--port", port]), Some(format!("Invalid port number: {port}")));
+        }
+    }
+
+    #[test]
+    fn log_level() {
+        assert_eq!(parse_log_level_arg(&args(&[])).ok(), Some(None), "RUST_LOG applies without the flag");
+        for (level_str, level) in [
+            ("off", LevelFilter::Off),
+            ("error", LevelFilter::Error),
+            ("warn", LevelFilter::Warn),
+            ("info", LevelFilter::Info),
+            ("debug", LevelFilter::Debug),
+            ("trace", LevelFilter::Trace),
+            ("WARN", LevelFilter::Warn),
+            ("Debug", LevelFilter::Debug),
+        ] {
+            assert_eq!(parse_log_level_arg(&args(&["--log-level", level_str])).ok(), Some(Some(level)), "--log-level {level_str}");
+        }
+        assert_eq!(parse_log_level_arg(&args(&["-l", "trace"])).ok(), Some(Some(LevelFilter::Trace)));
+        assert_eq!(refusal(parse_log_level_arg, &["--log-level", "loud"]).as_deref(),
+            Some("Invalid log level: loud (expected one of off, error, warn, info, debug, trace)"));
+    }
+
+    #[test]
+    fn paths() {
+        let parsers: [(&str, Parser<Option<PathBuf>>); 6] = [
+            ("--csv", parse_csv_arg),
+            ("--replay", parse_replay_arg),
+            ("--golden", parse_golden_arg),
+            ("--capture", parse_capture_arg),
+            ("--log-file", parse_log_file_arg),
+            ("--test-history", parse_test_history_arg),
+        ];
+        for (flag, parse) in parsers {
+            assert_eq!(parse(&args(&[])).ok(), Some(None), "{flag} is off unless given");
+            assert_eq!(parse(&args(&[flag, "runs/a.txt"])).ok(), Some(Some(PathBuf::from("runs/a.txt"))), "{flag} path");
+        }
+    }
+
+    #[test]
+    fn poll_interval_and_stable_reads() {
+        assert_eq!(parse_poll_interval_arg(&args(&[])).ok(), Some(TestConfig::DEFAULT_POLL_INTERVAL));
+        assert_eq!(parse_poll_interval_arg(&args(&["--poll-interval", "25"])).ok(), Some(Duration::from_millis(25)));
+        assert_eq!(refusal(parse_poll_interval_arg, &["--poll-interval", "0"]).as_deref(),
+            Some("Invalid poll interval: 0 (must be greater than 0)"));
+        assert_eq!(refusal(parse_poll_interval_arg, &["--poll-interval", "fast"]).as_deref(), Some("Invalid poll interval: fast"));
+        assert_eq!(parse_stable_reads_arg(&args(&[])).ok(), Some(TestConfig::DEFAULT_STABLE_READS));
+        assert_eq!(parse_stable_reads_arg(&args(&["--stable-reads", "3"])).ok(), Some(3));
+        assert_eq!(refusal(parse_stable_reads_arg, &["--stable-reads", "0"]).as_deref(),
+            Some("Invalid stable read count: 0 (must be greater than 0)"));
+        assert_eq!(refusal(parse_stable_reads_arg, &["--stable-reads", "-1"]).as_deref(), Some("Invalid stable read count: -1"));
+    }
+
+    #[test]
+    fn parallel() {
+        assert_eq!(parse_parallel_arg(&args(&[])).ok(), Some(1));
+        assert_eq!(parse_parallel_arg(&args(&["--parallel", "4"])).ok(), Some(4));
+        for parallel in ["0", "all"] {
+            assert_eq!(refusal(parse_parallel_arg, &["--parallel", parallel]),
+                Some(format!("Invalid parallel run count: {parallel} (must be greater than 0)")));
+        }
+    }
+
+    #[test]
+    fn timeouts() {
+        assert_eq!(parse_idle_timeout_arg(&args(&[])).ok(), Some(Some(DEFAULT_IDLE_TIMEOUT)));
+        assert_eq!(parse_idle_timeout_arg(&args(&["--idle-timeout", "5"])).ok(), Some(Some(Duration::from_secs(5))));
+        assert_eq!(parse_idle_timeout_arg(&args(&["--idle-timeout", "0"])).ok(), Some(None), "0 disables the idle timeout");
+        assert_eq!(refusal(parse_idle_timeout_arg, &["--idle-timeout", "soon"]).as_deref(), Some("Invalid idle timeout: soon"));
+
+        assert_eq!(parse_require_client_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_require_client_arg(&args(&["--require-client", "5"])).ok(), Some(Some(Duration::from_secs(5))));
+        assert_eq!(refusal(parse_require_client_arg, &["--require-client", "0"]).as_deref(), Some("Invalid client timeout: 0"));
+
+        assert_eq!(parse_test_budget_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_test_budget_arg(&args(&["--test-budget", "30"])).ok(), Some(Some(Duration::from_secs(30))));
+        assert_eq!(refusal(parse_test_budget_arg, &["--test-budget", "1.5"]).as_deref(), Some("Invalid test budget: 1.5"));
+
+        assert_eq!(parse_early_stop_margin_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_early_stop_margin_arg(&args(&["--early-stop-margin", "5"])).ok(), Some(Some(Duration::from_millis(5))));
+        assert_eq!(refusal(parse_early_stop_margin_arg, &["--early-stop-margin", "-5"]).as_deref(),
+            Some("Invalid early stop margin: -5"));
+
+        assert_eq!(parse_wait_for_ready_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_wait_for_ready_arg(&args(&["--wait-for-ready", "3"])).ok(), Some(Some(Duration::from_secs(3))));
+        assert_eq!(refusal(parse_wait_for_ready_arg, &["--wait-for-ready", "3s"]).as_deref(), Some("Invalid ready timeout: 3s"));
+    }
+
+    #[test]
+    fn request_floor() {
+        assert_eq!(parse_request_floor_arg(&args(&[])).ok(), Some(None));
+        let floor = |policy| Some(Some(RequestFloor { min_spacing: Duration::from_millis(20), policy }));
+        assert_eq!(parse_request_floor_arg(&args(&["--request-floor", "20"])).ok(), floor(FloorPolicy::Flag));
+        assert_eq!(parse_request_floor_arg(&args(&["--request-floor", "20:flag"])).ok(), floor(FloorPolicy::Flag));
+        assert_eq!(parse_request_floor_arg(&args(&["--request-floor", "20:reject"])).ok(), floor(FloorPolicy::Reject));
+        assert_eq!(refusal(parse_request_floor_arg, &["--request-floor", "20:drop"]).as_deref(),
+            Some("Invalid request floor policy: drop (expected flag or reject)"));
+        assert_eq!(refusal(parse_request_floor_arg, &["--request-floor", "0:reject"]).as_deref(), Some("Invalid request floor: 0"));
+    }
+
+    #[test]
+    fn identification() {
+        assert_eq!(parse_server_id_arg(&args(&[])).ok().as_deref(), Some(DEFAULT_SERVER_ID));
+        assert_eq!(parse_server_id_arg(&args(&["--server-id", "arm-7"])).ok().as_deref(), Some("arm-7"));
+        let too_long = "x".repeat(MAX_SERVER_ID_LEN + 1);
+        assert_eq!(refusal(parse_server_id_arg, &["--server-id", &too_long]),
+            Some(format!("Invalid server ID: {too_long} ({} bytes, max is {MAX_SERVER_ID_LEN})", too_long.len())));
+
+        assert_eq!(parse_device_identification_args(&args(&[])).ok(), Some(DeviceIdentification::default()));
+        let identification = parse_device_identification_args(&args(&["--vendor-name", "Acme", "--product-code", "A-1",
+            "--revision", "2.0"])).ok();
+        assert_eq!(identification, Some(DeviceIdentification {
+            vendor_name: "Acme".to_string(),
+            product_code: "A-1".to_string(),
+            revision: "2.0".to_string(),
+        }));
+        let too_long = "x".repeat(MAX_DEVICE_ID_LEN);
+        assert!(refusal(parse_device_identification_args, &["--vendor-name", &too_long])
+            .is_some_and(|err| err.starts_with("Invalid device identification: ")));
+    }
+
+    #[test]
+    fn read_only_coils() {
+        assert_eq!(parse_read_only_coils_args(&args(&[])).ok(), Some((HashSet::new(), ReadOnlyPolicy::Reject)));
+        assert_eq!(parse_read_only_coils_args(&args(&["--read-only-coils", "8, 9", "--read-only-policy", "ignore"])).ok(),
+            Some((HashSet::from([8, 9]), ReadOnlyPolicy::Ignore)));
+        assert_eq!(refusal(parse_read_only_coils_args, &["--read-only-coils", "8,x"]).as_deref(), Some("Invalid coil address: x"));
+        assert_eq!(refusal(parse_read_only_coils_args, &["--read-only-policy", "drop"]).as_deref(),
+            Some("Invalid read-only policy: drop (expected reject or ignore)"));
+    }
+
+    #[test]
+    fn region() {
+        assert!(parse_region_arg(&args(&[])).is_ok_and(|regions| regions.find(AddressSpace::Coil, 0).is_none()));
+        let regions = parse_region_arg(&args(&["--region", "coil:control=0..16, ireg:status=0..8"])).ok();
+        let name = |space, addr| regions.as_ref().and_then(|regions| regions.find(space, addr)).map(|region| region.name.as_str());
+        assert_eq!((name(AddressSpace::Coil, 15), name(AddressSpace::Coil, 16)), (Some("control"), None), "End is exclusive");
+        assert_eq!(name(AddressSpace::InputRegister, 3), Some("status"));
+        assert_eq!(name(AddressSpace::HoldingRegister, 3), None);
+        for entry in ["coil:empty=5..5", "coil:=0..4", "disk:spare=0..4", "coil:spare=0-4"] {
+            assert_eq!(refusal(parse_region_arg, &["--region", entry]),
+                Some(format!("Invalid --region entry: {entry} (expected <coil|hreg|ireg>:<name>=<start>..<end>)")));
+        }
+    }
+
+    #[test]
+    fn seed_range() {
+        assert_eq!(parse_seed_range_arg(&args(&[])).ok(), Some(Vec::new()));
+        assert_eq!(parse_seed_range_arg(&args(&["--seed-range", "coil:0..=31,hreg:0..=99:7"])).ok(), Some(vec![
+            SeedRange::new(AddressSpace::Coil, 0..=31, 0),
+            SeedRange::new(AddressSpace::HoldingRegister, 0..=99, 7),
+        ]));
+        for entry in ["coil:0..=3:2", "hreg:5..=4", "hreg:0..4", "ireg:0..=4:x"] {
+            assert_eq!(refusal(parse_seed_range_arg, &["--seed-range", entry]),
+                Some(format!("Invalid --seed-range entry: {entry} (expected <coil|hreg|ireg>:<start>..=<end>[:<value>])")));
+        }
+    }
+
+    #[test]
+    fn alias() {
+        assert_eq!(parse_alias_arg(&args(&[]), "--coil-alias").ok(), Some(HashMap::new()));
+        assert_eq!(parse_alias_arg(&args(&["--coil-alias", "110=10, 111=11"]), "--coil-alias").ok(),
+            Some(HashMap::from([(110, 10), (111, 11)])));
+        assert_eq!(refusal(|args| parse_alias_arg(args, "--register-alias"), &["--register-alias", "110"]).as_deref(),
+            Some("Invalid --register-alias: 110 (expected <alias>=<canonical>)"));
+    }
+
+    #[test]
+    fn disable_fc() {
+        assert_eq!(parse_disable_fc_arg(&args(&[])).ok(), Some(HashSet::new()));
+        assert_eq!(parse_disable_fc_arg(&args(&["--disable-fc", "5, 15"])).ok(), Some(HashSet::from([5, 15])));
+        assert_eq!(refusal(parse_disable_fc_arg, &["--disable-fc", "5,256"]).as_deref(), Some("Invalid function code: 256"));
+    }
+
+    #[test]
+    fn modes() {
+        for (mode, swap) in [
+            ("none", SwapMode::None),
+            ("byte", SwapMode::Byte),
+            ("word", SwapMode::Word),
+            ("byte-word", SwapMode::ByteAndWord),
+        ] {
+            assert_eq!(parse_swap_arg(&args(&["--swap", mode])).ok(), Some(swap), "--swap {mode}");
+        }
+        assert_eq!(parse_swap_arg(&args(&[])).ok(), Some(SwapMode::None));
+        assert_eq!(refusal(parse_swap_arg, &["--swap", "nibble"]).as_deref(),
+            Some("Invalid swap mode: nibble (expected none, byte, word or byte-word)"));
+
+        assert_eq!(parse_enable_mode_arg(&args(&[])).ok(), Some(EnableMode::Edge));
+        assert_eq!(parse_enable_mode_arg(&args(&["--enable-mode", "edge"])).ok(), Some(EnableMode::Edge));
+        assert_eq!(parse_enable_mode_arg(&args(&["--enable-mode", "held"])).ok(), Some(EnableMode::Held));
+        assert_eq!(refusal(parse_enable_mode_arg, &["--enable-mode", "level"]).as_deref(),
+            Some("Invalid enable mode: level (expected edge or held)"));
+
+        assert_eq!(parse_unknown_function_arg(&args(&[])).ok(), Some(UnknownFunctionPolicy::IllegalFunction));
+        for (policy_str, policy) in [
+            ("illegal-function", UnknownFunctionPolicy::IllegalFunction),
+            ("device-failure", UnknownFunctionPolicy::DeviceFailure),
+            ("drop", UnknownFunctionPolicy::Drop),
+        ] {
+            assert_eq!(parse_unknown_function_arg(&args(&["--unknown-function", policy_str])).ok(), Some(policy),
+                "--unknown-function {policy_str}");
+        }
+        assert_eq!(refusal(parse_unknown_function_arg, &["--unknown-function", "echo"]).as_deref(),
+            Some("Invalid unknown function policy: echo (expected illegal-function, device-failure or drop)"));
+    }
+
+    #[test]
+    fn exception_status_bits() {
+        assert_eq!(parse_exception_status_bits_arg(&args(&[])).ok(), Some(ExceptionStatusBits::DEFAULT));
+        assert_eq!(parse_exception_status_bits_arg(&args(&["--exception-status-bits", "3, 2, 1, 0"])).ok(),
+            Some(ExceptionStatusBits { enable: 3, running: 2, ready: 1, fault: 0 }));
+        assert_eq!(refusal(parse_exception_status_bits_arg, &["--exception-status-bits", "0,1,2"]).as_deref(),
+            Some("Invalid exception status bits: 0,1,2 (expected 4: enable,running,ready,fault)"));
+        assert_eq!(refusal(parse_exception_status_bits_arg, &["--exception-status-bits", "0,1,1,2"]).as_deref(),
+            Some("Invalid exception status bits: 0,1,1,2 (must be distinct)"));
+        assert_eq!(refusal(parse_exception_status_bits_arg, &["--exception-status-bits", "0,1,2,8"]).as_deref(),
+            Some("Invalid exception status bit: 8"));
+    }
+
+    #[test]
+    fn service_ports() {
+        assert_eq!(parse_prometheus_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_prometheus_arg(&args(&["--prometheus", "9100"])).ok(), Some(Some(9100)));
+        assert_eq!(refusal(parse_prometheus_arg, &["--prometheus", "http"]).as_deref(), Some("Invalid Prometheus port: http"));
+        assert_eq!(parse_grpc_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_grpc_arg(&args(&["--grpc", "50051"])).ok(), Some(Some(50051)));
+        assert_eq!(refusal(parse_grpc_arg, &["--grpc", "0"]).as_deref(), Some("Invalid gRPC port: 0"));
+    }
+
+    #[test]
+    fn undeclared_defaults() {
+        assert_eq!(parse_register_value("42"), Some(42));
+        assert_eq!(parse_register_value("0x2A"), Some(42));
+        assert_eq!(parse_register_value("0X2a"), Some(42));
+        for value in ["65536", "0x10000", "-1", "0x", ""] {
+            assert_eq!(parse_register_value(value), None, "{value:?} isn't a register value");
+        }
+
+        assert_eq!(parse_undeclared_defaults_args(&args(&[])).ok(), Some(UndeclaredDefaults::default()));
+        assert_eq!(parse_undeclared_defaults_args(&args(&["--undeclared-coil", "1", "--undeclared-hreg", "0xFFFF",
+            "--undeclared-ireg", "32768"])).ok(),
+            Some(UndeclaredDefaults { coil: true, holding_register: 0xFFFF, input_register: 0x8000 }));
+        assert_eq!(refusal(parse_undeclared_defaults_args, &["--undeclared-coil", "true"]).as_deref(),
+            Some("Invalid undeclared coil value: true (expected 0 or 1)"));
+        assert_eq!(refusal(parse_undeclared_defaults_args, &["--undeclared-hreg", "0x10000"]).as_deref(),
+            Some("Invalid undeclared holding register value: 0x10000"));
+        assert_eq!(refusal(parse_undeclared_defaults_args, &["--undeclared-ireg", "-1"]).as_deref(),
+            Some("Invalid undeclared input register value: -1"));
+    }
+
+    #[test]
+    fn addresses() {
+        let parsers: [(&str, Parser<Option<u16>>, &str); 3] = [
+            ("--index-echo", parse_index_echo_arg, "input register address"),
+            ("--progress-ireg", parse_progress_ireg_arg, "input register address"),
+            ("--mirror-writes", parse_mirror_writes_arg, "mirror offset"),
+        ];
+        for (flag, parse, what) in parsers {
+            assert_eq!(parse(&args(&[])).ok(), Some(None), "{flag} is off unless given");
+            assert_eq!(parse(&args(&[flag, "100"])).ok(), Some(Some(100)), "{flag} 100");
+            assert_eq!(refusal(parse, &[flag, "65536"]), Some(format!("Invalid {what}: 65536")));
+        }
+        assert_eq!(parse_auto_run_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_auto_run_arg(&args(&["--auto-run", "70000"])).ok(), Some(Some(70_000)), "Auto-run takes a 32-bit index");
+        assert_eq!(refusal(parse_auto_run_arg, &["--auto-run", "first"]).as_deref(), Some("Invalid auto-run index: first"));
+    }
+
+    #[test]
+    fn index_width() {
+        assert_eq!(parse_index_width_arg(&args(&[])).ok(), Some(IndexWidth::Single));
+        for (width_str, width) in [
+            ("1", IndexWidth::Single),
+            ("2", IndexWidth::Double(WordOrder::BigEndian)),
+            ("2:big", IndexWidth::Double(WordOrder::BigEndian)),
+            ("2:little", IndexWidth::Double(WordOrder::LittleEndian)),
+        ] {
+            assert_eq!(parse_index_width_arg(&args(&["--index-width", width_str])).ok(), Some(width), "--index-width {width_str}");
+        }
+        assert_eq!(refusal(parse_index_width_arg, &["--index-width", "3"]).as_deref(),
+            Some("Invalid index width: 3 (expected 1 or 2 registers)"));
+        assert_eq!(refusal(parse_index_width_arg, &["--index-width", "2:middle"]).as_deref(),
+            Some("Invalid index word order: middle (expected big or little)"));
+    }
+
+    #[test]
+    fn watchdog_fault_and_queue() {
+        assert_eq!(parse_watchdog_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_watchdog_arg(&args(&["--watchdog", "1001:100"])).ok(),
+            Some(Some(WatchdogConfig { coil: 1001, timeout: Duration::from_millis(100) })));
+        assert_eq!(refusal(parse_watchdog_arg, &["--watchdog", "1001"]).as_deref(),
+            Some("Invalid watchdog: 1001 (expected <coil>:<timeout ms>)"));
+        assert_eq!(refusal(parse_watchdog_arg, &["--watchdog", "1001:0"]).as_deref(), Some("Invalid watchdog timeout: 0"));
+
+        assert_eq!(parse_initial_fault_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_initial_fault_arg(&args(&["--initial-fault", "42:1002"])).ok(),
+            Some(Some(InitialFault { code: 42, reset_coil: 1002 })));
+        assert_eq!(refusal(parse_initial_fault_arg, &["--initial-fault", "0:1002"]).as_deref(), Some("Invalid fault code: 0"));
+        assert_eq!(refusal(parse_initial_fault_arg, &["--initial-fault", "42"]).as_deref(),
+            Some("Invalid initial fault: 42 (expected <code>:<reset coil>)"));
+
+        assert_eq!(parse_command_queue_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_command_queue_arg(&args(&["--command-queue", "2:3"])).ok(),
+            Some(Some(CommandQueue { depth: 2, pending_ireg: 3 })));
+        assert_eq!(refusal(parse_command_queue_arg, &["--command-queue", "0:3"]).as_deref(), Some("Invalid command queue depth: 0"));
+        assert_eq!(refusal(parse_command_queue_arg, &["--command-queue", "2"]).as_deref(),
+            Some("Invalid command queue: 2 (expected <depth>:<pending input register>)"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn units_and_connections() {
+        assert_eq!(parse_units_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_units_arg(&args(&["--units", "4"])).ok(), Some(Some(4)));
+        for units in ["0".to_string(), (MAX_UNIT_ID + 1).to_string()] {
+            assert_eq!(refusal(parse_units_arg, &["--units", &units]),
+                Some(format!("Invalid unit count: {units} (expected 1 to {MAX_UNIT_ID})")));
+        }
+        assert_eq!(parse_max_connections_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_max_connections_arg(&args(&["--max-connections", "2"])).ok(), Some(Some(2)));
+        assert_eq!(refusal(parse_max_connections_arg, &["--max-connections", "0"]).as_deref(), Some("Invalid max connections: 0"));
+    }
+
+    #[test]
+    fn degraded_link() {
+        assert!(parse_degraded_link_args(&args(&[])).is_ok_and(|link| link.is_none()), "A healthy link unless asked for");
+        assert!(parse_degraded_link_args(&args(&["--drop-percent", "0", "--seed", "7"])).is_ok_and(|link| link.is_none()),
+            "A 0% drop rate is a healthy link");
+        for degraded in [
+            ["--drop-percent", "5"],
+            ["--ignore-write-percent", "100"],
+            ["--latency-ms", "20"],
+            ["--fc-latency", "3=40"],
+        ] {
+            assert!(parse_degraded_link_args(&args(&degraded)).is_ok_and(|link| link.is_some()), "{degraded:?} degrades the link");
+        }
+        assert_eq!(refusal(parse_degraded_link_args, &["--drop-percent", "150"]).as_deref(),
+            Some("Invalid drop percentage: 150 (expected 0 to 100)"));
+        assert_eq!(refusal(parse_degraded_link_args, &["--ignore-write-percent", "-1"]).as_deref(),
+            Some("Invalid ignored write percentage: -1 (expected 0 to 100)"));
+        assert_eq!(refusal(parse_degraded_link_args, &["--latency-ms", "slow"]).as_deref(), Some("Invalid latency: slow"));
+
+        assert_eq!(parse_seed_arg(&args(&["--seed", "7"])).ok(), Some(7));
+        assert_eq!(refusal(parse_seed_arg, &["--seed", "lucky"]).as_deref(), Some("Invalid seed: lucky"));
+    }
+
+    #[test]
+    fn fc_latency() {
+        assert_eq!(parse_fc_latency_arg(&args(&[])).ok(), Some(BTreeMap::new()));
+        let latency = |base, jitter| FunctionLatency { base: Duration::from_millis(base), jitter };
+        assert_eq!(parse_fc_latency_arg(&args(&["--fc-latency", "3=40~10:gaussian, 5=5, 6=8~2"])).ok(), Some(BTreeMap::from([
+            (3, latency(40, Some((JitterDistribution::Gaussian, Duration::from_millis(10))))),
+            (5, latency(5, None)),
+            (6, latency(8, Some((JitterDistribution::Uniform, Duration::from_millis(2))))),
+        ])));
+        for entry in ["3=40~10:poisson", "256=5", "3", "3=fast"] {
+            assert_eq!(refusal(parse_fc_latency_arg, &["--fc-latency", entry]),
+                Some(format!("Invalid --fc-latency entry: {entry} (expected <fc>=<ms>[~<jitter ms>[:uniform|gaussian]])")));
+        }
+    }
+
+    #[test]
+    fn register_map_and_history() {
+        assert_eq!(parse_register_map_arg(&args(&[])).ok(), Some(None));
+        assert_eq!(parse_register_map_arg(&args(&["--register-map", "zero based"])).ok(), Some(Some(RegisterMap::ZERO_BASED)));
+        assert_eq!(refusal(parse_register_map_arg, &["--register-map", "mystery"]).as_deref(),
+            Some("Invalid register map: mystery (expected one of: Default, Zero based)"));
+        assert_eq!(parse_history_size_arg(&args(&[])).ok(), Some(SharedModbusState::DEFAULT_HISTORY_CAPACITY));
+        assert_eq!(parse_history_size_arg(&args(&["--history-size", "0"])).ok(), Some(0), "0 disables the history");
+        assert_eq!(refusal(parse_history_size_arg, &["--history-size", "all"]).as_deref(), Some("Invalid history size: all"));
+    }
+
+    #[test]
+    fn arm_sim() {
+        assert!(parse_arm_sim_args(&args(&["--sim-motion-ms", "200"])).is_ok_and(|config| config.is_none()),
+            "Arm settings do nothing without --simulate-arm");
+        let config = parse_arm_sim_args(&args(&["--simulate-arm", "--sim-motion-ms", "200", "--sim-ready-after-ms", "300",
+            "--sim-tick-ms", "5", "--sim-running-delay-ms", "40", "--sim-jitter-ms", "10:gaussian", "--seed", "9",
+            "--sim-warm-up", "2.5:30", "--sim-enable-debounce-ms", "15", "--enable-mode", "held"]));
+        let config = config.ok().flatten();
+        let timings = config.as_ref().map(|config| (config.motion_duration, config.ready_after, config.tick,
+            config.running_assert_delay, config.enable_debounce));
+        assert_eq!(timings, Some((Duration::from_millis(200), Duration::from_millis(300), Duration::from_millis(5),
+            Duration::from_millis(40), Duration::from_millis(15))));
+        assert_eq!(config.as_ref().map(|config| config.enable_mode), Some(EnableMode::Held), "--enable-mode applies to the arm");
+        assert_eq!(config.as_ref().and_then(|config| config.jitter),
+            Some(MotionJitter { distribution: JitterDistribution::Gaussian, magnitude: Duration::from_millis(10), seed: 9 }));
+        assert_eq!(config.as_ref().and_then(|config| config.warm_up),
+            Some(WarmUp { multiplier: 2.5, idle_after: Duration::from_secs(30) }));
+        for (setting, value, refusal_message) in [
+            ("--sim-tick-ms", "0", "Invalid simulation tick: 0"),
+            ("--sim-jitter-ms", "10:poisson", "Invalid jitter distribution: poisson (expected uniform or gaussian)"),
+            ("--sim-warm-up", "0.5:30", "Invalid simulated warm-up: 0.5:30 (expected <multiplier>:<idle secs>)"),
+            ("--sim-motion-model", "cubic",
+                "Invalid motion model: cubic (expected linear, trapezoidal[:<ramp fraction>] or s-curve)"),
+        ] {
+            assert_eq!(refusal(parse_arm_sim_args, &["--simulate-arm", setting, value]).as_deref(), Some(refusal_message));
+        }
+
+        for model in ["linear", "trapezoidal", "trapezoidal:0.25", "s-curve"] {
+            assert!(parse_motion_model(model).is_some(), "{model} is a motion model");
+        }
+        for model in ["trapezoidal:0.6", "trapezoidal:-0.1", "linear:0.25", "cubic"] {
+            assert!(parse_motion_model(model).is_none(), "{model} isn't a motion model");
+        }
+    }
+
+    #[test]
+    fn ramp() {
+        assert_eq!(parse_ramp_args(&args(&["--ramp-rate", "5"])).ok(), Some(None), "Ramp settings do nothing without --ramp");
+        assert_eq!(parse_ramp_args(&args(&["--ramp", "20:21"])).ok(), Some(Some(RampConfig::new(20, 21))));
+        assert_eq!(parse_ramp_args(&args(&["--ramp", "20:21", "--ramp-rate", "5", "--ramp-tick-ms", "20"])).ok(),
+            Some(Some(RampConfig { rate: 5, tick: Duration::from_millis(20), ..RampConfig::new(20, 21) })));
+        assert_eq!(refusal(parse_ramp_args, &["--ramp", "20"]).as_deref(),
+            Some("Invalid ramp registers: 20 (expected <target>:<actual>)"));
+        assert_eq!(refusal(parse_ramp_args, &["--ramp", "20:21", "--ramp-rate", "0"]).as_deref(), Some("Invalid ramp rate: 0"));
+        assert_eq!(refusal(parse_ramp_args, &["--ramp", "20:21", "--ramp-tick-ms", "0"]).as_deref(), Some("Invalid ramp tick: 0"));
+    }
+
+    #[test]
+    fn index_echo_clash() {
+        let check = |given: &[&str]| refusal(|given| check_index_echo_clash(parse_index_echo_arg(given)?,
+            parse_index_width_arg(given)?, parse_ramp_args(given)?), given);
+        let clash = |echo: &str| Some(format!("Invalid index echo register: {echo} (clashes with the ramp's actual input register)"));
+        assert_eq!(check(&["--index-echo", "21"]), None, "No clash without a ramp");
+        assert_eq!(check(&["--ramp", "20:21"]), None, "No clash without an echo");
+        assert_eq!(check(&["--ramp", "20:21", "--index-echo", "21"]), clash("21"), "Echo on the actual register");
+        assert_eq!(check(&["--ramp", "20:21", "--index-echo", "20"]), None, "Echo just below the actual register");
+        assert_eq!(check(&["--ramp", "20:21", "--index-echo", "22"]), None, "Echo just above the actual register");
+        assert_eq!(check(&["--ramp", "20:21", "--index-echo", "20", "--index-width", "2"]), clash("20"),
+            "Two register echo running onto the actual register");
+        assert_eq!(check(&["--ramp", "20:21", "--index-echo", "22", "--index-width", "2"]), None,
+            "Two register echo just above the actual register");
+        assert_eq!(check(&["--ramp", "20:0", "--index-echo", "65535", "--index-width", "2"]), None,
+            "Echo cut short at 0xFFFF doesn't wrap round onto register 0");
+    }
+}
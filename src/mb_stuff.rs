@@ -1,161 +1,1643 @@
-use std::collections::HashMap;
-use std::future;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::future::{self, Future};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use log::warn;
-use tokio_modbus::{ExceptionCode, Request, Response};
-use crate::{ENABLE_COIL_OFFSET, INDEX_HREG_OFFSET, RUNNING_COIL_OFFSET};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use log::{debug, warn};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_modbus::{ExceptionCode, Request, Response, SlaveId, SlaveRequest};
+use crate::arm_sim::{CommandQueue, InitialFault, SimControls};
+use crate::degraded_link::DegradedLink;
+use crate::device_id::{DeviceIdentification, ENCAPSULATED_INTERFACE_FUNCTION_CODE};
+use crate::golden::GoldenSequence;
+use crate::metrics::Metrics;
+use crate::ramp::RampConfig;
+use crate::rate_limit::WarningLimiter;
+use crate::regions::{AddressLabel, AddressSpace, Regions};
+use crate::register_map::RegisterMap;
+use crate::traffic_log::{format_request, CaptureLog, ReplayLog};
+use crate::watchdog::WatchdogConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+    Coil,
+    HoldingRegister,
+}
+
+impl From<ChangeKind> for AddressSpace {
+    fn from(kind: ChangeKind) -> Self {
+        match kind {
+            ChangeKind::Coil => Self::Coil,
+            ChangeKind::HoldingRegister => Self::HoldingRegister,
+        }
+    }
+}
+
+/// Order of the two 16-bit words making up a 32-bit value in consecutive holding registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WordOrder {
+    /// High word at the lower address.
+    BigEndian,
+    /// Low word at the lower address.
+    LittleEndian,
+}
+
+impl WordOrder {
+    /// The two registers holding `value`, lower address first.
+    pub fn split(self, value: u32) -> [u16; 2] {
+        let (high, low) = ((value >> 16) as u16, value as u16);
+        match self {
+            WordOrder::BigEndian => [high, low],
+            WordOrder::LittleEndian => [low, high],
+        }
+    }
+
+    /// The value held by two registers, lower address first.
+    pub fn join(self, words: [u16; 2]) -> u32 {
+        let (high, low) = match self {
+            WordOrder::BigEndian => (words[0], words[1]),
+            WordOrder::LittleEndian => (words[1], words[0]),
+        };
+        (high as u32) << 16 | low as u32
+    }
+}
+
+/// How many holding registers the sub routine index takes, and likewise its echo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum IndexWidth {
+    /// One register, indices up to 65535.
+    #[default]
+    Single,
+    /// Two consecutive registers holding a 32-bit index, for controllers with large program
+    /// libraries.
+    Double(WordOrder),
+}
+
+impl IndexWidth {
+    pub fn registers(self) -> u16 {
+        match self {
+            IndexWidth::Single => 1,
+            IndexWidth::Double(_) => 2,
+        }
+    }
+
+    /// Largest index the registers can hold.
+    pub fn max_index(self) -> u32 {
+        match self {
+            IndexWidth::Single => u16::MAX as u32,
+            IndexWidth::Double(_) => u32::MAX,
+        }
+    }
+
+    /// Whether the index registers stay clear of the fault register. The presets put the fault
+    /// register right after the index, so a two register index needs a custom map.
+    pub fn fits(self, map: &RegisterMap) -> bool {
+        map.fault_hreg.wrapping_sub(map.index_hreg) >= self.registers()
+    }
+
+    /// The index after `idx`, wrapping at [`Self::max_index`].
+    pub fn next(self, idx: u32) -> u32 {
+        if idx >= self.max_index() { 0 } else { idx + 1 }
+    }
+
+    /// The registers holding `idx`, lower address first. `idx` must be at most [`Self::max_index`].
+    fn split(self, idx: u32) -> Vec<u16> {
+        match self {
+            IndexWidth::Single => vec![idx as u16],
+            IndexWidth::Double(word_order) => word_order.split(idx).to_vec(),
+        }
+    }
+
+    fn join(self, words: &[u16]) -> u32 {
+        match self {
+            IndexWidth::Single => words[0] as u32,
+            IndexWidth::Double(word_order) => word_order.join([words[0], words[1]]),
+        }
+    }
+}
+
+/// How the service rearranges register payloads on the wire, mimicking controllers that deviate
+/// from Modbus' big-endian layout.
+///
+/// Applied to register reads and writes (FC 03, 04, 06 and 16). A single register write only
+/// sees the byte swap, having no pair to swap words with. Every mode is its own inverse, so a
+/// value written and read back through the service is unchanged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SwapMode {
+    #[default]
+    None,
+    /// Swap the two bytes within each register.
+    Byte,
+    /// Swap each pair of registers, as if every 32-bit value were little-endian word ordered.
+    /// A trailing unpaired register is left alone.
+    Word,
+    /// Both of the above.
+    ByteAndWord,
+}
+
+impl SwapMode {
+    pub fn apply(self, words: &mut [u16]) {
+        if matches!(self, SwapMode::Byte | SwapMode::ByteAndWord) {
+            for word in words.iter_mut() {
+                *word = word.swap_bytes();
+            }
+        }
+        if matches!(self, SwapMode::Word | SwapMode::ByteAndWord) {
+            for pair in words.chunks_exact_mut(2) {
+                pair.swap(0, 1);
+            }
+        }
+    }
+}
+
+/// What the f32 setpoint helpers do with NaN and infinities, which no real setpoint should be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum NonFinitePolicy {
+    /// Fail instead of writing or returning the value.
+    #[default]
+    Reject,
+    /// Treat them like any other bit pattern, e.g. to see how an arm copes with them.
+    PassThrough,
+}
+
+impl NonFinitePolicy {
+    fn check(self, addr: u16, value: f32) -> anyhow::Result<f32> {
+        match self {
+            NonFinitePolicy::Reject if !value.is_finite() =>
+                Err(anyhow::anyhow!("Setpoint at holding register {addr} is {value}, not a finite number")),
+            _ => Ok(value),
+        }
+    }
+}
+
+/// A single value change, coils recorded as 0/1.
+#[derive(Clone, Debug)]
+pub struct StateChange {
+    pub at: Instant,
+    pub kind: ChangeKind,
+    pub address: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+impl Display for StateChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>12?} ago  {:?} {}: {} -> {}", self.at.elapsed(), self.kind, self.address, self.old, self.new)
+    }
+}
+
+/// What the service does with a Modbus write to a read-only coil.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ReadOnlyPolicy {
+    /// Fail the whole request with `IllegalDataAddress`.
+    #[default]
+    Reject,
+    /// Acknowledge the request but leave protected coils unchanged.
+    Ignore,
+}
+
+/// What reads of addresses the state doesn't have return, e.g. 0xFFFF to mimic devices that
+/// answer undeclared registers with a sentinel instead of 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct UndeclaredDefaults {
+    pub coil: bool,
+    pub holding_register: u16,
+    pub input_register: u16,
+}
+
+/// A contiguous range of addresses declared with one default value, e.g. coils 0..=31 false, so
+/// large maps don't need an entry per address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeedRange {
+    pub space: AddressSpace,
+    pub range: RangeInclusive<u16>,
+    /// Register value, or for coils true unless 0.
+    pub value: u16,
+}
+
+impl SeedRange {
+    pub fn new(space: AddressSpace, range: RangeInclusive<u16>, value: u16) -> Self {
+        Self { space, range, value }
+    }
+}
 
 #[derive(Clone)]
 pub struct SharedModbusState {
     holding_registers: Arc<Mutex<HashMap<u16, u16>>>,
+    input_registers: Arc<Mutex<HashMap<u16, u16>>>,
+    /// What reads of the input registers return while frozen, see [`Self::freeze`].
+    frozen_input_registers: Arc<Mutex<Option<HashMap<u16, u16>>>>,
     coils: Arc<Mutex<HashMap<u16, bool>>>,
+    history: Arc<Mutex<VecDeque<StateChange>>>,
+    history_capacity: usize,
+    /// Every change as it happens, see [`Self::subscribe_changes`].
+    change_events: broadcast::Sender<StateChange>,
+    last_writes: Arc<Mutex<HashMap<(ChangeKind, u16), Instant>>>,
+    sim: Arc<Mutex<SimControls>>,
+    enable_rising_edges: Arc<AtomicU64>,
+    /// The index in the index registers at the last enable rising edge.
+    edge_index: Arc<Mutex<u32>>,
+    read_only_coils: Arc<HashSet<u16>>,
+    read_only_policy: ReadOnlyPolicy,
+    register_map: Arc<Mutex<RegisterMap>>,
+    ramp: Option<RampConfig>,
+    index_echo: Option<u16>,
+    progress_ireg: Option<u16>,
+    index_width: IndexWidth,
+    watchdog: Option<WatchdogConfig>,
+    initial_fault: Option<InitialFault>,
+    command_queue: Option<CommandQueue>,
+    coil_aliases: Arc<HashMap<u16, u16>>,
+    register_aliases: Arc<HashMap<u16, u16>>,
+    undeclared_defaults: UndeclaredDefaults,
+    regions: Arc<Regions>,
+    seed_ranges: Arc<Vec<SeedRange>>,
+    /// Offset from each holding register to the input register its writes are mirrored into.
+    write_mirror: Option<u16>,
+    /// Holds back repeats of the non-existent address warnings, see [`Self::warn_missing`].
+    missing_warnings: Arc<WarningLimiter<(&'static str, AddressSpace, u16)>>,
+    /// The unit this state answers as with `--units`, `None` when it answers every unit ID.
+    unit_id: Option<SlaveId>,
 }
 
+/// How often a non-existent address warning repeats while a master keeps accessing the address.
+pub const MISSING_ADDRESS_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
 impl SharedModbusState {
-    pub fn new() -> Self {
-        let mut coils = HashMap::new();
-        coils.insert(ENABLE_COIL_OFFSET, false);
-        coils.insert(RUNNING_COIL_OFFSET, false);
-        let mut holding_registers = HashMap::new();
-        holding_registers.insert(INDEX_HREG_OFFSET, 0);
+    pub const DEFAULT_HISTORY_CAPACITY: usize = 256;
+    /// How many changes a [`Self::subscribe_changes`] receiver may fall behind before it misses
+    /// the oldest.
+    pub const CHANGE_EVENT_CAPACITY: usize = 1024;
 
+    pub fn new() -> Self {
         Self {
-            coils: Arc::new(Mutex::new(coils)),
-            holding_registers: Arc::new(Mutex::new(holding_registers)),
+            coils: Arc::new(Mutex::new(Self::default_coils(&RegisterMap::DEFAULT, None, None))),
+            holding_registers: Arc::new(Mutex::new(Self::default_holding_registers(&RegisterMap::DEFAULT, None))),
+            input_registers: Arc::new(Mutex::new(Self::default_input_registers(None, None, None, None))),
+            frozen_input_registers: Arc::new(Mutex::new(None)),
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(Self::DEFAULT_HISTORY_CAPACITY))),
+            history_capacity: Self::DEFAULT_HISTORY_CAPACITY,
+            change_events: broadcast::channel(Self::CHANGE_EVENT_CAPACITY).0,
+            last_writes: Arc::new(Mutex::new(HashMap::new())),
+            sim: Arc::new(Mutex::new(SimControls::default())),
+            enable_rising_edges: Arc::new(AtomicU64::new(0)),
+            edge_index: Arc::new(Mutex::new(0)),
+            read_only_coils: Arc::new(HashSet::new()),
+            read_only_policy: ReadOnlyPolicy::default(),
+            register_map: Arc::new(Mutex::new(RegisterMap::DEFAULT)),
+            ramp: None,
+            index_echo: None,
+            progress_ireg: None,
+            index_width: IndexWidth::Single,
+            watchdog: None,
+            initial_fault: None,
+            command_queue: None,
+            coil_aliases: Arc::new(HashMap::new()),
+            register_aliases: Arc::new(HashMap::new()),
+            undeclared_defaults: UndeclaredDefaults::default(),
+            regions: Arc::new(Regions::default()),
+            seed_ranges: Arc::new(Vec::new()),
+            write_mirror: None,
+            missing_warnings: Arc::new(WarningLimiter::new(MISSING_ADDRESS_WARNING_INTERVAL)),
+            unit_id: None,
+        }
+    }
+
+    fn default_coils(map: &RegisterMap, watchdog: Option<&WatchdogConfig>, fault: Option<&InitialFault>) -> HashMap<u16, bool> {
+        map.coils().into_iter()
+            .chain(watchdog.map(|watchdog| watchdog.coil))
+            .chain(fault.map(|fault| fault.reset_coil))
+            .map(|addr| (addr, false))
+            .collect()
+    }
+
+    fn default_holding_registers(map: &RegisterMap, ramp: Option<&RampConfig>) -> HashMap<u16, u16> {
+        map.holding_registers().into_iter()
+            .chain(ramp.map(|ramp| ramp.target_hreg))
+            .map(|addr| (addr, 0))
+            .collect()
+    }
+
+    fn default_input_registers(ramp: Option<&RampConfig>, index_echo: Option<u16>, queue: Option<&CommandQueue>,
+        progress_ireg: Option<u16>) -> HashMap<u16, u16> {
+        ramp.map(|ramp| ramp.actual_ireg).into_iter()
+            .chain(index_echo)
+            .chain(queue.map(|queue| queue.pending_ireg))
+            .chain(progress_ireg)
+            .map(|addr| (addr, 0))
+            .collect()
+    }
+
+    /// The addresses the handshake currently lives at.
+    pub fn register_map(&self) -> RegisterMap {
+        *self.register_map.lock().unwrap()
+    }
+
+    /// Moves the handshake to `map` and [`Self::reset`]s, so only the new addresses exist.
+    pub fn set_register_map(&self, map: RegisterMap) {
+        *self.register_map.lock().unwrap() = map;
+        self.reset();
+    }
+
+    /// A state with this one's configuration and register map but its own coils, registers,
+    /// history and simulated arm controls, for another unit behind the same server.
+    pub fn independent_copy(&self) -> Self {
+        let copy = Self {
+            holding_registers: Arc::new(Mutex::new(HashMap::new())),
+            input_registers: Arc::new(Mutex::new(HashMap::new())),
+            frozen_input_registers: Arc::new(Mutex::new(None)),
+            coils: Arc::new(Mutex::new(HashMap::new())),
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(self.history_capacity))),
+            change_events: broadcast::channel(Self::CHANGE_EVENT_CAPACITY).0,
+            last_writes: Arc::new(Mutex::new(HashMap::new())),
+            sim: Arc::new(Mutex::new(SimControls::default())),
+            enable_rising_edges: Arc::new(AtomicU64::new(0)),
+            edge_index: Arc::new(Mutex::new(0)),
+            register_map: Arc::new(Mutex::new(self.register_map())),
+            missing_warnings: Arc::new(WarningLimiter::new(MISSING_ADDRESS_WARNING_INTERVAL)),
+            ..self.clone()
+        };
+        copy.reset();
+        copy
+    }
+
+    /// Restores every coil and register to its seeded default, including any initial fault, and
+    /// forgets the change history, the last write times and the simulated arm controls (jam,
+    /// mislatch, paused heartbeat), and unfreezes the input registers. Configuration (history capacity, read-only coils) is kept.
+    pub fn reset(&self) {
+        let map = self.register_map();
+        let mut coils = Self::default_coils(&map, self.watchdog.as_ref(), self.initial_fault.as_ref());
+        let mut holding_registers = Self::default_holding_registers(&map, self.ramp.as_ref());
+        if let Some(fault) = self.initial_fault {
+            holding_registers.insert(map.fault_hreg, fault.code);
+        }
+        let mut input_registers = Self::default_input_registers(self.ramp.as_ref(), self.index_echo, self.command_queue.as_ref(),
+            self.progress_ireg);
+        // The further words of a wide index and its echo, without clobbering what's seeded there
+        for word in 1..self.index_width.registers() {
+            holding_registers.entry(map.index_hreg.wrapping_add(word)).or_insert(0);
+            if let Some(echo_ireg) = self.index_echo {
+                input_registers.entry(echo_ireg.wrapping_add(word)).or_insert(0);
+            }
+        }
+        // Declared ranges fill in around the handshake's addresses rather than overriding them
+        for seed in self.seed_ranges.iter() {
+            for addr in seed.range.clone() {
+                match seed.space {
+                    AddressSpace::Coil => { coils.entry(addr).or_insert(seed.value != 0); }
+                    AddressSpace::HoldingRegister => { holding_registers.entry(addr).or_insert(seed.value); }
+                    AddressSpace::InputRegister => { input_registers.entry(addr).or_insert(seed.value); }
+                }
+            }
+        }
+        if let Some(offset) = self.write_mirror {
+            // Seeded with the holding registers' defaults, without clobbering what's seeded there
+            for (&addr, &value) in &holding_registers {
+                input_registers.entry(addr.wrapping_add(offset)).or_insert(value);
+            }
+        }
+        *self.coils.lock().unwrap() = coils;
+        *self.holding_registers.lock().unwrap() = holding_registers;
+        *self.input_registers.lock().unwrap() = input_registers;
+        *self.frozen_input_registers.lock().unwrap() = None;
+        self.history.lock().unwrap().clear();
+        self.last_writes.lock().unwrap().clear();
+        let mut sim = self.sim.lock().unwrap();
+        // Motion durations are tuning rather than state, so they outlive a reset between tests
+        *sim = SimControls {
+            motion_duration: sim.motion_duration,
+            index_motion_durations: std::mem::take(&mut sim.index_motion_durations),
+            ..SimControls::default()
+        };
+    }
+
+    /// Total number of false -> true writes to the enable coil.
+    ///
+    /// Unlike sampling the coil, this also catches a low/high re-pulse between two samples.
+    pub fn enable_rising_edges(&self) -> u64 {
+        // Pairs with the release in `latch_enable_edge`, so the edge's index is visible too
+        self.enable_rising_edges.load(Ordering::Acquire)
+    }
+
+    /// The index that was in the index registers when enable last rose, which is what an arm
+    /// latching on the edge runs even if the index changed since.
+    pub fn index_at_last_edge(&self) -> u32 {
+        *self.edge_index.lock().unwrap()
+    }
+
+    fn latch_enable_edge(&self, idx: u32) {
+        *self.edge_index.lock().unwrap() = idx;
+        self.enable_rising_edges.fetch_add(1, Ordering::Release);
+    }
+
+    /// Makes the simulated arm's next motion hang with running high until [`Self::clear_jam`].
+    pub fn jam_next_motion(&self) {
+        self.sim.lock().unwrap().jam_next = true;
+    }
+
+    /// Frees a jammed simulated arm (ending its motion) and cancels a pending jam.
+    pub fn clear_jam(&self) {
+        let mut sim = self.sim.lock().unwrap();
+        sim.jam_next = false;
+        sim.jammed = false;
+    }
+
+    /// Freezes the simulated arm's motion where it is, running staying high, until
+    /// [`Self::resume_motion`]. Unlike dropping enable this doesn't end the motion.
+    pub fn pause_motion(&self) {
+        self.sim.lock().unwrap().motion_paused = true;
+    }
+
+    pub fn resume_motion(&self) {
+        self.sim.lock().unwrap().motion_paused = false;
+    }
+
+    pub fn motion_paused(&self) -> bool {
+        self.sim.lock().unwrap().motion_paused
+    }
+
+    /// Latches the input registers as they are now: reads return the snapshot until
+    /// [`Self::unfreeze`], while the simulation keeps updating the real values underneath. Lets
+    /// the arm's feedback be inspected without it changing mid-look.
+    ///
+    /// Freezing while frozen takes a new snapshot.
+    pub fn freeze(&self) {
+        let snapshot = self.input_registers.lock().unwrap().clone();
+        *self.frozen_input_registers.lock().unwrap() = Some(snapshot);
+    }
+
+    pub fn unfreeze(&self) {
+        *self.frozen_input_registers.lock().unwrap() = None;
+    }
+
+    pub fn frozen(&self) -> bool {
+        self.frozen_input_registers.lock().unwrap().is_some()
+    }
+
+    /// Makes the simulated arm's motions take `duration` from the next motion on, in place of
+    /// the configured duration. Durations set for single indices still take precedence.
+    pub fn set_motion_duration(&self, duration: Duration) {
+        self.sim.lock().unwrap().motion_duration = Some(duration);
+    }
+
+    /// Makes the simulated arm's motions of `idx` take `duration` from the next one on, or with
+    /// `None` go back to the default duration.
+    pub fn set_index_motion_duration(&self, idx: u32, duration: Option<Duration>) {
+        let mut sim = self.sim.lock().unwrap();
+        match duration {
+            Some(duration) => sim.index_motion_durations.insert(idx, duration),
+            None => sim.index_motion_durations.remove(&idx),
+        };
+    }
+
+    /// The motion duration set at runtime for `idx`, `None` to use the configured one.
+    pub fn motion_duration_for(&self, idx: u32) -> Option<Duration> {
+        let sim = self.sim.lock().unwrap();
+        sim.index_motion_durations.get(&idx).copied().or(sim.motion_duration)
+    }
+
+    pub fn is_jammed(&self) -> bool {
+        self.sim.lock().unwrap().jammed
+    }
+
+    /// Raises the running coil for exactly `duration`, independent of any motion, e.g. to
+    /// reproduce the blip an arm gives for a sub routine it doesn't have.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn pulse_running(&self, duration: Duration) -> JoinHandle<()> {
+        let running_coil = self.register_map().running_coil;
+        // Raised before returning so the caller's next read already sees the pulse
+        self.write_coil(running_coil, true);
+        let state = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            state.write_coil(running_coil, false);
+        })
+    }
+
+    /// Makes the simulated arm latch the wrong index for its next motion, as reported by the
+    /// index echo register.
+    pub fn mislatch_next_index(&self) {
+        self.sim.lock().unwrap().mislatch_next = true;
+    }
+
+    /// Consumes a pending [`Self::mislatch_next_index`] request.
+    pub(crate) fn take_mislatch_request(&self) -> bool {
+        std::mem::take(&mut self.sim.lock().unwrap().mislatch_next)
+    }
+
+    /// Consumes a pending [`Self::jam_next_motion`] request, marking the arm as jammed if there was one.
+    pub(crate) fn take_jam_request(&self) -> bool {
+        let mut sim = self.sim.lock().unwrap();
+        sim.jammed = std::mem::take(&mut sim.jam_next);
+        sim.jammed
+    }
+
+    /// Protects `coils` from Modbus writes, handled according to `policy`.
+    ///
+    /// Only requests from the service and the gRPC control are checked; the simulation and the TUI
+    /// still write them freely.
+    pub fn with_read_only_coils(mut self, coils: HashSet<u16>, policy: ReadOnlyPolicy) -> Self {
+        self.read_only_coils = Arc::new(coils);
+        self.read_only_policy = policy;
+        self
+    }
+
+    pub fn is_read_only_coil(&self, addr: u16) -> bool {
+        self.read_only_coils.contains(&self.canonical_coil(addr))
+    }
+
+    pub fn read_only_policy(&self) -> ReadOnlyPolicy {
+        self.read_only_policy
+    }
+
+    /// Adds the setpoint holding register and actual input register driven by [`crate::ramp::run_ramp`].
+    pub fn with_ramp(mut self, ramp: Option<RampConfig>) -> Self {
+        self.ramp = ramp;
+        self.reset();
+        self
+    }
+
+    pub fn ramp(&self) -> Option<RampConfig> {
+        self.ramp
+    }
+
+    /// Adds an input register where the simulated arm reports the index it latched at the last
+    /// enable rising edge, like real controllers echo the commanded index.
+    pub fn with_index_echo(mut self, index_echo: Option<u16>) -> Self {
+        self.index_echo = index_echo;
+        self.reset();
+        self
+    }
+
+    pub fn index_echo(&self) -> Option<u16> {
+        self.index_echo
+    }
+
+    /// Adds an input register where the simulated arm reports the progress of its motion, see
+    /// [`crate::arm_sim::run_arm_sim`].
+    pub fn with_progress_register(mut self, progress_ireg: Option<u16>) -> Self {
+        self.progress_ireg = progress_ireg;
+        self.reset();
+        self
+    }
+
+    pub fn progress_register(&self) -> Option<u16> {
+        self.progress_ireg
+    }
+
+    /// Mirrors every holding register write into the input register `offset` addresses up, for
+    /// masters that verify their writes by reading a mirror region back. The mirror overwrites
+    /// whatever else lives at those input registers.
+    pub fn with_write_mirror(mut self, offset: Option<u16>) -> Self {
+        self.write_mirror = offset;
+        self.reset();
+        self
+    }
+
+    pub fn write_mirror(&self) -> Option<u16> {
+        self.write_mirror
+    }
+
+    /// Names the unit this state answers as, for [`Self::log_tag`].
+    pub fn with_unit_id(mut self, unit_id: Option<SlaveId>) -> Self {
+        self.unit_id = unit_id;
+        self
+    }
+
+    pub fn unit_id(&self) -> Option<SlaveId> {
+        self.unit_id
+    }
+
+    /// Prefix for test case log lines, so runs on several units at once can be told apart.
+    pub fn log_tag(&self) -> String {
+        match self.unit_id {
+            Some(unit_id) => format!("[unit {unit_id}]"),
+            None => "[unit any]".to_string(),
+        }
+    }
+
+    /// Spreads the index, and its echo, over [`IndexWidth::registers`] registers from the map's
+    /// index register on.
+    pub fn with_index_width(mut self, index_width: IndexWidth) -> Self {
+        self.index_width = index_width;
+        self.reset();
+        self
+    }
+
+    pub fn index_width(&self) -> IndexWidth {
+        self.index_width
+    }
+
+    /// Writes the sub routine index across the index registers. Fails without writing anything
+    /// if `idx` doesn't fit the [`IndexWidth`].
+    pub fn write_index(&self, idx: u32) -> anyhow::Result<()> {
+        let max = self.index_width.max_index();
+        if idx > max {
+            return Err(anyhow::anyhow!("Sub routine index {idx} doesn't fit the index registers, the largest is {max}"));
+        }
+        self.write_holding_registers(self.register_map().index_hreg, &self.index_width.split(idx));
+        Ok(())
+    }
+
+    /// Writes the sub routine index and raises enable as one update. Readers of the index block
+    /// until enable is up too, so nothing polling the state sees enable rise with the previous
+    /// index, unlike with [`Self::write_index`] followed by raising enable.
+    pub fn write_index_and_enable(&self, idx: u32) -> anyhow::Result<()> {
+        let max = self.index_width.max_index();
+        if idx > max {
+            return Err(anyhow::anyhow!("Sub routine index {idx} doesn't fit the index registers, the largest is {max}"));
+        }
+        let map = self.register_map();
+        let enable_rose = {
+            // The only place both are held, so the order can't deadlock
+            let mut registers = self.holding_registers.lock().unwrap();
+            self.write_holding_registers_locked(&mut registers, map.index_hreg, &self.index_width.split(idx));
+            self.write_coils_locked(&mut self.coils.lock().unwrap(), map.enable_coil, &[true])
+        };
+        if enable_rose {
+            self.latch_enable_edge(idx);
+        }
+        Ok(())
+    }
+
+    /// The sub routine index currently in the index registers.
+    pub fn read_index(&self) -> u32 {
+        let words = self.read_holding_registers(self.register_map().index_hreg, self.index_width.registers());
+        self.index_width.join(&words)
+    }
+
+    /// The index the arm reports it latched at the last enable rising edge, if there is an index
+    /// echo register.
+    pub fn read_index_echo(&self) -> Option<u32> {
+        let echo_ireg = self.index_echo?;
+        Some(self.index_width.join(&self.read_input_registers(echo_ireg, self.index_width.registers())))
+    }
+
+    pub(crate) fn write_index_echo(&self, idx: u32) {
+        if let Some(echo_ireg) = self.index_echo {
+            for (addr, word) in addresses(echo_ireg, self.index_width.registers() as usize).zip(self.index_width.split(idx)) {
+                self.write_input_register(addr, word);
+            }
+        }
+    }
+
+    /// Adds the heartbeat coil watched by [`crate::watchdog::run_watchdog`].
+    pub fn with_watchdog(mut self, watchdog: Option<WatchdogConfig>) -> Self {
+        self.watchdog = watchdog;
+        self.reset();
+        self
+    }
+
+    pub fn watchdog(&self) -> Option<WatchdogConfig> {
+        self.watchdog
+    }
+
+    /// Starts the arm faulted with `fault.code` after every reset, and adds the coil that clears
+    /// the fault, see [`crate::arm_sim::run_arm_sim`].
+    pub fn with_initial_fault(mut self, initial_fault: Option<InitialFault>) -> Self {
+        self.initial_fault = initial_fault;
+        self.reset();
+        self
+    }
+
+    pub fn initial_fault(&self) -> Option<InitialFault> {
+        self.initial_fault
+    }
+
+    /// Has the simulated arm queue sub routines commanded mid-motion, and adds the input
+    /// register reporting how many are waiting, see [`crate::arm_sim::run_arm_sim`].
+    pub fn with_command_queue(mut self, command_queue: Option<CommandQueue>) -> Self {
+        self.command_queue = command_queue;
+        self.reset();
+        self
+    }
+
+    pub fn command_queue(&self) -> Option<CommandQueue> {
+        self.command_queue
+    }
+
+    /// Stops or resumes [`crate::watchdog::run_heartbeat`], e.g. to trip the watchdog on purpose.
+    pub fn pause_heartbeat(&self, paused: bool) {
+        self.sim.lock().unwrap().heartbeat_paused = paused;
+    }
+
+    pub fn heartbeat_paused(&self) -> bool {
+        self.sim.lock().unwrap().heartbeat_paused
+    }
+
+    /// Makes each alias address (key) read and write the coil or holding register at its
+    /// canonical address (value), like a gateway exposing one signal at several addresses.
+    ///
+    /// Aliases don't chain, and an alias hides any real coil or register at its own address.
+    /// The change history records the canonical address.
+    pub fn with_aliases(mut self, coil_aliases: HashMap<u16, u16>, register_aliases: HashMap<u16, u16>) -> Self {
+        self.coil_aliases = Arc::new(coil_aliases);
+        self.register_aliases = Arc::new(register_aliases);
+        self
+    }
+
+    /// Values read back from addresses that don't exist. Writes to them are still dropped.
+    pub fn with_undeclared_defaults(mut self, undeclared_defaults: UndeclaredDefaults) -> Self {
+        self.undeclared_defaults = undeclared_defaults;
+        self
+    }
+
+    /// Declares every address in the given ranges with the range's default, on top of the
+    /// handshake's. Where the handshake or an earlier range already declares an address, that one
+    /// sets its default.
+    pub fn with_seed_ranges(mut self, seed_ranges: Vec<SeedRange>) -> Self {
+        self.seed_ranges = Arc::new(seed_ranges);
+        self.reset();
+        self
+    }
+
+    /// Names address ranges in warnings and change dumps. Purely descriptive, it doesn't declare
+    /// any coils or registers.
+    pub fn with_regions(mut self, regions: Regions) -> Self {
+        self.regions = Arc::new(regions);
+        self
+    }
+
+    pub fn regions(&self) -> &Regions {
+        &self.regions
+    }
+
+    /// How warnings refer to an address, with its region if it's in one.
+    pub fn address_label(&self, space: AddressSpace, addr: u16) -> AddressLabel<'_> {
+        self.regions.label(space, addr)
+    }
+
+    fn canonical_coil(&self, addr: u16) -> u16 {
+        self.coil_aliases.get(&addr).copied().unwrap_or(addr)
+    }
+
+    fn canonical_register(&self, addr: u16) -> u16 {
+        self.register_aliases.get(&addr).copied().unwrap_or(addr)
+    }
+
+    /// Keeps the last `history_capacity` value changes for [`Self::recent_changes`]. 0 disables the history.
+    pub fn with_history_capacity(mut self, history_capacity: usize) -> Self {
+        self.history = Arc::new(Mutex::new(VecDeque::with_capacity(history_capacity)));
+        self.history_capacity = history_capacity;
+        self
+    }
+
+    /// The last `n` value changes, oldest first. Writes that don't change a value aren't recorded.
+    pub fn recent_changes(&self, n: usize) -> Vec<StateChange> {
+        let history = self.history.lock().unwrap();
+        history.iter().skip(history.len().saturating_sub(n)).cloned().collect()
+    }
+
+    /// Every value change from now on, as it happens, whatever the history capacity. Like the
+    /// history, writes that don't change a value aren't sent.
+    pub fn subscribe_changes(&self) -> broadcast::Receiver<StateChange> {
+        self.change_events.subscribe()
+    }
+
+    /// When the coil or holding register at `addr` was last written, by anyone, whether or not the
+    /// write changed its value. `None` if it hasn't been written since the last reset.
+    pub fn last_written(&self, kind: ChangeKind, addr: u16) -> Option<Instant> {
+        let addr = match kind {
+            ChangeKind::Coil => self.canonical_coil(addr),
+            ChangeKind::HoldingRegister => self.canonical_register(addr),
+        };
+        self.last_writes.lock().unwrap().get(&(kind, addr)).copied()
+    }
+
+    /// Only called for existing addresses, so the last write times stay bounded by the map.
+    ///
+    /// Returns whether the change is a rising edge on enable. The caller latches it with
+    /// [`Self::latch_enable_edge`] once it no longer holds the holding register lock.
+    fn record_change(&self, kind: ChangeKind, address: u16, old: u16, new: u16) -> bool {
+        self.last_writes.lock().unwrap().insert((kind, address), Instant::now());
+        let enable_rose = kind == ChangeKind::Coil && address == self.register_map().enable_coil && old == 0 && new == 1;
+        if old == new {
+            return enable_rose;
         }
+        let change = StateChange { at: Instant::now(), kind, address, old, new };
+        // Fails only when nobody is subscribed
+        let _ = self.change_events.send(change.clone());
+        if self.history_capacity == 0 {
+            return enable_rose;
+        }
+        let mut history = self.history.lock().unwrap();
+        if history.len() == self.history_capacity {
+            history.pop_front();
+        }
+        history.push_back(change);
+        enable_rose
     }
 
     pub fn read_coil(&self, addr: u16) -> bool {
+        let addr = self.canonical_coil(addr);
         let coils = self.coils.lock().unwrap();
         if let Some(&value) = coils.get(&addr) {
             value
         } else {
-            warn!("Attempted to read from non-existent coil {addr}");
-            false
+            self.warn_missing("read from", AddressSpace::Coil, addr);
+            self.undeclared_defaults.coil
         }
     }
 
     pub fn read_coils(&self, addr: u16, count: u16) -> Vec<bool> {
         let coils = self.coils.lock().unwrap();
         let mut result = Vec::with_capacity(count as usize);
-        for i in 0..count {
-            let coil_addr = addr + i;
+        for coil_addr in addresses(addr, count as usize).map(|addr| self.canonical_coil(addr)) {
             if let Some(&value) = coils.get(&coil_addr) {
                 result.push(value);
             } else {
-                warn!("Attempted to read from non-existent coil {coil_addr}");
-                result.push(false);
+                self.warn_missing("read from", AddressSpace::Coil, coil_addr);
+                result.push(self.undeclared_defaults.coil);
             }
         }
         result
     }
 
     pub fn write_coil(&self, addr: u16, value: bool) {
-        if let Some(coil) = self.coils.lock().unwrap().get_mut(&addr) {
-            *coil = value;
-        } else {
-            warn!("Attempted to write to non-existent coil {addr}");
-        }
+        self.write_coils(addr, &[value]);
     }
 
     pub fn write_coils(&self, addr: u16, values: &[bool]) {
+        let enable_rose = self.write_coils_locked(&mut self.coils.lock().unwrap(), addr, values);
+        if enable_rose {
+            self.latch_enable_edge(self.read_index());
+        }
+    }
+
+    /// Writes `values` from `addr` like [`Self::write_coils`], all under one lock, skipping the
+    /// read-only coils.
+    pub fn write_writable_coils(&self, addr: u16, values: &[bool]) {
         let mut coils = self.coils.lock().unwrap();
-        for (i, &value) in values.iter().enumerate() {
-            let coil_addr = addr + i as u16;
+        let mut enable_rose = false;
+        for (coil_addr, &value) in addresses(addr, values.len()).zip(values) {
+            if !self.is_read_only_coil(coil_addr) {
+                enable_rose |= self.write_coils_locked(&mut coils, coil_addr, &[value]);
+            }
+        }
+        drop(coils);
+        if enable_rose {
+            self.latch_enable_edge(self.read_index());
+        }
+    }
+
+    /// Returns whether enable rose, see [`Self::record_change`].
+    fn write_coils_locked(&self, coils: &mut HashMap<u16, bool>, addr: u16, values: &[bool]) -> bool {
+        let mut enable_rose = false;
+        for (coil_addr, &value) in addresses(addr, values.len()).map(|addr| self.canonical_coil(addr)).zip(values) {
             if let Some(coil) = coils.get_mut(&coil_addr) {
+                enable_rose |= self.record_change(ChangeKind::Coil, coil_addr, *coil as u16, value as u16);
                 *coil = value;
             } else {
-                warn!("Attempted to write to non-existent coil {coil_addr}");
+                self.warn_missing("write to", AddressSpace::Coil, coil_addr);
             }
         }
+        enable_rose
+    }
+
+    /// Warns about an access to an address that doesn't exist, the first time and then at most
+    /// once per [`MISSING_ADDRESS_WARNING_INTERVAL`] for each address, so a master polling one
+    /// doesn't flood the log.
+    fn warn_missing(&self, access: &'static str, space: AddressSpace, addr: u16) {
+        match self.missing_warnings.admit((access, space, addr)) {
+            Some(0) => warn!("Attempted to {access} non-existent {}", self.address_label(space, addr)),
+            Some(repeats) => warn!("Attempted to {access} non-existent {}, {repeats} more times since the last warning",
+                self.address_label(space, addr)),
+            None => {}
+        }
     }
 
     pub fn read_holding_registers(&self, addr: u16, count: u16) -> Vec<u16> {
         let registers = self.holding_registers.lock().unwrap();
         let mut result = Vec::with_capacity(count as usize);
-        for i in 0..count {
-            let reg_addr = addr + i;
+        for reg_addr in addresses(addr, count as usize).map(|addr| self.canonical_register(addr)) {
             if let Some(&value) = registers.get(&reg_addr) {
                 result.push(value);
             } else {
-                warn!("Attempted to read from non-existent holding register {reg_addr}");
-                result.push(0);
+                self.warn_missing("read from", AddressSpace::HoldingRegister, reg_addr);
+                result.push(self.undeclared_defaults.holding_register);
             }
         }
         result
     }
 
-    pub fn write_holding_register(&self, addr: u16, value: u16) {
-        if let Some(register) = self.holding_registers.lock().unwrap().get_mut(&addr) {
+    /// What a master reads, the snapshot while [`Self::freeze`]d.
+    pub fn read_input_registers(&self, addr: u16, count: u16) -> Vec<u16> {
+        if let Some(snapshot) = &*self.frozen_input_registers.lock().unwrap() {
+            return self.read_input_registers_from(snapshot, addr, count);
+        }
+        self.read_live_input_registers(addr, count)
+    }
+
+    /// The input registers as the simulation last set them, frozen or not.
+    pub fn read_live_input_registers(&self, addr: u16, count: u16) -> Vec<u16> {
+        self.read_input_registers_from(&self.input_registers.lock().unwrap(), addr, count)
+    }
+
+    fn read_input_registers_from(&self, registers: &HashMap<u16, u16>, addr: u16, count: u16) -> Vec<u16> {
+        let mut result = Vec::with_capacity(count as usize);
+        for reg_addr in addresses(addr, count as usize) {
+            if let Some(&value) = registers.get(&reg_addr) {
+                result.push(value);
+            } else {
+                self.warn_missing("read from", AddressSpace::InputRegister, reg_addr);
+                result.push(self.undeclared_defaults.input_register);
+            }
+        }
+        result
+    }
+
+    /// Input registers are read-only over Modbus, only the simulation updates them. Not recorded
+    /// in the change history, a ramp would flood it.
+    pub fn write_input_register(&self, addr: u16, value: u16) {
+        if let Some(register) = self.input_registers.lock().unwrap().get_mut(&addr) {
             *register = value;
         } else {
-            warn!("Attempted to write to non-existent holding register {addr}");
+            self.warn_missing("write to", AddressSpace::InputRegister, addr);
         }
     }
 
+    pub fn write_holding_register(&self, addr: u16, value: u16) {
+        self.write_holding_registers(addr, &[value]);
+    }
+
     pub fn write_holding_registers(&self, addr: u16, values: &[u16]) {
-        let mut registers = self.holding_registers.lock().unwrap();
-        for (i, &value) in values.iter().enumerate() {
-            let reg_addr = addr + i as u16;
+        self.write_holding_registers_locked(&mut self.holding_registers.lock().unwrap(), addr, values);
+    }
+
+    fn write_holding_registers_locked(&self, registers: &mut HashMap<u16, u16>, addr: u16, values: &[u16]) {
+        for (reg_addr, &value) in addresses(addr, values.len()).map(|addr| self.canonical_register(addr)).zip(values) {
             if let Some(register) = registers.get_mut(&reg_addr) {
+                self.record_change(ChangeKind::HoldingRegister, reg_addr, *register, value);
                 *register = value;
+                if let Some(offset) = self.write_mirror {
+                    // Always holding registers first, then input registers
+                    self.write_input_register(reg_addr.wrapping_add(offset), value);
+                }
             } else {
-                warn!("Attempted to write to non-existent holding register {reg_addr}");
+                self.warn_missing("write to", AddressSpace::HoldingRegister, reg_addr);
             }
         }
     }
 }
 
+impl Default for SharedModbusState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Helpers reinterpreting raw holding registers as signed, 32-bit or float values, e.g. offsets,
+/// joint positions and setpoints.
+impl SharedModbusState {
+    /// Reads the holding register at `addr` as a two's-complement signed value.
+    pub fn read_i16(&self, addr: u16) -> i16 {
+        self.read_holding_registers(addr, 1)[0] as i16
+    }
+
+    /// Writes a signed value to the holding register at `addr` in two's-complement.
+    pub fn write_i16(&self, addr: u16, value: i16) {
+        self.write_holding_register(addr, value as u16);
+    }
+
+    /// Reads a 32-bit value from the holding registers at `addr` and `addr + 1`.
+    pub fn read_u32(&self, addr: u16, word_order: WordOrder) -> u32 {
+        let words = self.read_holding_registers(addr, 2);
+        word_order.join([words[0], words[1]])
+    }
+
+    /// Writes a 32-bit value to the holding registers at `addr` and `addr + 1`.
+    pub fn write_u32(&self, addr: u16, value: u32, word_order: WordOrder) {
+        self.write_holding_registers(addr, &word_order.split(value));
+    }
+
+    /// Reads an IEEE 754 float from the holding registers at `addr` and `addr + 1`.
+    pub fn read_f32(&self, addr: u16, word_order: WordOrder) -> f32 {
+        f32::from_bits(self.read_u32(addr, word_order))
+    }
+
+    /// Writes an IEEE 754 float to the holding registers at `addr` and `addr + 1`.
+    pub fn write_f32(&self, addr: u16, value: f32, word_order: WordOrder) {
+        self.write_u32(addr, value.to_bits(), word_order);
+    }
+
+    /// Writes a float setpoint, e.g. a speed or acceleration, unless `policy` rejects it.
+    pub fn write_f32_setpoint(&self, addr: u16, value: f32, word_order: WordOrder, policy: NonFinitePolicy) -> anyhow::Result<()> {
+        self.write_f32(addr, policy.check(addr, value)?, word_order);
+        Ok(())
+    }
+
+    /// Reads a float setpoint as written by the master, unless `policy` rejects it.
+    pub fn read_f32_setpoint(&self, addr: u16, word_order: WordOrder, policy: NonFinitePolicy) -> anyhow::Result<f32> {
+        policy.check(addr, self.read_f32(addr, word_order))
+    }
+}
+
+/// The `count` consecutive addresses starting at `addr`, cut short at 0xFFFF instead of wrapping
+/// around to 0.
+pub(crate) fn addresses(addr: u16, count: usize) -> impl Iterator<Item = u16> {
+    (addr..=u16::MAX).take(count)
+}
+
+/// Fails with `IllegalDataAddress` if `count` addresses starting at `addr` don't fit the 16-bit
+/// address space.
+fn check_range(addr: u16, count: usize, tag: &str) -> Result<(), ExceptionCode> {
+    if addr as usize + count > u16::MAX as usize + 1 {
+        warn!("{tag} Exception::IllegalDataAddress - {count} addresses from {addr} run past {}", u16::MAX);
+        return Err(ExceptionCode::IllegalDataAddress);
+    }
+    Ok(())
+}
+
+/// Largest quantity a ReadCoils request may ask for (Modbus spec, FC 01).
+pub const MAX_READ_COILS: u16 = 2000;
+/// Largest quantity a ReadDiscreteInputs request may ask for (Modbus spec, FC 02).
+pub const MAX_READ_DISCRETE_INPUTS: u16 = 2000;
+/// Largest quantity a ReadHoldingRegisters request may ask for (Modbus spec, FC 03).
+pub const MAX_READ_REGISTERS: u16 = 125;
+/// Largest quantity a WriteMultipleCoils request may carry (Modbus spec, FC 15).
+pub const MAX_WRITE_COILS: u16 = 1968;
+/// Largest quantity a WriteMultipleRegisters request may carry (Modbus spec, FC 16).
+pub const MAX_WRITE_REGISTERS: u16 = 123;
+
+/// Read Exception Status (FC 07); tokio-modbus has no dedicated request type for it.
+pub const READ_EXCEPTION_STATUS_FUNCTION_CODE: u8 = 0x07;
+
+/// Which bit of the Read Exception Status byte reports which handshake signal, 0 being the least
+/// significant. The remaining bits read 0.
+///
+/// The same bits are the discrete inputs: discrete input N reads bit N of the status byte, so a
+/// master can read the whole status block with one ReadDiscreteInputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExceptionStatusBits {
+    pub enable: u8,
+    pub running: u8,
+    pub ready: u8,
+    /// Set while the fault register is non-zero.
+    pub fault: u8,
+}
+
+impl ExceptionStatusBits {
+    /// Bits 0 to 3: enable, running, ready, fault.
+    pub const DEFAULT: ExceptionStatusBits = ExceptionStatusBits { enable: 0, running: 1, ready: 2, fault: 3 };
+
+    /// Packs the current handshake state into the status byte.
+    pub fn status_byte(&self, state: &SharedModbusState) -> u8 {
+        let map = state.register_map();
+        let fault = state.read_holding_registers(map.fault_hreg, 1)[0] != 0;
+        [
+            (self.enable, state.read_coil(map.enable_coil)),
+            (self.running, state.read_coil(map.running_coil)),
+            (self.ready, state.read_coil(map.ready_coil)),
+            (self.fault, fault),
+        ].into_iter()
+            .filter(|&(_, set)| set)
+            .fold(0, |byte, (bit, _)| byte | 1 << bit)
+    }
+
+    /// Maps a status block read from discrete input 0 on to its signals. `None` if the block is
+    /// too short to hold all four.
+    pub fn decode(&self, inputs: &[bool]) -> Option<StatusBlock> {
+        let bit = |bit: u8| inputs.get(bit as usize).copied();
+        Some(StatusBlock {
+            enable: bit(self.enable)?,
+            running: bit(self.running)?,
+            ready: bit(self.ready)?,
+            fault: bit(self.fault)?,
+        })
+    }
+}
+
+/// The handshake signals of a status block, see [`ExceptionStatusBits::decode`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct StatusBlock {
+    pub enable: bool,
+    pub running: bool,
+    pub ready: bool,
+    pub fault: bool,
+}
+
+/// The discrete inputs there are, one per bit of the status byte.
+pub const STATUS_INPUTS: u16 = u8::BITS as u16;
+
+impl Default for ExceptionStatusBits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Diagnostics (FC 08); tokio-modbus has no dedicated request type for it.
+pub const DIAGNOSTICS_FUNCTION_CODE: u8 = 0x08;
+const DIAG_RETURN_QUERY_DATA: u16 = 0x0000;
+const DIAG_RESTART_COMMUNICATIONS: u16 = 0x0001;
+const DIAG_RETURN_DIAGNOSTIC_REGISTER: u16 = 0x0002;
+const DIAG_CLEAR_COUNTERS: u16 = 0x000A;
+
+/// Identity reported by ReportServerId (FC 17) unless overridden with `--server-id`.
+pub const DEFAULT_SERVER_ID: &str = concat!("rtu-sim v", env!("CARGO_PKG_VERSION"));
+/// Longest identity that still fits a ReportServerId response PDU.
+pub const MAX_SERVER_ID_LEN: usize = 249;
+const REPORT_SERVER_ID_FUNCTION_CODE: u8 = 0x11;
+/// The device specific server ID byte at the start of a ReportServerId response.
+pub const SERVER_ID_BYTE: u8 = 0x01;
+
+/// Unit ID addressing every device on the line. Writes sent to it are applied but never answered.
+pub const BROADCAST_UNIT_ID: SlaveId = 0;
+/// The highest unit ID a device may have, the ones above are reserved.
+pub const MAX_UNIT_ID: SlaveId = 247;
+
+/// What the service does with a function code it doesn't implement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum UnknownFunctionPolicy {
+    /// Answer with `IllegalFunction`, as the spec asks.
+    #[default]
+    IllegalFunction,
+    /// Answer with `ServerDeviceFailure`, like some devices do.
+    DeviceFailure,
+    /// Don't answer at all, so the master has to time out.
+    Drop,
+}
+
+/// What happens to a request that arrives under the [`RequestFloor`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum FloorPolicy {
+    /// Logged and counted, otherwise handled as usual.
+    #[default]
+    Flag,
+    /// Also answered with `ServerDeviceBusy` instead of being handled.
+    Reject,
+}
+
+/// Shortest spacing allowed between two requests on one connection, to catch masters that send
+/// the next request without waiting for the response to the previous one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestFloor {
+    pub min_spacing: Duration,
+    pub policy: FloorPolicy,
+}
+
 pub struct ExampleService {
     shared_state: SharedModbusState,
+    peer: SocketAddr,
+    replay: Option<Arc<ReplayLog>>,
+    capture: Option<Arc<CaptureLog>>,
+    server_id: Arc<str>,
+    device_identification: Arc<DeviceIdentification>,
+    metrics: Option<Arc<Metrics>>,
+    degraded_link: Option<Arc<DegradedLink>>,
+    swap_mode: SwapMode,
+    exception_status_bits: ExceptionStatusBits,
+    unknown_function_policy: UnknownFunctionPolicy,
+    allow_zero_count: bool,
+    disabled_functions: Arc<HashSet<u8>>,
+    request_floor: Option<RequestFloor>,
+    golden: Option<Arc<GoldenSequence>>,
+    /// When the previous request on this connection arrived, for the request floor. On tokio's
+    /// clock, so a paused test clock spaces requests exactly.
+    last_request: Arc<Mutex<Option<tokio::time::Instant>>>,
+    units: Option<Arc<HashMap<SlaveId, SharedModbusState>>>,
 }
 
 impl tokio_modbus::server::Service for ExampleService {
-    type Request = Request<'static>;
-    type Response = Response;
+    type Request = SlaveRequest<'static>;
+    /// `None` leaves the request unanswered, see [`ExampleService::with_degraded_link`] and
+    /// [`UnknownFunctionPolicy::Drop`].
+    ///
+    /// Broadcasts are never answered, see [`BROADCAST_UNIT_ID`].
+    type Response = Option<Response>;
     type Exception = ExceptionCode;
-    type Future = future::Ready<Result<Self::Response, Self::Exception>>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Exception>> + Send>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        let res = match req {
+        let SlaveRequest { slave, request: req } = req;
+        let tag = log_tag(self.peer, slave);
+        debug!("{tag} {req:?}");
+        if let Some(golden) = &self.golden {
+            golden.check(&req);
+        }
+        if self.under_request_floor(&tag) {
+            return Box::pin(future::ready(Err(ExceptionCode::ServerDeviceBusy)));
+        }
+        if let Some(link) = &self.degraded_link && link.should_drop() {
+            debug!("{tag} Dropping request, no response will be sent");
+            return Box::pin(future::ready(Ok(None)));
+        }
+        let broadcast = slave == BROADCAST_UNIT_ID;
+        if broadcast && !is_write(&req) {
+            warn!("{tag} Ignoring broadcast of a request that isn't a write: {req:?}");
+            return Box::pin(future::ready(Ok(None)));
+        }
+        let started = Instant::now();
+        let function = req.function_code();
+        let captured_request = self.capture.as_ref().and_then(|_| format_request(&req));
+        let replayed = self.replay.as_ref().and_then(|replay| replay.next_response(&req));
+        let res = match replayed {
+            Some(res) => {
+                debug!("{tag} Replaying recorded response: {res:?}");
+                res.map(Some)
+            }
+            None => match self.unit_states(slave, broadcast).as_slice() {
+                [] => {
+                    warn!("{tag} Exception::GatewayTargetDevice - No unit {slave}: {req:?}");
+                    Err(ExceptionCode::GatewayTargetDevice)
+                }
+                [state] => self.handle(state, req, &tag),
+                states => states.iter()
+                    .map(|state| self.handle(state, req.clone(), &tag))
+                    .reduce(Result::and)
+                    .expect("more than one unit"),
+            },
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.record(function, started.elapsed(), res.is_err());
+        }
+        // The capture format has no way to express an unanswered request
+        if let (Some(capture), Some(request)) = (&self.capture, captured_request)
+            && let Some(res) = res.as_ref().map(Option::as_ref).transpose() {
+            capture.record(&request, &res.cloned().map_err(|err| *err));
+        }
+        let res = match res {
+            Err(exception) if broadcast => {
+                warn!("{tag} Broadcast failed with {exception:?}, no response will be sent");
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_broadcast_failure();
+                }
+                Ok(None)
+            }
+            _ if broadcast => {
+                debug!("{tag} Broadcast applied, no response will be sent");
+                Ok(None)
+            }
+            res => res,
+        };
+        match self.degraded_link.as_ref().map(|link| link.latency_for(function)) {
+            Some(latency) if !latency.is_zero() => {
+                Box::pin(async move {
+                    tokio::time::sleep(latency).await;
+                    res
+                })
+            }
+            _ => Box::pin(future::ready(res)),
+        }
+    }
+}
+
+impl ExampleService {
+
+    pub fn with_shared_state(shared_state: SharedModbusState, peer: SocketAddr) -> Self {
+        Self {
+            shared_state,
+            peer,
+            replay: None,
+            capture: None,
+            server_id: DEFAULT_SERVER_ID.into(),
+            device_identification: Arc::new(DeviceIdentification::default()),
+            metrics: None,
+            degraded_link: None,
+            swap_mode: SwapMode::None,
+            exception_status_bits: ExceptionStatusBits::DEFAULT,
+            unknown_function_policy: UnknownFunctionPolicy::default(),
+            allow_zero_count: false,
+            disabled_functions: Arc::new(HashSet::new()),
+            request_floor: None,
+            golden: None,
+            last_request: Arc::new(Mutex::new(None)),
+            units: None,
+        }
+    }
+
+    /// Answers each unit ID in `units` from its own state, like several arms behind one gateway,
+    /// and any other unit ID with a GatewayTargetDevice exception. Broadcasts reach every unit.
+    /// Without units, every unit ID shares the service's state.
+    pub fn with_units(mut self, units: Option<Arc<HashMap<SlaveId, SharedModbusState>>>) -> Self {
+        self.units = units;
+        self
+    }
+
+    /// The states a request to `slave` goes to, none if it isn't one of the units.
+    fn unit_states(&self, slave: SlaveId, broadcast: bool) -> Vec<&SharedModbusState> {
+        match &self.units {
+            None => vec![&self.shared_state],
+            Some(units) if broadcast => units.values().collect(),
+            Some(units) => units.get(&slave).into_iter().collect(),
+        }
+    }
+
+    /// Answers requests found in `replay` with their recorded responses instead of computing them.
+    pub fn with_replay(mut self, replay: Option<Arc<ReplayLog>>) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    /// Records every exchange handled by this service to `capture`.
+    pub fn with_capture(mut self, capture: Option<Arc<CaptureLog>>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// Identity string returned to ReportServerId requests, at most [`MAX_SERVER_ID_LEN`] bytes.
+    pub fn with_server_id(mut self, server_id: Arc<str>) -> Self {
+        debug_assert!(server_id.len() <= MAX_SERVER_ID_LEN);
+        self.server_id = server_id;
+        self
+    }
+
+    /// Objects returned to Read Device Identification (FC 43 / MEI type 14) requests.
+    pub fn with_device_identification(mut self, device_identification: Arc<DeviceIdentification>) -> Self {
+        self.device_identification = device_identification;
+        self
+    }
+
+    /// Counts every request handled by this service in `metrics`.
+    pub fn with_metrics(mut self, metrics: Option<Arc<Metrics>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Drops and delays requests according to `degraded_link`. Dropped requests are not handled
+    /// at all, as if lost on the way in; the latency holds back the response.
+    pub fn with_degraded_link(mut self, degraded_link: Option<Arc<DegradedLink>>) -> Self {
+        self.degraded_link = degraded_link;
+        self
+    }
+
+    /// Rearranges multi-register payloads on the wire, see [`SwapMode`].
+    pub fn with_swap_mode(mut self, swap_mode: SwapMode) -> Self {
+        self.swap_mode = swap_mode;
+        self
+    }
+
+    /// Where the handshake signals go in the Read Exception Status (FC 07) byte.
+    pub fn with_exception_status_bits(mut self, bits: ExceptionStatusBits) -> Self {
+        self.exception_status_bits = bits;
+        self
+    }
+
+    /// How requests with function codes the service doesn't implement are answered.
+    pub fn with_unknown_function_policy(mut self, policy: UnknownFunctionPolicy) -> Self {
+        self.unknown_function_policy = policy;
+        self
+    }
+
+    /// Answers these function codes with `IllegalFunction` even where implemented, like a device
+    /// that lacks them, e.g. FC 16 to push the master onto single register writes.
+    pub fn with_disabled_functions(mut self, function_codes: Arc<HashSet<u8>>) -> Self {
+        self.disabled_functions = function_codes;
+        self
+    }
+
+    /// Answer reads of zero coils or registers with an empty response instead of the
+    /// `IllegalDataValue` the spec asks for, like some lenient devices do.
+    pub fn with_zero_count_reads(mut self, allow_zero_count: bool) -> Self {
+        self.allow_zero_count = allow_zero_count;
+        self
+    }
+
+    /// Flags requests arriving within `request_floor.min_spacing` of the previous one on the same
+    /// connection, which means the master sent it without waiting for the previous response.
+    pub fn with_request_floor(mut self, request_floor: Option<RequestFloor>) -> Self {
+        self.request_floor = request_floor;
+        self
+    }
+
+    /// Checks every request against `golden` as it arrives, whether or not it's answered.
+    pub fn with_golden_sequence(mut self, golden: Option<Arc<GoldenSequence>>) -> Self {
+        self.golden = golden;
+        self
+    }
+
+    /// Notes the arrival of a request and checks it against the request floor. Returns whether the
+    /// request is to be rejected.
+    fn under_request_floor(&self, tag: &str) -> bool {
+        let Some(floor) = self.request_floor else {
+            return false;
+        };
+        let now = tokio::time::Instant::now();
+        let Some(previous) = self.last_request.lock().unwrap().replace(now) else {
+            return false;
+        };
+        let spacing = now - previous;
+        if spacing >= floor.min_spacing {
+            return false;
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.record_too_fast();
+        }
+        match floor.policy {
+            FloorPolicy::Flag => {
+                warn!("{tag} Request arrived {spacing:?} after the previous one, under the {:?} floor", floor.min_spacing);
+                false
+            }
+            FloorPolicy::Reject => {
+                warn!("{tag} Exception::ServerDeviceBusy - Request arrived {spacing:?} after the previous one, \
+                    under the {:?} floor", floor.min_spacing);
+                true
+            }
+        }
+    }
+
+    /// Builds the ReportServerId (FC 17) response. The run indicator is ON while the arm is in motion.
+    ///
+    /// Encoded by hand because tokio-modbus 0.16 under-counts `Response::ReportServerId` by one byte
+    /// in the MBAP length, which truncates the identity and desyncs the client's stream.
+    fn report_server_id(&self, state: &SharedModbusState) -> Response {
+        let running = state.read_coil(state.register_map().running_coil);
+        let identity = self.server_id.as_bytes();
+        let mut data = Vec::with_capacity(3 + identity.len());
+        data.push((2 + identity.len()) as u8);
+        data.push(SERVER_ID_BYTE);
+        data.push(if running { 0xFF } else { 0x00 });
+        data.extend_from_slice(identity);
+        Response::Custom(REPORT_SERVER_ID_FUNCTION_CODE, data.into())
+    }
+
+    fn handle(&self, state: &SharedModbusState, req: Request<'static>, tag: &str) -> Result<Option<Response>, ExceptionCode> {
+        let function_code = req.function_code().value();
+        if self.disabled_functions.contains(&function_code) {
+            warn!("{tag} Exception::IllegalFunction - Function code {function_code} is disabled: {req:?}");
+            return Err(ExceptionCode::IllegalFunction);
+        }
+        match req {
+            // The spec's minimum quantity is 1
+            Request::ReadCoils(_, 0) | Request::ReadDiscreteInputs(_, 0) | Request::ReadHoldingRegisters(_, 0)
+                | Request::ReadInputRegisters(_, 0) if !self.allow_zero_count => {
+                warn!("{tag} Exception::IllegalDataValue - Requested a quantity of 0: {req:?}");
+                Err(ExceptionCode::IllegalDataValue)
+            }
+            Request::ReadHoldingRegisters(_, cnt) if cnt > MAX_READ_REGISTERS => {
+                warn!("{tag} Exception::IllegalDataValue - Requested {cnt} holding registers, max is {MAX_READ_REGISTERS}");
+                Err(ExceptionCode::IllegalDataValue)
+            }
             Request::ReadHoldingRegisters(addr, cnt) => {
-                let values = self.shared_state.read_holding_registers(addr, cnt);
-                Ok(Response::ReadHoldingRegisters(values))
+                check_range(addr, cnt as usize, tag)?;
+                let mut values = state.read_holding_registers(addr, cnt);
+                self.swap_mode.apply(&mut values);
+                Ok(Some(Response::ReadHoldingRegisters(values)))
+            }
+            Request::ReadInputRegisters(_, cnt) if cnt > MAX_READ_REGISTERS => {
+                warn!("{tag} Exception::IllegalDataValue - Requested {cnt} input registers, max is {MAX_READ_REGISTERS}");
+                Err(ExceptionCode::IllegalDataValue)
+            }
+            Request::ReadInputRegisters(addr, cnt) => {
+                check_range(addr, cnt as usize, tag)?;
+                let mut values = state.read_input_registers(addr, cnt);
+                self.swap_mode.apply(&mut values);
+                Ok(Some(Response::ReadInputRegisters(values)))
+            }
+            // tokio-modbus already checked the byte count against the quantity while decoding
+            Request::WriteMultipleRegisters(_, ref values) if values.len() > MAX_WRITE_REGISTERS as usize => {
+                warn!("{tag} Exception::IllegalDataValue - Wrote {} holding registers, max is {MAX_WRITE_REGISTERS}", values.len());
+                Err(ExceptionCode::IllegalDataValue)
             }
             Request::WriteMultipleRegisters(addr, values) => {
-                self.shared_state.write_holding_registers(addr, &values);
-                Ok(Response::WriteMultipleRegisters(addr, values.len() as u16))
+                check_range(addr, values.len(), tag)?;
+                let mut values = values.into_owned();
+                self.swap_mode.apply(&mut values);
+                state.write_holding_registers(addr, &values);
+                Ok(Some(Response::WriteMultipleRegisters(addr, values.len() as u16)))
             }
             Request::WriteSingleRegister(addr, value) => {
-                self.shared_state.write_holding_register(addr, value);
-                Ok(Response::WriteSingleRegister(addr, value))
+                let mut stored = [value];
+                self.swap_mode.apply(&mut stored);
+                state.write_holding_register(addr, stored[0]);
+                // The response echoes the request as it was on the wire
+                Ok(Some(Response::WriteSingleRegister(addr, value)))
+            }
+            Request::ReadCoils(_, cnt) if cnt > MAX_READ_COILS => {
+                warn!("{tag} Exception::IllegalDataValue - Requested {cnt} coils, max is {MAX_READ_COILS}");
+                Err(ExceptionCode::IllegalDataValue)
             }
             Request::ReadCoils(addr, cnt) => {
-                let values = self.shared_state.read_coils(addr, cnt);
-                Ok(Response::ReadCoils(values))
+                check_range(addr, cnt as usize, tag)?;
+                // tokio-modbus packs exactly these `cnt` bools LSB-first and zero-pads the last byte,
+                // so the vec must not be rounded up to a multiple of 8 here.
+                let values = state.read_coils(addr, cnt);
+                debug_assert_eq!(values.len(), cnt as usize);
+                Ok(Some(Response::ReadCoils(values)))
+            }
+            Request::ReadDiscreteInputs(_, cnt) if cnt > MAX_READ_DISCRETE_INPUTS => {
+                warn!("{tag} Exception::IllegalDataValue - Requested {cnt} discrete inputs, max is {MAX_READ_DISCRETE_INPUTS}");
+                Err(ExceptionCode::IllegalDataValue)
+            }
+            Request::ReadDiscreteInputs(addr, cnt) => {
+                if addr as usize + cnt as usize > STATUS_INPUTS as usize {
+                    warn!("{tag} Exception::IllegalDataAddress - Requested {cnt} discrete inputs from {addr}, \
+                        only 0..{STATUS_INPUTS} exist");
+                    return Err(ExceptionCode::IllegalDataAddress);
+                }
+                let status = self.exception_status_bits.status_byte(state);
+                Ok(Some(Response::ReadDiscreteInputs(addresses(addr, cnt as usize).map(|bit| status >> bit & 1 != 0).collect())))
+            }
+            Request::WriteMultipleCoils(_, ref values) if values.len() > MAX_WRITE_COILS as usize => {
+                warn!("{tag} Exception::IllegalDataValue - Wrote {} coils, max is {MAX_WRITE_COILS}", values.len());
+                Err(ExceptionCode::IllegalDataValue)
             }
             Request::WriteMultipleCoils(addr, values) => {
-                self.shared_state.write_coils(addr, &values);
-                Ok(Response::WriteMultipleCoils(addr, values.len() as u16))
+                check_range(addr, values.len(), tag)?;
+                let protected: Vec<u16> = addresses(addr, values.len())
+                    .filter(|&coil_addr| state.is_read_only_coil(coil_addr))
+                    .collect();
+                if protected.is_empty() {
+                    state.write_coils(addr, &values);
+                } else {
+                    match state.read_only_policy() {
+                        ReadOnlyPolicy::Reject => {
+                            warn!("{tag} Exception::IllegalDataAddress - Write to read-only coils {protected:?}");
+                            return Err(ExceptionCode::IllegalDataAddress);
+                        }
+                        ReadOnlyPolicy::Ignore => {
+                            debug!("{tag} Ignoring write to read-only coils {protected:?}");
+                            state.write_writable_coils(addr, &values);
+                        }
+                    }
+                }
+                Ok(Some(Response::WriteMultipleCoils(addr, values.len() as u16)))
             }
+            // Only 0xFF00 and 0x0000 reach this point: tokio-modbus refuses any other coil value while
+            // decoding the frame and drops the connection, so there is no request left to answer with
+            // `IllegalDataValue`.
             Request::WriteSingleCoil(addr, value) => {
-                self.shared_state.write_coil(addr, value);
-                Ok(Response::WriteSingleCoil(addr, value))
+                if state.is_read_only_coil(addr) {
+                    if state.read_only_policy() == ReadOnlyPolicy::Reject {
+                        warn!("{tag} Exception::IllegalDataAddress - Write to read-only coil {addr}");
+                        return Err(ExceptionCode::IllegalDataAddress);
+                    }
+                    debug!("{tag} Ignoring write to read-only coil {addr}");
+                } else if let Some(link) = &self.degraded_link && link.should_ignore_write() {
+                    warn!("{tag} Acknowledging write of {value} to coil {addr} without applying it");
+                } else {
+                    state.write_coil(addr, value);
+                }
+                Ok(Some(Response::WriteSingleCoil(addr, value)))
             }
-            _ => {
-                println!("SERVER: Exception::IllegalFunction - Unimplemented function code in request: {req:?}");
-                Err(ExceptionCode::IllegalFunction)
+            Request::ReportServerId => Ok(Some(self.report_server_id(state))),
+            Request::Custom(DIAGNOSTICS_FUNCTION_CODE, data) => diagnostics(&data, tag).map(Some),
+            Request::Custom(ENCAPSULATED_INTERFACE_FUNCTION_CODE, data) =>
+                self.device_identification.respond(&data, tag).map(Some),
+            Request::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, data) if !data.is_empty() => {
+                warn!("{tag} Exception::IllegalDataValue - Read Exception Status request with {} data bytes", data.len());
+                Err(ExceptionCode::IllegalDataValue)
             }
-        };
-        future::ready(res)
+            Request::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, _) => {
+                let status = self.exception_status_bits.status_byte(state);
+                Ok(Some(Response::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, vec![status].into())))
+            }
+            _ => match self.unknown_function_policy {
+                UnknownFunctionPolicy::IllegalFunction => {
+                    warn!("{tag} Exception::IllegalFunction - Unimplemented function code in request: {req:?}");
+                    Err(ExceptionCode::IllegalFunction)
+                }
+                UnknownFunctionPolicy::DeviceFailure => {
+                    warn!("{tag} Exception::ServerDeviceFailure - Unimplemented function code in request: {req:?}");
+                    Err(ExceptionCode::ServerDeviceFailure)
+                }
+                UnknownFunctionPolicy::Drop => {
+                    warn!("{tag} Dropping request with unimplemented function code, no response will be sent: {req:?}");
+                    Ok(None)
+                }
+            },
+        }
     }
 }
 
-impl ExampleService {
+/// Requests that change state, the only ones that make sense as a broadcast.
+fn is_write(req: &Request<'_>) -> bool {
+    matches!(req, Request::WriteSingleCoil(..) | Request::WriteMultipleCoils(..)
+        | Request::WriteSingleRegister(..) | Request::WriteMultipleRegisters(..))
+}
 
-    pub fn with_shared_state(shared_state: SharedModbusState) -> Self {
-        Self {
-            shared_state,
+/// Answers a diagnostics request. `data` is the PDU after the function code: a 16-bit
+/// sub-function followed by its data.
+///
+/// Only the loopback is meaningful here; the other supported sub-functions are stubs since the
+/// simulator keeps no serial line state or counters.
+fn diagnostics(data: &[u8], tag: &str) -> Result<Response, ExceptionCode> {
+    let Some((sub_function, _)) = data.split_first_chunk::<2>() else {
+        warn!("{tag} Exception::IllegalDataValue - Diagnostics request without sub-function");
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    match u16::from_be_bytes(*sub_function) {
+        // Restart and clear counters echo the request like the loopback does
+        DIAG_RETURN_QUERY_DATA | DIAG_RESTART_COMMUNICATIONS | DIAG_CLEAR_COUNTERS =>
+            Ok(Response::Custom(DIAGNOSTICS_FUNCTION_CODE, data.to_vec().into())),
+        DIAG_RETURN_DIAGNOSTIC_REGISTER => {
+            let mut response = sub_function.to_vec();
+            response.extend_from_slice(&0u16.to_be_bytes());
+            Ok(Response::Custom(DIAGNOSTICS_FUNCTION_CODE, response.into()))
+        }
+        other => {
+            warn!("{tag} Exception::IllegalFunction - Unsupported diagnostics sub-function {other:#06X}");
+            Err(ExceptionCode::IllegalFunction)
         }
     }
+}
+
+/// Prefix for service log lines so interleaved traffic from several masters/unit IDs can be told apart.
+pub fn log_tag(peer: SocketAddr, unit_id: SlaveId) -> String {
+    format!("[{peer} unit {unit_id}]")
 }
\ No newline at end of file
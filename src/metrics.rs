@@ -0,0 +1,173 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio_modbus::FunctionCode;
+
+/// Function codes are 7 bits, the high bit marks exception responses.
+const FUNCTION_CODES: usize = 0x80;
+
+/// Upper bounds of the latency histogram buckets. Requests slower than the last one only show
+/// up in the totals.
+pub const LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_micros(10),
+    Duration::from_micros(25),
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+];
+
+/// Request counters and handling latency, kept per function code.
+///
+/// Shared by every connection's service; all updates are lock-free.
+pub struct Metrics {
+    functions: [FunctionMetrics; FUNCTION_CODES],
+    too_fast: AtomicU64,
+    broadcast_failures: AtomicU64,
+}
+
+struct FunctionMetrics {
+    requests: AtomicU64,
+    exceptions: AtomicU64,
+    latency_sum_ns: AtomicU64,
+    latency_min_ns: AtomicU64,
+    latency_max_ns: AtomicU64,
+    /// Non-cumulative count per [`LATENCY_BUCKETS`] entry.
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+}
+
+impl FunctionMetrics {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            exceptions: AtomicU64::new(0),
+            latency_sum_ns: AtomicU64::new(0),
+            latency_min_ns: AtomicU64::new(u64::MAX),
+            latency_max_ns: AtomicU64::new(0),
+            latency_buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+        }
+    }
+}
+
+/// Point-in-time copy of one function code's counters.
+#[derive(Clone, Debug)]
+pub struct FunctionSnapshot {
+    pub function: FunctionCode,
+    pub requests: u64,
+    pub exceptions: u64,
+    pub latency_min: Duration,
+    pub latency_max: Duration,
+    pub latency_avg: Duration,
+    pub latency_sum: Duration,
+    /// Cumulative count of requests at or below each [`LATENCY_BUCKETS`] entry.
+    pub latency_buckets: [u64; LATENCY_BUCKETS.len()],
+}
+
+/// Point-in-time copy of [`Metrics`], only listing function codes that were requested.
+#[derive(Clone, Debug)]
+pub struct MetricsSnapshot {
+    pub functions: Vec<FunctionSnapshot>,
+    /// Requests that arrived sooner after the previous one than the request floor allows.
+    pub too_fast: u64,
+    /// Broadcast writes that failed, with no response to tell the master.
+    pub broadcast_failures: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            functions: [const { FunctionMetrics::new() }; FUNCTION_CODES],
+            too_fast: AtomicU64::new(0),
+            broadcast_failures: AtomicU64::new(0),
+        }
+    }
+
+    /// Records one handled request and how long it took to answer.
+    pub fn record(&self, function: FunctionCode, latency: Duration, exception: bool) {
+        let Some(metrics) = self.functions.get(function.value() as usize) else {
+            return;
+        };
+        let latency_ns = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        if exception {
+            metrics.exceptions.fetch_add(1, Ordering::Relaxed);
+        }
+        metrics.latency_sum_ns.fetch_add(latency_ns, Ordering::Relaxed);
+        metrics.latency_min_ns.fetch_min(latency_ns, Ordering::Relaxed);
+        metrics.latency_max_ns.fetch_max(latency_ns, Ordering::Relaxed);
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| latency <= bound) {
+            metrics.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts one request that came in under the request floor.
+    pub fn record_too_fast(&self) {
+        self.too_fast.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts one broadcast write whose exception was suppressed.
+    pub fn record_broadcast_failure(&self) {
+        self.broadcast_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let functions = self.functions.iter().enumerate()
+            .filter_map(|(code, metrics)| {
+                let requests = metrics.requests.load(Ordering::Relaxed);
+                if requests == 0 {
+                    return None;
+                }
+                let latency_sum_ns = metrics.latency_sum_ns.load(Ordering::Relaxed);
+                let mut latency_buckets = [0; LATENCY_BUCKETS.len()];
+                let mut cumulative = 0;
+                for (bucket, count) in latency_buckets.iter_mut().zip(&metrics.latency_buckets) {
+                    cumulative += count.load(Ordering::Relaxed);
+                    *bucket = cumulative;
+                }
+                Some(FunctionSnapshot {
+                    function: FunctionCode::new(code as u8),
+                    requests,
+                    exceptions: metrics.exceptions.load(Ordering::Relaxed),
+                    latency_min: Duration::from_nanos(metrics.latency_min_ns.load(Ordering::Relaxed)),
+                    latency_max: Duration::from_nanos(metrics.latency_max_ns.load(Ordering::Relaxed)),
+                    latency_avg: Duration::from_nanos(latency_sum_ns / requests),
+                    latency_sum: Duration::from_nanos(latency_sum_ns),
+                    latency_buckets,
+                })
+            })
+            .collect();
+        MetricsSnapshot {
+            functions,
+            too_fast: self.too_fast.load(Ordering::Relaxed),
+            broadcast_failures: self.broadcast_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MetricsSnapshot {
+    pub fn requests_total(&self) -> u64 {
+        self.functions.iter().map(|function| function.requests).sum()
+    }
+
+    pub fn exceptions_total(&self) -> u64 {
+        self.functions.iter().map(|function| function.exceptions).sum()
+    }
+}
+
+impl Display for FunctionSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<28} {:>8} req {:>6} exc   latency min {:?} / avg {:?} / max {:?}",
+            format!("{:?}", self.function), self.requests, self.exceptions,
+            self.latency_min, self.latency_avg, self.latency_max)
+    }
+}
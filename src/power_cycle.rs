@@ -0,0 +1,20 @@
+use std::time::Duration;
+use log::{info, warn};
+use tokio::time;
+use crate::connections::ConnectionTracker;
+use crate::mb_stuff::SharedModbusState;
+
+/// How long a simulated power cycle keeps the device offline unless told otherwise.
+pub const DEFAULT_BOOT_DELAY: Duration = Duration::from_secs(2);
+
+/// Simulates the device rebooting: every connection is dropped, all coils and registers go back
+/// to their defaults, and no connection is accepted for `boot_delay`. The master has to reconnect
+/// and initialize the handshake again.
+pub async fn simulate_power_cycle(state: &SharedModbusState, connections: &ConnectionTracker, boot_delay: Duration) {
+    warn!("Simulated power cycle: dropping {} connections, back in {boot_delay:?}", connections.active());
+    connections.power_off();
+    state.reset();
+    time::sleep(boot_delay).await;
+    connections.power_on();
+    info!("Simulated power cycle: booted, accepting connections again");
+}
@@ -0,0 +1,112 @@
+use std::fmt::Write as _;
+use std::sync::Arc;
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_modbus::FunctionCode;
+use crate::metrics::{Metrics, MetricsSnapshot, LATENCY_BUCKETS};
+
+/// Scrapers only send a short GET, anything bigger is not a scrape.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Serves [`Metrics`] at `GET /metrics` in the Prometheus text exposition format, on a listener
+/// the caller has already bound.
+///
+/// Deliberately minimal HTTP/1.0: one request per connection, then the connection is closed.
+pub async fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    info!("Serving Prometheus metrics on http://{}/metrics", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_scrape(stream, &metrics).await {
+                warn!("Metrics request from {peer} failed: {err}");
+            }
+        });
+    }
+}
+
+async fn handle_scrape(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request_line = String::from_utf8_lossy(&request);
+    let request_line = request_line.lines().next().unwrap_or_default();
+    debug!("Metrics request: {request_line}");
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = format_exposition(&metrics.snapshot());
+            format!("HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len())
+        }
+        _ => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn format_exposition(snapshot: &MetricsSnapshot) -> String {
+    let mut out = String::new();
+    // Writing to a String can't fail
+    let _ = writeln!(out, "# HELP modbus_requests_total Modbus requests handled, by function.");
+    let _ = writeln!(out, "# TYPE modbus_requests_total counter");
+    for function in &snapshot.functions {
+        let _ = writeln!(out, "modbus_requests_total{{function=\"{}\"}} {}",
+            function_label(function.function), function.requests);
+    }
+    let _ = writeln!(out, "# HELP modbus_exceptions_total Modbus exception responses returned, by function.");
+    let _ = writeln!(out, "# TYPE modbus_exceptions_total counter");
+    for function in &snapshot.functions {
+        let _ = writeln!(out, "modbus_exceptions_total{{function=\"{}\"}} {}",
+            function_label(function.function), function.exceptions);
+    }
+    let _ = writeln!(out, "# HELP modbus_request_duration_seconds Time taken to answer a Modbus request, by function.");
+    let _ = writeln!(out, "# TYPE modbus_request_duration_seconds histogram");
+    for function in &snapshot.functions {
+        let label = function_label(function.function);
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(function.latency_buckets) {
+            let _ = writeln!(out, "modbus_request_duration_seconds_bucket{{function=\"{label}\",le=\"{}\"}} {count}",
+                bound.as_secs_f64());
+        }
+        let _ = writeln!(out, "modbus_request_duration_seconds_bucket{{function=\"{label}\",le=\"+Inf\"}} {}",
+            function.requests);
+        let _ = writeln!(out, "modbus_request_duration_seconds_sum{{function=\"{label}\"}} {}",
+            function.latency_sum.as_secs_f64());
+        let _ = writeln!(out, "modbus_request_duration_seconds_count{{function=\"{label}\"}} {}",
+            function.requests);
+    }
+    let _ = writeln!(out, "# HELP modbus_requests_too_fast_total Modbus requests that arrived under the request floor.");
+    let _ = writeln!(out, "# TYPE modbus_requests_too_fast_total counter");
+    let _ = writeln!(out, "modbus_requests_too_fast_total {}", snapshot.too_fast);
+    let _ = writeln!(out, "# HELP modbus_broadcast_failures_total Broadcast writes that failed, unanswered as broadcasts are.");
+    let _ = writeln!(out, "# TYPE modbus_broadcast_failures_total counter");
+    let _ = writeln!(out, "modbus_broadcast_failures_total {}", snapshot.broadcast_failures);
+    out
+}
+
+/// `ReadCoils` -> `read_coils`, unnamed codes as `custom_<code>`.
+fn function_label(function: FunctionCode) -> String {
+    if let FunctionCode::Custom(code) = function {
+        return format!("custom_{code}");
+    }
+    let mut label = String::new();
+    for (i, c) in format!("{function:?}").chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                label.push('_');
+            }
+            label.push(c.to_ascii_lowercase());
+        } else {
+            label.push(c);
+        }
+    }
+    label
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+use log::info;
+use tokio::time;
+use crate::mb_stuff::SharedModbusState;
+
+/// Settings for a simulated servo that follows a written setpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RampConfig {
+    /// Holding register the client writes the setpoint to.
+    pub target_hreg: u16,
+    /// Input register reporting where the servo currently is.
+    pub actual_ireg: u16,
+    /// How far actual moves toward target per tick.
+    pub rate: u16,
+    /// How often actual is moved.
+    pub tick: Duration,
+}
+
+impl RampConfig {
+    pub const DEFAULT_RATE: u16 = 10;
+    pub const DEFAULT_TICK: Duration = Duration::from_millis(10);
+
+    pub fn new(target_hreg: u16, actual_ireg: u16) -> Self {
+        Self {
+            target_hreg,
+            actual_ireg,
+            rate: Self::DEFAULT_RATE,
+            tick: Self::DEFAULT_TICK,
+        }
+    }
+
+    /// How long the ramp needs to move actual from `from` to `to`, ignoring scheduling jitter.
+    pub fn expected_duration(&self, from: u16, to: u16) -> Duration {
+        let ticks = from.abs_diff(to).div_ceil(self.rate.max(1));
+        self.tick * ticks as u32
+    }
+}
+
+/// Moves the actual input register toward the target holding register by at most `rate` each
+/// tick, like a servo ramping to a new position instead of jumping there.
+pub async fn run_ramp(state: SharedModbusState, config: RampConfig) {
+    info!("Simulated ramp started: register {} -> input register {}, {} per {:?}",
+        config.target_hreg, config.actual_ireg, config.rate, config.tick);
+    let mut interval = time::interval(config.tick);
+    loop {
+        interval.tick().await;
+        let target = state.read_holding_registers(config.target_hreg, 1)[0];
+        let actual = state.read_live_input_registers(config.actual_ireg, 1)[0];
+        if actual == target {
+            continue;
+        }
+        let step = actual.abs_diff(target).min(config.rate);
+        let next = if target > actual { actual + step } else { actual - step };
+        state.write_input_register(config.actual_ireg, next);
+    }
+}
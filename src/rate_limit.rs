@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Lets a recurring warning through the first time, then at most once per `interval`, separately
+/// for each key.
+pub struct WarningLimiter<K> {
+    interval: Duration,
+    seen: Mutex<HashMap<K, Repeats>>,
+}
+
+struct Repeats {
+    logged_at: Instant,
+    suppressed: u64,
+}
+
+impl<K: Eq + Hash> WarningLimiter<K> {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, seen: Mutex::new(HashMap::new()) }
+    }
+
+    /// Whether the warning for `key` should be logged now, and if so how many times it was held
+    /// back since it last was.
+    pub fn admit(&self, key: K) -> Option<u64> {
+        match self.seen.lock().unwrap().entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(Repeats { logged_at: Instant::now(), suppressed: 0 });
+                Some(0)
+            }
+            Entry::Occupied(mut entry) => {
+                let repeats = entry.get_mut();
+                if repeats.logged_at.elapsed() < self.interval {
+                    repeats.suppressed += 1;
+                    return None;
+                }
+                repeats.logged_at = Instant::now();
+                Some(std::mem::take(&mut repeats.suppressed))
+            }
+        }
+    }
+}
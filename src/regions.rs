@@ -0,0 +1,86 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+/// Which of the state's address spaces an address or region is in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AddressSpace {
+    Coil,
+    HoldingRegister,
+    InputRegister,
+}
+
+impl AddressSpace {
+    /// Parses the short names used on the command line: `coil`, `hreg` or `ireg`.
+    pub fn from_short_name(name: &str) -> Option<Self> {
+        match name {
+            "coil" => Some(Self::Coil),
+            "hreg" => Some(Self::HoldingRegister),
+            "ireg" => Some(Self::InputRegister),
+            _ => None,
+        }
+    }
+}
+
+impl Display for AddressSpace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Coil => "coil",
+            Self::HoldingRegister => "holding register",
+            Self::InputRegister => "input register",
+        })
+    }
+}
+
+/// A named range of addresses, e.g. `control_coils` for coils 0..16, so logs can say what an
+/// address is for instead of just its number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub space: AddressSpace,
+    pub range: Range<u16>,
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}s {}..{}", self.name, self.space, self.range.start, self.range.end)
+    }
+}
+
+/// The declared regions. Where regions overlap, the one declared first names the address.
+#[derive(Clone, Debug, Default)]
+pub struct Regions(Vec<Region>);
+
+impl Regions {
+    pub fn new(regions: Vec<Region>) -> Self {
+        Self(regions)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.0.iter()
+    }
+
+    pub fn find(&self, space: AddressSpace, addr: u16) -> Option<&Region> {
+        self.0.iter().find(|region| region.space == space && region.range.contains(&addr))
+    }
+
+    /// The address as logs should show it, e.g. `coil 12 (control_coils+12)`.
+    pub fn label(&self, space: AddressSpace, addr: u16) -> AddressLabel<'_> {
+        AddressLabel { space, addr, region: self.find(space, addr) }
+    }
+}
+
+pub struct AddressLabel<'a> {
+    space: AddressSpace,
+    addr: u16,
+    region: Option<&'a Region>,
+}
+
+impl Display for AddressLabel<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.space, self.addr)?;
+        match self.region {
+            Some(region) => write!(f, " ({}+{})", region.name, self.addr - region.range.start),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt::{Display, Formatter};
+
+/// Where the arm handshake lives in the Modbus address space.
+///
+/// Vendors disagree on the layout, so the simulator, the simulated arm and the test cases all go
+/// through the active map in [`crate::mb_stuff::SharedModbusState`] instead of fixed addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegisterMap {
+    pub enable_coil: u16,
+    pub running_coil: u16,
+    pub ready_coil: u16,
+    pub index_hreg: u16,
+    pub fault_hreg: u16,
+}
+
+impl RegisterMap {
+    /// The layout this simulator was written against.
+    pub const DEFAULT: RegisterMap = RegisterMap {
+        enable_coil: 8,
+        running_coil: 9,
+        ready_coil: 10,
+        index_hreg: 8,
+        fault_hreg: 9,
+    };
+
+    /// Same handshake packed from address 0, as used by devices without reserved low addresses.
+    pub const ZERO_BASED: RegisterMap = RegisterMap {
+        enable_coil: 0,
+        running_coil: 1,
+        ready_coil: 2,
+        index_hreg: 0,
+        fault_hreg: 1,
+    };
+
+    /// Named presets offered at startup. Lab specific layouts belong here.
+    pub const PRESETS: &[(&str, RegisterMap)] = &[
+        ("Default", Self::DEFAULT),
+        ("Zero based", Self::ZERO_BASED),
+    ];
+
+    /// Looks up a preset by name, ignoring case.
+    pub fn preset(name: &str) -> Option<RegisterMap> {
+        Self::PRESETS.iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+            .map(|(_, map)| *map)
+    }
+
+    pub fn coils(&self) -> [u16; 3] {
+        [self.enable_coil, self.running_coil, self.ready_coil]
+    }
+
+    pub fn holding_registers(&self) -> [u16; 2] {
+        [self.index_hreg, self.fault_hreg]
+    }
+}
+
+impl Default for RegisterMap {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Display for RegisterMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "enable coil {}, running coil {}, ready coil {}, index register {}, fault register {}",
+            self.enable_coil, self.running_coil, self.ready_coil, self.index_hreg, self.fault_hreg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RegisterMap;
+
+    /// Each preset by name, in any case, with the addresses masters in the field are wired to.
+    #[test]
+    fn presets() {
+        for (name, coils, holding_registers) in [
+            ("Default", [8, 9, 10], [8, 9]),
+            ("default", [8, 9, 10], [8, 9]),
+            ("Zero based", [0, 1, 2], [0, 1]),
+            ("ZERO BASED", [0, 1, 2], [0, 1]),
+        ] {
+            let map = RegisterMap::preset(name);
+            assert_eq!(map.map(|map| (map.coils(), map.holding_registers())), Some((coils, holding_registers)),
+                "{name} preset addresses");
+        }
+        assert_eq!(RegisterMap::PRESETS.len(), 2, "Every preset is pinned above");
+        assert_eq!(RegisterMap::preset("Zero-based"), None, "Unknown preset name");
+        assert_eq!(RegisterMap::preset(""), None, "Empty preset name");
+    }
+}
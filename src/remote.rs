@@ -0,0 +1,245 @@
+use log::{debug, error, info, warn};
+use tokio::time::{self, Duration, Instant};
+use tokio_modbus::client::{Context, Reader, Writer};
+use crate::arm_sim::EnableMode;
+use crate::duration_format::format_duration;
+use crate::mb_stuff::{ExceptionStatusBits, StatusBlock, WordOrder};
+use crate::register_map::RegisterMap;
+use crate::sweep_csv::SweepCsv;
+use crate::test_cases::{DelaySweep, EarlyStopResult, MotionHangError, RunningNeverAssertedError, SubroutineRun, TestCases,
+    TestConfig, classify_completed, EARLY_STOP_WAIT, MOTION_TIMEOUT, RUNNING_START_TIMEOUT};
+
+// The handshake over Modbus, for `--connect`: the same steps as the `_shared` test functions,
+// against the registers of a remote arm instead of the embedded state. Only the test cases that
+// need nothing but the handshake coils and registers are available.
+
+/// How far a handshake got before it ended.
+enum Handshake {
+    /// Running dropped at `ended`.
+    Completed { ended: Instant },
+    /// The early stop came due first.
+    StopDue,
+}
+
+/// Runs sub routine `idx` on the arm behind `ctx`, like [`crate::test_cases::sr_single_shared`]
+/// does on the embedded state.
+pub async fn sr_single(ctx: &mut Context, map: &RegisterMap, config: &TestConfig, idx: u16) -> anyhow::Result<()> {
+    handshake(ctx, map, config, idx, None).await.map(|_| ())
+}
+
+/// Runs sub routine `idx` and drops enable `delay` after commanding it, like
+/// [`crate::test_cases::sr_single_early_stop_shared`].
+///
+/// The stop only comes due between requests, as abandoning one halfway would leave its response
+/// in the stream for the next request to trip over. It can be up to a poll interval and a round
+/// trip late.
+pub async fn sr_single_early_stop(ctx: &mut Context, map: &RegisterMap, config: &TestConfig, idx: u16, delay: Duration)
+    -> anyhow::Result<EarlyStopResult> {
+    let stop_at = Instant::now() + delay;
+    match handshake(ctx, map, config, idx, Some(stop_at)).await {
+        Ok(Handshake::Completed { ended }) => {
+            debug!("Subroutine #{} completed before the early stop could be initiated", idx);
+            Ok(classify_completed(config, stop_at.saturating_duration_since(ended)))
+        }
+        Err(err) => {
+            debug!("Subroutine #{} failed to complete before the early stop could be initiated: {}", idx, err);
+            Err(err)
+        }
+        Ok(Handshake::StopDue) => {
+            ctx.write_single_coil(map.enable_coil, false).await??;
+            time::sleep(EARLY_STOP_WAIT).await;
+            if ctx.read_coils(map.running_coil, 1).await??[0] {
+                return Err(anyhow::anyhow!("Arm still running after early stop on index: {idx}. \
+                    Stopped at {} and waited 1 second", format_duration(delay)));
+            }
+            Ok(EarlyStopResult::Success)
+        }
+    }
+}
+
+/// Reads the first `count` discrete inputs of the arm behind `ctx` in one request and maps them
+/// with `bits`, like a controller polling its status block.
+pub async fn read_status_block(ctx: &mut Context, bits: &ExceptionStatusBits, count: u16) -> anyhow::Result<StatusBlock> {
+    let inputs = ctx.read_discrete_inputs(0, count).await??;
+    bits.decode(&inputs).ok_or_else(|| anyhow::anyhow!("A status block of {count} discrete inputs doesn't cover {bits:?}"))
+}
+
+/// Reads the holding register at `addr` of the arm behind `ctx` as a two's-complement signed
+/// value, like [`SharedModbusState::read_i16`](crate::mb_stuff::SharedModbusState::read_i16).
+pub async fn read_i16(ctx: &mut Context, addr: u16) -> anyhow::Result<i16> {
+    Ok(ctx.read_holding_registers(addr, 1).await??[0] as i16)
+}
+
+/// Writes a signed value to the holding register at `addr` of the arm behind `ctx` in
+/// two's-complement.
+pub async fn write_i16(ctx: &mut Context, addr: u16, value: i16) -> anyhow::Result<()> {
+    Ok(ctx.write_single_register(addr, value as u16).await??)
+}
+
+/// Reads a 32-bit value from the holding registers at `addr` and `addr + 1` of the arm behind
+/// `ctx`, like [`SharedModbusState::read_u32`](crate::mb_stuff::SharedModbusState::read_u32).
+pub async fn read_u32(ctx: &mut Context, addr: u16, word_order: WordOrder) -> anyhow::Result<u32> {
+    let words = ctx.read_holding_registers(addr, 2).await??;
+    Ok(word_order.join([words[0], words[1]]))
+}
+
+/// Writes a 32-bit value to the holding registers at `addr` and `addr + 1` of the arm behind
+/// `ctx` in one request.
+pub async fn write_u32(ctx: &mut Context, addr: u16, value: u32, word_order: WordOrder) -> anyhow::Result<()> {
+    Ok(ctx.write_multiple_registers(addr, &word_order.split(value)).await??)
+}
+
+async fn handshake(ctx: &mut Context, map: &RegisterMap, config: &TestConfig, idx: u16, stop_at: Option<Instant>)
+    -> anyhow::Result<Handshake> {
+    let stop_due = || stop_at.is_some_and(|at| Instant::now() >= at);
+    ctx.write_single_register(map.index_hreg, idx).await??;
+    ctx.write_single_coil(map.enable_coil, true).await??;
+    if !wait_for_running(ctx, map, config, true, RUNNING_START_TIMEOUT, stop_at).await? {
+        if stop_due() {
+            return Ok(Handshake::StopDue);
+        }
+        return Err(RunningNeverAssertedError { idx: idx.into(), running_coil: map.running_coil, waited: RUNNING_START_TIMEOUT }.into());
+    }
+
+    debug!("Arm set to running, should be executing sub routine #{}. Waiting up to {} for motion to complete", idx, format_duration(MOTION_TIMEOUT));
+    if !wait_for_running(ctx, map, config, false, MOTION_TIMEOUT, stop_at).await? {
+        if stop_due() {
+            return Ok(Handshake::StopDue);
+        }
+        return Err(MotionHangError { idx: idx.into(), running_coil: map.running_coil, elapsed: MOTION_TIMEOUT }.into());
+    }
+
+    let ended = Instant::now();
+    debug!("Motion complete");
+    ctx.write_single_coil(map.enable_coil, false).await??;
+    if config.enable_mode == EnableMode::Edge {
+        time::sleep(SubroutineRun::IDLE_CHECK_DELAY).await;
+        if ctx.read_coils(map.running_coil, 1).await??[0] {
+            return Err(anyhow::anyhow!("Arm still running after motion complete. \
+                Enable coil was set to false, and then running was set true again. Likely arm is \
+                blindly running when enable is true, not only on rising edge"));
+        }
+    }
+    Ok(Handshake::Completed { ended })
+}
+
+/// Polls running until `stable_reads` reads in a row are `target_state`. Returns `false` once
+/// `timeout` passes or the stop at `stop_at` is due, whichever comes first.
+async fn wait_for_running(ctx: &mut Context, map: &RegisterMap, config: &TestConfig, target_state: bool, timeout: Duration,
+    stop_at: Option<Instant>) -> anyhow::Result<bool> {
+    let deadline = stop_at.map_or(Instant::now() + timeout, |stop_at| stop_at.min(Instant::now() + timeout));
+    let mut consecutive = 0;
+    loop {
+        if ctx.read_coils(map.running_coil, 1).await??[0] == target_state {
+            consecutive += 1;
+            if consecutive >= config.stable_reads {
+                return Ok(true);
+            }
+        } else {
+            consecutive = 0;
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        time::sleep(config.poll_interval).await;
+    }
+}
+
+/// Runs `test_case` against the arm behind `ctx` and reports whether it passed. Enable is dropped
+/// again after a failure.
+pub async fn run_remote_test_case(
+    ctx: &mut Context,
+    map: &RegisterMap,
+    config: &TestConfig,
+    test_case: &TestCases,
+    sweep_csv: &mut Option<SweepCsv>,
+) -> bool {
+    let passed = match test_case {
+        TestCases::SrSingle(idx) => report_single(sr_single(ctx, map, config, *idx).await, *idx),
+        TestCases::SrOutOfBounds => report_single(sr_single(ctx, map, config, u16::MAX).await, u16::MAX),
+        TestCases::SrUpTo(last) => {
+            let mut passed = true;
+            for idx in 0..=*last {
+                passed = report_single(sr_single(ctx, map, config, idx).await, idx);
+                if !passed {
+                    break;
+                }
+            }
+            passed
+        }
+        TestCases::SrEarlyStopWithDelay(idx, delay) =>
+            report_early_stop(sr_single_early_stop(ctx, map, config, *idx, Duration::from_millis(u64::from(*delay))).await, *idx).is_some(),
+        TestCases::SrEarlyStopWithDelayOnAllUpTo(last, delay) => {
+            let mut passed = true;
+            for idx in 0..=*last {
+                let result = sr_single_early_stop(ctx, map, config, idx, Duration::from_millis(u64::from(*delay))).await;
+                passed = report_early_stop(result, idx).is_some();
+                if !passed {
+                    break;
+                }
+            }
+            passed
+        }
+        TestCases::SrEarlyStopAllDelays(idx, schedule) => {
+            let mut sweep = match DelaySweep::new(*schedule) {
+                Ok(sweep) => sweep,
+                Err(err) => {
+                    error!("{err}");
+                    return false;
+                }
+            };
+            let mut passed = true;
+            while let Some(delay) = sweep.next_delay() {
+                debug!("Testing with delay: {}", format_duration(delay));
+                let result = sr_single_early_stop(ctx, map, config, *idx, delay).await;
+                if let Some(csv) = sweep_csv.as_mut()
+                    && let Err(err) = csv.append(delay, &result) {
+                    error!("Failed to write CSV row: {err}");
+                }
+                match report_early_stop(result, *idx) {
+                    Some(result) => sweep.report(&result),
+                    None => {
+                        passed = false;
+                        break;
+                    }
+                }
+            }
+            if passed && let Some((low, high)) = sweep.bracket() {
+                info!("Subroutine {idx} motion duration is between {} and {}", format_duration(low), format_duration(high));
+            }
+            passed
+        }
+        other => {
+            error!("{other:?} needs the embedded simulator, it can't be run with --connect");
+            false
+        }
+    };
+    if !passed && let Err(err) = ctx.write_single_coil(map.enable_coil, false).await {
+        error!("Failed to drop enable after the failure: {err}");
+    }
+    passed
+}
+
+fn report_single(result: anyhow::Result<()>, idx: u16) -> bool {
+    match result {
+        Ok(()) => {
+            info!("Subroutine {idx} completed successfully");
+            true
+        }
+        Err(err) => {
+            error!("Subroutine {idx} failed: {err}");
+            false
+        }
+    }
+}
+
+fn report_early_stop(result: anyhow::Result<EarlyStopResult>, idx: u16) -> Option<EarlyStopResult> {
+    match &result {
+        Ok(EarlyStopResult::Success) => info!("Subroutine {idx} was stopped early successfully"),
+        Ok(EarlyStopResult::TooLate) => warn!("Subroutine {idx} completed before it could be stopped early"),
+        Ok(EarlyStopResult::Marginal { slack }) =>
+            warn!("Subroutine {idx} completed {} before it could be stopped early, within the margin", format_duration(*slack)),
+        Err(err) => error!("Subroutine {idx} failed stopping early: {err}"),
+    }
+    result.ok()
+}
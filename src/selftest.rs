@@ -0,0 +1,160 @@
+use std::borrow::Cow;
+use std::fmt::Debug;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::ensure;
+use log::info;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_modbus::client::{self, Client, Reader, Writer};
+use tokio_modbus::{ExceptionCode, Request, Response};
+use rtu_sim::connections::ConnectionTracker;
+use rtu_sim::device_id::{DeviceIdentification, ENCAPSULATED_INTERFACE_FUNCTION_CODE, READ_DEVICE_ID_MEI_TYPE};
+use rtu_sim::mb_stuff::{ExampleService, SeedRange, SharedModbusState, BROADCAST_UNIT_ID, DEFAULT_SERVER_ID, DIAGNOSTICS_FUNCTION_CODE, MAX_READ_REGISTERS, READ_EXCEPTION_STATUS_FUNCTION_CODE, SERVER_ID_BYTE, STATUS_INPUTS};
+use rtu_sim::regions::AddressSpace;
+use rtu_sim::register_map::RegisterMap;
+use rtu_sim::server_context;
+
+const SELFTEST_INPUT_REGISTER: u16 = 0;
+/// How long a broadcast gets to (wrongly) produce a reply.
+const SELFTEST_BROADCAST_WAIT: Duration = Duration::from_millis(200);
+
+/// `--selftest`: serves a fresh state on a loopback port and round-trips every implemented
+/// function code through a real tokio-modbus client, so users can check their build before
+/// trusting a test run.
+///
+/// Reads are checked against values planted in the state, writes by reading the state back.
+/// The behaviour beyond the wire format is covered by the integration tests.
+pub async fn run_selftest() -> anyhow::Result<()> {
+    let map = RegisterMap::DEFAULT;
+    let input_registers = SELFTEST_INPUT_REGISTER..=SELFTEST_INPUT_REGISTER;
+    let state = SharedModbusState::new().with_seed_ranges(vec![SeedRange::new(AddressSpace::InputRegister, input_registers, 0)]);
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await?;
+    let addr = listener.local_addr()?;
+    let service_state = state.clone();
+    let server = tokio::spawn(server_context(listener, None, Arc::new(ConnectionTracker::new(None)),
+        move |peer| ExampleService::with_shared_state(service_state.clone(), peer)));
+    let result = round_trip(&state, map, addr).await;
+    server.abort();
+    result
+}
+
+async fn round_trip(state: &SharedModbusState, map: RegisterMap, addr: SocketAddr) -> anyhow::Result<()> {
+    let mut ctx = client::tcp::connect(addr).await?;
+
+    ctx.write_single_coil(map.enable_coil, true).await??;
+    expect("WriteSingleCoil on", state.read_coil(map.enable_coil), true)?;
+    ctx.write_single_coil(map.enable_coil, false).await??;
+    expect("WriteSingleCoil off", state.read_coil(map.enable_coil), false)?;
+
+    ctx.write_multiple_coils(map.enable_coil, &[true, false, true]).await??;
+    expect("WriteMultipleCoils", state.read_coils(map.enable_coil, 3), vec![true, false, true])?;
+
+    state.write_coils(map.enable_coil, &[false, true, false]);
+    expect("ReadCoils", ctx.read_coils(map.enable_coil, 3).await??, vec![false, true, false])?;
+
+    ctx.write_single_register(map.index_hreg, 0x1234).await??;
+    expect("WriteSingleRegister", state.read_holding_registers(map.index_hreg, 1), vec![0x1234])?;
+
+    ctx.write_multiple_registers(map.index_hreg, &[0xBEEF, 7]).await??;
+    expect("WriteMultipleRegisters", state.read_holding_registers(map.index_hreg, 2), vec![0xBEEF, 7])?;
+
+    state.write_holding_registers(map.index_hreg, &[42, 0xFFFF]);
+    expect("ReadHoldingRegisters", ctx.read_holding_registers(map.index_hreg, 2).await??, vec![42, 0xFFFF])?;
+
+    state.write_input_register(SELFTEST_INPUT_REGISTER, 4321);
+    expect("ReadInputRegisters", ctx.read_input_registers(SELFTEST_INPUT_REGISTER, 1).await??, vec![4321])?;
+
+    state.write_coils(map.enable_coil, &[false, true, false]);
+    state.write_holding_register(map.fault_hreg, 1);
+    expect("ReadDiscreteInputs", ctx.read_discrete_inputs(0, 4).await??, vec![false, true, false, true])?;
+    state.write_holding_register(map.fault_hreg, 0);
+
+    let echo = [0x00, 0x00, 0xAB, 0xCD];
+    let response = ctx.call(Request::Custom(DIAGNOSTICS_FUNCTION_CODE, Cow::Borrowed(&echo))).await??;
+    expect("Diagnostics echo", response, Response::Custom(DIAGNOSTICS_FUNCTION_CODE, echo.to_vec().into()))?;
+
+    state.write_coils(map.enable_coil, &[true, false, true]);
+    let response = ctx.call(Request::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, Cow::Borrowed(&[]))).await??;
+    expect("ReadExceptionStatus idle", response, Response::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, vec![0b0101].into()))?;
+    state.write_coil(map.running_coil, true);
+    let response = ctx.call(Request::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, Cow::Borrowed(&[]))).await??;
+    expect("ReadExceptionStatus running", response, Response::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, vec![0b0111].into()))?;
+
+    let response = ctx.call(Request::ReportServerId).await??;
+    expect("ReportServerId", response,
+        Response::ReportServerId(SERVER_ID_BYTE, true, DEFAULT_SERVER_ID.as_bytes().to_vec()))?;
+
+    // Basic device identification as a stream from VendorName on
+    let request = [READ_DEVICE_ID_MEI_TYPE, 0x01, 0x00];
+    let response = ctx.call(Request::Custom(ENCAPSULATED_INTERFACE_FUNCTION_CODE, Cow::Borrowed(&request))).await??;
+    let identification = DeviceIdentification::default();
+    expect("Read Device Identification", decode_device_identification(response)?, vec![
+        (0x00, identification.vendor_name.clone()),
+        (0x01, identification.product_code.clone()),
+        (0x02, identification.revision.clone()),
+    ])?;
+    let request = [READ_DEVICE_ID_MEI_TYPE, 0x04, 0x02];
+    let response = ctx.call(Request::Custom(ENCAPSULATED_INTERFACE_FUNCTION_CODE, Cow::Borrowed(&request))).await??;
+    expect("Read one device identification object", decode_device_identification(response)?,
+        vec![(0x02, identification.revision.clone())])?;
+
+    let exception = ctx.read_holding_registers(map.index_hreg, MAX_READ_REGISTERS + 1).await?;
+    expect("Oversized read is rejected", exception, Err(ExceptionCode::IllegalDataValue))?;
+    expect("Zero coil read is rejected", ctx.read_coils(map.enable_coil, 0).await?.map(|_| ()),
+        Err(ExceptionCode::IllegalDataValue))?;
+    expect("Zero holding register read is rejected", ctx.read_holding_registers(map.index_hreg, 0).await?.map(|_| ()),
+        Err(ExceptionCode::IllegalDataValue))?;
+    expect("Zero input register read is rejected", ctx.read_input_registers(SELFTEST_INPUT_REGISTER, 0).await?.map(|_| ()),
+        Err(ExceptionCode::IllegalDataValue))?;
+    expect("Zero discrete input read is rejected", ctx.read_discrete_inputs(0, 0).await?.map(|_| ()),
+        Err(ExceptionCode::IllegalDataValue))?;
+    expect("Discrete input past the status byte is rejected", ctx.read_discrete_inputs(STATUS_INPUTS - 1, 2).await?.map(|_| ()),
+        Err(ExceptionCode::IllegalDataAddress))?;
+
+    ctx.disconnect().await?;
+
+    // A broadcast write is applied but not answered, so the next frame is the next reply
+    let mut stream = TcpStream::connect(addr).await?;
+    state.write_coil(map.enable_coil, false);
+    let [coil_hi, coil_lo] = map.enable_coil.to_be_bytes();
+    stream.write_all(&[0, 1, 0, 0, 0, 6, BROADCAST_UNIT_ID, 0x05, coil_hi, coil_lo, 0xFF, 0x00]).await?;
+    let mut reply = [0; 12];
+    let unanswered = tokio::time::timeout(SELFTEST_BROADCAST_WAIT, stream.read(&mut reply)).await.is_err();
+    expect("Broadcast WriteSingleCoil is not answered", unanswered, true)?;
+    expect("Broadcast WriteSingleCoil is applied", state.read_coil(map.enable_coil), true)?;
+    stream.write_all(&[0, 2, 0, 0, 0, 6, 1, 0x05, coil_hi, coil_lo, 0x00, 0x00]).await?;
+    stream.read_exact(&mut reply).await?;
+    expect("Reply after a broadcast belongs to the next request", reply,
+        [0, 2, 0, 0, 0, 6, 1, 0x05, coil_hi, coil_lo, 0x00, 0x00])
+}
+
+/// The objects in a Read Device Identification response, checking the header on the way.
+fn decode_device_identification(response: Response) -> anyhow::Result<Vec<(u8, String)>> {
+    let Response::Custom(ENCAPSULATED_INTERFACE_FUNCTION_CODE, data) = response else {
+        anyhow::bail!("Expected a Read Device Identification response, got {response:?}");
+    };
+    let Some((&[mei_type, _read_code, _conformity, more_follows, _next_object, count], mut objects)) =
+        data.split_first_chunk::<6>() else {
+        anyhow::bail!("Read Device Identification response too short: {data:02X?}");
+    };
+    ensure!(mei_type == READ_DEVICE_ID_MEI_TYPE && more_follows == 0, "Unexpected Read Device Identification header: {data:02X?}");
+    let mut decoded = Vec::new();
+    for _ in 0..count {
+        let Some((&[id, len], rest)) = objects.split_first_chunk::<2>() else {
+            anyhow::bail!("Read Device Identification response cut short: {data:02X?}");
+        };
+        ensure!(rest.len() >= usize::from(len), "Read Device Identification object {id} cut short: {data:02X?}");
+        let (value, rest) = rest.split_at(usize::from(len));
+        decoded.push((id, String::from_utf8(value.to_vec())?));
+        objects = rest;
+    }
+    Ok(decoded)
+}
+
+fn expect<T: PartialEq + Debug>(step: &str, actual: T, expected: T) -> anyhow::Result<()> {
+    ensure!(actual == expected, "{step}: expected {expected:?}, got {actual:?}");
+    info!("Self-test {step}: ok");
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::duration_format::Millis;
+use crate::test_cases::EarlyStopResult;
+
+const HEADER: &str = "delay_ms,result,timestamp_ms";
+
+/// Appends one row per early-stop sweep iteration to a CSV file.
+///
+/// Rows are flushed immediately so a sweep that is interrupted still leaves usable data behind.
+pub struct SweepCsv {
+    writer: BufWriter<File>,
+}
+
+impl SweepCsv {
+    /// Opens `path` for appending, writing the header only if the file is new or empty.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+        let mut writer = BufWriter::new(file);
+        if is_empty {
+            writeln!(writer, "{HEADER}")?;
+            writer.flush()?;
+        }
+        Ok(Self { writer })
+    }
+
+    pub fn append(&mut self, delay: Duration, result: &anyhow::Result<EarlyStopResult>) -> anyhow::Result<()> {
+        let result = match result {
+            Ok(EarlyStopResult::Success) => "Success",
+            Ok(EarlyStopResult::TooLate) => "TooLate",
+            Ok(EarlyStopResult::Marginal { .. }) => "Marginal",
+            Err(_) => "Error",
+        };
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        writeln!(self.writer, "{},{result},{timestamp_ms}", Millis(delay))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
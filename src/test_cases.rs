@@ -1,61 +1,622 @@
-use log::{debug, error, info};
+use std::fmt::{Debug, Display, Formatter};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
 use tokio::time::{self, Duration, error};
-use crate::{ENABLE_COIL_OFFSET, INDEX_HREG_OFFSET, RUNNING_COIL_OFFSET};
+use tracing::{debug_span, field, Instrument};
+use crate::FAULT_BUSY;
+use crate::arm_sim::EnableMode;
+use crate::duration_format::format_duration;
 use crate::mb_stuff::SharedModbusState;
+use crate::register_map::RegisterMap;
+use crate::sweep_csv::SweepCsv;
 
+/// A test case and its parameters, as picked in the TUI and kept in the test history.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Serialize, Deserialize)]
+pub enum TestCases {
+    SrSingle(u16),
+    SrUpTo(u16),
+    SrOutOfBounds,
+    SrEarlyStopWithDelay(u16, u16),
+    SrEarlyStopWithDelayOnAllUpTo(u16, u16),
+    SrEarlyStopAllDelays(u16, SweepSchedule),
+    RapidEnableToggle(u16, u16),
+    StartWhileBusy(u16),
+    RampConverges(u16),
+    FaultRecovery(u16),
+    Soak { idx: u16, iterations: u32 },
+}
 
-pub async fn sr_single_shared(shared_state: &SharedModbusState, idx: u16) -> anyhow::Result<()> {
-    shared_state.write_holding_register(INDEX_HREG_OFFSET, idx);
-    shared_state.write_coil(ENABLE_COIL_OFFSET, true);
+impl Debug for TestCases {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TestCases::SrSingle(index) =>
+                write!(f, "Test single sub routine: {}", index),
+            TestCases::SrUpTo(index) =>
+                write!(f, "Test all sub routines up to {}", index),
+            TestCases::SrOutOfBounds =>
+                write!(f, "Test out of bounds sub routine."),
+            TestCases::SrEarlyStopWithDelay(index, delay) =>
+                write!(f, "Test sub routine #{} early stop with delay: {}", index, delay),
+            TestCases::SrEarlyStopAllDelays(index, schedule) =>
+                write!(f, "Test sub routine #{} early stop with all delays ({:?})", index, schedule),
+            TestCases::SrEarlyStopWithDelayOnAllUpTo(index, delay) => {
+                write!(f, "Test all sub routines up to #{} early stop with delay {}", index, delay)
+            }
+            TestCases::RapidEnableToggle(count, interval) =>
+                write!(f, "Toggle enable {} times every {} ms", count, interval),
+            TestCases::StartWhileBusy(index) =>
+                write!(f, "Test re-commanding while sub routine #{} is running", index),
+            TestCases::RampConverges(target) =>
+                write!(f, "Test the actual value ramps to setpoint {}", target),
+            TestCases::FaultRecovery(index) =>
+                write!(f, "Test sub routine #{} is refused until the fault is reset", index),
+            TestCases::Soak { idx, iterations } =>
+                write!(f, "Soak test sub routine #{} for {} iterations", idx, iterations),
+        }
+    }
+}
 
-    let timeout_dur = Duration::from_secs(1);
-    let err_msg = format!("Timeout waiting for arm to set `running` to true running \
-        subroutine #{idx} at modbus address {RUNNING_COIL_OFFSET}. \
-        Waited {} ms", timeout_dur.as_millis());
-    wait_for_running_shared(shared_state, true, timeout_dur).await.map_err(|_| anyhow::anyhow!(err_msg))?;
+/// Knobs shared by all test cases.
+#[derive(Clone, Debug)]
+pub struct TestConfig {
+    /// How long to sleep between reads of the running coil.
+    pub poll_interval: Duration,
+    /// How many consecutive reads of the running coil must agree before a transition is accepted.
+    /// At least 1, `--stable-reads` refuses 0.
+    pub stable_reads: u32,
+    /// If set, wait up to this long for the arm's ready coil before commanding a sub routine.
+    pub ready_timeout: Option<Duration>,
+    /// Reset the shared state to its defaults before each test so leftovers can't leak between tests.
+    pub reset_between_tests: bool,
+    /// If set, a test case running longer than this is aborted and counted as failed.
+    pub test_budget: Option<Duration>,
+    /// Log what each test case would do instead of running it.
+    pub dry_run: bool,
+    /// How the arm under test reads enable, which decides whether a restart is a failure.
+    pub enable_mode: EnableMode,
+    /// An early stop whose motion completed less than this before the stop was due is
+    /// [`EarlyStopResult::Marginal`] instead of too late.
+    pub early_stop_margin: Option<Duration>,
+    /// How many units run the sub routines of a [`TestCases::SrUpTo`] at once, each on its own
+    /// arm. 1 runs them one after the other on the first unit.
+    pub parallel_runs: usize,
+}
 
-    debug!("Arm set to running, should be executing sub routine #{}. Waiting up to 60 seconds for motion to complete", idx);
+/// How long the arm gets to clear its fault after the reset coil is set.
+pub const FAULT_RESET_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the arm gets to raise running after enable is set.
+pub const RUNNING_START_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a single motion may take before the arm is considered hung.
+pub const MOTION_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the arm gets to drop running after an early stop drops enable.
+pub const EARLY_STOP_WAIT: Duration = Duration::from_secs(1);
 
-    let timeout_dur = Duration::from_secs(60);
-    let err_msg = format!("Timeout waiting for arm to set `running` to false running \
-        subroutine #{idx} at modbus address {RUNNING_COIL_OFFSET}. \
-        Waited {} ms", timeout_dur.as_millis());
-    wait_for_running_shared(shared_state, false, timeout_dur).await.map_err(|_| anyhow::anyhow!(err_msg))?;
+impl TestConfig {
+    pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+    pub const DEFAULT_STABLE_READS: u32 = 1;
+}
 
-    debug!("Motion complete");
-    shared_state.write_coil(ENABLE_COIL_OFFSET, false);
-    time::sleep(Duration::from_millis(100)).await;
-    if shared_state.read_coil(RUNNING_COIL_OFFSET) {
-        return Err(anyhow::anyhow!("Arm still running after motion complete. \
-            Enable coil was set to false, and then running was set true again. Likely arm is \
-            blindly running when enable is true, not only on rising edge"));
+impl Default for TestConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Self::DEFAULT_POLL_INTERVAL,
+            stable_reads: Self::DEFAULT_STABLE_READS,
+            ready_timeout: None,
+            reset_between_tests: true,
+            test_budget: None,
+            dry_run: false,
+            enable_mode: EnableMode::default(),
+            early_stop_margin: None,
+            parallel_runs: 1,
+        }
+    }
+}
+
+/// Whether the arm reports it has finished booting and will accept commands.
+pub fn read_ready(shared_state: &SharedModbusState) -> bool {
+    shared_state.read_coil(shared_state.register_map().ready_coil)
+}
+
+/// How many sub routines the arm has queued behind the running one, if it queues them.
+pub fn read_pending_runs(shared_state: &SharedModbusState) -> Option<u16> {
+    shared_state.command_queue().map(|queue| shared_state.read_input_registers(queue.pending_ireg, 1)[0])
+}
+
+/// Commands sub routine `idx` while another runs, for an arm that queues it: writes the index
+/// and re-pulses enable, leaving it high so the running motion carries on. Returns once the
+/// pending count goes up, so the next command can't overwrite the index before it's queued.
+pub async fn enqueue_shared(shared_state: &SharedModbusState, idx: u32, poll_interval: Duration) -> anyhow::Result<()> {
+    let pending_before = read_pending_runs(shared_state)
+        .ok_or_else(|| anyhow::anyhow!("Can't queue sub routine #{idx}, the arm has no command queue"))?;
+    shared_state.write_coil(shared_state.register_map().enable_coil, false);
+    shared_state.write_index_and_enable(idx)?;
+    time::timeout(RUNNING_START_TIMEOUT, async {
+        loop {
+            if read_pending_runs(shared_state).is_some_and(|pending| pending > pending_before) {
+                return Ok(());
+            }
+            let fault = read_fault(shared_state);
+            if fault != 0 {
+                return Err(anyhow::anyhow!("Arm refused to queue sub routine #{idx} with fault code {fault}"));
+            }
+            time::sleep(poll_interval).await;
+        }
+    }).await
+        .map_err(|_| anyhow::anyhow!("Timeout waiting for arm to queue sub routine #{idx}. Waited {} ms",
+            RUNNING_START_TIMEOUT.as_millis()))?
+}
+
+/// Checks the handshake is back at rest: enable and running low, no fault.
+///
+/// Meant for the end of every test, so a test can't pass while leaving the arm enabled.
+pub fn assert_idle(shared_state: &SharedModbusState) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    if shared_state.read_coil(shared_state.register_map().enable_coil) {
+        problems.push("enable is still set".to_string());
+    }
+    if shared_state.read_coil(shared_state.register_map().running_coil) {
+        problems.push("running is still set".to_string());
+    }
+    let fault = read_fault(shared_state);
+    if fault != 0 {
+        problems.push(format!("fault code is {fault}"));
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Arm is not idle: {}", problems.join(", ")))
+    }
+}
+
+/// The arm's current fault code, 0 when healthy.
+pub fn read_fault(shared_state: &SharedModbusState) -> u16 {
+    shared_state.read_holding_registers(shared_state.register_map().fault_hreg, 1)[0]
+}
+
+/// Sets the fault reset coil and waits up to `timeout` for the fault register to clear.
+pub async fn reset_fault_shared(shared_state: &SharedModbusState, config: &TestConfig, timeout: Duration) -> anyhow::Result<()> {
+    let fault = shared_state.initial_fault()
+        .ok_or_else(|| anyhow::anyhow!("No fault reset coil configured, start with `--initial-fault <code>:<reset coil>`"))?;
+    shared_state.write_coil(fault.reset_coil, true);
+    time::timeout(timeout, async {
+        while read_fault(shared_state) != 0 {
+            time::sleep(config.poll_interval).await;
+        }
+    }).await.map_err(|_| anyhow::anyhow!("Timeout waiting for arm to clear fault code {} after setting the \
+        reset coil {}. Waited {} ms", read_fault(shared_state), fault.reset_coil, timeout.as_millis()))
+}
+
+pub async fn wait_for_ready_shared(shared_state: &SharedModbusState, timeout: Duration, poll_interval: Duration) -> anyhow::Result<()> {
+    time::timeout(timeout, async {
+        while !read_ready(shared_state) {
+            time::sleep(poll_interval).await;
+        }
+    }).await.map_err(|_| anyhow::anyhow!("Timeout waiting for arm to set `ready` at modbus address \
+        {}. Waited {} ms", shared_state.register_map().ready_coil, timeout.as_millis()))
+}
+
+/// How a [`SubroutineRun`] ended. Only [`RunOutcome::Completed`] is a pass.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// Running rose and fell again, and stayed low after enable was dropped if that was checked.
+    Completed { start_latency: Duration, motion: Duration },
+    /// The arm didn't raise ready in time, the sub routine wasn't commanded.
+    NotReady { waited: Duration },
+    /// Running never rose after enable was set.
+    NeverStarted { waited: Duration },
+    /// The index echo register reports a different index than the one commanded.
+    WrongIndexLatched { latched: u32 },
+    /// The index doesn't fit the index registers, nothing was commanded.
+    IndexOutOfRange { max: u32 },
+    /// Running rose but never fell again.
+    MotionTimedOut { waited: Duration },
+    /// Running rose again after enable was dropped at the end of the motion.
+    RestartedAfterDisable,
+}
+
+impl RunOutcome {
+    pub fn is_success(&self) -> bool {
+        matches!(self, RunOutcome::Completed { .. })
+    }
+
+    /// The outcome of running sub routine `idx` as an error for the test report, `Ok` if it
+    /// completed. A start that never happened and a motion that never ended come out as
+    /// [`RunningNeverAssertedError`] and [`MotionHangError`], so they can be told apart by
+    /// downcasting.
+    pub fn into_result(self, idx: u32, map: &RegisterMap) -> anyhow::Result<()> {
+        match self {
+            RunOutcome::Completed { .. } => Ok(()),
+            RunOutcome::NotReady { waited } => Err(anyhow::anyhow!("Timeout waiting for arm to set `ready` at modbus address \
+                {}. Waited {} ms", map.ready_coil, waited.as_millis())),
+            RunOutcome::NeverStarted { waited } =>
+                Err(RunningNeverAssertedError { idx, running_coil: map.running_coil, waited }.into()),
+            RunOutcome::WrongIndexLatched { latched } => Err(anyhow::anyhow!("Arm latched sub routine #{latched} \
+                instead of the commanded #{idx}, according to the index echo register")),
+            RunOutcome::IndexOutOfRange { max } => Err(anyhow::anyhow!("Sub routine #{idx} doesn't fit the index \
+                registers at modbus address {}, the largest index is {max}", map.index_hreg)),
+            RunOutcome::MotionTimedOut { waited } =>
+                Err(MotionHangError { idx, running_coil: map.running_coil, elapsed: waited }.into()),
+            RunOutcome::RestartedAfterDisable => Err(anyhow::anyhow!("Arm still running after motion complete. \
+                Enable coil was set to false, and then running was set true again. Likely arm is \
+                blindly running when enable is true, not only on rising edge")),
+        }
+    }
+}
+
+/// Enable was set but the arm never raised running: it refused or never saw the command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunningNeverAssertedError {
+    pub idx: u32,
+    pub running_coil: u16,
+    pub waited: Duration,
+}
+
+impl Display for RunningNeverAssertedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timeout waiting for arm to set `running` to true running subroutine #{} at modbus address {}. \
+            Waited {} ms", self.idx, self.running_coil, self.waited.as_millis())
+    }
+}
+
+impl std::error::Error for RunningNeverAssertedError {}
+
+/// The arm raised running but never dropped it again: the motion started and then hung.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MotionHangError {
+    pub idx: u32,
+    pub running_coil: u16,
+    /// How long running stayed high before giving up.
+    pub elapsed: Duration,
+}
+
+impl Display for MotionHangError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Arm started subroutine #{} but `running` at modbus address {} was still true after {} ms, \
+            the motion hung", self.idx, self.running_coil, self.elapsed.as_millis())
+    }
+}
+
+impl std::error::Error for MotionHangError {}
+
+/// One sub routine run through the enable/running handshake, configured builder style, e.g.
+/// `SubroutineRun::new(3).motion_timeout(Duration::from_secs(5)).execute(&state)`.
+///
+/// [`sr_single_shared`] is this with the defaults and a [`TestConfig`] applied.
+#[derive(Clone, Debug)]
+pub struct SubroutineRun {
+    idx: u32,
+    ready_timeout: Option<Duration>,
+    running_timeout: Duration,
+    motion_timeout: Duration,
+    poll_interval: Duration,
+    stable_reads: u32,
+    verify_idle: bool,
+    enable_mode: EnableMode,
+}
+
+impl SubroutineRun {
+    /// How long enable is held low after the motion before checking the arm didn't restart.
+    pub const IDLE_CHECK_DELAY: Duration = Duration::from_millis(100);
+
+    pub fn new(idx: u32) -> Self {
+        Self {
+            idx,
+            ready_timeout: None,
+            running_timeout: RUNNING_START_TIMEOUT,
+            motion_timeout: MOTION_TIMEOUT,
+            poll_interval: TestConfig::DEFAULT_POLL_INTERVAL,
+            stable_reads: TestConfig::DEFAULT_STABLE_READS,
+            verify_idle: true,
+            enable_mode: EnableMode::default(),
+        }
+    }
+
+    /// Takes polling, the ready wait and the enable mode from `config`.
+    pub fn with_config(self, config: &TestConfig) -> Self {
+        Self {
+            ready_timeout: config.ready_timeout,
+            poll_interval: config.poll_interval,
+            stable_reads: config.stable_reads,
+            enable_mode: config.enable_mode,
+            ..self
+        }
+    }
+
+    /// Wait up to `timeout` for the ready coil before commanding the sub routine.
+    pub fn ready_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.ready_timeout = timeout;
+        self
+    }
+
+    /// How long the arm gets to raise running after enable is set.
+    pub fn running_timeout(mut self, timeout: Duration) -> Self {
+        self.running_timeout = timeout;
+        self
+    }
+
+    /// How long running may stay high before the motion counts as hung.
+    pub fn motion_timeout(mut self, timeout: Duration) -> Self {
+        self.motion_timeout = timeout;
+        self
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn stable_reads(mut self, stable_reads: u32) -> Self {
+        self.stable_reads = stable_reads;
+        self
+    }
+
+    /// After dropping enable, check running stays low, catching arms that run whenever enable is
+    /// high instead of on its rising edge. Only done in [`EnableMode::Edge`].
+    pub fn verify_idle(mut self, verify_idle: bool) -> Self {
+        self.verify_idle = verify_idle;
+        self
+    }
+
+    /// How the arm reads enable. A [`EnableMode::Held`] arm is expected to restart, so the
+    /// idle check is skipped.
+    pub fn enable_mode(mut self, enable_mode: EnableMode) -> Self {
+        self.enable_mode = enable_mode;
+        self
+    }
+
+    /// Commands the sub routine and follows the handshake to the end. Enable is left high if
+    /// the run doesn't complete.
+    ///
+    /// Runs in a `subroutine_run` span with the `idx` and, once known, the `result`.
+    pub async fn execute(&self, shared_state: &SharedModbusState) -> RunOutcome {
+        let span = debug_span!("subroutine_run", idx = self.idx, result = field::Empty);
+        let outcome = self.handshake(shared_state).instrument(span.clone()).await;
+        span.record("result", field::debug(&outcome));
+        outcome
+    }
+
+    async fn handshake(&self, shared_state: &SharedModbusState) -> RunOutcome {
+        if let Some(ready_timeout) = self.ready_timeout
+            && wait_for_ready_shared(shared_state, ready_timeout, self.poll_interval).await.is_err() {
+            return RunOutcome::NotReady { waited: ready_timeout };
+        }
+        if shared_state.write_index_and_enable(self.idx).is_err() {
+            return RunOutcome::IndexOutOfRange { max: shared_state.index_width().max_index() };
+        }
+        let enabled_at = time::Instant::now();
+
+        if wait_for_running_shared(shared_state, true, self.running_timeout, self.poll_interval, self.stable_reads).await.is_err() {
+            return RunOutcome::NeverStarted { waited: self.running_timeout };
+        }
+        let start_latency = enabled_at.elapsed();
+        if let Some(latched) = shared_state.read_index_echo()
+            && latched != self.idx {
+            return RunOutcome::WrongIndexLatched { latched };
+        }
+
+        debug!("{} Arm set to running, should be executing sub routine #{}. Waiting up to {} for motion to complete",
+            shared_state.log_tag(), self.idx, format_duration(self.motion_timeout));
+        let started_at = time::Instant::now();
+        if wait_for_running_shared(shared_state, false, self.motion_timeout, self.poll_interval, self.stable_reads).await.is_err() {
+            return RunOutcome::MotionTimedOut { waited: self.motion_timeout };
+        }
+        let motion = started_at.elapsed();
+
+        debug!("{} Motion complete", shared_state.log_tag());
+        shared_state.write_coil(shared_state.register_map().enable_coil, false);
+        if self.verify_idle && self.enable_mode == EnableMode::Edge {
+            time::sleep(Self::IDLE_CHECK_DELAY).await;
+            if shared_state.read_coil(shared_state.register_map().running_coil) {
+                return RunOutcome::RestartedAfterDisable;
+            }
+        }
+        RunOutcome::Completed { start_latency, motion }
     }
-    Ok(())
 }
 
+pub async fn sr_single_shared(shared_state: &SharedModbusState, config: &TestConfig, idx: u16) -> anyhow::Result<()> {
+    SubroutineRun::new(idx.into()).with_config(config).execute(shared_state).await
+        .into_result(idx.into(), &shared_state.register_map())
+}
+
+/// Runs sub routines 0 to `idx` spread over `units`, each unit running one at a time on its own
+/// arm and at most [`TestConfig::parallel_runs`] units at once. Once one fails no more are
+/// started, and the ones already running finish.
+///
+/// Returns the result of each sub routine that ran, in index order whichever unit finished first.
+pub async fn sr_up_to_parallel_shared(units: &[SharedModbusState], config: &TestConfig, idx: u16) -> Vec<(u16, anyhow::Result<()>)> {
+    // Popped from the back, so the first units take the first indices
+    let mut idle: Vec<SharedModbusState> = units.iter().take(config.parallel_runs.max(1)).rev().cloned().collect();
+    let mut pending = 0..=idx;
+    let mut running = JoinSet::new();
+    let mut results = Vec::new();
+    let mut failed = false;
+    loop {
+        while !failed && let Some(unit) = idle.pop() {
+            let Some(i) = pending.next() else {
+                idle.push(unit);
+                break;
+            };
+            debug!("{} Starting sub routine {i}", unit.log_tag());
+            let config = config.clone();
+            running.spawn(async move {
+                let result = sr_single_shared(&unit, &config, i).await;
+                (unit, i, result)
+            }.in_current_span());
+        }
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        match joined {
+            Ok((unit, i, result)) => {
+                if result.is_err() {
+                    failed = true;
+                    unit.write_coil(unit.register_map().enable_coil, false);
+                }
+                debug!("{} Sub routine {i} {}", unit.log_tag(), if result.is_ok() { "completed" } else { "failed" });
+                results.push((i, result));
+                idle.push(unit);
+            }
+            Err(err) => {
+                warn!("Sub routine task ended without a result, stopping: {err}");
+                failed = true;
+            }
+        }
+    }
+    results.sort_by_key(|(i, _)| *i);
+    results
+}
+
+/// Commands sub routine `idx` on a faulted arm and expects it to be refused, then clears the
+/// fault with [`reset_fault_shared`] and expects the same command to run.
+pub async fn fault_recovery_shared(shared_state: &SharedModbusState, config: &TestConfig, idx: u16) -> anyhow::Result<()> {
+    let fault = read_fault(shared_state);
+    if fault == 0 {
+        return Err(anyhow::anyhow!("Arm is not faulted, nothing to recover from. \
+            Start with `--initial-fault <code>:<reset coil>`"));
+    }
+    let outcome = SubroutineRun::new(idx.into()).with_config(config).execute(shared_state).await;
+    shared_state.write_coil(shared_state.register_map().enable_coil, false);
+    if !matches!(outcome, RunOutcome::NeverStarted { .. }) {
+        return Err(anyhow::anyhow!("Arm with fault code {fault} wasn't expected to start sub routine #{idx}, \
+            but the run ended with {outcome:?}"));
+    }
+    debug!("{} Arm refused #{idx} while faulted, resetting the fault", shared_state.log_tag());
+    reset_fault_shared(shared_state, config, FAULT_RESET_TIMEOUT).await?;
+    sr_single_shared(shared_state, config, idx).await
+}
+
+/// One failed iteration of a soak test.
+#[derive(Clone, Debug)]
+pub struct SoakFailure {
+    /// 0-based.
+    pub iteration: u32,
+    pub error: String,
+}
+
+/// Outcome of [`soak_shared`]. Cycle times only cover passed iterations.
+#[derive(Clone, Debug, Default)]
+pub struct SoakStats {
+    /// Iterations actually run, fewer than asked for if the soak was cut short.
+    pub iterations: u32,
+    pub passed: u32,
+    pub failures: Vec<SoakFailure>,
+    pub min_cycle: Option<Duration>,
+    pub max_cycle: Option<Duration>,
+    pub total_cycle: Duration,
+}
+
+impl SoakStats {
+    fn record_pass(&mut self, cycle: Duration) {
+        self.passed += 1;
+        self.min_cycle = Some(self.min_cycle.map_or(cycle, |min| min.min(cycle)));
+        self.max_cycle = Some(self.max_cycle.map_or(cycle, |max| max.max(cycle)));
+        self.total_cycle += cycle;
+    }
+
+    /// Passed iterations as a fraction of those run, 0 if none ran.
+    pub fn success_rate(&self) -> f64 {
+        if self.iterations == 0 {
+            return 0.0;
+        }
+        self.passed as f64 / self.iterations as f64
+    }
+
+    pub fn average_cycle(&self) -> Option<Duration> {
+        (self.passed > 0).then(|| self.total_cycle / self.passed)
+    }
+
+    /// The stats as one JSON object, cycle times in milliseconds, for collecting soak results.
+    pub fn to_json(&self) -> serde_json::Value {
+        let millis = |cycle: Option<Duration>| cycle.map(|cycle| cycle.as_secs_f64() * 1000.0);
+        serde_json::json!({
+            "iterations": self.iterations,
+            "passed": self.passed,
+            "success_rate": self.success_rate(),
+            "min_cycle_ms": millis(self.min_cycle),
+            "max_cycle_ms": millis(self.max_cycle),
+            "avg_cycle_ms": millis(self.average_cycle()),
+            "failures": self.failures.iter()
+                .map(|failure| serde_json::json!({ "iteration": failure.iteration, "error": failure.error }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Runs sub routine `idx` `iterations` times back to back, like [`sr_single_shared`], collecting
+/// failures instead of stopping at the first one.
+///
+/// After a failure enable is dropped; if running doesn't clear within [`RUNNING_START_TIMEOUT`]
+/// the arm is considered stuck and the soak stops early.
+pub async fn soak_shared(shared_state: &SharedModbusState, config: &TestConfig, idx: u16, iterations: u32) -> SoakStats {
+    let mut stats = SoakStats::default();
+    for iteration in 0..iterations {
+        stats.iterations += 1;
+        let started = time::Instant::now();
+        match sr_single_shared(shared_state, config, idx).await {
+            Ok(()) => stats.record_pass(started.elapsed()),
+            Err(err) => {
+                debug!("{} Soak iteration {iteration} failed: {err}", shared_state.log_tag());
+                stats.failures.push(SoakFailure { iteration, error: err.to_string() });
+                shared_state.write_coil(shared_state.register_map().enable_coil, false);
+                if wait_for_running_shared(shared_state, false, RUNNING_START_TIMEOUT, config.poll_interval, config.stable_reads).await.is_err() {
+                    stats.failures.push(SoakFailure { iteration, error: "Arm still running after enable was dropped, \
+                        stopping the soak".to_string() });
+                    break;
+                }
+            }
+        }
+    }
+    stats
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum EarlyStopResult {
     Success,
     TooLate,
+    /// Completed, but only `slack` before the stop was due, within the
+    /// [`TestConfig::early_stop_margin`] of the boundary where runs flip between stopped and
+    /// completed. Neither a pass nor a failure of the early stop.
+    Marginal { slack: Duration },
 }
 
+/// Runs sub routine `idx` and drops enable `duration` after commanding it, expecting running to
+/// drop within a second.
+///
+/// Like everything here it only keeps time through `tokio::time`, so on a runtime with the clock
+/// paused it runs on virtual time, branches and all.
+pub async fn sr_single_early_stop_shared(shared_state: &SharedModbusState, config: &TestConfig, idx: u16, duration: Duration) -> anyhow::Result<EarlyStopResult> {
+    let span = debug_span!("early_stop", idx, delay = ?duration, result = field::Empty);
+    let result = early_stop(shared_state, config, idx, duration).instrument(span.clone()).await;
+    match &result {
+        Ok(early_stop) => span.record("result", field::debug(early_stop)),
+        Err(err) => span.record("result", field::display(err)),
+    };
+    result
+}
 
-pub async fn sr_single_early_stop_shared(shared_state: &SharedModbusState, idx: u16, duration: Duration) -> anyhow::Result<EarlyStopResult> {
-    match time::timeout(duration, sr_single_shared(shared_state, idx)).await {
-        Ok(Ok(())) => {
-            debug!("Subroutine #{} completed before the early stop could be initiated", idx);
-            Ok(EarlyStopResult::TooLate)
+async fn early_stop(shared_state: &SharedModbusState, config: &TestConfig, idx: u16, duration: Duration) -> anyhow::Result<EarlyStopResult> {
+    let run = SubroutineRun::new(idx.into()).with_config(config);
+    match time::timeout(duration, run.execute(shared_state)).await {
+        Ok(RunOutcome::Completed { start_latency, motion }) => {
+            debug!("{} Subroutine #{idx} completed before the early stop could be initiated", shared_state.log_tag());
+            Ok(classify_completed(config, duration.saturating_sub(start_latency + motion)))
         }
-        Ok(Err(e)) => {
-            debug!("Subroutine #{} failed to complete before the early stop could be initiated: {}", idx, e);
+        Ok(outcome) => {
+            let e = outcome.into_result(idx.into(), &shared_state.register_map())
+                .expect_err("only a completed run is Ok");
+            debug!("{} Subroutine #{idx} failed to complete before the early stop could be initiated: {e}", shared_state.log_tag());
             Err(e)
         }
         Err(_) => {
-            shared_state.write_coil(ENABLE_COIL_OFFSET, false);
-            time::sleep(Duration::from_millis(1000)).await;
-            if shared_state.read_coil(RUNNING_COIL_OFFSET) {
+            shared_state.write_coil(shared_state.register_map().enable_coil, false);
+            time::sleep(EARLY_STOP_WAIT).await;
+            if shared_state.read_coil(shared_state.register_map().running_coil) {
                 let err_msg = format!("Arm still running after early stop on index: {idx}. \
-                    Stopped at {:?} ms and waited 1 second", duration);
-                debug!("{}", err_msg);
+                    Stopped at {} and waited 1 second", format_duration(duration));
+                debug!("{} {err_msg}", shared_state.log_tag());
                 return Err(anyhow::anyhow!(err_msg));
             }
             Ok(EarlyStopResult::Success)
@@ -63,17 +624,583 @@ pub async fn sr_single_early_stop_shared(shared_state: &SharedModbusState, idx:
     }
 }
 
+/// A run that completed `slack` before the early stop was due: too late, or marginal if that's
+/// within the configured margin.
+pub(crate) fn classify_completed(config: &TestConfig, slack: Duration) -> EarlyStopResult {
+    match config.early_stop_margin {
+        Some(margin) if slack < margin => EarlyStopResult::Marginal { slack },
+        _ => EarlyStopResult::TooLate,
+    }
+}
+
+/// Toggles enable `count` times, holding each level for `interval`, and checks that every rising
+/// edge starts at most one motion. Returns the number of motions the arm started.
+///
+/// Starts are attributed to the latest rising edge, so an arm that reacts after enable already
+/// fell again is not penalized. The arm runs whatever index is currently latched.
+pub async fn rapid_enable_toggle_shared(shared_state: &SharedModbusState, config: &TestConfig, count: u16, interval: Duration) -> anyhow::Result<u32> {
+    if shared_state.read_coil(shared_state.register_map().running_coil) {
+        return Err(anyhow::anyhow!("Arm was already running before the first enable edge"));
+    }
+    let mut was_running = false;
+    let mut starts_per_edge = vec![0u32; count as usize];
+    for (edge, starts) in starts_per_edge.iter_mut().enumerate() {
+        for enable in [true, false] {
+            shared_state.write_coil(shared_state.register_map().enable_coil, enable);
+            let phase_end = time::Instant::now() + interval;
+            while time::Instant::now() < phase_end {
+                let running = shared_state.read_coil(shared_state.register_map().running_coil);
+                if running && !was_running {
+                    *starts += 1;
+                    debug!("{} Motion start #{starts} observed after enable edge #{edge}", shared_state.log_tag());
+                }
+                was_running = running;
+                time::sleep(config.poll_interval).await;
+            }
+        }
+    }
+
+    if let Some((edge, starts)) = starts_per_edge.iter().enumerate().find(|(_, starts)| **starts > 1) {
+        return Err(anyhow::anyhow!("Arm started {starts} motions from the single enable rising edge #{edge}. \
+            Likely arm is blindly running when enable is true, not only on rising edge"));
+    }
+
+    let timeout_dur = MOTION_TIMEOUT;
+    wait_for_running_shared(shared_state, false, timeout_dur, config.poll_interval, config.stable_reads).await
+        .map_err(|_| anyhow::anyhow!("Arm did not return to idle within {} ms after the last enable edge", timeout_dur.as_millis()))?;
+    time::sleep(Duration::from_millis(100)).await;
+    if shared_state.read_coil(shared_state.register_map().running_coil) {
+        return Err(anyhow::anyhow!("Arm started running again with enable low after the toggle sequence"));
+    }
+    Ok(starts_per_edge.iter().sum())
+}
+
+/// Starts sub routine `idx`, then re-pulses enable with a different index while it runs.
+///
+/// The arm must answer with [`FAULT_BUSY`] and keep running the original motion to completion.
+pub async fn start_while_busy_shared(shared_state: &SharedModbusState, config: &TestConfig, idx: u16) -> anyhow::Result<()> {
+    shared_state.write_index_and_enable(idx.into())?;
+    let timeout_dur = RUNNING_START_TIMEOUT;
+    wait_for_running_shared(shared_state, true, timeout_dur, config.poll_interval, config.stable_reads).await
+        .map_err(|_| anyhow::anyhow!("Timeout waiting for arm to set `running` to true running \
+            subroutine #{idx}. Waited {} ms", timeout_dur.as_millis()))?;
+
+    let second_idx = idx.wrapping_add(1);
+    debug!("{} Arm running #{idx}, commanding #{second_idx} while busy", shared_state.log_tag());
+    shared_state.write_coil(shared_state.register_map().enable_coil, false);
+    shared_state.write_index_and_enable(second_idx.into())?;
+    time::sleep(Duration::from_millis(100)).await;
+
+    let fault = read_fault(shared_state);
+    if fault != FAULT_BUSY {
+        return Err(anyhow::anyhow!("Arm reported fault code {fault} instead of busy ({FAULT_BUSY}) \
+            after being re-commanded mid-motion"));
+    }
+    if !shared_state.read_coil(shared_state.register_map().running_coil) {
+        return Err(anyhow::anyhow!("Arm stopped the running sub routine #{idx} when re-commanded \
+            instead of rejecting the new command"));
+    }
+
+    let timeout_dur = MOTION_TIMEOUT;
+    wait_for_running_shared(shared_state, false, timeout_dur, config.poll_interval, config.stable_reads).await
+        .map_err(|_| anyhow::anyhow!("Timeout waiting for arm to finish sub routine #{idx} after \
+            rejecting the busy command. Waited {} ms", timeout_dur.as_millis()))?;
+    shared_state.write_coil(shared_state.register_map().enable_coil, false);
+    time::sleep(Duration::from_millis(100)).await;
+    if shared_state.read_coil(shared_state.register_map().running_coil) {
+        return Err(anyhow::anyhow!("Arm started the rejected sub routine #{second_idx} after finishing #{idx}"));
+    }
+    Ok(())
+}
+
+/// Waits for the running coil to read `target_state` on `stable_reads` consecutive polls.
+///
+/// A single spurious sample resets the count, so a flickering signal doesn't end the wait early.
 pub async fn wait_for_running_shared(
     shared_state: &SharedModbusState,
     target_state: bool,
-    timeout: Duration
+    timeout: Duration,
+    poll_interval: Duration,
+    stable_reads: u32,
 ) -> Result<(), error::Elapsed> {
+    let span = debug_span!("wait_for_running", running = target_state, timeout = ?timeout);
     time::timeout(timeout, async {
+        let mut consecutive = 0;
         loop {
-            if shared_state.read_coil(RUNNING_COIL_OFFSET) == target_state {
-                return(());
+            if shared_state.read_coil(shared_state.register_map().running_coil) == target_state {
+                consecutive += 1;
+                if consecutive >= stable_reads {
+                    return;
+                }
+            } else {
+                consecutive = 0;
             }
-            time::sleep(Duration::from_millis(1)).await;
+            time::sleep(poll_interval).await;
         }
-    }).await
-}
\ No newline at end of file
+    }.instrument(span)).await
+}
+
+
+/// How the early-stop sweep picks the next delay to try.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum SweepSchedule {
+    /// Start at 1µs and multiply the increment by `factor` each step, capping the increment at 2s.
+    Geometric { factor: u32 },
+    /// Increase the delay by a fixed `step` each iteration.
+    Linear { step: Duration },
+    /// Bisect between a delay known to stop the arm early and one known to let it finish,
+    /// until the bracket is no wider than `resolution`.
+    BinarySearch { too_short: Duration, long_enough: Duration, resolution: Duration },
+}
+
+impl SweepSchedule {
+    /// Smallest geometric factor, below it the increment never grows and the sweep crawls at 1µs.
+    pub const MIN_GEOMETRIC_FACTOR: u32 = 2;
+
+    /// Checks the schedule can make progress, as a deserialized one wasn't through the prompt.
+    pub fn validate(&self) -> Result<(), InvalidSweepSchedule> {
+        match *self {
+            SweepSchedule::Geometric { factor } if factor < Self::MIN_GEOMETRIC_FACTOR =>
+                Err(InvalidSweepSchedule::FactorTooSmall(factor)),
+            SweepSchedule::Linear { step } if step.is_zero() => Err(InvalidSweepSchedule::ZeroStep),
+            SweepSchedule::BinarySearch { too_short, long_enough, .. } if too_short >= long_enough =>
+                Err(InvalidSweepSchedule::EmptyBracket { too_short, long_enough }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A [`SweepSchedule`] that would never get anywhere.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidSweepSchedule {
+    FactorTooSmall(u32),
+    ZeroStep,
+    EmptyBracket { too_short: Duration, long_enough: Duration },
+}
+
+impl Display for InvalidSweepSchedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidSweepSchedule::FactorTooSmall(factor) => write!(f, "Geometric sweep factor {factor} is below {}, \
+                the delay would never grow", SweepSchedule::MIN_GEOMETRIC_FACTOR),
+            InvalidSweepSchedule::ZeroStep => write!(f, "Linear sweep step is 0, the delay would never grow"),
+            InvalidSweepSchedule::EmptyBracket { too_short, long_enough } => write!(f, "Binary search delay known to stop \
+                early ({}) must be shorter than the one known to complete ({})", format_duration(*too_short), format_duration(*long_enough)),
+        }
+    }
+}
+
+impl std::error::Error for InvalidSweepSchedule {}
+
+/// Drives the delays of an early-stop sweep according to a [`SweepSchedule`].
+///
+/// Geometric and linear sweeps end on their own once the delay reaches [`Self::MAX_DELAY`], so a
+/// sweep left running against an arm that never completes can't overflow.
+pub struct DelaySweep {
+    schedule: SweepSchedule,
+    delay: Duration,
+    increment: Duration,
+    low: Duration,
+    high: Duration,
+    finished: bool,
+}
+
+impl DelaySweep {
+    /// Most a geometric sweep adds to the delay per attempt, so a large factor can't overflow the
+    /// increment or skip straight past the motion.
+    pub const MAX_GEOMETRIC_INCREMENT: Duration = Duration::from_secs(2);
+    /// Longest delay a geometric or linear sweep tries, it ends after that.
+    pub const MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+    /// Fails for a schedule [`SweepSchedule::validate`] refuses.
+    pub fn new(schedule: SweepSchedule) -> Result<Self, InvalidSweepSchedule> {
+        schedule.validate()?;
+        let (low, high) = match schedule {
+            SweepSchedule::BinarySearch { too_short, long_enough, .. } => (too_short, long_enough),
+            _ => (Duration::ZERO, Duration::ZERO),
+        };
+        let increment = match schedule {
+            SweepSchedule::Linear { step } => step,
+            _ => Duration::from_micros(1),
+        };
+        Ok(Self {
+            schedule,
+            delay: Duration::ZERO,
+            increment,
+            low,
+            high,
+            finished: false,
+        })
+    }
+
+    /// The next delay to test, or `None` once the sweep is done.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.finished {
+            return None;
+        }
+        match self.schedule {
+            SweepSchedule::Geometric { .. } | SweepSchedule::Linear { .. } if self.delay >= Self::MAX_DELAY => {
+                warn!("Early-stop sweep reached the longest delay of {} without the sub routine completing", format_duration(Self::MAX_DELAY));
+                self.finished = true;
+                return None;
+            }
+            SweepSchedule::Geometric { factor } => {
+                self.delay = self.delay.saturating_add(self.increment).min(Self::MAX_DELAY);
+                self.increment = self.increment.saturating_mul(factor).min(Self::MAX_GEOMETRIC_INCREMENT);
+            }
+            SweepSchedule::Linear { .. } => {
+                self.delay = self.delay.saturating_add(self.increment).min(Self::MAX_DELAY);
+            }
+            SweepSchedule::BinarySearch { resolution, .. } => {
+                if self.high.saturating_sub(self.low) <= resolution {
+                    self.finished = true;
+                    return None;
+                }
+                self.delay = self.low + (self.high - self.low) / 2;
+            }
+        }
+        Some(self.delay)
+    }
+
+    /// Feeds back the outcome of the delay last returned by [`Self::next_delay`].
+    pub fn report(&mut self, result: &EarlyStopResult) {
+        match (self.schedule, result) {
+            (SweepSchedule::BinarySearch { .. }, EarlyStopResult::Success) => self.low = self.delay,
+            // A marginal run completed too, just barely
+            (SweepSchedule::BinarySearch { .. }, EarlyStopResult::TooLate | EarlyStopResult::Marginal { .. }) => self.high = self.delay,
+            (_, EarlyStopResult::TooLate | EarlyStopResult::Marginal { .. }) => self.finished = true,
+            (_, EarlyStopResult::Success) => {}
+        }
+    }
+
+    /// The `(stopped early, completed)` delay bracket found by a binary search sweep.
+    pub fn bracket(&self) -> Option<(Duration, Duration)> {
+        match self.schedule {
+            SweepSchedule::BinarySearch { .. } => Some((self.low, self.high)),
+            _ => None,
+        }
+    }
+}
+
+/// Extra time the ramp gets beyond [`crate::ramp::RampConfig::expected_duration`] before the
+/// test fails, covering tick and polling jitter.
+pub const RAMP_SLACK: Duration = Duration::from_millis(500);
+
+/// Writes `target` to the ramp's setpoint register and waits for the actual input register to
+/// reach it. Returns how long that took.
+pub async fn ramp_converges_shared(shared_state: &SharedModbusState, config: &TestConfig, target: u16) -> anyhow::Result<Duration> {
+    let ramp = shared_state.ramp()
+        .ok_or_else(|| anyhow::anyhow!("No ramp configured, start with `--ramp <target>:<actual>`"))?;
+    let from = shared_state.read_input_registers(ramp.actual_ireg, 1)[0];
+    let timeout_dur = ramp.expected_duration(from, target) + RAMP_SLACK;
+    let started = time::Instant::now();
+    shared_state.write_holding_register(ramp.target_hreg, target);
+    time::timeout(timeout_dur, async {
+        while shared_state.read_input_registers(ramp.actual_ireg, 1)[0] != target {
+            time::sleep(config.poll_interval).await;
+        }
+    }).await.map_err(|_| anyhow::anyhow!("Timeout waiting for input register {} to ramp from {from} to {target}. \
+        Waited {} ms, it is at {}", ramp.actual_ireg, timeout_dur.as_millis(),
+        shared_state.read_input_registers(ramp.actual_ireg, 1)[0]))?;
+    Ok(started.elapsed())
+}
+
+/// Runs `test_case` on the embedded state to completion, logging its progress, and reports
+/// whether it passed. Enable is dropped again after a failure.
+///
+/// [`TestCases::SrUpTo`] runs on `other_units` too when [`TestConfig::parallel_runs`] allows,
+/// see [`sr_up_to_parallel_shared`].
+pub async fn run_test_case_shared(
+    shared_state: &SharedModbusState,
+    other_units: &[SharedModbusState],
+    test_config: &TestConfig,
+    test_case: &TestCases,
+    sweep_csv: &mut Option<SweepCsv>,
+) -> bool {
+    let mut test_success = true;
+    match test_case {
+        TestCases::SrSingle(index) => {
+            info!("Arm should execute sub routine: {index} and then stop.");
+            match sr_single_shared(shared_state, test_config, *index).await {
+                Ok(_) => info!("Subroutine {index} completed successfully"),
+                Err(err) => {
+                    error!("Subroutine failed: {err}");
+                    test_success = false;
+                    shared_state.write_coil(shared_state.register_map().enable_coil, false);
+                }
+            };
+        },
+        TestCases::SrUpTo(index) if test_config.parallel_runs > 1 && !other_units.is_empty() => {
+            let units: Vec<SharedModbusState> = std::iter::once(shared_state.clone()).chain(other_units.iter().cloned()).collect();
+            info!("Arms should fully execute all sub routines from 0 up to {index}, {} units at a time.",
+                test_config.parallel_runs.min(units.len()));
+            for (i, result) in sr_up_to_parallel_shared(&units, test_config, *index).await {
+                match result {
+                    Ok(_) => info!("Subroutine {i}/{index} completed successfully."),
+                    Err(err) => {
+                        error!("Subroutine {i} failed: {err}");
+                        test_success = false;
+                    }
+                }
+            }
+        },
+        TestCases::SrUpTo(index) => {
+            info!("Arm should fully execute all sub routines from 0 up to {index} and then stop.");
+            for i in 0..=*index {
+                match sr_single_shared(shared_state, test_config, i).await {
+                    Ok(_) => {
+                        info!("Subroutine {i}/{index} completed successfully.");
+                    },
+                    Err(err) => {
+                        error!("Subroutine failed: {err}");
+                        test_success = false;
+                        shared_state.write_coil(shared_state.register_map().enable_coil, false);
+                        break;
+                    }
+                }
+            }
+        },
+        TestCases::SrOutOfBounds => {
+            info!("Arm should execute sub routine 65535 (assumed this does not exist). \
+            Just make sure nothing breaks. Could just run a default sr or do nothing \
+            as long as running is blipped for enough time to be read true");
+            match sr_single_shared(shared_state, test_config, 65535).await {
+                Ok(_) => info!("Subroutine 65535 completed successfully"),
+                Err(err) => {
+                    test_success = false;
+                    error!("Subroutine 65535 failed: {err}")
+                }
+            }
+        },
+        TestCases::SrEarlyStopWithDelay(idx, delay) => {
+            info!("Arm should start execution of sub routine {idx} and then stop after {delay} ms.");
+            match sr_single_early_stop_shared(shared_state, test_config, *idx, Duration::from_millis(u64::from(*delay))).await {
+                Ok(EarlyStopResult::Success) => info!("Subroutine {idx} was stopped early successfully"),
+                Ok(EarlyStopResult::TooLate) => warn!("Subroutine {idx} completed before it could be stopped early"),
+                Ok(EarlyStopResult::Marginal { slack }) =>
+                    warn!("Subroutine {idx} completed {} before it could be stopped early, within the margin", format_duration(slack)),
+                Err(err) => {
+                    test_success = false;
+                    error!("Subroutine {idx} failed stopping early: {err}");
+                    shared_state.write_coil(shared_state.register_map().enable_coil, false);
+                }
+            }
+        },
+        TestCases::SrEarlyStopWithDelayOnAllUpTo(idx, delay) => {
+            info!("Arm should start execution of each sub routine [0..={idx}] and stop each one after {delay} ms.");
+            for i in 0..=*idx {
+                match sr_single_early_stop_shared(shared_state, test_config, i, Duration::from_millis(u64::from(*delay))).await {
+                    Ok(EarlyStopResult::Success) => info!("Subroutine {i} was stopped early successfully"),
+                    Ok(EarlyStopResult::TooLate) => warn!("Subroutine {i} completed before it could be stopped early"),
+                    Ok(EarlyStopResult::Marginal { slack }) =>
+                        warn!("Subroutine {i} completed {} before it could be stopped early, within the margin", format_duration(slack)),
+                    Err(err) => {
+                        test_success = false;
+                        error!("Subroutine {i} failed stopping early: {err}");
+                        shared_state.write_coil(shared_state.register_map().enable_coil, false);
+                        break;
+                    }
+                }
+            }
+        },
+        TestCases::SrEarlyStopAllDelays(idx, schedule) => {
+            info!("Arm should be given longer and longer periods of time to complete sub routine {idx} until it fully completes");
+            let mut sweep = match DelaySweep::new(*schedule) {
+                Ok(sweep) => sweep,
+                Err(err) => {
+                    error!("{err}");
+                    return false;
+                }
+            };
+            while let Some(delay) = sweep.next_delay() {
+                debug!("Testing with delay: {}", format_duration(delay));
+                let result = sr_single_early_stop_shared(shared_state, test_config, *idx, delay).await;
+                if let Some(csv) = sweep_csv.as_mut()
+                    && let Err(err) = csv.append(delay, &result) {
+                    error!("Failed to write CSV row: {err}");
+                }
+                match result {
+                    Ok(result) => {
+                        match result {
+                            EarlyStopResult::Success => info!("Subroutine {idx} was stopped early at {} successfully", format_duration(delay)),
+                            EarlyStopResult::TooLate =>
+                                warn!("Subroutine {idx} completed before it could be stopped early at {}", format_duration(delay)),
+                            EarlyStopResult::Marginal { slack } =>
+                                warn!("Subroutine {idx} completed {} before it could be stopped early at {}, within the margin",
+                                    format_duration(slack), format_duration(delay)),
+                        }
+                        sweep.report(&result);
+                    },
+                    Err(err) => {
+                        test_success = false;
+                        error!("Subroutine {idx} failed stopping early at {}: {err}", format_duration(delay));
+                        shared_state.write_coil(shared_state.register_map().enable_coil, false);
+                        break;
+                    }
+                }
+            }
+            if test_success && let Some((low, high)) = sweep.bracket() {
+                info!("Subroutine {idx} motion duration is between {} and {}", format_duration(low), format_duration(high));
+            }
+        }
+        TestCases::RapidEnableToggle(count, interval) => {
+            info!("Arm should start at most one motion per enable rising edge ({count} edges, {interval} ms apart) and end idle.");
+            match rapid_enable_toggle_shared(shared_state, test_config, *count, Duration::from_millis(u64::from(*interval))).await {
+                Ok(0) => warn!("Arm never started a motion during the toggle sequence"),
+                Ok(starts) => info!("Arm started {starts} motions from {count} enable rising edges"),
+                Err(err) => {
+                    test_success = false;
+                    error!("Rapid enable toggle failed: {err}");
+                    shared_state.write_coil(shared_state.register_map().enable_coil, false);
+                }
+            }
+        }
+        TestCases::StartWhileBusy(index) => {
+            info!("Arm should reject a second command sent while sub routine {index} runs, report busy, and finish the original motion.");
+            match start_while_busy_shared(shared_state, test_config, *index).await {
+                Ok(_) => info!("Arm rejected the command while busy"),
+                Err(err) => {
+                    test_success = false;
+                    error!("Start while busy failed: {err}");
+                    shared_state.write_coil(shared_state.register_map().enable_coil, false);
+                }
+            }
+        }
+        TestCases::Soak { idx, iterations } => {
+            info!("Arm should execute sub routine {idx} {iterations} times in a row without a failure.");
+            let stats = soak_shared(shared_state, test_config, *idx, *iterations).await;
+            for failure in &stats.failures {
+                error!("Iteration {} failed: {}", failure.iteration, failure.error);
+            }
+            info!("Soak: {}/{} passed ({:.1}%), cycle min {:?} / avg {:?} / max {:?}",
+                stats.passed, stats.iterations, stats.success_rate() * 100.0,
+                stats.min_cycle.unwrap_or_default(), stats.average_cycle().unwrap_or_default(), stats.max_cycle.unwrap_or_default());
+            info!("Soak report: {}", stats.to_json());
+            test_success = stats.failures.is_empty() && stats.iterations == *iterations;
+        }
+        TestCases::FaultRecovery(index) => {
+            info!("Arm should refuse sub routine {index} while faulted, clear the fault on reset, then run it.");
+            match fault_recovery_shared(shared_state, test_config, *index).await {
+                Ok(_) => info!("Subroutine {index} ran after the fault was reset"),
+                Err(err) => {
+                    test_success = false;
+                    error!("Fault recovery failed: {err}");
+                    shared_state.write_coil(shared_state.register_map().enable_coil, false);
+                }
+            }
+        }
+        TestCases::RampConverges(target) => {
+            info!("Actual value should ramp to setpoint {target} within the time the configured rate allows.");
+            match ramp_converges_shared(shared_state, test_config, *target).await {
+                Ok(elapsed) => info!("Actual value reached {target} after {elapsed:?}"),
+                Err(err) => {
+                    test_success = false;
+                    error!("Ramp failed: {err}");
+                }
+            }
+        }
+    }
+    test_success
+}
+
+/// Awaits a test case `run`, such as [`run_test_case_shared`], within
+/// [`TestConfig::test_budget`], dropping enable if it's aborted at the budget. Either way it
+/// fails if the arm isn't back at rest afterwards, see [`assert_idle`].
+pub async fn run_within_budget(
+    shared_state: &SharedModbusState,
+    test_config: &TestConfig,
+    run: impl Future<Output = bool>,
+) -> bool {
+    let mut test_success = match test_config.test_budget {
+        Some(budget) => time::timeout(budget, run).await.unwrap_or_else(|_| {
+            error!("Test exceeded its {budget:?} budget and was aborted");
+            shared_state.write_coil(shared_state.register_map().enable_coil, false);
+            false
+        }),
+        None => run.await,
+    };
+    if let Err(err) = assert_idle(shared_state) {
+        test_success = false;
+        error!("{err}");
+    }
+    test_success
+}
+
+/// How many delays of an adaptive sweep a dry run previews.
+pub const DRY_RUN_SWEEP_PREVIEW: usize = 8;
+
+/// Describes the steps a test case would take for `--dry-run`, without touching any state.
+pub fn plan_test_case(test_case: &TestCases, test_config: &TestConfig, shared_state: &SharedModbusState) -> Vec<String> {
+    let RegisterMap { enable_coil, running_coil, ready_coil, index_hreg, fault_hreg } = shared_state.register_map();
+    let (ramp, initial_fault) = (shared_state.ramp(), shared_state.initial_fault());
+    let mut steps = Vec::new();
+    let ready = match test_config.ready_timeout {
+        Some(timeout) => format!("wait up to {timeout:?} for ready (coil {ready_coil}), "),
+        None => String::new(),
+    };
+    let idle_check = match test_config.enable_mode {
+        EnableMode::Edge => " and check running stays clear",
+        EnableMode::Held => "",
+    };
+    let run = |idx: u16| format!("Run sub routine {idx}: {ready}write index {idx} to register {index_hreg}, \
+        set enable (coil {enable_coil}), wait up to {RUNNING_START_TIMEOUT:?} for running (coil {running_coil}) \
+        and up to {MOTION_TIMEOUT:?} for it to clear, then clear enable{idle_check}");
+    let early_stop = |idx: u16, delay: Duration| format!("Start sub routine {idx} the same way, clear enable \
+        after {delay:?} and expect running to clear within 1s");
+    match test_case {
+        TestCases::SrSingle(idx) => steps.push(run(*idx)),
+        TestCases::SrUpTo(idx) => steps.extend((0..=*idx).map(run)),
+        TestCases::SrOutOfBounds => steps.push(run(65535)),
+        TestCases::SrEarlyStopWithDelay(idx, delay) =>
+            steps.push(early_stop(*idx, Duration::from_millis(u64::from(*delay)))),
+        TestCases::SrEarlyStopWithDelayOnAllUpTo(idx, delay) =>
+            steps.extend((0..=*idx).map(|i| early_stop(i, Duration::from_millis(u64::from(*delay))))),
+        TestCases::SrEarlyStopAllDelays(idx, schedule) => {
+            steps.push(format!("Sweep early stop delays on sub routine {idx} ({schedule:?}). Later delays \
+                depend on the results; the first {DRY_RUN_SWEEP_PREVIEW} if every attempt stops early:"));
+            let mut sweep = match DelaySweep::new(*schedule) {
+                Ok(sweep) => sweep,
+                Err(err) => {
+                    steps.push(format!("Refuse the sweep: {err}"));
+                    return steps;
+                }
+            };
+            for _ in 0..DRY_RUN_SWEEP_PREVIEW {
+                let Some(delay) = sweep.next_delay() else { break };
+                steps.push(early_stop(*idx, delay));
+                sweep.report(&EarlyStopResult::Success);
+            }
+        }
+        TestCases::RapidEnableToggle(count, interval) => {
+            steps.push(format!("Toggle enable {count} times, {interval} ms high then {interval} ms low, \
+                expecting at most one motion start per rising edge"));
+            steps.push(format!("Wait up to {MOTION_TIMEOUT:?} for running to clear"));
+        }
+        TestCases::StartWhileBusy(idx) => {
+            steps.push(format!("Start sub routine {idx}, then write index {} and re-pulse enable while it runs",
+                idx.wrapping_add(1)));
+            steps.push(format!("Expect fault code {FAULT_BUSY} (register {fault_hreg}) and running still set, \
+                then wait up to {MOTION_TIMEOUT:?} for the original motion to finish"));
+        }
+        TestCases::RampConverges(target) => match ramp {
+            Some(ramp) => steps.push(format!("Write {target} to register {}, expect input register {} to \
+                reach it {} per {:?}, with {RAMP_SLACK:?} slack", ramp.target_hreg, ramp.actual_ireg, ramp.rate, ramp.tick)),
+            None => steps.push("Fail, no ramp is configured".to_string()),
+        },
+        TestCases::Soak { idx, iterations } => {
+            steps.push(format!("{iterations} times: {}", run(*idx)));
+            steps.push("Keep going after failures, report the success rate and cycle times".to_string());
+        }
+        TestCases::FaultRecovery(idx) => {
+            steps.push(format!("Expect a fault code in register {fault_hreg}, then {}, expecting running never to be set",
+                run(*idx).replacen("Run", "try to run", 1)));
+            match initial_fault {
+                Some(fault) => steps.push(format!("Set the reset coil {} and wait up to {FAULT_RESET_TIMEOUT:?} \
+                    for the fault to clear", fault.reset_coil)),
+                None => steps.push("Fail, no fault reset coil is configured".to_string()),
+            }
+            steps.push(run(*idx));
+        }
+    }
+    steps
+}
@@ -0,0 +1,113 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+use dialoguer::console::Style;
+use log::warn;
+use crate::test_cases::TestCases;
+
+/// Most test cases offered for a rerun, older ones stay in the file but aren't loaded.
+const MAX_ENTRIES: usize = 50;
+
+/// Test cases run in this and, when backed by a file, earlier sessions, oldest first.
+///
+/// The file holds one JSON encoded [`TestCases`] per line and is only ever appended to.
+pub struct TestHistory {
+    entries: Vec<TestCases>,
+    writer: Option<BufWriter<File>>,
+}
+
+impl TestHistory {
+    /// Loads the history in `path`, if any, and appends newly run test cases to it. Without a
+    /// path the history only lasts for this session.
+    pub fn open(path: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self { entries: Vec::new(), writer: None });
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut entries = Self::parse(&contents);
+        entries.drain(..entries.len().saturating_sub(MAX_ENTRIES));
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { entries, writer: Some(BufWriter::new(file)) })
+    }
+
+    /// Parses history file contents, skipping lines from incompatible versions.
+    fn parse(contents: &str) -> Vec<TestCases> {
+        contents.lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(test_case) => Some(test_case),
+                Err(err) => {
+                    warn!("Skipping unreadable test history entry {line:?}: {err}");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn record(&mut self, test_case: &TestCases) -> anyhow::Result<()> {
+        self.entries.push(test_case.clone());
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+        if let Some(writer) = self.writer.as_mut() {
+            serde_json::to_writer(&mut *writer, test_case)?;
+            writeln!(writer)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn last(&self) -> Option<&TestCases> {
+        self.entries.last()
+    }
+
+    pub fn entries(&self) -> &[TestCases] {
+        &self.entries
+    }
+}
+
+/// Pass/fail outcome of every test case run this session, oldest first.
+#[derive(Default)]
+pub struct SessionTally {
+    outcomes: Vec<(TestCases, bool)>,
+}
+
+impl SessionTally {
+    pub fn record(&mut self, test_case: &TestCases, passed: bool) {
+        self.outcomes.push((test_case.clone(), passed));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+
+    pub fn passed(&self) -> usize {
+        self.outcomes.iter().filter(|(_, passed)| *passed).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.outcomes.len() - self.passed()
+    }
+
+    /// One line like `3 passed, 1 failed of 4 test cases`, the counts styled with `pass_style` and
+    /// `fail_style`.
+    pub fn render(&self, pass_style: &Style, fail_style: &Style) -> String {
+        format!("{}, {} of {} test cases",
+            pass_style.apply_to(format!("{} passed", self.passed())),
+            fail_style.apply_to(format!("{} failed", self.failed())),
+            self.outcomes.len())
+    }
+
+    /// The tally followed by one line per failed test case, for the end of the session.
+    pub fn summary(&self, pass_style: &Style, fail_style: &Style) -> Vec<String> {
+        let mut lines = vec![self.render(pass_style, fail_style)];
+        lines.extend(self.outcomes.iter()
+            .filter(|(_, passed)| !passed)
+            .map(|(test_case, _)| format!("    {} {test_case:?}", fail_style.apply_to("failed:"))));
+        lines
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::{FromStr, SplitWhitespace};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail, Context};
+use log::{error, info};
+use tokio_modbus::{ExceptionCode, Request, Response};
+use crate::error::Error;
+
+// Plain-text request/response log, one exchange per line:
+//
+//     [<unix ms>] <Request> <args..> => <Response> <args..>
+//
+// Addresses, quantities and values are decimal, lists are comma separated (`-` for empty),
+// coils are `1`/`0` and exception responses are written as `Exception <code>`.
+// Blank lines and lines starting with `#` are ignored.
+
+const SEPARATOR: &str = " => ";
+
+pub type ServiceResult = Result<Response, ExceptionCode>;
+
+/// One recorded exchange.
+pub struct Entry {
+    pub request: Request<'static>,
+    pub response: ServiceResult,
+}
+
+/// Formats an exchange as a log line, or `None` if the response can't be represented.
+pub fn format_line(timestamp_ms: u128, request: &str, response: &ServiceResult) -> Option<String> {
+    let response = format_response(response)?;
+    Some(format!("{timestamp_ms} {request}{SEPARATOR}{response}"))
+}
+
+/// Parses a log line. Returns `Ok(None)` for blank and comment lines.
+pub fn parse_line(line: &str) -> anyhow::Result<Option<Entry>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (request, response) = line.split_once(SEPARATOR)
+        .ok_or_else(|| anyhow!("missing `{}` between request and response", SEPARATOR.trim()))?;
+    // The leading timestamp only matters for offline timing analysis
+    let request = match request.split_once(' ') {
+        Some((first, rest)) if first.chars().all(|c| c.is_ascii_digit()) => rest,
+        _ => request,
+    };
+    Ok(Some(Entry {
+        request: parse_request(request)?,
+        response: parse_response(response)?,
+    }))
+}
+
+pub fn format_request(request: &Request<'_>) -> Option<String> {
+    Some(match request {
+        Request::ReadCoils(addr, cnt) => format!("ReadCoils {addr} {cnt}"),
+        Request::ReadDiscreteInputs(addr, cnt) => format!("ReadDiscreteInputs {addr} {cnt}"),
+        Request::ReadInputRegisters(addr, cnt) => format!("ReadInputRegisters {addr} {cnt}"),
+        Request::ReadHoldingRegisters(addr, cnt) => format!("ReadHoldingRegisters {addr} {cnt}"),
+        Request::WriteSingleCoil(addr, value) => format!("WriteSingleCoil {addr} {}", u8::from(*value)),
+        Request::WriteMultipleCoils(addr, values) => format!("WriteMultipleCoils {addr} {}", format_coils(values)),
+        Request::WriteSingleRegister(addr, value) => format!("WriteSingleRegister {addr} {value}"),
+        Request::WriteMultipleRegisters(addr, values) => format!("WriteMultipleRegisters {addr} {}", format_words(values)),
+        Request::MaskWriteRegister(addr, and, or) => format!("MaskWriteRegister {addr} {and} {or}"),
+        Request::ReadWriteMultipleRegisters(read_addr, cnt, write_addr, values) =>
+            format!("ReadWriteMultipleRegisters {read_addr} {cnt} {write_addr} {}", format_words(values)),
+        _ => return None,
+    })
+}
+
+pub fn format_response(response: &ServiceResult) -> Option<String> {
+    Some(match response {
+        Ok(Response::ReadCoils(values)) => format!("ReadCoils {}", format_coils(values)),
+        Ok(Response::ReadDiscreteInputs(values)) => format!("ReadDiscreteInputs {}", format_coils(values)),
+        Ok(Response::ReadInputRegisters(values)) => format!("ReadInputRegisters {}", format_words(values)),
+        Ok(Response::ReadHoldingRegisters(values)) => format!("ReadHoldingRegisters {}", format_words(values)),
+        Ok(Response::ReadWriteMultipleRegisters(values)) => format!("ReadWriteMultipleRegisters {}", format_words(values)),
+        Ok(Response::WriteSingleCoil(addr, value)) => format!("WriteSingleCoil {addr} {}", u8::from(*value)),
+        Ok(Response::WriteMultipleCoils(addr, cnt)) => format!("WriteMultipleCoils {addr} {cnt}"),
+        Ok(Response::WriteSingleRegister(addr, value)) => format!("WriteSingleRegister {addr} {value}"),
+        Ok(Response::WriteMultipleRegisters(addr, cnt)) => format!("WriteMultipleRegisters {addr} {cnt}"),
+        Ok(Response::MaskWriteRegister(addr, and, or)) => format!("MaskWriteRegister {addr} {and} {or}"),
+        Err(code) => format!("Exception {}", u8::from(*code)),
+        _ => return None,
+    })
+}
+
+pub fn parse_request(s: &str) -> anyhow::Result<Request<'static>> {
+    let mut tokens = s.split_whitespace();
+    let name = tokens.next().ok_or_else(|| anyhow!("empty request"))?;
+    let request = match name {
+        "ReadCoils" => Request::ReadCoils(next(&mut tokens)?, next(&mut tokens)?),
+        "ReadDiscreteInputs" => Request::ReadDiscreteInputs(next(&mut tokens)?, next(&mut tokens)?),
+        "ReadInputRegisters" => Request::ReadInputRegisters(next(&mut tokens)?, next(&mut tokens)?),
+        "ReadHoldingRegisters" => Request::ReadHoldingRegisters(next(&mut tokens)?, next(&mut tokens)?),
+        "WriteSingleCoil" => Request::WriteSingleCoil(next(&mut tokens)?, next_coil(&mut tokens)?),
+        "WriteMultipleCoils" => Request::WriteMultipleCoils(next(&mut tokens)?, next_coils(&mut tokens)?.into()),
+        "WriteSingleRegister" => Request::WriteSingleRegister(next(&mut tokens)?, next(&mut tokens)?),
+        "WriteMultipleRegisters" => Request::WriteMultipleRegisters(next(&mut tokens)?, next_words(&mut tokens)?.into()),
+        "MaskWriteRegister" => Request::MaskWriteRegister(next(&mut tokens)?, next(&mut tokens)?, next(&mut tokens)?),
+        "ReadWriteMultipleRegisters" => Request::ReadWriteMultipleRegisters(
+            next(&mut tokens)?, next(&mut tokens)?, next(&mut tokens)?, next_words(&mut tokens)?.into()),
+        _ => bail!("unknown request `{name}`"),
+    };
+    expect_end(tokens)?;
+    Ok(request)
+}
+
+pub fn parse_response(s: &str) -> anyhow::Result<ServiceResult> {
+    let mut tokens = s.split_whitespace();
+    let name = tokens.next().ok_or_else(|| anyhow!("empty response"))?;
+    let response = match name {
+        "ReadCoils" => Ok(Response::ReadCoils(next_coils(&mut tokens)?)),
+        "ReadDiscreteInputs" => Ok(Response::ReadDiscreteInputs(next_coils(&mut tokens)?)),
+        "ReadInputRegisters" => Ok(Response::ReadInputRegisters(next_words(&mut tokens)?)),
+        "ReadHoldingRegisters" => Ok(Response::ReadHoldingRegisters(next_words(&mut tokens)?)),
+        "ReadWriteMultipleRegisters" => Ok(Response::ReadWriteMultipleRegisters(next_words(&mut tokens)?)),
+        "WriteSingleCoil" => Ok(Response::WriteSingleCoil(next(&mut tokens)?, next_coil(&mut tokens)?)),
+        "WriteMultipleCoils" => Ok(Response::WriteMultipleCoils(next(&mut tokens)?, next(&mut tokens)?)),
+        "WriteSingleRegister" => Ok(Response::WriteSingleRegister(next(&mut tokens)?, next(&mut tokens)?)),
+        "WriteMultipleRegisters" => Ok(Response::WriteMultipleRegisters(next(&mut tokens)?, next(&mut tokens)?)),
+        "MaskWriteRegister" => Ok(Response::MaskWriteRegister(next(&mut tokens)?, next(&mut tokens)?, next(&mut tokens)?)),
+        "Exception" => Err(ExceptionCode::new(next(&mut tokens)?)),
+        _ => bail!("unknown response `{name}`"),
+    };
+    expect_end(tokens)?;
+    Ok(response)
+}
+
+fn format_coils(values: &[bool]) -> String {
+    if values.is_empty() {
+        return "-".to_string();
+    }
+    values.iter().map(|&value| u8::from(value).to_string()).collect::<Vec<_>>().join(",")
+}
+
+fn format_words(values: &[u16]) -> String {
+    if values.is_empty() {
+        return "-".to_string();
+    }
+    values.iter().map(u16::to_string).collect::<Vec<_>>().join(",")
+}
+
+fn next<T: FromStr>(tokens: &mut SplitWhitespace<'_>) -> anyhow::Result<T> {
+    let token = tokens.next().ok_or_else(|| anyhow!("missing argument"))?;
+    token.parse().map_err(|_| anyhow!("invalid number `{token}`"))
+}
+
+fn next_coil(tokens: &mut SplitWhitespace<'_>) -> anyhow::Result<bool> {
+    match next::<u8>(tokens)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        other => bail!("invalid coil value `{other}`, expected 0 or 1"),
+    }
+}
+
+fn next_coils(tokens: &mut SplitWhitespace<'_>) -> anyhow::Result<Vec<bool>> {
+    next_list(tokens)?.into_iter()
+        .map(|value: u8| match value {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => bail!("invalid coil value `{other}`, expected 0 or 1"),
+        })
+        .collect()
+}
+
+fn next_words(tokens: &mut SplitWhitespace<'_>) -> anyhow::Result<Vec<u16>> {
+    next_list(tokens)
+}
+
+fn next_list<T: FromStr>(tokens: &mut SplitWhitespace<'_>) -> anyhow::Result<Vec<T>> {
+    let token = tokens.next().ok_or_else(|| anyhow!("missing list argument"))?;
+    if token == "-" {
+        return Ok(Vec::new());
+    }
+    token.split(',')
+        .map(|item| item.parse().map_err(|_| anyhow!("invalid number `{item}` in list")))
+        .collect()
+}
+
+fn expect_end(mut tokens: SplitWhitespace<'_>) -> anyhow::Result<()> {
+    match tokens.next() {
+        Some(extra) => bail!("unexpected trailing argument `{extra}`"),
+        None => Ok(()),
+    }
+}
+
+/// Recorded responses, handed out in recorded order per distinct request.
+pub struct ReplayLog {
+    responses: Mutex<HashMap<String, VecDeque<ServiceResult>>>,
+}
+
+impl ReplayLog {
+    pub fn load(path: &Path) -> crate::error::Result<Self> {
+        let config_error = |source| Error::Config { path: path.to_path_buf(), source };
+        let contents = std::fs::read_to_string(path).map_err(|err| config_error(err.into()))?;
+        let mut responses: HashMap<String, VecDeque<ServiceResult>> = HashMap::new();
+        let mut count = 0;
+        for (line_no, line) in contents.lines().enumerate() {
+            let entry = parse_line(line)
+                .with_context(|| format!("line {}", line_no + 1)).map_err(config_error)?;
+            if let Some(entry) = entry {
+                let key = format_request(&entry.request).expect("parsed requests are always formattable");
+                responses.entry(key).or_default().push_back(entry.response);
+                count += 1;
+            }
+        }
+        info!("Loaded {count} recorded exchanges from {}", path.display());
+        Ok(Self { responses: Mutex::new(responses) })
+    }
+
+    /// Takes the next recorded response for `request`, or `None` if none is left.
+    pub fn next_response(&self, request: &Request<'_>) -> Option<ServiceResult> {
+        let key = format_request(request)?;
+        self.responses.lock().unwrap().get_mut(&key)?.pop_front()
+    }
+}
+
+
+/// Appends every handled exchange to a file in the format [`ReplayLog`] reads.
+///
+/// Lines are handed to a writer thread so request handling never waits on disk I/O.
+pub struct CaptureLog {
+    lines: Sender<String>,
+}
+
+impl CaptureLog {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("opening capture file {}", path.display()))?;
+        let (lines, rx) = mpsc::channel::<String>();
+        let writer_path = path.to_path_buf();
+        std::thread::spawn(move || {
+            let mut writer = BufWriter::new(file);
+            while let Ok(line) = rx.recv() {
+                let mut res = writeln!(writer, "{line}");
+                // Drain whatever queued up meanwhile before paying for a flush
+                for line in rx.try_iter() {
+                    res = res.and_then(|_| writeln!(writer, "{line}"));
+                }
+                if let Err(err) = res.and_then(|_| writer.flush()) {
+                    error!("Failed to write to capture file {}, capture stopped: {err}", writer_path.display());
+                    return;
+                }
+            }
+        });
+        info!("Capturing traffic to {}", path.display());
+        Ok(Self { lines })
+    }
+
+    /// Records one exchange. `request` is the output of [`format_request`].
+    pub fn record(&self, request: &str, response: &ServiceResult) {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        if let Some(line) = format_line(timestamp_ms, request, response) {
+            // A send error means the writer gave up and already logged why
+            let _ = self.lines.send(line);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::Duration;
+use log::{debug, info, warn};
+use tokio::time::{self, Instant};
+use crate::FAULT_WATCHDOG;
+use crate::mb_stuff::SharedModbusState;
+
+/// Settings for a heartbeat the master has to keep toggling for the arm to stay enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    /// Coil the master toggles.
+    pub coil: u16,
+    /// Longest time between two toggles before the watchdog trips.
+    pub timeout: Duration,
+}
+
+impl WatchdogConfig {
+    /// How often the master's side toggles the coil, well within the timeout.
+    pub fn heartbeat_period(&self) -> Duration {
+        self.timeout / 3
+    }
+
+    /// How often the arm's side checks the coil.
+    fn tick(&self) -> Duration {
+        (self.timeout / 10).max(Duration::from_millis(1))
+    }
+}
+
+/// Plays the arm's safety controller: when the watchdog coil hasn't changed for longer than the
+/// timeout, enable and running are forced low and [`FAULT_WATCHDOG`] is set.
+///
+/// The fault stays until it is cleared by writing the fault register. The watchdog re-arms at the
+/// next toggle.
+pub async fn run_watchdog(state: SharedModbusState, config: WatchdogConfig) {
+    info!("Watchdog started: coil {} must toggle at least every {:?}", config.coil, config.timeout);
+    let mut last_value = state.read_coil(config.coil);
+    let mut last_toggle = Instant::now();
+    let mut tripped = false;
+    let mut interval = time::interval(config.tick());
+    loop {
+        interval.tick().await;
+        let value = state.read_coil(config.coil);
+        if value != last_value {
+            if tripped {
+                info!("Watchdog: heartbeat is back, re-armed");
+            }
+            last_value = value;
+            last_toggle = Instant::now();
+            tripped = false;
+        } else if !tripped && last_toggle.elapsed() > config.timeout {
+            warn!("Watchdog: no heartbeat for {:?}, disabling the arm", last_toggle.elapsed());
+            tripped = true;
+            let map = state.register_map();
+            state.write_coil(map.enable_coil, false);
+            state.write_coil(map.running_coil, false);
+            state.write_holding_register(map.fault_hreg, FAULT_WATCHDOG);
+        }
+    }
+}
+
+/// Plays the master's side, toggling the watchdog coil every [`WatchdogConfig::heartbeat_period`]
+/// unless paused through [`SharedModbusState::pause_heartbeat`].
+pub async fn run_heartbeat(state: SharedModbusState, config: WatchdogConfig) {
+    let mut interval = time::interval(config.heartbeat_period());
+    loop {
+        interval.tick().await;
+        if state.heartbeat_paused() {
+            continue;
+        }
+        let value = !state.read_coil(config.coil);
+        debug!("Heartbeat: watchdog coil {} -> {value}", config.coil);
+        state.write_coil(config.coil, value);
+    }
+}
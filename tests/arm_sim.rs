@@ -0,0 +1,416 @@
+//! The simulated arm: booting, latching, motion timing, running pulses, rapid enable toggles, and the models and queue
+//! around it.
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_modbus::server::Service;
+use tokio_modbus::{ExceptionCode, Request, SlaveRequest};
+use rtu_sim::arm_sim::{run_arm_sim, ArmSimConfig, CommandQueue, MotionModel, Trapezoidal, WarmUp, PROGRESS_FULL_SCALE};
+use rtu_sim::mb_stuff::{ChangeKind, ExampleService, IndexWidth, SharedModbusState, WordOrder, BROADCAST_UNIT_ID};
+use rtu_sim::register_map::RegisterMap;
+use rtu_sim::test_cases::{enqueue_shared, rapid_enable_toggle_shared, read_fault, read_pending_runs, wait_for_running_shared, RunOutcome,
+    SubroutineRun, TestConfig};
+use common::{start_arm, NO_PEER, TIMING_SLACK};
+
+const INDEX_ECHO_REGISTER: u16 = 1;
+const MOTION_DURATION: Duration = Duration::from_millis(50);
+/// Needs both words of a two register index.
+const WIDE_INDEX: u32 = 0x0001_0002;
+const WIDE_INDEX_FAULT_REGISTER: u16 = 30;
+const WARM_UP: WarmUp = WarmUp { multiplier: 3.0, idle_after: Duration::from_millis(200) };
+/// Long enough to sample both units halfway through.
+const UNIT_MOTION: Duration = Duration::from_millis(100);
+/// Three times the configured motion, set while the arm runs.
+const LIVE_MOTION: Duration = Duration::from_millis(150);
+const PROGRESS_REGISTER: u16 = 3;
+/// Points along a motion the trapezoidal curve is sampled at.
+const PROGRESS_SAMPLES: u32 = 100;
+const ENABLE_DEBOUNCE: Duration = Duration::from_millis(50);
+const PULSE_WIDTH: Duration = Duration::from_millis(40);
+const COMMAND_QUEUE: CommandQueue = CommandQueue { depth: 2, pending_ireg: 2 };
+const TOGGLES: u16 = 10;
+const READY_AFTER: Duration = Duration::from_millis(200);
+/// Twice the motion, so every motion ends before enable rises again.
+const TOGGLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Overwrites the index right after commanding a sub routine, before a slow arm asserts running:
+/// the arm must still run the index that was there at the edge.
+#[tokio::test(start_paused = true)]
+async fn edge_latch() -> anyhow::Result<()> {
+    let state = SharedModbusState::new().with_index_echo(Some(INDEX_ECHO_REGISTER));
+    let arm = tokio::spawn(run_arm_sim(state.clone(), ArmSimConfig {
+        motion_duration: MOTION_DURATION,
+        running_assert_delay: Duration::from_millis(30),
+        ..ArmSimConfig::default()
+    }));
+    tokio::time::sleep(ArmSimConfig::DEFAULT_TICK).await;
+    state.write_index_and_enable(5)?;
+    assert_eq!((state.read_index(), state.read_coil(state.register_map().enable_coil)), (5, true),
+        "Index and enable are written together");
+    state.write_index(6)?;
+    let running = wait_for_running_shared(&state, true, Duration::from_secs(1), Duration::from_millis(1), 1).await;
+    let latched = state.read_index_echo();
+    arm.abort();
+    running?;
+    assert_eq!(latched, Some(5), "Index at the edge is latched when running asserts");
+    Ok(())
+}
+
+/// A running pulse measured from the outside, sampling every millisecond.
+#[tokio::test(start_paused = true)]
+async fn pulse_width() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let started = tokio::time::Instant::now();
+    let pulse = state.pulse_running(PULSE_WIDTH);
+    assert!(state.read_coil(state.register_map().running_coil), "Pulse is up straight away");
+    wait_for_running_shared(&state, false, PULSE_WIDTH * 2, Duration::from_millis(1), 1).await?;
+    let width = started.elapsed();
+    pulse.await?;
+    assert!(width >= PULSE_WIDTH && width <= PULSE_WIDTH + TIMING_SLACK,
+        "Pulse of {PULSE_WIDTH:?} was up for {width:?}");
+    Ok(())
+}
+
+/// Runs sub routines on two units, the second starting halfway through the first, and talks to
+/// each unit through one service.
+#[tokio::test(start_paused = true)]
+async fn units() -> anyhow::Result<()> {
+    let first = SharedModbusState::new().with_index_echo(Some(INDEX_ECHO_REGISTER));
+    let second = first.independent_copy();
+    let map = first.register_map();
+    let arms = [&first, &second].map(|state| tokio::spawn(run_arm_sim(state.clone(), ArmSimConfig {
+        motion_duration: UNIT_MOTION,
+        ..ArmSimConfig::default()
+    })));
+    tokio::time::sleep(ArmSimConfig::DEFAULT_TICK).await;
+    let run = |idx| SubroutineRun::new(idx).poll_interval(Duration::from_millis(1)).stable_reads(1);
+    let (first_run, second_run) = (run(1), run(2));
+    let staggered = async {
+        tokio::time::sleep(UNIT_MOTION / 2).await;
+        let running = (first.read_coil(map.running_coil), second.read_coil(map.running_coil));
+        (running, second_run.execute(&second).await)
+    };
+    let (first_outcome, (running, second_outcome)) = tokio::join!(first_run.execute(&first), staggered);
+    arms.iter().for_each(|arm| arm.abort());
+    assert_eq!(running, (true, false), "Unit 2 stays idle while unit 1 runs");
+    assert!(first_outcome.is_success() && second_outcome.is_success(),
+        "Units: expected both motions to complete, got {first_outcome:?} and {second_outcome:?}");
+    assert_eq!((first.read_index_echo(), second.read_index_echo()), (Some(1), Some(2)), "Each unit latched its own index");
+
+    let units = Arc::new(HashMap::from([(1, first.clone()), (2, second.clone())]));
+    let service = ExampleService::with_shared_state(first.clone(), NO_PEER)
+        .with_units(Some(units));
+    service.call(SlaveRequest { slave: 2, request: Request::WriteSingleCoil(map.enable_coil, true) }).await?;
+    assert_eq!((first.read_coil(map.enable_coil), second.read_coil(map.enable_coil)), (false, true),
+        "Write to unit 2 leaves unit 1 alone");
+    let unknown = service.call(SlaveRequest { slave: 3, request: Request::ReadCoils(map.enable_coil, 1) }).await;
+    assert_eq!(unknown, Err(ExceptionCode::GatewayTargetDevice), "Unit without a state is a gateway target failure");
+    service.call(SlaveRequest { slave: BROADCAST_UNIT_ID, request: Request::WriteSingleCoil(map.enable_coil, false) }).await?;
+    assert_eq!((first.read_coil(map.enable_coil), second.read_coil(map.enable_coil)), (false, false),
+        "Broadcast reaches every unit");
+    Ok(())
+}
+
+/// Runs three sub routines on a cold arm: the first since boot is slow, the next one right after
+/// nominal, and one after an idle pause slow again.
+#[tokio::test(start_paused = true)]
+async fn warm_up() {
+    let state = SharedModbusState::new();
+    let arm = tokio::spawn(run_arm_sim(state.clone(), ArmSimConfig {
+        motion_duration: MOTION_DURATION,
+        warm_up: Some(WARM_UP),
+        ..ArmSimConfig::default()
+    }));
+    tokio::time::sleep(ArmSimConfig::DEFAULT_TICK).await;
+    let mut motions = Vec::new();
+    for (idx, idle_before) in [(1, Duration::ZERO), (2, Duration::ZERO), (3, WARM_UP.idle_after)] {
+        tokio::time::sleep(idle_before).await;
+        match SubroutineRun::new(idx).poll_interval(Duration::from_millis(1)).stable_reads(1).execute(&state).await {
+            RunOutcome::Completed { motion, .. } => motions.push(motion),
+            outcome => {
+                arm.abort();
+                panic!("Warm-up: expected sub routine #{idx} to complete, got {outcome:?}");
+            }
+        }
+    }
+    arm.abort();
+    // Polling sees running rise a little late, so a motion can look slightly short
+    let cold = MOTION_DURATION.mul_f64(WARM_UP.multiplier) - TIMING_SLACK;
+    assert!(motions[0] >= cold, "First motion since boot is cold");
+    assert!(motions[1] < cold / 2, "Motion right after is nominal");
+    assert!(motions[2] >= cold, "Motion after an idle pause is cold again");
+}
+
+/// Changes the motion duration of a running simulated arm, first for all indices, then back for
+/// one of them.
+#[tokio::test(start_paused = true)]
+async fn live_motion_duration() {
+    let state = SharedModbusState::new();
+    let arm = tokio::spawn(run_arm_sim(state.clone(), ArmSimConfig {
+        motion_duration: MOTION_DURATION,
+        ..ArmSimConfig::default()
+    }));
+    tokio::time::sleep(ArmSimConfig::DEFAULT_TICK).await;
+    let mut motions = Vec::new();
+    for idx in [1, 1, 2] {
+        match SubroutineRun::new(idx).poll_interval(Duration::from_millis(1)).stable_reads(1).execute(&state).await {
+            RunOutcome::Completed { motion, .. } => motions.push(motion),
+            outcome => {
+                arm.abort();
+                panic!("Live motion duration: expected sub routine #{idx} to complete, got {outcome:?}");
+            }
+        }
+        if motions.len() == 1 {
+            state.set_motion_duration(LIVE_MOTION);
+            state.set_index_motion_duration(2, Some(MOTION_DURATION));
+            // Tuning isn't state, a reset between tests keeps it
+            state.reset();
+        }
+    }
+    arm.abort();
+    let live = LIVE_MOTION - TIMING_SLACK;
+    assert!(motions[0] < live / 2, "Configured duration before the change");
+    assert!(motions[1] >= live, "Next motion takes the live duration");
+    assert!(motions[2] < live / 2, "Index with its own duration keeps it");
+}
+
+/// Samples the trapezoidal model's progress curve for its shape.
+#[test]
+fn motion_model() {
+    let model = Trapezoidal::DEFAULT;
+    let duration = Duration::from_secs(1);
+    let progress = |fraction: f64| model.progress(duration.mul_f64(fraction), duration);
+    let curve: Vec<f64> = (0..=PROGRESS_SAMPLES)
+        .map(|sample| progress(f64::from(sample) / f64::from(PROGRESS_SAMPLES)))
+        .collect();
+    assert_eq!(curve[0], 0.0, "Trapezoid starts at 0");
+    assert_eq!(curve[curve.len() - 1], 1.0, "Trapezoid ends at 1");
+    assert!(curve.windows(2).all(|pair| pair[0] <= pair[1]), "Trapezoid never goes backwards");
+    let ramp = model.ramp;
+    assert!(progress(ramp / 2.0) < ramp / 2.0, "Trapezoid is slow while accelerating");
+    // Covering half the cruise speed's worth of distance while ramping up
+    assert!((progress(ramp) - ramp / (2.0 * (1.0 - ramp))).abs() < 1e-9, "Trapezoid finishes accelerating");
+    assert!(((progress(0.5) - progress(0.4)) - (progress(0.6) - progress(0.5))).abs() < 1e-9,
+        "Trapezoid cruises at constant speed");
+    assert!((progress(0.5) - 0.5).abs() < 1e-9, "Trapezoid is halfway at half time");
+    assert!(curve.iter().zip(curve.iter().rev()).all(|(early, late)| (early + late - 1.0).abs() < 1e-9),
+        "Trapezoid decelerates like it accelerated");
+    assert!(progress(1.0 - ramp / 2.0) > 1.0 - ramp / 2.0, "Trapezoid is slow while decelerating");
+    assert_eq!((model.is_complete(duration.mul_f64(0.99), duration), model.is_complete(duration, duration)), (false, true),
+        "Trapezoid completes on time");
+}
+
+/// Has a simulated arm with the trapezoidal model report its progress in the progress register.
+#[tokio::test(start_paused = true)]
+async fn progress_register() -> anyhow::Result<()> {
+    let state = SharedModbusState::new().with_progress_register(Some(PROGRESS_REGISTER));
+    let arm = tokio::spawn(run_arm_sim(state.clone(), ArmSimConfig {
+        motion_model: Arc::new(Trapezoidal::DEFAULT),
+        ..ArmSimConfig::default()
+    }));
+    let run_state = state.clone();
+    let run = tokio::spawn(async move { SubroutineRun::new(1).execute(&run_state).await });
+    let mut reported = Vec::new();
+    while !run.is_finished() {
+        reported.push(state.read_input_registers(PROGRESS_REGISTER, 1)[0]);
+        tokio::time::sleep(ArmSimConfig::DEFAULT_TICK).await;
+    }
+    let outcome = run.await?;
+    arm.abort();
+    assert!(matches!(outcome, RunOutcome::Completed { .. }), "Run with a progress register completes");
+    assert!(reported.windows(2).all(|pair| pair[0] <= pair[1]), "Progress never goes backwards");
+    assert!(reported.iter().any(|progress| (1..PROGRESS_FULL_SCALE).contains(progress)), "Progress is reported mid-motion");
+    assert_eq!(state.read_input_registers(PROGRESS_REGISTER, 1)[0], PROGRESS_FULL_SCALE, "Progress is full once arrived");
+    Ok(())
+}
+
+/// Freezes the input registers mid-motion: reads hold still while the progress register keeps
+/// moving underneath, and show it again once unfrozen.
+#[tokio::test(start_paused = true)]
+async fn freeze() -> anyhow::Result<()> {
+    let state = SharedModbusState::new().with_progress_register(Some(PROGRESS_REGISTER));
+    let arm = tokio::spawn(run_arm_sim(state.clone(), ArmSimConfig::default()));
+    let run_state = state.clone();
+    let run = tokio::spawn(async move { SubroutineRun::new(1).execute(&run_state).await });
+    tokio::time::sleep(ArmSimConfig::DEFAULT_MOTION_DURATION / 4).await;
+    state.freeze();
+    let frozen = state.read_input_registers(PROGRESS_REGISTER, 1);
+    let mut frozen_reads = Vec::new();
+    let mut live_reads = Vec::new();
+    for _ in 0..4 {
+        tokio::time::sleep(ArmSimConfig::DEFAULT_MOTION_DURATION / 8).await;
+        frozen_reads.push(state.read_input_registers(PROGRESS_REGISTER, 1)[0]);
+        live_reads.push(state.read_live_input_registers(PROGRESS_REGISTER, 1)[0]);
+    }
+    assert_eq!(frozen_reads, vec![frozen[0]; 4], "Frozen reads hold still");
+    assert!(live_reads.windows(2).all(|pair| pair[0] < pair[1]) && live_reads[0] > frozen[0], "Motion progresses underneath");
+    state.unfreeze();
+    assert_eq!(state.read_input_registers(PROGRESS_REGISTER, 1), state.read_live_input_registers(PROGRESS_REGISTER, 1),
+        "Reads show reality once unfrozen");
+    let outcome = run.await?;
+    arm.abort();
+    assert!(matches!(outcome, RunOutcome::Completed { .. }), "Run completes after the freeze");
+    assert_eq!(state.read_input_registers(PROGRESS_REGISTER, 1)[0], PROGRESS_FULL_SCALE, "Progress reads full after the run");
+    Ok(())
+}
+
+/// An enable glitch shorter than the debounce doesn't start a motion, a stable enable starts one
+/// once the debounce passed, and a glitch low mid-motion doesn't stop it.
+#[tokio::test(start_paused = true)]
+async fn enable_debounce() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let arm = tokio::spawn(run_arm_sim(state.clone(), ArmSimConfig {
+        enable_debounce: ENABLE_DEBOUNCE,
+        ..ArmSimConfig::default()
+    }));
+    tokio::time::sleep(ArmSimConfig::DEFAULT_TICK * 2).await;
+    let running_within = |window: Duration| {
+        let state = state.clone();
+        async move {
+            let until = tokio::time::Instant::now() + window;
+            while tokio::time::Instant::now() < until {
+                if state.read_coil(map.running_coil) {
+                    return true;
+                }
+                tokio::time::sleep(ArmSimConfig::DEFAULT_TICK).await;
+            }
+            false
+        }
+    };
+
+    state.write_coil(map.enable_coil, true);
+    tokio::time::sleep(ENABLE_DEBOUNCE / 2).await;
+    state.write_coil(map.enable_coil, false);
+    assert!(!running_within(ENABLE_DEBOUNCE * 4).await, "Enable glitch shorter than the debounce starts nothing");
+
+    let raised = tokio::time::Instant::now();
+    state.write_coil(map.enable_coil, true);
+    assert!(running_within(ENABLE_DEBOUNCE * 4).await, "Stable enable starts a motion");
+    let started_after = raised.elapsed();
+    assert!((ENABLE_DEBOUNCE..ENABLE_DEBOUNCE + TIMING_SLACK).contains(&started_after), "Motion starts once the debounce passed");
+
+    state.write_coil(map.enable_coil, false);
+    tokio::time::sleep(ENABLE_DEBOUNCE / 2).await;
+    state.write_coil(map.enable_coil, true);
+    tokio::time::sleep(ENABLE_DEBOUNCE * 2).await;
+    let still_running = state.read_coil(map.running_coil);
+    arm.abort();
+    assert!(still_running, "Enable glitch low mid-motion doesn't stop it");
+    Ok(())
+}
+
+/// Latches a 32-bit index on a simulated arm with a two register, little endian index and echo.
+#[tokio::test(start_paused = true)]
+async fn wide_index() {
+    let state = SharedModbusState::new()
+        .with_index_echo(Some(INDEX_ECHO_REGISTER))
+        .with_index_width(IndexWidth::Double(WordOrder::LittleEndian));
+    // The presets keep the fault register right after the index
+    let map = RegisterMap { fault_hreg: WIDE_INDEX_FAULT_REGISTER, ..RegisterMap::DEFAULT };
+    assert!(!IndexWidth::Double(WordOrder::LittleEndian).fits(&RegisterMap::DEFAULT),
+        "Two register index overlaps the preset fault register");
+    state.set_register_map(map);
+    let index_hreg = map.index_hreg;
+    let arm = tokio::spawn(run_arm_sim(state.clone(), ArmSimConfig {
+        motion_duration: MOTION_DURATION,
+        ..ArmSimConfig::default()
+    }));
+    tokio::time::sleep(ArmSimConfig::DEFAULT_TICK).await;
+    let outcome = SubroutineRun::new(WIDE_INDEX)
+        .poll_interval(Duration::from_millis(1))
+        .stable_reads(1)
+        .execute(&state).await;
+    arm.abort();
+    assert!(outcome.is_success(), "Wide index: expected the motion to complete, got {outcome:?}");
+    assert_eq!(state.read_holding_registers(index_hreg, 2), vec![0x0002, 0x0001], "Wide index is split low word first");
+    assert_eq!(state.read_index_echo(), Some(WIDE_INDEX), "Wide index is echoed whole");
+
+    let narrow = SharedModbusState::new();
+    let too_wide = u32::from(u16::MAX) + 1;
+    assert_eq!(SubroutineRun::new(too_wide).execute(&narrow).await, RunOutcome::IndexOutOfRange { max: u16::MAX.into() },
+        "Index past one register is refused");
+    assert_eq!(narrow.read_holding_registers(index_hreg, 1), vec![0], "Refused index isn't written");
+}
+
+/// Queues two sub routines behind a running one and follows them through the index echo.
+#[tokio::test(start_paused = true)]
+async fn command_queue() -> anyhow::Result<()> {
+    let state = SharedModbusState::new()
+        .with_index_echo(Some(INDEX_ECHO_REGISTER))
+        .with_command_queue(Some(COMMAND_QUEUE));
+    let enable_coil = state.register_map().enable_coil;
+    let arm = tokio::spawn(run_arm_sim(state.clone(), ArmSimConfig {
+        motion_duration: MOTION_DURATION,
+        ..ArmSimConfig::default()
+    }));
+    tokio::time::sleep(ArmSimConfig::DEFAULT_TICK).await;
+    let result = async {
+        let wait_running = |running| wait_for_running_shared(&state, running, Duration::from_secs(1), Duration::from_millis(1), 1);
+        state.write_index(3)?;
+        state.write_coil(enable_coil, true);
+        wait_running(true).await.map_err(|_| anyhow::anyhow!("Command queue: first sub routine never started"))?;
+        enqueue_shared(&state, 4, Duration::from_millis(1)).await?;
+        enqueue_shared(&state, 5, Duration::from_millis(1)).await?;
+        assert_eq!((read_pending_runs(&state), read_fault(&state)), (Some(2), 0), "Commands mid-motion are queued, not rejected");
+        for idx in [4, 5] {
+            wait_running(false).await.map_err(|_| anyhow::anyhow!("Command queue: motion before #{idx} never ended"))?;
+            wait_running(true).await.map_err(|_| anyhow::anyhow!("Command queue: queued #{idx} never started"))?;
+            assert_eq!(state.read_index_echo(), Some(idx), "Queued sub routine #{idx} runs in order");
+        }
+        wait_running(false).await.map_err(|_| anyhow::anyhow!("Command queue: last queued motion never ended"))?;
+        assert_eq!(read_pending_runs(&state), Some(0), "Command queue drains");
+        Ok(())
+    }.await;
+    arm.abort();
+    result
+}
+
+/// Toggles enable with each level held longer than a motion: every rising edge starts exactly one
+/// motion, counted both by the test case and from the running coil's changes.
+#[tokio::test(start_paused = true)]
+async fn rapid_enable_toggle() {
+    let state = SharedModbusState::new();
+    let running_coil = state.register_map().running_coil;
+    let mut changes = state.subscribe_changes();
+    let _arm = start_arm(&state, ArmSimConfig { motion_duration: MOTION_DURATION, ..ArmSimConfig::default() }).await;
+    let starts = rapid_enable_toggle_shared(&state, &TestConfig::default(), TOGGLES, TOGGLE_INTERVAL).await;
+    let mut running_rises = 0;
+    while let Ok(change) = changes.try_recv() {
+        if change.kind == ChangeKind::Coil && change.address == running_coil && change.new == 1 {
+            running_rises += 1;
+        }
+    }
+    assert_eq!(starts.map_err(|err| err.to_string()), Ok(u32::from(TOGGLES)), "Each enable toggle starts one motion");
+    assert_eq!(running_rises, TOGGLES, "Running rose once per enable toggle");
+}
+
+/// Commands a booting arm: an edge before it is ready is ignored, one after starts the motion.
+#[tokio::test(start_paused = true)]
+async fn ready_after() {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let mut changes = state.subscribe_changes();
+    let _arm = start_arm(&state, ArmSimConfig {
+        motion_duration: MOTION_DURATION,
+        ready_after: READY_AFTER,
+        ..ArmSimConfig::default()
+    }).await;
+    assert!(!state.read_coil(map.ready_coil), "Arm isn't ready while booting");
+    state.write_coil(map.enable_coil, true);
+    tokio::time::sleep(READY_AFTER).await;
+    let mut running_changed = false;
+    while let Ok(change) = changes.try_recv() {
+        running_changed |= change.kind == ChangeKind::Coil && change.address == map.running_coil;
+    }
+    assert!(state.read_coil(map.ready_coil), "Arm is ready after {READY_AFTER:?}");
+    assert!(!running_changed, "Enable raised while booting starts nothing");
+
+    state.write_coil(map.enable_coil, false);
+    state.write_coil(map.enable_coil, true);
+    let started = wait_for_running_shared(&state, true, MOTION_DURATION, Duration::from_millis(1), 1).await;
+    assert!(started.is_ok(), "Enable raised once ready starts a motion");
+}
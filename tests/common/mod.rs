@@ -0,0 +1,72 @@
+//! Fixtures shared by the integration tests. Each test file includes this as a module, so not
+//! every file uses every helper.
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use rtu_sim::arm_sim::{run_arm_sim, ArmSimConfig};
+use rtu_sim::connections::ConnectionTracker;
+use rtu_sim::mb_stuff::{ExampleService, SharedModbusState};
+use rtu_sim::server_context;
+
+/// How late a poll may see a change, on top of the durations a test sets up.
+pub const TIMING_SLACK: Duration = Duration::from_millis(5);
+/// The peer address of services called directly rather than over a connection.
+pub const NO_PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// A task aborted when dropped, so a failing test doesn't leave servers and arms running.
+pub struct Background<T>(JoinHandle<T>);
+
+impl<T> Background<T> {
+    pub fn spawn(task: impl Future<Output = T> + Send + 'static) -> Self
+    where
+        T: Send + 'static,
+    {
+        Self(tokio::spawn(task))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+}
+
+impl<T> Drop for Background<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// A server on a loopback port, stopped when dropped.
+pub struct Served {
+    pub addr: SocketAddr,
+    pub server: Background<anyhow::Result<()>>,
+}
+
+/// Serves `state` on a loopback port with no idle timeout or connection limit.
+pub async fn serve(state: &SharedModbusState) -> anyhow::Result<Served> {
+    let state = state.clone();
+    serve_with(None, Arc::new(ConnectionTracker::new(None)), move |peer| ExampleService::with_shared_state(state.clone(), peer)).await
+}
+
+/// Serves whatever `new_service` builds on a loopback port.
+pub async fn serve_with(
+    idle_timeout: Option<Duration>,
+    connections: Arc<ConnectionTracker>,
+    new_service: impl Fn(SocketAddr) -> ExampleService + Clone + Send + Sync + 'static,
+) -> anyhow::Result<Served> {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await?;
+    let addr = listener.local_addr()?;
+    Ok(Served { addr, server: Background::spawn(server_context(listener, idle_timeout, connections, new_service)) })
+}
+
+/// Starts a simulated arm on `state` and lets it sample enable once, or it takes the next command
+/// for an edge from before it started.
+pub async fn start_arm(state: &SharedModbusState, config: ArmSimConfig) -> Background<()> {
+    let tick = config.tick;
+    let arm = Background::spawn(run_arm_sim(state.clone(), config));
+    tokio::time::sleep(tick).await;
+    arm
+}
@@ -0,0 +1,162 @@
+//! Early stops, and the delay sweeps that look for the latest one still in time.
+
+mod common;
+
+use std::time::Duration;
+use rtu_sim::arm_sim::{run_arm_sim, ArmSimConfig};
+use rtu_sim::mb_stuff::SharedModbusState;
+use rtu_sim::remote::run_remote_test_case;
+use rtu_sim::sweep_csv::SweepCsv;
+use rtu_sim::test_cases::{sr_single_early_stop_shared, DelaySweep, EarlyStopResult, InvalidSweepSchedule, RunningNeverAssertedError,
+    SubroutineRun, SweepSchedule, TestCases, TestConfig};
+use tokio_modbus::client;
+use common::{serve, start_arm, TIMING_SLACK};
+
+/// Wide enough to take in the idle check after the motion.
+const EARLY_STOP_MARGIN: Duration = Duration::from_millis(500);
+/// How long the early stop waits for running to drop.
+const EARLY_STOP_WAIT: Duration = Duration::from_secs(1);
+const SEARCHED_MOTION: Duration = Duration::from_millis(500);
+const SEARCH_RESOLUTION: Duration = Duration::from_millis(5);
+/// Over the wire, a linear sweep of this step stops the motion twice before it completes.
+const CSV_MOTION: Duration = Duration::from_millis(600);
+const CSV_STEP: Duration = Duration::from_millis(260);
+/// What a run adds to the motion before it counts as completed: the idle check and the polls.
+const RUN_OVERHEAD: Duration = Duration::from_millis(150);
+
+/// Runs early-stop sweeps as if against an arm that never completes, with growth that would
+/// overflow `Duration` if it weren't capped.
+#[test]
+fn sweep_caps() -> anyhow::Result<()> {
+    let mut sweep = DelaySweep::new(SweepSchedule::Geometric { factor: u32::MAX })?;
+    let mut delays = Vec::new();
+    while let Some(delay) = sweep.next_delay() {
+        sweep.report(&EarlyStopResult::Success);
+        delays.push(delay);
+    }
+    let increments: Vec<Duration> = delays.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    assert!(increments.contains(&DelaySweep::MAX_GEOMETRIC_INCREMENT),
+        "Geometric sweep with factor u32::MAX reaches the increment cap");
+    assert!(increments.iter().all(|&increment| increment <= DelaySweep::MAX_GEOMETRIC_INCREMENT),
+        "Geometric sweep increment is capped");
+    assert_eq!(delays.last().copied(), Some(DelaySweep::MAX_DELAY), "Geometric sweep ends at the longest delay");
+
+    let mut sweep = DelaySweep::new(SweepSchedule::Linear { step: Duration::MAX })?;
+    let mut delays = Vec::new();
+    while let Some(delay) = sweep.next_delay() {
+        sweep.report(&EarlyStopResult::Success);
+        delays.push(delay);
+    }
+    assert_eq!(delays, vec![DelaySweep::MAX_DELAY], "Linear sweep with a huge step is capped");
+    Ok(())
+}
+
+/// Schedules that would never get anywhere are refused before the first attempt.
+#[test]
+fn invalid_schedules() {
+    for factor in [0, 1] {
+        assert_eq!(SweepSchedule::Geometric { factor }.validate(), Err(InvalidSweepSchedule::FactorTooSmall(factor)),
+            "Geometric factor {factor} is rejected");
+    }
+    assert_eq!(SweepSchedule::Geometric { factor: 2 }.validate(), Ok(()), "Geometric factor 2 is accepted");
+    assert_eq!(SweepSchedule::Linear { step: Duration::ZERO }.validate(), Err(InvalidSweepSchedule::ZeroStep),
+        "Zero linear step is rejected");
+    for (too_short, long_enough) in [(SEARCHED_MOTION, SEARCHED_MOTION), (SEARCHED_MOTION * 2, SEARCHED_MOTION)] {
+        let schedule = SweepSchedule::BinarySearch { too_short, long_enough, resolution: SEARCH_RESOLUTION };
+        assert_eq!(DelaySweep::new(schedule).err(), Some(InvalidSweepSchedule::EmptyBracket { too_short, long_enough }),
+            "Binary search from {too_short:?} to {long_enough:?} is rejected");
+    }
+}
+
+/// A binary search against a [`SEARCHED_MOTION`] arm narrows down to the run's length.
+#[tokio::test(start_paused = true)]
+async fn binary_search_converges() -> anyhow::Result<()> {
+    let config = TestConfig::default();
+    let state = SharedModbusState::new();
+    let _arm = start_arm(&state, ArmSimConfig { motion_duration: SEARCHED_MOTION, ..ArmSimConfig::default() }).await;
+    let mut sweep = DelaySweep::new(SweepSchedule::BinarySearch {
+        too_short: Duration::ZERO,
+        long_enough: SEARCHED_MOTION * 4,
+        resolution: SEARCH_RESOLUTION,
+    })?;
+    let mut attempts = 0;
+    while let Some(delay) = sweep.next_delay() {
+        sweep.report(&sr_single_early_stop_shared(&state, &config, 1, delay).await?);
+        attempts += 1;
+    }
+    let (low, high) = sweep.bracket().ok_or_else(|| anyhow::anyhow!("Binary search finished without a bracket"))?;
+    assert!(high - low <= SEARCH_RESOLUTION, "Binary search narrows to the resolution");
+    assert!(low >= SEARCHED_MOTION && high <= SEARCHED_MOTION + RUN_OVERHEAD, "Binary search brackets the run");
+    assert_eq!(attempts, 9, "Binary search halves the bracket each attempt");
+    Ok(())
+}
+
+/// Drives every branch of the early stop on a paused clock: stopped in time, completed before
+/// the stop (well before or within the margin), still running after it, and failed before it.
+#[tokio::test(start_paused = true)]
+async fn early_stop_branches() -> anyhow::Result<()> {
+    let config = TestConfig::default();
+    let state = SharedModbusState::new();
+    let arm = tokio::spawn(run_arm_sim(state.clone(), ArmSimConfig::default()));
+    let early = ArmSimConfig::DEFAULT_MOTION_DURATION / 4;
+    let started = tokio::time::Instant::now();
+    let result = sr_single_early_stop_shared(&state, &config, 1, early).await?;
+    let elapsed = started.elapsed();
+    assert_eq!(result, EarlyStopResult::Success, "Stop mid-motion succeeds");
+    assert!((early + EARLY_STOP_WAIT..early + EARLY_STOP_WAIT + TIMING_SLACK).contains(&elapsed),
+        "Stop waits a second for running to drop");
+
+    state.write_coil(state.register_map().enable_coil, false);
+    let late = ArmSimConfig::DEFAULT_MOTION_DURATION * 2;
+    let result = sr_single_early_stop_shared(&state, &config, 1, late).await?;
+    assert_eq!(result, EarlyStopResult::TooLate, "Stop after the motion is too late");
+
+    let marginal_config = TestConfig { early_stop_margin: Some(EARLY_STOP_MARGIN), ..TestConfig::default() };
+    // Just late enough for the run to finish, idle check included
+    let boundary = ArmSimConfig::DEFAULT_MOTION_DURATION + SubroutineRun::IDLE_CHECK_DELAY + TIMING_SLACK;
+    let result = sr_single_early_stop_shared(&state, &marginal_config, 1, boundary).await?;
+    assert!(matches!(result, EarlyStopResult::Marginal { slack } if slack < EARLY_STOP_MARGIN),
+        "Completing just before the stop is marginal");
+    let result = sr_single_early_stop_shared(&state, &marginal_config, 1, late).await?;
+    assert_eq!(result, EarlyStopResult::TooLate, "Completing well before the stop is still too late");
+
+    state.jam_next_motion();
+    let result = sr_single_early_stop_shared(&state, &config, 1, early).await;
+    assert_eq!(result.map_err(|err| err.to_string().starts_with("Arm still running after early stop")).err(), Some(true),
+        "Jammed arm still runs after the stop");
+    state.clear_jam();
+    arm.abort();
+
+    // Without an arm running never rises, which fails the run before the stop is due
+    let unanswered = SharedModbusState::new();
+    let result = sr_single_early_stop_shared(&unanswered, &config, 1, late).await;
+    assert_eq!(result.map_err(|err| err.is::<RunningNeverAssertedError>()).err(), Some(true),
+        "Run failing before the stop is reported as such");
+    Ok(())
+}
+
+/// A three attempt sweep run with `--connect` appends a CSV row per attempt.
+#[tokio::test]
+async fn sweep_csv() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let _arm = start_arm(&state, ArmSimConfig { motion_duration: CSV_MOTION, ..ArmSimConfig::default() }).await;
+    let served = serve(&state).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    let path = std::env::temp_dir().join(format!("rtu-sim-test-{}.csv", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut csv = Some(SweepCsv::open(&path)?);
+    let test_case = TestCases::SrEarlyStopAllDelays(1, SweepSchedule::Linear { step: CSV_STEP });
+    let passed = run_remote_test_case(&mut ctx, &map, &TestConfig::default(), &test_case, &mut csv).await;
+    drop(csv);
+    let contents = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    let rows: Vec<Vec<String>> = contents?.lines().skip(1)
+        .map(|line| line.split(',').map(str::to_string).collect())
+        .collect();
+    assert!(passed, "Sweep passes");
+    assert_eq!(rows.iter().map(|row| (row[0].as_str(), row[1].as_str())).collect::<Vec<_>>(),
+        vec![("260.000", "Success"), ("520.000", "Success"), ("780.000", "TooLate")],
+        "One CSV row per attempt, with the delay and result");
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! The typed errors setting up the simulator fails with.
+
+mod common;
+
+use std::error::Error as _;
+use std::net::Ipv4Addr;
+use std::process::Command;
+use tokio::net::TcpListener;
+use rtu_sim::error::{bind, parse_port, resolve_local_ipv4, Error};
+use rtu_sim::golden::GoldenSequence;
+use common::NO_PEER;
+
+/// Bad ports, taken addresses and broken startup files surface as their own [`Error`] variants.
+#[tokio::test]
+async fn setup_errors() -> anyhow::Result<()> {
+    assert_eq!(parse_port("502").ok(), Some(502), "Port 502 parses");
+    for port in ["0", "65536", "modbus"] {
+        assert!(matches!(parse_port(port), Err(Error::BadArg { arg: "port number", .. })), "Port {port:?} is a bad argument");
+    }
+    let taken = TcpListener::bind(NO_PEER).await?;
+    let addr = taken.local_addr()?;
+    let bound = bind(addr).await;
+    assert!(matches!(&bound, Err(Error::Bind { addr: failed, source })
+        if *failed == addr && source.kind() == std::io::ErrorKind::AddrInUse),
+        "Binding a taken address is a bind failure");
+    let missing = std::env::temp_dir().join(format!("rtu-sim-test-{}-missing.golden", std::process::id()));
+    assert!(matches!(GoldenSequence::load(&missing), Err(Error::Config { path, .. }) if path == missing),
+        "A missing golden sequence is a config failure");
+    Ok(())
+}
+
+/// A lookup failure stays reachable as the source, for callers that log the whole chain.
+#[test]
+fn no_local_ip_source() {
+    let err = Error::NoLocalIp(local_ip_address::Error::LocalIpAddressNotFound);
+    assert_eq!(err.source().map(|source| source.is::<local_ip_address::Error>()), Some(true), "The lookup failure is the source");
+}
+
+/// With no usable interface the simulator serves on loopback rather than failing to start.
+#[test]
+fn no_local_ip_fallback() -> anyhow::Result<()> {
+    let lan = Ipv4Addr::new(192, 168, 1, 20);
+    assert_eq!(resolve_local_ipv4(Ok(lan)), lan, "A found address is served on");
+    assert_eq!(resolve_local_ipv4(Err(Error::NoLocalIp(local_ip_address::Error::LocalIpAddressNotFound))),
+        Ipv4Addr::LOCALHOST, "No local IPv4 address falls back to loopback");
+    Ok(())
+}
+
+/// Bad arguments stop the binary before it serves, naming the argument and the value.
+#[test]
+fn bad_args() -> anyhow::Result<()> {
+    for (args, message) in [
+        (&["--poll-interval", "fast"][..], "Invalid poll interval: fast"),
+        (&["--poll-interval", "0"], "Invalid poll interval: 0 (must be greater than 0)"),
+        (&["--stable-reads", "0"], "Invalid stable read count: 0 (must be greater than 0)"),
+        (&["--swap", "nibble"], "Invalid swap mode: nibble (expected none, byte, word or byte-word)"),
+        (&["--port"], "Invalid --port: nothing (a value is required)"),
+        (&["--grpc", "0"], "Invalid gRPC port: 0"),
+        (&["--json", "--headless"], "Invalid control mode"),
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_rtu-sim")).args(args).output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "{args:?} fails");
+        assert!(stderr.contains(message), "{args:?} is reported as {message:?}, got {stderr:?}");
+    }
+    Ok(())
+}
@@ -0,0 +1,260 @@
+//! Function codes through a real tokio-modbus client, beyond the plain round trips of
+//! `--selftest`: bit counts, read-only coils, aliases, signed registers, 32-bit values, float setpoints, swap modes, the status byte and device identification.
+
+mod common;
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio_modbus::client::{self, Client, Reader, Writer};
+use tokio_modbus::{ExceptionCode, Request, Response};
+use rtu_sim::connections::ConnectionTracker;
+use rtu_sim::device_id::{DeviceIdentification, READ_DEVICE_ID_MEI_TYPE};
+use rtu_sim::mb_stuff::{ChangeKind, ExampleService, ExceptionStatusBits, NonFinitePolicy, ReadOnlyPolicy, SeedRange, SharedModbusState, StatusBlock,
+    SwapMode, WordOrder, READ_EXCEPTION_STATUS_FUNCTION_CODE, STATUS_INPUTS};
+use rtu_sim::regions::AddressSpace;
+use rtu_sim::remote::{read_i16, read_status_block, read_u32, write_i16, write_u32};
+use rtu_sim::FAULT_WATCHDOG;
+use common::{serve, serve_with};
+
+/// Representative float setpoints: zero, fractional, negative, tiny and the largest finite.
+const SETPOINTS: [f32; 5] = [0.0, 1.5, -273.15, 1e-6, f32::MAX];
+/// Alias addresses for the enable coil and index register, away from anything the map uses.
+const COIL_ALIAS: u16 = 1000;
+const REGISTER_ALIAS: u16 = 1000;
+/// A 32-bit payload whose bytes and words are all told apart.
+const PAYLOAD: [u16; 2] = [0x1234, 0x5678];
+/// A 32-bit value with its high word first, as big endian stores it.
+const WIDE_VALUE: u32 = 0x1234_5678;
+/// Bit counts either side of a byte boundary.
+const BIT_COUNTS: [u16; 5] = [1, 7, 8, 9, 16];
+/// Coils declared for the bit count reads, away from the handshake coils.
+const BIT_BASE: u16 = 100;
+
+/// A pattern with no period of 8, so bits shifted across a byte boundary show.
+fn bit_pattern(offset: u16) -> bool {
+    offset.is_multiple_of(3) || offset == 7
+}
+
+/// ReadCoils and ReadDiscreteInputs decode to exactly the requested bits, however many bytes they fill.
+#[tokio::test]
+async fn bit_counts() -> anyhow::Result<()> {
+    let last = BIT_COUNTS.iter().max().copied().unwrap_or_default();
+    let state = SharedModbusState::new()
+        .with_seed_ranges(vec![SeedRange::new(AddressSpace::Coil, BIT_BASE..=BIT_BASE + last, 0)]);
+    let pattern: Vec<bool> = (0..last).map(bit_pattern).collect();
+    state.write_coils(BIT_BASE, &pattern);
+    // The coil just past the longest read is set, so it would show if a read ran over
+    state.write_coil(BIT_BASE + last, true);
+    let served = serve(&state).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    for count in BIT_COUNTS {
+        assert_eq!(ctx.read_coils(BIT_BASE, count).await??, pattern[..count as usize].to_vec(), "ReadCoils of {count}");
+    }
+
+    let map = state.register_map();
+    state.write_coils(map.enable_coil, &[true, false, true]);
+    let status = ExceptionStatusBits::DEFAULT.status_byte(&state);
+    for count in BIT_COUNTS.into_iter().filter(|&count| count <= STATUS_INPUTS) {
+        let bits: Vec<bool> = (0..count).map(|bit| status >> bit & 1 != 0).collect();
+        assert_eq!(ctx.read_discrete_inputs(0, count).await??, bits, "ReadDiscreteInputs of {count}");
+    }
+    Ok(())
+}
+
+/// A master can't write a read-only coil, rejected or ignored, while the simulation still can.
+#[tokio::test]
+async fn read_only_coils() -> anyhow::Result<()> {
+    for policy in [ReadOnlyPolicy::Reject, ReadOnlyPolicy::Ignore] {
+        let map = SharedModbusState::new().register_map();
+        let state = SharedModbusState::new().with_read_only_coils(HashSet::from([map.running_coil]), policy);
+        let served = serve(&state).await?;
+        let mut ctx = client::tcp::connect(served.addr).await?;
+
+        let single = ctx.write_single_coil(map.running_coil, true).await?.map(|_| ());
+        let multiple = ctx.write_multiple_coils(map.enable_coil, &[true, true]).await?.map(|_| ());
+        let expected = match policy {
+            ReadOnlyPolicy::Reject => Err(ExceptionCode::IllegalDataAddress),
+            ReadOnlyPolicy::Ignore => Ok(()),
+        };
+        assert_eq!(single, expected, "Single write to a read-only coil ({policy:?})");
+        assert_eq!(multiple, expected, "Multiple write over a read-only coil ({policy:?})");
+        // A rejected request writes nothing, an ignored one only skips the protected coil
+        assert_eq!(ctx.read_coils(map.enable_coil, 2).await??, vec![policy == ReadOnlyPolicy::Ignore, false],
+            "Read-only coil is unchanged ({policy:?})");
+        state.write_coil(map.running_coil, true);
+        assert_eq!(ctx.read_coils(map.running_coil, 1).await??, vec![true],
+            "Simulation still sets a read-only coil ({policy:?})");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn aliases() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let state = state.with_aliases(HashMap::from([(COIL_ALIAS, map.enable_coil)]), HashMap::from([(REGISTER_ALIAS, map.index_hreg)]));
+    let served = serve(&state).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+
+    ctx.write_single_coil(COIL_ALIAS, true).await??;
+    assert!(state.read_coil(map.enable_coil), "Coil alias write");
+    state.write_coil(map.enable_coil, false);
+    assert_eq!(ctx.read_coils(COIL_ALIAS, 1).await??, vec![false], "Coil alias read");
+
+    ctx.write_single_register(REGISTER_ALIAS, 99).await??;
+    assert_eq!(state.read_holding_registers(map.index_hreg, 1), vec![99], "Register alias write");
+    state.write_holding_register(map.index_hreg, 100);
+    assert_eq!(ctx.read_holding_registers(REGISTER_ALIAS, 1).await??, vec![100], "Register alias read");
+    Ok(())
+}
+
+#[tokio::test]
+async fn last_written() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let served = serve(&state).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    assert_eq!(state.last_written(ChangeKind::Coil, map.enable_coil), None, "Nothing written yet");
+    ctx.write_multiple_coils(map.enable_coil, &[true, false, true]).await??;
+    let written = state.last_written(ChangeKind::Coil, map.enable_coil);
+    assert!(written.is_some(), "Last write time is recorded for a Modbus write");
+    ctx.read_coils(map.enable_coil, 1).await??;
+    assert_eq!(state.last_written(ChangeKind::Coil, map.enable_coil), written, "Last write time is kept by a read");
+    Ok(())
+}
+
+/// Signed values through the holding registers, written on one side and read on the other.
+#[tokio::test]
+async fn signed_registers() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let served = serve(&state).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    for value in [-1, i16::MIN] {
+        state.write_i16(map.index_hreg, value);
+        assert_eq!(state.read_holding_registers(map.index_hreg, 1), vec![value as u16], "{value} is stored in two's-complement");
+        assert_eq!(read_i16(&mut ctx, map.index_hreg).await?, value, "{value} written by the state reads back over the wire");
+        state.write_holding_register(map.index_hreg, 0);
+        write_i16(&mut ctx, map.index_hreg, value).await?;
+        assert_eq!(state.read_i16(map.index_hreg), value, "{value} written over the wire reads back from the state");
+    }
+    Ok(())
+}
+
+/// 32-bit values in both word orders, written on one side and read on the other.
+#[tokio::test]
+async fn u32_registers() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let served = serve(&state).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    for (word_order, words) in [(WordOrder::BigEndian, vec![0x1234, 0x5678]), (WordOrder::LittleEndian, vec![0x5678, 0x1234])] {
+        state.write_u32(map.index_hreg, WIDE_VALUE, word_order);
+        assert_eq!(state.read_holding_registers(map.index_hreg, 2), words, "{word_order:?} register layout");
+        assert_eq!(read_u32(&mut ctx, map.index_hreg, word_order).await?, WIDE_VALUE,
+            "{word_order:?} value written by the state reads back over the wire");
+        state.write_holding_registers(map.index_hreg, &[0, 0]);
+        write_u32(&mut ctx, map.index_hreg, WIDE_VALUE, word_order).await?;
+        assert_eq!(state.read_holding_registers(map.index_hreg, 2), words, "{word_order:?} register layout written over the wire");
+        assert_eq!(state.read_u32(map.index_hreg, word_order), WIDE_VALUE,
+            "{word_order:?} value written over the wire reads back from the state");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn float_setpoints() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let served = serve(&state).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    for word_order in [WordOrder::BigEndian, WordOrder::LittleEndian] {
+        for value in SETPOINTS {
+            state.write_f32_setpoint(map.index_hreg, value, word_order, NonFinitePolicy::Reject)?;
+            let words = ctx.read_holding_registers(map.index_hreg, 2).await??;
+            assert_eq!(word_order.join([words[0], words[1]]), value.to_bits(),
+                "Float setpoint {value:?} on the wire ({word_order:?})");
+            assert_eq!(state.read_f32_setpoint(map.index_hreg, word_order, NonFinitePolicy::Reject)?, value,
+                "Float setpoint {value:?} read back ({word_order:?})");
+        }
+    }
+    assert!(state.write_f32_setpoint(map.index_hreg, f32::NAN, WordOrder::BigEndian, NonFinitePolicy::Reject).is_err(),
+        "NaN setpoint is rejected");
+    state.write_f32_setpoint(map.index_hreg, f32::INFINITY, WordOrder::BigEndian, NonFinitePolicy::PassThrough)?;
+    assert_eq!(state.read_f32_setpoint(map.index_hreg, WordOrder::BigEndian, NonFinitePolicy::PassThrough)?, f32::INFINITY,
+        "Infinite setpoint passes through");
+    Ok(())
+}
+
+/// The handshake signals as the exception status byte, and the same byte as discrete inputs.
+#[tokio::test]
+async fn status_byte() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let served = serve(&state).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+
+    state.write_coils(map.enable_coil, &[true, false, true]);
+    let response = ctx.call(Request::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, Cow::Borrowed(&[]))).await??;
+    assert_eq!(response, Response::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, vec![0b0101].into()), "ReadExceptionStatus idle");
+    state.write_coil(map.running_coil, true);
+    let response = ctx.call(Request::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, Cow::Borrowed(&[]))).await??;
+    assert_eq!(response, Response::Custom(READ_EXCEPTION_STATUS_FUNCTION_CODE, vec![0b0111].into()),
+        "ReadExceptionStatus running");
+
+    state.write_coils(map.enable_coil, &[false, true, false]);
+    state.write_holding_register(map.fault_hreg, FAULT_WATCHDOG);
+    assert_eq!(ctx.read_discrete_inputs(0, 4).await??, vec![false, true, false, true], "Status block of discrete inputs");
+    assert_eq!(read_status_block(&mut ctx, &ExceptionStatusBits::DEFAULT, 4).await?,
+        StatusBlock { enable: false, running: true, ready: false, fault: true }, "Status block decodes to its signals");
+    assert!(read_status_block(&mut ctx, &ExceptionStatusBits::DEFAULT, 3).await.is_err(),
+        "Status block too short for the fault bit");
+    Ok(())
+}
+
+/// Not over the wire, the client mistakes exceptions to custom function codes for a mismatch.
+#[test]
+fn missing_device_identification_object() {
+    let identification = DeviceIdentification::default();
+    assert_eq!(identification.respond(&[READ_DEVICE_ID_MEI_TYPE, 0x04, 0x03], "[test]").map(|_| ()),
+        Err(ExceptionCode::IllegalDataAddress), "Missing device identification object is rejected");
+}
+
+/// How each swap mode stores a payload written over the wire, and that reads undo it.
+#[tokio::test]
+async fn swap_modes() -> anyhow::Result<()> {
+    for (swap_mode, stored) in [
+        (SwapMode::None, [0x1234, 0x5678]),
+        (SwapMode::Byte, [0x3412, 0x7856]),
+        (SwapMode::Word, [0x5678, 0x1234]),
+        (SwapMode::ByteAndWord, [0x7856, 0x3412]),
+    ] {
+        let state = SharedModbusState::new().with_seed_ranges(vec![SeedRange::new(AddressSpace::InputRegister, 0..=1, 0)]);
+        let map = state.register_map();
+        let service_state = state.clone();
+        let served = serve_with(None, Arc::new(ConnectionTracker::new(None)), move |peer|
+            ExampleService::with_shared_state(service_state.clone(), peer).with_swap_mode(swap_mode)).await?;
+        let mut ctx = client::tcp::connect(served.addr).await?;
+
+        ctx.write_multiple_registers(map.index_hreg, &PAYLOAD).await??;
+        assert_eq!(state.read_holding_registers(map.index_hreg, 2), stored.to_vec(),
+            "WriteMultipleRegisters stores the payload ({swap_mode:?})");
+        assert_eq!(ctx.read_holding_registers(map.index_hreg, 2).await??, PAYLOAD.to_vec(),
+            "ReadHoldingRegisters gives the payload back ({swap_mode:?})");
+        for (addr, value) in (0..).zip(stored) {
+            state.write_input_register(addr, value);
+        }
+        assert_eq!(ctx.read_input_registers(0, 2).await??, PAYLOAD.to_vec(),
+            "ReadInputRegisters gives the payload back ({swap_mode:?})");
+
+        // A lone register has no pair to swap words with
+        ctx.write_single_register(map.index_hreg, PAYLOAD[0]).await??;
+        let swapped_bytes = matches!(swap_mode, SwapMode::Byte | SwapMode::ByteAndWord);
+        assert_eq!(state.read_holding_registers(map.index_hreg, 1)[0], if swapped_bytes { 0x3412 } else { 0x1234 },
+            "WriteSingleRegister stores the register ({swap_mode:?})");
+        assert_eq!(ctx.read_holding_registers(map.index_hreg, 1).await??, vec![PAYLOAD[0]],
+            "ReadHoldingRegisters gives the register back ({swap_mode:?})");
+    }
+    Ok(())
+}
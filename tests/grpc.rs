@@ -0,0 +1,128 @@
+//! The `--grpc` control interface through its Rust client: reads and writes, read-only coils and
+//! empty writes, test cases and the change stream.
+
+mod common;
+
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::time::Duration;
+use tonic::Code;
+use rtu_sim::arm_sim::ArmSimConfig;
+use rtu_sim::grpc::{serve_grpc, ChangeEvent, ChangeSpace, ControlClient, ControlServer, ReadRequest, RunTestCaseRequest,
+    WatchChangesRequest, WriteCoilsRequest, WriteRegistersRequest};
+use rtu_sim::mb_stuff::{ReadOnlyPolicy, SharedModbusState};
+use rtu_sim::test_cases::TestConfig;
+use common::{start_arm, Background};
+
+/// How long the server gets to start listening, and a watched change to arrive.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(1);
+const MOTION_DURATION: Duration = Duration::from_millis(50);
+
+/// Serves `state` over gRPC on a loopback port and connects a client to it.
+async fn connect(state: &SharedModbusState) -> anyhow::Result<(Background<anyhow::Result<()>>, ControlClient)> {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?.port()));
+    let server = Background::spawn(serve_grpc(addr, ControlServer::new(state.clone(), TestConfig::default())));
+    let client = tokio::time::timeout(STARTUP_TIMEOUT, async {
+        loop {
+            match ControlClient::connect(addr).await {
+                Ok(client) => return client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }).await?;
+    Ok((server, client))
+}
+
+/// Writes coils and registers through the client and reads them back both ways.
+#[tokio::test]
+async fn read_write() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let (_server, mut client) = connect(&state).await?;
+
+    client.write_coils(WriteCoilsRequest { addr: map.enable_coil.into(), values: vec![true] }).await?;
+    client.write_holding_registers(WriteRegistersRequest { addr: map.index_hreg.into(), values: vec![7] }).await?;
+    assert_eq!((state.read_coil(map.enable_coil), state.read_index()), (true, 7), "Writes reach the state");
+    let coils = client.read_coils(ReadRequest { addr: map.enable_coil.into(), count: 2 }).await?.into_inner();
+    assert_eq!(coils.values, vec![true, false], "Coils read back");
+    state.write_holding_register(map.fault_hreg, 3);
+    let registers = client.read_holding_registers(ReadRequest { addr: map.index_hreg.into(), count: 1 }).await?.into_inner();
+    assert_eq!(registers.values, vec![7], "Holding register reads back");
+    let fault = client.read_holding_registers(ReadRequest { addr: map.fault_hreg.into(), count: 1 }).await?.into_inner();
+    assert_eq!(fault.values, vec![3], "State writes are read through the client");
+
+    let too_far = client.read_coils(ReadRequest { addr: 0xFFFF, count: 2 }).await;
+    assert_eq!(too_far.err().map(|status| status.code()), Some(Code::OutOfRange), "Read past 0xFFFF is out of range");
+    let too_big = client.write_holding_registers(WriteRegistersRequest { addr: map.index_hreg.into(), values: vec![0x1_0000] }).await;
+    assert_eq!(too_big.err().map(|status| status.code()), Some(Code::InvalidArgument), "Register value over 65535 is invalid");
+    assert_eq!(state.read_index(), 7, "Refused write changes nothing");
+    Ok(())
+}
+
+/// Refuses a write touching a read-only coil under the reject policy, writes only the others
+/// under the ignore policy, and refuses empty writes.
+#[tokio::test]
+async fn read_only_and_empty_writes() -> anyhow::Result<()> {
+    for policy in [ReadOnlyPolicy::Reject, ReadOnlyPolicy::Ignore] {
+        let state = SharedModbusState::new();
+        let map = state.register_map();
+        let state = state.with_read_only_coils(HashSet::from([map.running_coil]), policy);
+        let (_server, mut client) = connect(&state).await?;
+
+        let write = client.write_coils(WriteCoilsRequest { addr: map.enable_coil.into(), values: vec![true, true] }).await;
+        let written = (state.read_coil(map.enable_coil), state.read_coil(map.running_coil));
+        match policy {
+            ReadOnlyPolicy::Reject => {
+                assert_eq!(write.err().map(|status| status.code()), Some(Code::PermissionDenied), "Read-only coil is refused");
+                assert_eq!(written, (false, false), "Refused write changes nothing");
+            }
+            ReadOnlyPolicy::Ignore => {
+                assert!(write.is_ok(), "Read-only coil is skipped: {write:?}");
+                assert_eq!(written, (true, false), "Only the writable coil is written");
+            }
+        }
+
+        let no_coils = client.write_coils(WriteCoilsRequest { addr: map.enable_coil.into(), values: vec![] }).await;
+        assert_eq!(no_coils.err().map(|status| status.code()), Some(Code::InvalidArgument), "Empty coil write is invalid");
+        let no_registers = client.write_holding_registers(WriteRegistersRequest { addr: map.index_hreg.into(), values: vec![] }).await;
+        assert_eq!(no_registers.err().map(|status| status.code()), Some(Code::InvalidArgument), "Empty register write is invalid");
+    }
+    Ok(())
+}
+
+/// Runs a sub routine on the simulated arm, and refuses a test case that doesn't parse.
+#[tokio::test]
+async fn run_test_case() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let _arm = start_arm(&state, ArmSimConfig { motion_duration: MOTION_DURATION, ..ArmSimConfig::default() }).await;
+    let (_server, mut client) = connect(&state).await?;
+
+    let reply = client.run_test_case(RunTestCaseRequest { test_case: r#"{"SrSingle":3}"#.to_string() }).await?.into_inner();
+    assert_eq!((reply.passed, state.read_index()), (true, 3), "Sub routine test case passes");
+    let unknown = client.run_test_case(RunTestCaseRequest { test_case: r#"{"Dance":3}"#.to_string() }).await;
+    assert_eq!(unknown.err().map(|status| status.code()), Some(Code::InvalidArgument), "Unknown test case is invalid");
+    Ok(())
+}
+
+/// Streams changes made after the call, whoever made them, and not writes that change nothing.
+#[tokio::test]
+async fn watch_changes() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let (_server, mut client) = connect(&state).await?;
+    state.write_coil(map.enable_coil, true);
+
+    let mut changes = client.watch_changes(WatchChangesRequest {}).await?.into_inner();
+    state.write_coil(map.enable_coil, true);
+    state.write_holding_register(map.index_hreg, 4);
+    client.write_coils(WriteCoilsRequest { addr: map.enable_coil.into(), values: vec![false] }).await?;
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        received.push(tokio::time::timeout(STARTUP_TIMEOUT, changes.message()).await??);
+    }
+    assert_eq!(received, vec![
+        Some(ChangeEvent { space: ChangeSpace::HoldingRegister.into(), addr: map.index_hreg.into(), old: 0, new: 4 }),
+        Some(ChangeEvent { space: ChangeSpace::Coil.into(), addr: map.enable_coil.into(), old: 1, new: 0 }),
+    ], "Changes arrive in order");
+    Ok(())
+}
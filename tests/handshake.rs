@@ -0,0 +1,398 @@
+//! The enable/running handshake against the simulated arm: completing, slow to assert running,
+//! faulted, mislatched, jammed, paused, busy, edge triggered and held, with a coarse tick and with jitter, polled fast and slow,
+//! debounced over stable reads, the idle check, the test budget, several units run in parallel and the watchdog.
+
+mod common;
+
+use std::time::Duration;
+use tokio::time::error::Elapsed;
+use tokio::time::Instant;
+use rtu_sim::arm_sim::{ArmSimConfig, EnableMode, InitialFault, JitterDistribution, MotionJitter};
+use rtu_sim::mb_stuff::SharedModbusState;
+use rtu_sim::test_cases::{assert_idle, fault_recovery_shared, read_fault, run_test_case_shared, run_within_budget, soak_shared,
+    sr_up_to_parallel_shared, start_while_busy_shared, wait_for_running_shared, MotionHangError, RunOutcome, RunningNeverAssertedError,
+    SubroutineRun, TestCases, TestConfig, RUNNING_START_TIMEOUT};
+use rtu_sim::watchdog::{run_heartbeat, run_watchdog, WatchdogConfig};
+use rtu_sim::{FAULT_BUSY, FAULT_WATCHDOG};
+use common::{start_arm, Background, TIMING_SLACK};
+
+const INDEX_ECHO_REGISTER: u16 = 1;
+const MOTION_DURATION: Duration = Duration::from_millis(50);
+/// Either side of [`RUNNING_START_TIMEOUT`], a slow controller that makes it and one that doesn't.
+/// Units run side by side, one sub routine each.
+const PARALLEL_UNITS: u8 = 4;
+const SLOW_ASSERT: Duration = Duration::from_millis(900);
+const TOO_SLOW_ASSERT: Duration = Duration::from_millis(1100);
+/// Far short of the default motion timeout, so the budget is what stops a jammed arm.
+const TEST_BUDGET: Duration = Duration::from_secs(2);
+const WATCHDOG: WatchdogConfig = WatchdogConfig { coil: 1001, timeout: Duration::from_millis(100) };
+const FAULT: InitialFault = InitialFault { code: 42, reset_coil: 1002 };
+/// Long enough to be re-commanded mid-motion and checked on before it ends.
+const BUSY_MOTION: Duration = Duration::from_millis(300);
+/// A motion shorter than the coarse tick still has to show up as running.
+const SHORT_MOTION: Duration = Duration::from_millis(2);
+const COARSE_TICK: Duration = Duration::from_millis(10);
+const JITTER: MotionJitter = MotionJitter {
+    distribution: JitterDistribution::Uniform,
+    magnitude: Duration::from_millis(10),
+    seed: 7,
+};
+const JITTER_SAMPLES: u32 = 1000;
+const JITTERED_RUNS: u32 = 5;
+/// When the running blip rises and how long it stays up, both off any poll boundary.
+const BLIP_START: Duration = Duration::from_millis(23);
+const BLIP_LENGTH: Duration = Duration::from_millis(60);
+const FAST_POLL: Duration = Duration::from_millis(1);
+const SLOW_POLL: Duration = Duration::from_millis(50);
+/// The running flicker changes halfway between these polls.
+const FLICKER_POLL: Duration = Duration::from_millis(10);
+
+/// An arm running [`MOTION_DURATION`] motions, with everything else at its defaults.
+fn arm_config() -> ArmSimConfig {
+    ArmSimConfig { motion_duration: MOTION_DURATION, ..ArmSimConfig::default() }
+}
+
+async fn wait_running(state: &SharedModbusState, running: bool, timeout: Duration) -> Result<(), Elapsed> {
+    wait_for_running_shared(state, running, timeout, Duration::from_millis(1), 1).await
+}
+
+#[tokio::test(start_paused = true)]
+async fn fault_recovery() -> anyhow::Result<()> {
+    let state = SharedModbusState::new().with_initial_fault(Some(FAULT));
+    let _arm = start_arm(&state, arm_config()).await;
+    fault_recovery_shared(&state, &TestConfig::default(), 4).await?;
+    assert_eq!(read_fault(&state), 0, "Motion is refused until the fault is reset");
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn completes() {
+    let state = SharedModbusState::new();
+    let _arm = start_arm(&state, arm_config()).await;
+    let outcome = SubroutineRun::new(5)
+        .ready_timeout(Some(Duration::from_secs(1)))
+        .running_timeout(Duration::from_millis(500))
+        .poll_interval(Duration::from_millis(2))
+        .stable_reads(2)
+        .execute(&state).await;
+    assert!(outcome.is_success(), "SubroutineRun completes");
+}
+
+/// Runs a sub routine with the default timeouts on arms that take a while to assert running.
+#[tokio::test(start_paused = true)]
+async fn assert_delay() {
+    for (delay, completes) in [(SLOW_ASSERT, true), (TOO_SLOW_ASSERT, false)] {
+        let state = SharedModbusState::new();
+        let _arm = start_arm(&state, ArmSimConfig { running_assert_delay: delay, ..arm_config() }).await;
+        let outcome = SubroutineRun::new(5).execute(&state).await;
+        match outcome {
+            RunOutcome::Completed { start_latency, .. } => {
+                assert!(completes, "Sub routine completes with running asserted after {delay:?}");
+                assert!(start_latency >= delay, "Running asserted after {start_latency:?}, before the {delay:?} delay");
+            }
+            RunOutcome::NeverStarted { waited } => {
+                assert_eq!((completes, waited), (false, RUNNING_START_TIMEOUT), "Running asserted after {delay:?} times out");
+            }
+            outcome => panic!("Running asserted after {delay:?}: unexpected outcome {outcome:?}"),
+        }
+    }
+}
+
+/// Runs one sub routine on each of four units at once, with the third unit's arm never
+/// answering, and gets every result back in index order.
+#[tokio::test(start_paused = true)]
+async fn parallel_units() {
+    let units: Vec<SharedModbusState> = (1..=PARALLEL_UNITS).map(|unit| SharedModbusState::new().with_unit_id(Some(unit))).collect();
+    let config = TestConfig { parallel_runs: units.len(), ..TestConfig::default() };
+    let mut arms = Vec::new();
+    for unit in &units {
+        arms.push(start_arm(unit, arm_config()).await);
+    }
+    let started = Instant::now();
+    let results = sr_up_to_parallel_shared(&units, &config, u16::from(PARALLEL_UNITS) - 1).await;
+    let elapsed = started.elapsed();
+    assert_eq!(results.iter().map(|(idx, result)| (*idx, result.is_ok())).collect::<Vec<_>>(),
+        vec![(0, true), (1, true), (2, true), (3, true)], "Every unit runs one sub routine, reported in index order");
+    assert_eq!(units.iter().map(|unit| unit.read_index()).collect::<Vec<_>>(), vec![0, 1, 2, 3],
+        "Each unit ran its own sub routine");
+    let sequential_elapsed = {
+        let state = SharedModbusState::new();
+        let _arm = start_arm(&state, arm_config()).await;
+        let started = Instant::now();
+        sr_up_to_parallel_shared(std::slice::from_ref(&state), &config, u16::from(PARALLEL_UNITS) - 1).await;
+        started.elapsed()
+    };
+    assert!(elapsed * 2 < sequential_elapsed, "{PARALLEL_UNITS} units took {elapsed:?}, one unit took {sequential_elapsed:?}");
+
+    drop(arms.remove(2));
+    units.iter().for_each(SharedModbusState::reset);
+    let results = sr_up_to_parallel_shared(&units, &config, u16::from(PARALLEL_UNITS) - 1).await;
+    assert_eq!(results.iter().map(|(idx, result)| (*idx, result.is_ok())).collect::<Vec<_>>(),
+        vec![(0, true), (1, true), (2, false), (3, true)], "Only the unit without an arm fails, reported in index order");
+    assert!(!units[2].read_coil(units[2].register_map().enable_coil), "The failed unit's enable is dropped");
+}
+
+#[tokio::test(start_paused = true)]
+async fn soak() {
+    let state = SharedModbusState::new();
+    let _arm = start_arm(&state, arm_config()).await;
+    let soak = soak_shared(&state, &TestConfig::default(), 3, 5).await;
+    assert_eq!((soak.iterations, soak.passed), (5, 5), "Soak passes every iteration");
+    assert!(soak.min_cycle <= soak.average_cycle() && soak.average_cycle() <= soak.max_cycle, "Soak cycle times are ordered");
+}
+
+#[tokio::test(start_paused = true)]
+async fn wrong_index_latched() {
+    let state = SharedModbusState::new().with_index_echo(Some(INDEX_ECHO_REGISTER));
+    let _arm = start_arm(&state, arm_config()).await;
+    state.mislatch_next_index();
+    let outcome = SubroutineRun::new(7).execute(&state).await;
+    assert_eq!(outcome, RunOutcome::WrongIndexLatched { latched: 8 }, "SubroutineRun detects a wrong index echo");
+}
+
+#[tokio::test(start_paused = true)]
+async fn jam() {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let _arm = start_arm(&state, arm_config()).await;
+    state.jam_next_motion();
+    let outcome = SubroutineRun::new(6)
+        .motion_timeout(MOTION_DURATION * 4)
+        .verify_idle(false)
+        .execute(&state).await;
+    assert_eq!(outcome, RunOutcome::MotionTimedOut { waited: MOTION_DURATION * 4 }, "SubroutineRun detects a jam");
+    let hang = outcome.into_result(6, &map).err().and_then(|err| err.downcast::<MotionHangError>().ok());
+    assert_eq!(hang.map(|hang| hang.elapsed), Some(MOTION_DURATION * 4), "A jam is reported as a motion hang");
+}
+
+/// A jammed arm keeps the test case running until its budget aborts it and drops enable.
+#[tokio::test(start_paused = true)]
+async fn test_budget() {
+    let state = SharedModbusState::new();
+    let _arm = start_arm(&state, arm_config()).await;
+    state.jam_next_motion();
+    let config = TestConfig { test_budget: Some(TEST_BUDGET), ..TestConfig::default() };
+    let mut sweep_csv = None;
+    let started = Instant::now();
+    let run = run_test_case_shared(&state, &[], &config, &TestCases::SrSingle(6), &mut sweep_csv);
+    let passed = run_within_budget(&state, &config, run).await;
+    let elapsed = started.elapsed();
+    assert!(!passed, "Jammed test case fails");
+    assert!(elapsed >= TEST_BUDGET && elapsed <= TEST_BUDGET + TIMING_SLACK, "Jammed test case aborted after {elapsed:?}");
+    assert!(!state.read_coil(state.register_map().enable_coil), "Aborted test case drops enable");
+}
+
+/// A faulted arm refuses the command, so running never rises.
+#[tokio::test(start_paused = true)]
+async fn refused_start() {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let _arm = start_arm(&state, arm_config()).await;
+    state.write_holding_register(map.fault_hreg, FAULT.code);
+    let outcome = SubroutineRun::new(6)
+        .running_timeout(MOTION_DURATION)
+        .execute(&state).await;
+    let never_started = outcome.into_result(6, &map).err().and_then(|err| err.downcast::<RunningNeverAssertedError>().ok());
+    assert_eq!(never_started.map(|err| err.waited), Some(MOTION_DURATION),
+        "A refused start is reported as running never asserted");
+}
+
+#[tokio::test(start_paused = true)]
+async fn pause_resume() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let _arm = start_arm(&state, arm_config()).await;
+    state.write_coil(map.enable_coil, true);
+    wait_running(&state, true, Duration::from_secs(1)).await?;
+    state.pause_motion();
+    tokio::time::sleep(MOTION_DURATION * 3).await;
+    assert!(state.read_coil(map.running_coil), "Paused motion doesn't complete");
+    state.resume_motion();
+    let finished = wait_running(&state, false, MOTION_DURATION + TIMING_SLACK).await;
+    assert!(finished.is_ok(), "Resumed motion finishes");
+    Ok(())
+}
+
+/// Re-commanded mid-motion, the arm answers busy, finishes the first motion and never runs the second.
+#[tokio::test(start_paused = true)]
+async fn busy() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let _arm = start_arm(&state, ArmSimConfig { motion_duration: BUSY_MOTION, ..ArmSimConfig::default() }).await;
+    state.write_index_and_enable(3)?;
+    wait_running(&state, true, Duration::from_secs(1)).await?;
+    state.write_coil(map.enable_coil, false);
+    state.write_index_and_enable(4)?;
+    tokio::time::sleep(BUSY_MOTION / 4).await;
+    assert_eq!((read_fault(&state), state.read_coil(map.running_coil)), (FAULT_BUSY, true),
+        "Command mid-motion is rejected as busy");
+    wait_running(&state, false, BUSY_MOTION).await?;
+    state.write_coil(map.enable_coil, false);
+    tokio::time::sleep(BUSY_MOTION / 4).await;
+    assert!(!state.read_coil(map.running_coil), "Rejected command never runs");
+
+    let outcome = start_while_busy_shared(&state, &TestConfig::default(), 5).await;
+    assert_eq!(outcome.map_err(|err| err.to_string()), Ok(()), "StartWhileBusy passes against the simulated arm");
+    Ok(())
+}
+
+/// Enable left high after the motion doesn't start another.
+#[tokio::test(start_paused = true)]
+async fn edge_triggered() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let _arm = start_arm(&state, arm_config()).await;
+    state.write_coil(state.register_map().enable_coil, true);
+    wait_running(&state, true, Duration::from_secs(1)).await?;
+    wait_running(&state, false, MOTION_DURATION * 2).await?;
+    let restarted = wait_running(&state, true, MOTION_DURATION).await;
+    assert!(restarted.is_err(), "Edge triggered arm runs once per edge");
+    Ok(())
+}
+
+/// Held, enable left high starts the motion over until it drops.
+#[tokio::test(start_paused = true)]
+async fn held() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let _arm = start_arm(&state, ArmSimConfig { enable_mode: EnableMode::Held, ..arm_config() }).await;
+    state.write_coil(map.enable_coil, true);
+    wait_running(&state, true, Duration::from_secs(1)).await?;
+    wait_running(&state, false, MOTION_DURATION * 2).await?;
+    let restarted = wait_running(&state, true, ArmSimConfig::HELD_RESTART_GAP + TIMING_SLACK).await;
+    assert!(restarted.is_ok(), "Held enable restarts the motion");
+    state.write_coil(map.enable_coil, false);
+    wait_running(&state, false, Duration::from_secs(1)).await?;
+    let outcome = SubroutineRun::new(2)
+        .enable_mode(EnableMode::Held)
+        .execute(&state).await;
+    assert!(outcome.is_success(), "SubroutineRun completes against a held enable arm");
+    Ok(())
+}
+
+/// A coarse tick stretches motions, but by less than a tick.
+#[tokio::test(start_paused = true)]
+async fn coarse_tick() {
+    let state = SharedModbusState::new();
+    let _arm = start_arm(&state, ArmSimConfig { motion_duration: SHORT_MOTION, tick: COARSE_TICK, ..ArmSimConfig::default() }).await;
+    match SubroutineRun::new(9).execute(&state).await {
+        RunOutcome::Completed { motion, .. } => assert!(
+            motion >= SHORT_MOTION && motion <= SHORT_MOTION + COARSE_TICK + TIMING_SLACK,
+            "Coarse tick: a {SHORT_MOTION:?} motion took {motion:?} with a {COARSE_TICK:?} tick"),
+        outcome => panic!("Coarse tick: expected the motion to complete, got {outcome:?}"),
+    }
+}
+
+/// Jitter stays within its magnitude around the motion duration, and averages out to it.
+#[test]
+fn jitter_distribution() {
+    let lowest = MOTION_DURATION - JITTER.magnitude;
+    let highest = MOTION_DURATION + JITTER.magnitude;
+    for distribution in [JitterDistribution::Uniform, JitterDistribution::Gaussian] {
+        let jitter = MotionJitter { distribution, ..JITTER };
+        let mut rng = jitter.rng();
+        let samples: Vec<Duration> = (0..JITTER_SAMPLES)
+            .map(|_| jitter.sample(MOTION_DURATION, &mut rng))
+            .collect();
+        let mean = samples.iter().sum::<Duration>() / JITTER_SAMPLES;
+        assert!(samples.iter().all(|sample| (lowest..=highest).contains(sample)), "{distribution:?} jitter stays within bounds");
+        assert!(mean.abs_diff(MOTION_DURATION) < JITTER.magnitude / 5, "{distribution:?} jitter averages to the motion duration");
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn jittered_runs() {
+    let lowest = MOTION_DURATION - JITTER.magnitude;
+    let highest = MOTION_DURATION + JITTER.magnitude;
+    let state = SharedModbusState::new();
+    let _arm = start_arm(&state, ArmSimConfig { jitter: Some(JITTER), ..arm_config() }).await;
+    for idx in 0..JITTERED_RUNS {
+        let outcome = SubroutineRun::new(idx)
+            .poll_interval(Duration::from_millis(1))
+            .stable_reads(1)
+            .execute(&state).await;
+        match outcome {
+            // Seen from the master, so off by up to a poll either way
+            RunOutcome::Completed { motion, .. } => assert!(
+                motion + TIMING_SLACK >= lowest && motion <= highest + TIMING_SLACK,
+                "Jittered motion took {motion:?}, expected {lowest:?} to {highest:?}"),
+            outcome => panic!("Jitter: expected the motion to complete, got {outcome:?}"),
+        }
+    }
+}
+
+/// The same running blip, seen by a fast and a slow poll: the fast one within a poll of it rising.
+#[tokio::test(start_paused = true)]
+async fn poll_interval() -> anyhow::Result<()> {
+    let mut detected = Vec::new();
+    for poll_interval in [FAST_POLL, SLOW_POLL] {
+        let state = SharedModbusState::new();
+        let running_coil = state.register_map().running_coil;
+        let blip_state = state.clone();
+        let _blip = Background::spawn(async move {
+            tokio::time::sleep(BLIP_START).await;
+            blip_state.write_coil(running_coil, true);
+            tokio::time::sleep(BLIP_LENGTH).await;
+            blip_state.write_coil(running_coil, false);
+        });
+        let started = Instant::now();
+        wait_for_running_shared(&state, true, Duration::from_secs(1), poll_interval, 1).await?;
+        detected.push(started.elapsed());
+    }
+    assert!(detected[0] <= BLIP_START + FAST_POLL, "Fast poll sees the blip within a poll");
+    assert_eq!(detected[1], SLOW_POLL, "Slow poll sees the blip on its next poll");
+    assert!(detected[0] < detected[1], "Fast poll sees the blip before the slow one");
+    Ok(())
+}
+
+/// Running flickers true for one poll before it stays: one stable read takes the flicker, three
+/// wait for three reads in a row after it.
+#[tokio::test(start_paused = true)]
+async fn stable_reads() -> anyhow::Result<()> {
+    for (stable_reads, accepted_after) in [(1, FLICKER_POLL), (3, FLICKER_POLL * 5)] {
+        let state = SharedModbusState::new();
+        let running_coil = state.register_map().running_coil;
+        let flicker_state = state.clone();
+        let _flicker = Background::spawn(async move {
+            tokio::time::sleep(FLICKER_POLL / 2).await;
+            for running in [true, false, true] {
+                flicker_state.write_coil(running_coil, running);
+                tokio::time::sleep(FLICKER_POLL).await;
+            }
+        });
+        let started = Instant::now();
+        wait_for_running_shared(&state, true, Duration::from_secs(1), FLICKER_POLL, stable_reads).await?;
+        assert_eq!(started.elapsed(), accepted_after, "{stable_reads} stable reads accept running after {accepted_after:?}");
+    }
+    Ok(())
+}
+
+/// A lingering enable, and anything else not at rest, fails the idle check by name.
+#[test]
+fn idle_check() {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    assert_eq!(assert_idle(&state).map_err(|err| err.to_string()), Ok(()), "Fresh state is idle");
+    state.write_coil(map.enable_coil, true);
+    assert_eq!(assert_idle(&state).map_err(|err| err.to_string()), Err("Arm is not idle: enable is still set".to_string()),
+        "Lingering enable fails the idle check");
+    state.write_coil(map.running_coil, true);
+    state.write_holding_register(map.fault_hreg, FAULT.code);
+    assert_eq!(assert_idle(&state).map_err(|err| err.to_string()),
+        Err(format!("Arm is not idle: enable is still set, running is still set, fault code is {}", FAULT.code)),
+        "Every leftover is listed");
+}
+
+#[tokio::test(start_paused = true)]
+async fn watchdog() {
+    let state = SharedModbusState::new().with_watchdog(Some(WATCHDOG));
+    let map = state.register_map();
+    let _watchdog = Background::spawn(run_watchdog(state.clone(), WATCHDOG));
+    let _heartbeat = Background::spawn(run_heartbeat(state.clone(), WATCHDOG));
+    state.write_coil(map.enable_coil, true);
+    tokio::time::sleep(WATCHDOG.timeout * 3).await;
+    assert_eq!((state.read_coil(map.enable_coil), read_fault(&state)), (true, 0), "Heartbeat keeps the arm enabled");
+    state.pause_heartbeat(true);
+    tokio::time::sleep(WATCHDOG.timeout * 3).await;
+    assert_eq!((state.read_coil(map.enable_coil), read_fault(&state)), (false, FAULT_WATCHDOG),
+        "Missing heartbeat trips the watchdog");
+}
@@ -0,0 +1,62 @@
+//! `rtu-sim --headless` as a fixture for another harness would run it: the built binary serving
+//! on loopback with nothing on stdin, and a client reading and writing through it.
+
+mod common;
+
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_modbus::client::{self, Reader, Writer};
+use rtu_sim::register_map::RegisterMap;
+
+/// How long the binary gets to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_RETRY: Duration = Duration::from_millis(20);
+
+/// Kills the server when the test ends, passed or not.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A loopback port nothing listens on, for the binary to bind.
+fn free_port() -> anyhow::Result<u16> {
+    Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?.port())
+}
+
+#[tokio::test]
+async fn headless_serves() -> anyhow::Result<()> {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, free_port()?));
+    let mut server = Server(Command::new(env!("CARGO_BIN_EXE_rtu-sim"))
+        .args(["--headless", "--bind", "127.0.0.1", "--port", &addr.port().to_string(), "--log-level", "warn"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?);
+
+    let started = Instant::now();
+    let mut ctx = loop {
+        match client::tcp::connect(addr).await {
+            Ok(ctx) => break ctx,
+            Err(err) if started.elapsed() > STARTUP_TIMEOUT => anyhow::bail!("Headless server never listened on {addr}: {err}"),
+            Err(_) => {
+                if let Some(status) = server.0.try_wait()? {
+                    anyhow::bail!("Headless server exited with {status} before listening");
+                }
+                tokio::time::sleep(CONNECT_RETRY).await;
+            }
+        }
+    };
+
+    let map = RegisterMap::DEFAULT;
+    assert_eq!(ctx.read_coils(map.enable_coil, 3).await??, vec![false; 3], "Headless server starts idle");
+    ctx.write_single_register(map.index_hreg, 42).await??;
+    assert_eq!(ctx.read_holding_registers(map.index_hreg, 1).await??, vec![42], "Headless server keeps a write");
+    assert!(server.0.try_wait()?.is_none(), "Headless server is still running");
+    Ok(())
+}
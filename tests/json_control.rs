@@ -0,0 +1,38 @@
+//! `--json` mode: newline-delimited commands in, one reply line out for each.
+
+mod common;
+
+use rtu_sim::json_control::run_json_control;
+use rtu_sim::mb_stuff::SharedModbusState;
+
+/// Sets enable and the index, reads them back, sends a line that isn't a command, and resets.
+#[test]
+fn json_commands() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let input = format!(concat!(
+        "{{\"op\":\"write_coil\",\"addr\":{enable},\"value\":true}}\n",
+        "{{\"op\":\"write_holding_register\",\"addr\":{index},\"value\":7}}\n",
+        "\n",
+        "{{\"op\":\"read_coils\",\"addr\":{enable},\"count\":2}}\n",
+        "{{\"op\":\"read_holding_registers\",\"addr\":{index},\"count\":1}}\n",
+        "{{\"op\":\"spin\"}}\n",
+        "{{\"op\":\"reset\"}}\n",
+    ), enable = map.enable_coil, index = map.index_hreg);
+    let mut output = Vec::new();
+    run_json_control(&state, input.as_bytes(), &mut output)?;
+    let replies: Vec<String> = String::from_utf8(output)?.lines().map(str::to_string).collect();
+
+    assert_eq!(replies.len(), 6, "One reply per command, blank lines skipped");
+    assert_eq!(replies[..4].to_vec(), vec![
+        r#"{"result":"done"}"#.to_string(),
+        r#"{"result":"done"}"#.to_string(),
+        r#"{"result":"coils","values":[true,false]}"#.to_string(),
+        r#"{"result":"registers","values":[7]}"#.to_string(),
+    ], "Replies to the commands");
+    assert!(replies[4].starts_with(r#"{"result":"error","message":"unknown variant `spin`"#),
+        "Unknown command gets an error reply");
+    assert_eq!(replies[5].as_str(), r#"{"result":"done"}"#, "Session goes on after an error");
+    assert_eq!((state.read_coil(map.enable_coil), state.read_index()), (false, 0), "Reset is applied to the state");
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! The Prometheus endpoint, scraped like Prometheus would after some Modbus traffic.
+
+mod common;
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_modbus::client::{self, Reader};
+use rtu_sim::connections::ConnectionTracker;
+use rtu_sim::mb_stuff::{ExampleService, SharedModbusState, MAX_READ_REGISTERS};
+use rtu_sim::metrics::Metrics;
+use rtu_sim::prometheus::serve_metrics;
+use common::{serve_with, Background};
+
+/// Sends a GET for `path` and returns the whole response.
+async fn get(addr: SocketAddr, path: &str) -> anyhow::Result<String> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(format!("GET {path} HTTP/1.0\r\n\r\n").as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn prometheus_scrape() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let metrics = Arc::new(Metrics::new());
+    let service_state = state.clone();
+    let service_metrics = metrics.clone();
+    let served = serve_with(None, Arc::new(ConnectionTracker::new(None)), move |peer|
+        ExampleService::with_shared_state(service_state.clone(), peer).with_metrics(Some(service_metrics.clone()))).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    ctx.read_holding_registers(map.index_hreg, 1).await??;
+    ctx.read_holding_registers(map.index_hreg, 1).await??;
+    ctx.read_holding_registers(map.index_hreg, MAX_READ_REGISTERS + 1).await?.ok();
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    let _endpoint = Background::spawn(serve_metrics(listener, metrics));
+    let response = get(addr, "/metrics").await?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or_default();
+    assert_eq!(head.lines().next(), Some("HTTP/1.0 200 OK"), "Scrape is answered");
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"), "Scrape is the text exposition format");
+
+    // Every sample line is `<name>{<labels>} <value>` or `<name> <value>`
+    let mut samples = Vec::new();
+    for line in body.lines().filter(|line| !line.starts_with('#')) {
+        let (series, value) = line.rsplit_once(' ').unwrap_or_default();
+        assert!(value.parse::<f64>().is_ok() && !series.is_empty(), "Sample line doesn't parse: {line:?}");
+        samples.push((series.to_string(), value.to_string()));
+    }
+    for (series, value) in [
+        ("modbus_requests_total{function=\"read_holding_registers\"}", "3"),
+        ("modbus_exceptions_total{function=\"read_holding_registers\"}", "1"),
+        ("modbus_request_duration_seconds_count{function=\"read_holding_registers\"}", "3"),
+        ("modbus_request_duration_seconds_bucket{function=\"read_holding_registers\",le=\"+Inf\"}", "3"),
+        ("modbus_requests_too_fast_total", "0"),
+        ("modbus_broadcast_failures_total", "0"),
+    ] {
+        assert_eq!(samples.iter().find(|(name, _)| name == series).map(|(_, value)| value.as_str()), Some(value),
+            "Scrape has {series}");
+    }
+    for metric in ["modbus_requests_total", "modbus_exceptions_total", "modbus_request_duration_seconds",
+        "modbus_requests_too_fast_total", "modbus_broadcast_failures_total"] {
+        assert!(body.contains(&format!("# TYPE {metric} ")), "Scrape declares the type of {metric}");
+    }
+    let not_found = get(addr, "/").await?;
+    assert_eq!(not_found.lines().next(), Some("HTTP/1.0 404 Not Found"), "Anything but /metrics is not found");
+    Ok(())
+}
@@ -0,0 +1,38 @@
+//! The simulated servo ramp: how fast actual follows a written setpoint, both ways.
+
+mod common;
+
+use rtu_sim::mb_stuff::SharedModbusState;
+use rtu_sim::ramp::{run_ramp, RampConfig};
+use common::Background;
+
+const TARGET_REGISTER: u16 = 20;
+const ACTUAL_REGISTER: u16 = 20;
+/// Setpoints off a multiple of the rate, so the last step is a short one.
+const SETPOINTS: [u16; 2] = [95, 3];
+
+/// Samples actual halfway between ramp ticks on the way up to a setpoint and back down: it moves
+/// by at most the rate per tick, never past the setpoint, and is there within the expected time.
+#[tokio::test(start_paused = true)]
+async fn run_ramp_converges() {
+    let config = RampConfig::new(TARGET_REGISTER, ACTUAL_REGISTER);
+    let state = SharedModbusState::new().with_ramp(Some(config));
+    let _ramp = Background::spawn(run_ramp(state.clone(), config));
+    tokio::time::sleep(config.tick / 2).await;
+    let mut actual = state.read_input_registers(ACTUAL_REGISTER, 1)[0];
+    for setpoint in SETPOINTS {
+        let from = actual;
+        state.write_holding_register(TARGET_REGISTER, setpoint);
+        let ticks = config.expected_duration(from, setpoint).div_duration_f64(config.tick) as u32;
+        for tick in 1..=ticks + 2 {
+            tokio::time::sleep(config.tick).await;
+            let next = state.read_input_registers(ACTUAL_REGISTER, 1)[0];
+            assert!(next.abs_diff(actual) <= config.rate, "Ramp from {from} to {setpoint} moved {actual} -> {next} in a tick");
+            assert!((from.min(setpoint)..=from.max(setpoint)).contains(&next), "Ramp from {from} to {setpoint} overshot to {next}");
+            if tick >= ticks {
+                assert_eq!(next, setpoint, "Ramp from {from} reaches {setpoint} within {ticks} ticks and stays");
+            }
+            actual = next;
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! What a session reports: durations, the banner, tallies, test history, dry run plans, the log file and tracing spans.
+
+mod common;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use dialoguer::console::Style;
+use log::{Level, LevelFilter, Log, Record};
+use tracing::field::{Field, Visit};
+use tracing::instrument::WithSubscriber;
+use tracing::span::{self, Attributes, Id};
+use tracing::{Dispatch, Event, Metadata, Subscriber};
+use rtu_sim::arm_sim::ArmSimConfig;
+use rtu_sim::banner::Banner;
+use rtu_sim::duration_format::{format_duration, Millis};
+use rtu_sim::log_file::{log_to_file, TeeLogger};
+use rtu_sim::mb_stuff::SharedModbusState;
+use rtu_sim::register_map::RegisterMap;
+use rtu_sim::test_cases::{plan_test_case, SubroutineRun, SweepSchedule, TestCases, TestConfig, DRY_RUN_SWEEP_PREVIEW};
+use rtu_sim::test_history::{SessionTally, TestHistory};
+use common::start_arm;
+
+const MOTION_DURATION: Duration = Duration::from_millis(50);
+
+/// Durations from below a microsecond to several seconds all come out in milliseconds.
+#[test]
+fn duration_format() {
+    let formatted: Vec<String> = [
+        Duration::from_nanos(400),
+        Duration::from_nanos(500),
+        Duration::from_nanos(4_100),
+        Duration::from_micros(999_999) + Duration::from_nanos(600),
+        Duration::from_millis(250),
+        Duration::from_millis(1_500),
+        Duration::from_secs(12) + Duration::from_micros(3),
+    ].into_iter().map(format_duration).collect();
+    assert_eq!(formatted.iter().map(String::as_str).collect::<Vec<_>>(), vec![
+        "0.000 ms", "0.001 ms", "0.004 ms", "1000.000 ms", "250.000 ms", "1500.000 ms", "12000.003 ms",
+    ], "Durations format in milliseconds");
+    assert_eq!(Millis(Duration::from_micros(2_500)).to_string(), "2.500".to_string(), "Milliseconds alone for CSV fields");
+}
+
+#[test]
+fn banner() {
+    let banner = Banner {
+        addr: SocketAddr::from((Ipv4Addr::new(192, 168, 1, 20), 5020)),
+        register_map: RegisterMap::ZERO_BASED,
+        map_pending: false,
+        units: None,
+    };
+    assert_eq!(banner.render().as_str(), concat!(
+        "rtu-sim v", env!("CARGO_PKG_VERSION"), " listening on 192.168.1.20:5020\n",
+        "  Address:        192.168.1.20\n",
+        "  Port:           5020\n",
+        "  Unit IDs:       any, 0 is broadcast\n",
+        "  Enable coil:    0\n",
+        "  Running coil:   1\n",
+        "  Ready coil:     2\n",
+        "  Index register: 0\n",
+        "  Fault register: 1\n"), "Banner shows the address and register map");
+    let pending = Banner { map_pending: true, ..banner }.render();
+    assert!(pending.contains("  Register map:   picked in the TUI"), "Banner flags a register map still to be picked");
+    let units = Banner { units: Some(3), ..banner }.render();
+    assert!(units.contains("  Unit IDs:       1-3, 0 is broadcast\n"), "Banner lists the units");
+}
+
+#[test]
+fn tally() {
+    let mut tally = SessionTally::default();
+    for (test_case, passed) in [(TestCases::SrSingle(1), true), (TestCases::SrOutOfBounds, false), (TestCases::SrUpTo(3), true)] {
+        tally.record(&test_case, passed);
+    }
+    let plain = Style::new();
+    assert_eq!(tally.render(&plain, &plain).as_str(), "2 passed, 1 failed of 3 test cases", "Tally counts passes and failures");
+    assert_eq!(tally.summary(&plain, &plain), vec![
+        "2 passed, 1 failed of 3 test cases".to_string(),
+        format!("    failed: {:?}", TestCases::SrOutOfBounds)], "Summary lists the failed test cases");
+}
+
+/// Test cases recorded to a history file are offered again by the next session, in order, with
+/// lines it can't read skipped.
+#[test]
+fn test_history() -> anyhow::Result<()> {
+    let path = std::env::temp_dir().join(format!("rtu-sim-test-{}-history.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let recorded = [
+        TestCases::SrSingle(3),
+        TestCases::SrEarlyStopAllDelays(2, SweepSchedule::BinarySearch {
+            too_short: Duration::from_millis(100),
+            long_enough: Duration::from_millis(900),
+            resolution: Duration::from_millis(5),
+        }),
+        TestCases::Soak { idx: 1, iterations: 20 },
+    ];
+    let reloaded = record_and_reload(&path, &recorded);
+    let _ = std::fs::remove_file(&path);
+    let described = |test_cases: &[TestCases]| test_cases.iter().map(|test_case| format!("{test_case:?}")).collect::<Vec<_>>();
+    assert_eq!(described(&reloaded?), described(&recorded), "History round trips through its file");
+    assert_eq!(TestHistory::open(None)?.entries().len(), 0, "History without a file starts empty");
+    Ok(())
+}
+
+/// Records `test_cases` to the history in `path`, appends a line no version could read, and
+/// loads the history again.
+fn record_and_reload(path: &Path, test_cases: &[TestCases]) -> anyhow::Result<Vec<TestCases>> {
+    let mut history = TestHistory::open(Some(path))?;
+    for test_case in test_cases {
+        history.record(test_case)?;
+    }
+    drop(history);
+    writeln!(OpenOptions::new().append(true).open(path)?, "{{\"NoSuchTest\":1}}")?;
+    Ok(TestHistory::open(Some(path))?.entries().to_vec())
+}
+
+/// Plans a few test cases against a state and finds the steps they'd take, with nothing written.
+#[test]
+fn dry_run() {
+    let state = SharedModbusState::new();
+    let config = TestConfig::default();
+    assert_eq!(plan_test_case(&TestCases::SrSingle(3), &config, &state), vec![
+        "Run sub routine 3: write index 3 to register 8, set enable (coil 8), wait up to 1s for running (coil 9) \
+            and up to 60s for it to clear, then clear enable and check running stays clear".to_string()],
+        "Dry run of a single sub routine");
+    let up_to = plan_test_case(&TestCases::SrUpTo(4), &config, &state);
+    assert_eq!(up_to.iter().map(|step| step.split(':').next().unwrap_or_default().to_string()).collect::<Vec<_>>(),
+        (0..=4).map(|idx| format!("Run sub routine {idx}")).collect::<Vec<_>>(),
+        "Dry run of SrUpTo has a step per sub routine");
+    let sweep = plan_test_case(&TestCases::SrEarlyStopAllDelays(2, SweepSchedule::Linear { step: Duration::from_millis(50) }),
+        &config, &state);
+    assert_eq!(sweep.len(), DRY_RUN_SWEEP_PREVIEW + 1, "Dry run of a sweep previews its first delays");
+    assert!(sweep[1].starts_with("Start sub routine 2 the same way, clear enable after 50ms"),
+        "Dry run of a sweep starts with the first step");
+    let refused = plan_test_case(&TestCases::SrEarlyStopAllDelays(2, SweepSchedule::Geometric { factor: 1 }), &config, &state);
+    assert!(refused.last().is_some_and(|step| step.starts_with("Refuse the sweep")), "Dry run of an invalid sweep refuses it");
+
+    let map = state.register_map();
+    assert_eq!((state.recent_changes(1).len(), state.enable_rising_edges(), state.read_holding_registers(map.index_hreg, 1)),
+        (0, 0, vec![0]), "Dry run writes nothing");
+}
+
+/// Logs one record through a [`TeeLogger`] with the console side off and finds it, timestamped,
+/// in the file.
+#[test]
+fn log_file() -> anyhow::Result<()> {
+    let path = std::env::temp_dir().join(format!("rtu-sim-test-{}.log", std::process::id()));
+    let mut file_builder = env_logger::Builder::new();
+    file_builder.filter_level(LevelFilter::Info);
+    log_to_file(&mut file_builder, &path)?;
+    let console = env_logger::Builder::new().filter_level(LevelFilter::Off).build();
+    let logger = TeeLogger::new(console, file_builder.build());
+    logger.log(&Record::builder()
+        .args(format_args!("log file test record"))
+        .level(Level::Info)
+        .target("reporting")
+        .build());
+    logger.flush();
+    let contents = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    let line = contents?.lines().last().unwrap_or_default().to_string();
+    assert!(line.ends_with("reporting] log file test record"), "Log records reach the log file");
+    // env_logger's default format opens with an RFC 3339 timestamp, e.g. `[2024-01-31T12:00:00Z INFO ...`
+    assert!(line.as_bytes().get(5) == Some(&b'-') && line.contains('T'), "Log file lines are timestamped");
+    Ok(())
+}
+
+/// Runs a sub routine on a simulated arm under a [`SpanRecorder`] and finds its span.
+#[tokio::test(start_paused = true)]
+async fn spans() {
+    let state = SharedModbusState::new();
+    let arm = start_arm(&state, ArmSimConfig { motion_duration: MOTION_DURATION, ..ArmSimConfig::default() }).await;
+    let recorder = SpanRecorder::default();
+    let outcome = SubroutineRun::new(7)
+        .poll_interval(Duration::from_millis(1))
+        .stable_reads(1)
+        .execute(&state)
+        .with_subscriber(Dispatch::new(recorder.clone())).await;
+    drop(arm);
+    assert!(outcome.is_success(), "Spans: expected the motion to complete, got {outcome:?}");
+    let spans = recorder.spans.lock().unwrap();
+    let run = spans.iter().find(|span| span.name == "subroutine_run");
+    assert_eq!(run.map(|span| (
+        span.fields.get("idx").cloned(),
+        span.fields.get("result").is_some_and(|result| result.starts_with("Completed")))),
+        Some((Some("7".to_string()), true)), "Sub routine run span has the index and result");
+    assert!(spans.iter().filter(|span| span.name == "wait_for_running").count() >= 2, "Waits get their own spans");
+}
+
+/// Keeps the name and fields of every span, to check what a run would show in a trace viewer.
+#[derive(Clone, Default)]
+struct SpanRecorder {
+    spans: Arc<Mutex<Vec<RecordedSpan>>>,
+}
+
+struct RecordedSpan {
+    name: &'static str,
+    fields: HashMap<&'static str, String>,
+}
+
+struct FieldRecorder<'a>(&'a mut HashMap<&'static str, String>);
+
+impl Visit for FieldRecorder<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name(), format!("{value:?}"));
+    }
+}
+
+impl Subscriber for SpanRecorder {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let mut span = RecordedSpan { name: attributes.metadata().name(), fields: HashMap::new() };
+        attributes.record(&mut FieldRecorder(&mut span.fields));
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(span);
+        // Ids start at 1
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, id: &Id, values: &span::Record<'_>) {
+        let mut spans = self.spans.lock().unwrap();
+        values.record(&mut FieldRecorder(&mut spans[id.into_u64() as usize - 1].fields));
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
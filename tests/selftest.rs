@@ -0,0 +1,12 @@
+//! `rtu-sim --selftest` as CI and users run it: the built binary against itself over loopback.
+
+use std::process::Command;
+
+#[test]
+fn selftest_passes() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rtu-sim"))
+        .args(["--selftest", "--log-level", "warn"])
+        .output()
+        .expect("rtu-sim runs");
+    assert!(output.status.success(), "--selftest failed:\n{}", String::from_utf8_lossy(&output.stderr));
+}
@@ -0,0 +1,333 @@
+//! The server around the state: connections, power cycles, how a session shuts down, and clients driving it over Modbus.
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio_modbus::client::{self, Client, Reader, Writer};
+use rtu_sim::arm_sim::ArmSimConfig;
+use rtu_sim::bench::measure_throughput;
+use rtu_sim::connections::{wait_for_shutdown, ConnectionTracker, NoClientError, ShutdownReason};
+use rtu_sim::context_pool::ContextPool;
+use rtu_sim::degraded_link::DegradedLink;
+use rtu_sim::mb_stuff::{ExampleService, SharedModbusState};
+use rtu_sim::power_cycle::simulate_power_cycle;
+use rtu_sim::remote::{run_remote_test_case, sr_single_early_stop};
+use rtu_sim::test_cases::{EarlyStopResult, RunOutcome, TestCases, TestConfig};
+use common::{serve, serve_with, start_arm};
+
+const MOTION_DURATION: Duration = Duration::from_millis(50);
+/// How long `--require-client` waits here.
+const CLIENT_WINDOW: Duration = Duration::from_millis(50);
+/// Far enough apart to tell which one a request got.
+const READ_LATENCY: Duration = Duration::from_millis(80);
+/// Long enough to connect and send a request while the device is still booting.
+const BOOT_DELAY: Duration = Duration::from_millis(300);
+/// Long enough for the early stop to come due mid-motion, even a round trip late.
+const REMOTE_MOTION: Duration = Duration::from_millis(300);
+const REMOTE_STOP: u16 = 50;
+const POOL_SIZE: usize = 3;
+/// Callers sharing the pool at once, twice as many as it has connections.
+const POOL_CALLERS: usize = 6;
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_millis(150);
+const MAX_CONNECTIONS: usize = 2;
+const IDLE_TIMEOUT: Duration = Duration::from_millis(150);
+/// How often the active master polls, well within the idle timeout.
+const ACTIVE_POLL: Duration = Duration::from_millis(30);
+/// How long a master waits for an answer before it counts the request as lost.
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(200);
+const DROP_RATE_REQUESTS: usize = 5;
+/// Each phase of the benchmark smoke run, just long enough to get answers.
+const BENCH_DURATION: Duration = Duration::from_millis(50);
+
+/// Waits until the server has released every connection, or `timeout` passed.
+async fn all_released(connections: &ConnectionTracker, timeout: Duration) -> bool {
+    released_down_to(connections, 0, timeout).await
+}
+
+/// Waits until the server is down to `open` connections, or `timeout` passed.
+async fn released_down_to(connections: &ConnectionTracker, open: usize, timeout: Duration) -> bool {
+    tokio::time::timeout(timeout, async {
+        while connections.active() > open {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }).await.is_ok()
+}
+
+/// Shares a connection pool between more callers than it has connections, then has the server
+/// drop every pooled connection for idling and checks the pool recovers.
+#[tokio::test]
+async fn context_pool() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let connections = Arc::new(ConnectionTracker::new(None));
+    let link = Arc::new(DegradedLink::new(0.0, READ_LATENCY, 1));
+    let service_state = state.clone();
+    let served = serve_with(Some(POOL_IDLE_TIMEOUT), connections.clone(), move |peer|
+        ExampleService::with_shared_state(service_state.clone(), peer).with_degraded_link(Some(link.clone()))).await?;
+
+    let pool = Arc::new(ContextPool::connect(served.addr, POOL_SIZE).await?);
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let most_in_flight = Arc::new(AtomicUsize::new(0));
+    let callers: Vec<_> = (0..POOL_CALLERS).map(|_| {
+        let (pool, in_flight, most_in_flight) = (pool.clone(), in_flight.clone(), most_in_flight.clone());
+        let coil = map.enable_coil;
+        tokio::spawn(async move {
+            pool.with_context(async move |ctx| {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(now, Ordering::SeqCst);
+                let response = ctx.read_coils(coil, 1).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                response
+            }).await
+        })
+    }).collect();
+    for caller in callers {
+        assert_eq!(caller.await???, vec![false], "Pooled caller is answered");
+    }
+    assert_eq!(most_in_flight.load(Ordering::SeqCst), POOL_SIZE, "Callers share the pool's connections");
+    assert_eq!(pool.idle(), POOL_SIZE, "Connections are back in the pool");
+
+    assert!(all_released(&connections, POOL_IDLE_TIMEOUT * 10).await, "Server drops the idle pooled connections");
+    let response = pool.with_context(async |ctx| ctx.read_coils(map.enable_coil, 1).await).await??;
+    assert_eq!(response, vec![false], "Pool reconnects a dropped connection");
+    assert_eq!(pool.idle(), POOL_SIZE, "Pool keeps its size");
+    Ok(())
+}
+
+/// Opens one connection more than the limit: the last is closed straight away, and a slot freed
+/// by a master leaving takes the next one.
+#[tokio::test]
+async fn max_connections() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let connections = Arc::new(ConnectionTracker::new(Some(MAX_CONNECTIONS)));
+    let service_state = state.clone();
+    let served = serve_with(None, connections.clone(), move |peer| ExampleService::with_shared_state(service_state.clone(), peer)).await?;
+
+    let mut accepted = Vec::new();
+    for _ in 0..MAX_CONNECTIONS {
+        let mut ctx = client::tcp::connect(served.addr).await?;
+        assert_eq!(ctx.read_coils(map.enable_coil, 1).await??, vec![false], "Connection within the limit is answered");
+        accepted.push(ctx);
+    }
+    // Accepted and closed right away, so only the request fails
+    let mut refused = client::tcp::connect(served.addr).await?;
+    assert!(refused.read_coils(map.enable_coil, 1).await.is_err(), "Connection over the limit is refused");
+    assert_eq!(connections.active(), MAX_CONNECTIONS, "Refused connection isn't counted");
+
+    drop(accepted.pop());
+    assert!(released_down_to(&connections, MAX_CONNECTIONS - 1, Duration::from_secs(1)).await, "Leaving master frees its slot");
+    let mut next = client::tcp::connect(served.addr).await?;
+    assert_eq!(next.read_coils(map.enable_coil, 1).await??, vec![false], "Freed slot takes the next connection");
+    Ok(())
+}
+
+/// Two masters on a server with an idle timeout: the silent one is dropped, the one polling
+/// for several timeouts' worth is kept.
+#[tokio::test]
+async fn idle_timeout() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let connections = Arc::new(ConnectionTracker::new(None));
+    let service_state = state.clone();
+    let served = serve_with(Some(IDLE_TIMEOUT), connections.clone(), move |peer|
+        ExampleService::with_shared_state(service_state.clone(), peer)).await?;
+
+    let mut idle = client::tcp::connect(served.addr).await?;
+    let mut active = client::tcp::connect(served.addr).await?;
+    let polling_until = tokio::time::Instant::now() + IDLE_TIMEOUT * 4;
+    while tokio::time::Instant::now() < polling_until {
+        active.read_coils(map.enable_coil, 1).await??;
+        tokio::time::sleep(ACTIVE_POLL).await;
+    }
+    assert_eq!(connections.active(), 1, "Only the idle connection is released");
+    assert!(idle.read_coils(map.enable_coil, 1).await.is_err(), "Idle connection is dropped");
+    assert_eq!(active.read_coils(map.enable_coil, 1).await??, vec![false], "Active connection survives");
+    Ok(())
+}
+
+/// A link dropping every request leaves the master timing out, one dropping none answers all.
+#[tokio::test]
+async fn drop_rates() -> anyhow::Result<()> {
+    for (drop_fraction, answered) in [(1.0, 0), (0.0, DROP_RATE_REQUESTS)] {
+        let state = SharedModbusState::new();
+        let map = state.register_map();
+        let link = Arc::new(DegradedLink::new(drop_fraction, Duration::ZERO, 1));
+        let service_state = state.clone();
+        let served = serve_with(None, Arc::new(ConnectionTracker::new(None)), move |peer|
+            ExampleService::with_shared_state(service_state.clone(), peer).with_degraded_link(Some(link.clone()))).await?;
+        let mut ctx = client::tcp::connect(served.addr).await?;
+        let mut timed_out = 0;
+        for _ in 0..DROP_RATE_REQUESTS {
+            match tokio::time::timeout(CLIENT_TIMEOUT, ctx.read_coils(map.enable_coil, 1)).await {
+                Ok(response) => assert_eq!(response??, vec![false], "Request that isn't dropped is answered"),
+                Err(_) => {
+                    timed_out += 1;
+                    // The late answer would never come, a master reconnects instead
+                    ctx = client::tcp::connect(served.addr).await?;
+                }
+            }
+        }
+        assert_eq!(DROP_RATE_REQUESTS - timed_out, answered, "Requests answered at a drop fraction of {drop_fraction}");
+    }
+    Ok(())
+}
+
+/// Runs a sub routine once on the first connection, with a simulated arm standing in for the
+/// client in the meantime, and not again for the next connection.
+#[tokio::test]
+async fn auto_run() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let connections = Arc::new(ConnectionTracker::new(None));
+    let _arm = start_arm(&state, ArmSimConfig { motion_duration: MOTION_DURATION, ..ArmSimConfig::default() }).await;
+    connections.mark_client_seen();
+    let service_state = state.clone();
+    let served = serve_with(None, connections.clone(), move |peer| ExampleService::with_shared_state(service_state.clone(), peer)).await?;
+    let run = tokio::spawn(rtu_sim::auto_run::auto_run(state.clone(), connections, TestConfig::default(), 2));
+
+    tokio::time::sleep(MOTION_DURATION * 2).await;
+    assert_eq!(state.enable_rising_edges(), 0, "Nothing runs before a master connects");
+    let first = client::tcp::connect(served.addr).await?;
+    let outcome = tokio::time::timeout(MOTION_DURATION * 20, run).await??;
+    assert!(matches!(outcome, RunOutcome::Completed { .. }), "First connection auto-runs the sub routine");
+    drop(first);
+    let mut second = client::tcp::connect(served.addr).await?;
+    second.read_coils(state.register_map().running_coil, 1).await??;
+    tokio::time::sleep(MOTION_DURATION * 2).await;
+    assert_eq!((state.enable_rising_edges(), state.read_index()), (1, 2), "Exactly one auto-run across connections");
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn require_client() {
+    let unused = ConnectionTracker::new(None);
+    assert_eq!(unused.require_client(CLIENT_WINDOW).await, Err(NoClientError { waited: CLIENT_WINDOW }),
+        "No client within the window fails");
+    let connected = Arc::new(ConnectionTracker::new(None));
+    let _connection = connected.try_open();
+    assert_eq!(connected.require_client(CLIENT_WINDOW).await, Ok(()), "A client within the window passes");
+}
+
+/// Ends the session on whichever comes first: the interrupt, or the TUI thread finishing.
+#[tokio::test(start_paused = true)]
+async fn wait_for_shutdown_reasons() {
+    let no_client_check = std::future::pending::<Result<(), NoClientError>>;
+    let (_tui_done, tui_running) = oneshot::channel();
+    let interrupt = async {
+        tokio::time::sleep(CLIENT_WINDOW).await;
+        Ok(())
+    };
+    assert_eq!(wait_for_shutdown(tui_running, interrupt, no_client_check()).await, ShutdownReason::Interrupted,
+        "Interrupt ends a session while the TUI runs");
+
+    let (tui_done, tui_finished) = oneshot::channel();
+    tui_done.send(()).unwrap();
+    assert_eq!(wait_for_shutdown(tui_finished, std::future::pending(), no_client_check()).await, ShutdownReason::TuiFinished,
+        "TUI finishing ends the session before any interrupt");
+
+    let (tui_done, tui_panicked) = oneshot::channel::<()>();
+    drop(tui_done);
+    let broken_listener = async { Err(std::io::Error::other("no signal handler")) };
+    assert_eq!(wait_for_shutdown(tui_panicked, broken_listener, no_client_check()).await, ShutdownReason::TuiFinished,
+        "A broken interrupt listener waits for the TUI, which panicking also finishes");
+}
+
+/// Power cycles a served state under a connected master: its connection is dropped, connections
+/// are refused while booting, and afterwards the state is back at its defaults.
+#[tokio::test]
+async fn power_cycle() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let connections = Arc::new(ConnectionTracker::new(None));
+    let service_state = state.clone();
+    let served = serve_with(None, connections.clone(), move |peer| ExampleService::with_shared_state(service_state.clone(), peer)).await?;
+
+    let mut before = client::tcp::connect(served.addr).await?;
+    before.write_single_coil(map.enable_coil, true).await??;
+    before.write_single_register(map.index_hreg, 5).await??;
+
+    let power_cycle = tokio::spawn({
+        let (state, connections) = (state.clone(), connections.clone());
+        async move { simulate_power_cycle(&state, &connections, BOOT_DELAY).await }
+    });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(before.read_coils(map.enable_coil, 1).await.is_err(), "Power cycle drops the open connection");
+    assert!(!state.read_coil(map.enable_coil), "Power cycle resets the enable coil");
+    assert_eq!(state.read_holding_registers(map.index_hreg, 1), vec![0], "Power cycle resets the index register");
+    // The connection is accepted and dropped right away, so only the request fails
+    let booting = async {
+        let mut ctx = client::tcp::connect(served.addr).await?;
+        anyhow::Ok(ctx.read_coils(map.enable_coil, 1).await??)
+    };
+    assert!(booting.await.is_err(), "Connections are refused while booting");
+
+    power_cycle.await?;
+    let mut after = client::tcp::connect(served.addr).await?;
+    assert_eq!(after.read_coils(map.enable_coil, 1).await??, vec![false], "Connections are accepted after booting");
+    Ok(())
+}
+
+/// Hangs up while the response is held back by latency. The connection must be released and the
+/// server keep serving.
+#[tokio::test]
+async fn disconnect_mid_request() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let connections = Arc::new(ConnectionTracker::new(None));
+    let link = Arc::new(DegradedLink::new(0.0, READ_LATENCY, 1));
+    let service_state = state.clone();
+    let served = serve_with(None, connections.clone(), move |peer|
+        ExampleService::with_shared_state(service_state.clone(), peer).with_degraded_link(Some(link.clone()))).await?;
+
+    let mut stream = TcpStream::connect(served.addr).await?;
+    let [coil_hi, coil_lo] = map.enable_coil.to_be_bytes();
+    stream.write_all(&[0, 1, 0, 0, 0, 6, 1, 0x01, coil_hi, coil_lo, 0, 1]).await?;
+    tokio::time::sleep(READ_LATENCY / 4).await;
+    drop(stream);
+    assert!(all_released(&connections, READ_LATENCY * 10).await, "Connection dropped mid-request is released");
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    assert_eq!(ctx.read_coils(map.enable_coil, 1).await??, vec![false], "Server still answers after the hang-up");
+    assert!(!served.server.is_finished(), "Server task survived the hang-up");
+    Ok(())
+}
+
+/// Runs test cases the way the TUI does with `--connect`, over Modbus against a second server
+/// with its own simulated arm.
+#[tokio::test]
+async fn remote() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let _arm = start_arm(&state, ArmSimConfig { motion_duration: REMOTE_MOTION, ..ArmSimConfig::default() }).await;
+    let served = serve(&state).await?;
+
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    let config = TestConfig::default();
+    assert!(run_remote_test_case(&mut ctx, &map, &config, &TestCases::SrSingle(3), &mut None).await,
+        "Remote sub routine run passes");
+    assert_eq!(state.read_index(), 3, "Remote run commanded the index");
+    let delay = Duration::from_millis(REMOTE_STOP.into());
+    assert_eq!(sr_single_early_stop(&mut ctx, &map, &config, 4, delay).await?, EarlyStopResult::Success,
+        "Remote early stop mid-motion succeeds");
+    assert!(run_remote_test_case(&mut ctx, &map, &config,
+        &TestCases::SrEarlyStopWithDelay(4, REMOTE_STOP), &mut None).await, "Remote early stop passes as a test case");
+    assert!(!run_remote_test_case(&mut ctx, &map, &config, &TestCases::FaultRecovery(3), &mut None).await,
+        "Test cases needing the embedded state fail remotely");
+    ctx.disconnect().await?;
+    Ok(())
+}
+
+/// A short run of the benchmark, only checking it gets answers over one and several connections.
+#[tokio::test]
+async fn bench() -> anyhow::Result<()> {
+    for connections in [1, 3] {
+        let throughput = measure_throughput(connections, BENCH_DURATION).await?;
+        assert!(throughput.reads_per_sec > 0.0, "Benchmark gets reads answered");
+        assert!(throughput.writes_per_sec > 0.0, "Benchmark gets writes answered");
+    }
+    Ok(())
+}
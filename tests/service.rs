@@ -0,0 +1,255 @@
+//! The service's answers beyond the plain function codes: limits, ranges past the top of the
+//! address space, disabled, unknown and degraded functions, failed broadcasts, the request floor
+//! and the golden sequence.
+
+mod common;
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_modbus::server::Service;
+use tokio_modbus::{ExceptionCode, Request, Response, SlaveRequest};
+use rtu_sim::degraded_link::{DegradedLink, FunctionLatency};
+use rtu_sim::golden::{Divergence, GoldenSequence};
+use rtu_sim::mb_stuff::{ExampleService, FloorPolicy, ReadOnlyPolicy, RequestFloor, SeedRange, SharedModbusState, UnknownFunctionPolicy,
+    BROADCAST_UNIT_ID, MAX_WRITE_COILS, MAX_WRITE_REGISTERS};
+use rtu_sim::regions::AddressSpace;
+use rtu_sim::metrics::Metrics;
+use common::NO_PEER;
+
+/// Running sub routine 3 on the default register map, with a capture line mixed in.
+const GOLDEN_SEQUENCE: &str = "\
+# Run sub routine 3
+WriteSingleRegister 8 3
+1700000000000 WriteSingleCoil 8 1 => WriteSingleCoil 8 1
+ReadCoils 9 1
+WriteSingleCoil 8 0
+";
+/// Far longer than two back to back requests take.
+const REQUEST_FLOOR: Duration = Duration::from_secs(10);
+/// Far enough apart to tell which one a request got.
+const READ_LATENCY: Duration = Duration::from_millis(80);
+/// A user-defined function code the service doesn't implement.
+const UNKNOWN_FUNCTION_CODE: u8 = 0x41;
+const WRITE_LATENCY: Duration = Duration::from_millis(5);
+/// Clear of every input register the self-test seeds.
+const MIRROR_OFFSET: u16 = 100;
+
+/// A write acknowledged but silently ignored can only be caught by reading the coil back, while
+/// a read-only coil is still refused however many writes the link ignores.
+#[tokio::test(start_paused = true)]
+async fn ignored_writes() {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let state = state.with_read_only_coils(HashSet::from([map.running_coil]), ReadOnlyPolicy::Reject);
+    let link = DegradedLink::new(0.0, Duration::ZERO, 1).with_ignored_writes(1.0);
+    let service = ExampleService::with_shared_state(state.clone(), NO_PEER)
+        .with_degraded_link(Some(Arc::new(link)));
+    let response = service.call(SlaveRequest { slave: 1, request: Request::WriteSingleCoil(map.enable_coil, true) }).await;
+    assert_eq!(response, Ok(Some(Response::WriteSingleCoil(map.enable_coil, true))), "Ignored write is acknowledged");
+    assert!(!state.read_coil(map.enable_coil), "Ignored write is caught by a read-back");
+    let read_only = service.call(SlaveRequest { slave: 1, request: Request::WriteSingleCoil(map.running_coil, true) }).await;
+    assert_eq!(read_only, Err(ExceptionCode::IllegalDataAddress), "Read-only coil is refused before the link ignores it");
+}
+
+/// Times a read and a write through a link where reads are much slower, as on many devices.
+#[tokio::test(start_paused = true)]
+async fn function_latencies() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let enable_coil = state.register_map().enable_coil;
+    let link = DegradedLink::new(0.0, Duration::ZERO, 1).with_function_latencies(BTreeMap::from([
+        (1, FunctionLatency { base: READ_LATENCY, jitter: None }),
+        (5, FunctionLatency { base: WRITE_LATENCY, jitter: None }),
+    ]));
+    let service = ExampleService::with_shared_state(state.clone(), NO_PEER)
+        .with_degraded_link(Some(Arc::new(link)));
+    let started = Instant::now();
+    service.call(SlaveRequest { slave: 1, request: Request::WriteSingleCoil(enable_coil, true) }).await?;
+    let write = started.elapsed();
+    let started = Instant::now();
+    service.call(SlaveRequest { slave: 1, request: Request::ReadCoils(enable_coil, 1) }).await?;
+    let read = started.elapsed();
+    assert_eq!((write, read), (WRITE_LATENCY, READ_LATENCY), "Each function code observes its own latency");
+    Ok(())
+}
+
+/// Called on the service directly, as an oversized FC 16 frame wouldn't even get through the
+/// decoder.
+#[tokio::test(start_paused = true)]
+async fn write_limits() {
+    let state = SharedModbusState::new();
+    let service = ExampleService::with_shared_state(state.clone(), NO_PEER);
+    let coils = vec![true; MAX_WRITE_COILS as usize + 1];
+    let response = service.call(SlaveRequest { slave: 1, request: Request::WriteMultipleCoils(0, Cow::Owned(coils)) }).await;
+    assert_eq!(response, Err(ExceptionCode::IllegalDataValue), "Oversized WriteMultipleCoils is rejected");
+    assert_eq!(state.read_coils(0, 1), vec![false], "Rejected coil write changes nothing");
+    let registers = vec![1; MAX_WRITE_REGISTERS as usize + 1];
+    let response = service.call(SlaveRequest { slave: 1, request: Request::WriteMultipleRegisters(0, Cow::Owned(registers)) }).await;
+    assert_eq!(response, Err(ExceptionCode::IllegalDataValue), "Oversized WriteMultipleRegisters is rejected");
+    assert_eq!(state.read_holding_registers(0, 1), vec![0], "Rejected register write changes nothing");
+}
+
+/// Ranges running past 0xFFFF are cut short by the state and refused by the service, and never
+/// wrap round to the low addresses.
+#[tokio::test(start_paused = true)]
+async fn address_wrap() {
+    let state = SharedModbusState::new().with_seed_ranges(vec![
+        SeedRange::new(AddressSpace::HoldingRegister, 0xFFFE..=0xFFFF, 0),
+        SeedRange::new(AddressSpace::HoldingRegister, 0..=1, 0),
+        SeedRange::new(AddressSpace::Coil, 0xFFFE..=0xFFFF, 0),
+        SeedRange::new(AddressSpace::Coil, 0..=1, 0),
+    ]);
+    state.write_holding_registers(0xFFFE, &[1, 2, 3, 4]);
+    state.write_coils(0xFFFE, &[true; 4]);
+    assert_eq!(state.read_holding_registers(0xFFFE, 2), vec![1, 2], "State write stops at 0xFFFF");
+    assert_eq!((state.read_holding_registers(0xFFFE, 4), state.read_coils(0xFFFE, 4)), (vec![1, 2], vec![true; 2]),
+        "State read stops at 0xFFFF");
+    assert_eq!((state.read_holding_registers(0, 2), state.read_coils(0, 2)), (vec![0, 0], vec![false; 2]),
+        "State writes don't wrap to the low addresses");
+
+    let service = ExampleService::with_shared_state(state.clone(), NO_PEER);
+    for request in [
+        Request::ReadHoldingRegisters(0xFFFE, 3),
+        Request::ReadCoils(0xFFFF, 2),
+        Request::WriteMultipleRegisters(0xFFFF, Cow::Owned(vec![5, 6])),
+        Request::WriteMultipleCoils(0xFFFE, Cow::Owned(vec![false; 3])),
+    ] {
+        let step = format!("{request:?} is an illegal data address");
+        assert_eq!(service.call(SlaveRequest { slave: 1, request }).await, Err(ExceptionCode::IllegalDataAddress), "{}", step);
+    }
+    let response = service.call(SlaveRequest { slave: 1, request: Request::ReadHoldingRegisters(0xFFFE, 2) }).await;
+    assert_eq!(response, Ok(Some(Response::ReadHoldingRegisters(vec![1, 2]))), "Range ending on 0xFFFF is served");
+    assert_eq!((state.read_holding_registers(0xFFFE, 2), state.read_holding_registers(0, 2), state.read_coils(0xFFFE, 2)),
+        (vec![1, 2], vec![0, 0], vec![true; 2]), "Refused writes change nothing, at either end");
+}
+
+/// Writes holding registers over Modbus and reads them back from the mirror region.
+#[tokio::test(start_paused = true)]
+async fn write_mirror() -> anyhow::Result<()> {
+    let state = SharedModbusState::new().with_write_mirror(Some(MIRROR_OFFSET));
+    let map = state.register_map();
+    let service = ExampleService::with_shared_state(state.clone(), NO_PEER);
+    assert_eq!(state.read_input_registers(map.index_hreg + MIRROR_OFFSET, 1), vec![0],
+        "Mirror starts out with the holding registers' defaults");
+    service.call(SlaveRequest { slave: 1, request: Request::WriteMultipleRegisters(map.index_hreg, Cow::Owned(vec![7])) }).await?;
+    assert_eq!(state.read_input_registers(map.index_hreg + MIRROR_OFFSET, 1), vec![7], "FC 16 write appears in the mirror");
+    service.call(SlaveRequest { slave: 1, request: Request::WriteSingleRegister(map.fault_hreg, 9) }).await?;
+    let response = service.call(SlaveRequest {
+        slave: 1,
+        request: Request::ReadInputRegisters(map.fault_hreg + MIRROR_OFFSET, 1),
+    }).await;
+    assert_eq!(response, Ok(Some(Response::ReadInputRegisters(vec![9]))), "FC 06 write reads back through the mirror");
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn disabled_functions() {
+    let state = SharedModbusState::new();
+    let index_hreg = state.register_map().index_hreg;
+    let service = ExampleService::with_shared_state(state.clone(), NO_PEER)
+        .with_disabled_functions(Arc::new(HashSet::from([16])));
+    let response = service.call(SlaveRequest {
+        slave: 1,
+        request: Request::WriteMultipleRegisters(index_hreg, Cow::Owned(vec![3])),
+    }).await;
+    assert_eq!(response, Err(ExceptionCode::IllegalFunction), "Disabled FC 16 is an illegal function");
+    let response = service.call(SlaveRequest { slave: 1, request: Request::WriteSingleRegister(index_hreg, 4) }).await;
+    assert_eq!((response, state.read_holding_registers(index_hreg, 1)),
+        (Ok(Some(Response::WriteSingleRegister(index_hreg, 4))), vec![4]), "FC 06 still works with FC 16 disabled");
+}
+
+/// A broadcast write that fails is still unanswered, but counted.
+#[tokio::test(start_paused = true)]
+async fn broadcast_failures() {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let state = state.with_read_only_coils(HashSet::from([map.running_coil]), ReadOnlyPolicy::Reject);
+    let metrics = Arc::new(Metrics::new());
+    let service = ExampleService::with_shared_state(state.clone(), NO_PEER).with_metrics(Some(metrics.clone()));
+    let broadcast = |coil| SlaveRequest { slave: BROADCAST_UNIT_ID, request: Request::WriteSingleCoil(coil, true) };
+    assert_eq!(service.call(broadcast(map.enable_coil)).await, Ok(None), "Applied broadcast is unanswered");
+    assert_eq!(service.call(broadcast(map.running_coil)).await, Ok(None), "Failed broadcast is unanswered");
+    assert_eq!(metrics.snapshot().broadcast_failures, 1, "Only the failed broadcast is counted");
+}
+
+/// Sends a function code the service doesn't implement under each fallback policy.
+#[tokio::test(start_paused = true)]
+async fn unknown_functions() {
+    for (policy, expected) in [
+        (UnknownFunctionPolicy::IllegalFunction, Err(ExceptionCode::IllegalFunction)),
+        (UnknownFunctionPolicy::DeviceFailure, Err(ExceptionCode::ServerDeviceFailure)),
+        (UnknownFunctionPolicy::Drop, Ok(None)),
+    ] {
+        let service = ExampleService::with_shared_state(SharedModbusState::new(), NO_PEER).with_unknown_function_policy(policy);
+        let request = Request::Custom(UNKNOWN_FUNCTION_CODE, Cow::Owned(vec![1, 2]));
+        let response = service.call(SlaveRequest { slave: 1, request }).await;
+        assert_eq!(response, expected, "Unknown function code under {policy:?}");
+    }
+}
+
+/// Sends requests back to back, as a master that doesn't wait for responses would, under a floor
+/// they can't meet.
+#[tokio::test(start_paused = true)]
+async fn request_floor() {
+    let state = SharedModbusState::new();
+    let enable_coil = state.register_map().enable_coil;
+    let read = || SlaveRequest { slave: 1, request: Request::ReadCoils(enable_coil, 1) };
+    for (policy, second) in [
+        (FloorPolicy::Flag, Ok(Some(Response::ReadCoils(vec![false])))),
+        (FloorPolicy::Reject, Err(ExceptionCode::ServerDeviceBusy)),
+    ] {
+        let metrics = Arc::new(Metrics::new());
+        let service = ExampleService::with_shared_state(state.clone(), NO_PEER)
+            .with_metrics(Some(metrics.clone()))
+            .with_request_floor(Some(RequestFloor { min_spacing: REQUEST_FLOOR, policy }));
+        let first = service.call(read()).await;
+        assert_eq!(first, Ok(Some(Response::ReadCoils(vec![false]))), "First request isn't under the floor ({policy:?})");
+        assert_eq!(service.call(read()).await, second, "Back to back request under the floor ({policy:?})");
+        assert_eq!(metrics.snapshot().too_fast, 1, "Request under the floor is counted ({policy:?})");
+        tokio::time::sleep(REQUEST_FLOOR).await;
+        assert_eq!(service.call(read()).await, Ok(Some(Response::ReadCoils(vec![false]))),
+            "Request spaced by the floor passes ({policy:?})");
+    }
+}
+
+/// Plays a master through a service checking the golden sequence: one polling running several
+/// times, one writing the wrong index, and one stopping early.
+#[tokio::test(start_paused = true)]
+async fn golden_sequence() -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let matching = [
+        Request::WriteSingleRegister(map.index_hreg, 3),
+        Request::WriteSingleCoil(map.enable_coil, true),
+        Request::ReadCoils(map.running_coil, 1),
+        Request::ReadCoils(map.running_coil, 1),
+        Request::ReadCoils(map.running_coil, 1),
+        Request::WriteSingleCoil(map.enable_coil, false),
+    ];
+    let wrong_index = [Request::WriteSingleRegister(map.index_hreg, 4), Request::WriteSingleCoil(map.enable_coil, true)];
+    let cases: [(&str, &[Request<'static>], _); 3] = [
+        ("matches with polling", &matching, Ok(())),
+        ("reports the first divergence", &wrong_index, Err(Divergence {
+            position: 0,
+            expected: Some("WriteSingleRegister 8 3".to_string()),
+            actual: Some("WriteSingleRegister 8 4".to_string()),
+        })),
+        ("reports stopping early", &matching[..2], Err(Divergence {
+            position: 2,
+            expected: Some("ReadCoils 9 1".to_string()),
+            actual: None,
+        })),
+    ];
+    for (name, requests, result) in cases {
+        let golden = Arc::new(GoldenSequence::parse(GOLDEN_SEQUENCE)?);
+        let service = ExampleService::with_shared_state(state.clone(), NO_PEER)
+            .with_golden_sequence(Some(golden.clone()));
+        for request in requests {
+            let _ = service.call(SlaveRequest { slave: 1, request: request.clone() }).await;
+        }
+        assert_eq!(golden.result(), result, "Golden sequence {name}");
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! Coils and registers as the state declares, seeds, resets, records and labels them.
+
+mod common;
+
+use std::time::Duration;
+use rtu_sim::arm_sim::InitialFault;
+use rtu_sim::mb_stuff::{log_tag, ChangeKind, SeedRange, SharedModbusState, UndeclaredDefaults};
+use rtu_sim::rate_limit::WarningLimiter;
+use rtu_sim::regions::{AddressSpace, Region, Regions};
+use rtu_sim::register_map::RegisterMap;
+use common::NO_PEER;
+
+/// Neither a coil nor a register here, to read the undeclared defaults.
+const UNDECLARED_ADDRESS: u16 = 2000;
+const WARNING_INTERVAL: Duration = Duration::from_millis(200);
+const HISTORY_CAPACITY: usize = 3;
+
+#[test]
+fn undeclared_defaults() {
+    let state = SharedModbusState::new().with_undeclared_defaults(UndeclaredDefaults {
+        coil: true,
+        holding_register: 0xFFFF,
+        input_register: 0x8000,
+    });
+    let undeclared = UNDECLARED_ADDRESS;
+    let index_hreg = RegisterMap::DEFAULT.index_hreg;
+    let read = state.read_holding_registers(index_hreg, 1).into_iter().chain(state.read_holding_registers(undeclared, 1));
+    assert_eq!(read.collect::<Vec<_>>(), vec![0, 0xFFFF], "Undeclared holding register reads the configured default");
+    assert_eq!(state.read_input_registers(undeclared, 1), vec![0x8000], "Undeclared input register reads the configured default");
+    assert!(state.read_coil(undeclared), "Undeclared coil reads the configured default");
+}
+
+/// The non-existent address warnings print [`SharedModbusState::address_label`].
+#[test]
+fn regions() {
+    let state = SharedModbusState::new().with_regions(Regions::new(vec![Region {
+        name: "spare_coils".to_string(),
+        space: AddressSpace::Coil,
+        range: UNDECLARED_ADDRESS..UNDECLARED_ADDRESS + 16,
+    }]));
+    let undeclared = UNDECLARED_ADDRESS + 3;
+    assert_eq!(state.address_label(AddressSpace::Coil, undeclared).to_string(), format!("coil {undeclared} (spare_coils+3)"),
+        "Warning for an address in a region names it");
+    assert_eq!(state.address_label(AddressSpace::HoldingRegister, undeclared).to_string(),
+        format!("holding register {undeclared}"), "Regions only name their own address space");
+}
+
+/// Declares ranges of coils and registers, reading undeclared addresses back as sentinels to tell
+/// the declared ones apart.
+#[test]
+fn seed_ranges() {
+    let base = UNDECLARED_ADDRESS;
+    let enable_coil = RegisterMap::DEFAULT.enable_coil;
+    let state = SharedModbusState::new()
+        .with_undeclared_defaults(UndeclaredDefaults { coil: true, holding_register: 0xFFFF, input_register: 0x8000 })
+        .with_seed_ranges(vec![
+            SeedRange::new(AddressSpace::Coil, base..=base + 31, 0),
+            SeedRange::new(AddressSpace::HoldingRegister, base..=base + 99, 0),
+            SeedRange::new(AddressSpace::InputRegister, base..=base + 9, 7),
+            SeedRange::new(AddressSpace::Coil, enable_coil..=enable_coil, 1),
+        ]);
+    assert_eq!(state.read_coils(base, 33), [vec![false; 32], vec![true]].concat(), "Declared coil range reads its default");
+    assert_eq!(state.read_holding_registers(base, 101), [vec![0; 100], vec![0xFFFF]].concat(),
+        "Declared holding register range reads its default");
+    assert_eq!(state.read_input_registers(base, 11), [vec![7; 10], vec![0x8000]].concat(),
+        "Declared input register range reads its default");
+    assert_eq!(state.read_holding_registers(base - 1, 1), vec![0xFFFF], "Nothing is declared before the range");
+    assert!(!state.read_coil(enable_coil), "Range doesn't override the handshake's default");
+    state.write_holding_register(base + 50, 123);
+    state.reset();
+    assert_eq!(state.read_holding_registers(base + 50, 1), vec![0], "Reset restores a declared range");
+}
+
+/// Everything a test can leave behind, undone by a reset: handshake values, a cleared initial
+/// fault, the history, last write times, a jam and frozen inputs.
+#[test]
+fn reset() {
+    let fault = InitialFault { code: 42, reset_coil: UNDECLARED_ADDRESS };
+    let state = SharedModbusState::new().with_initial_fault(Some(fault));
+    let map = state.register_map();
+    state.write_coils(map.enable_coil, &[true, true, true]);
+    state.write_holding_register(map.index_hreg, 7);
+    state.write_holding_register(map.fault_hreg, 0);
+    state.jam_next_motion();
+    state.freeze();
+    assert!(!state.recent_changes(1).is_empty(), "Writes are in the history");
+
+    state.reset();
+    assert_eq!(state.read_coils(map.enable_coil, 3), vec![false; 3], "Reset clears the handshake coils");
+    assert_eq!(state.read_holding_registers(map.index_hreg, 2), vec![0, fault.code],
+        "Reset restores the index and initial fault");
+    assert_eq!((state.recent_changes(1).len(), state.last_written(ChangeKind::Coil, map.enable_coil)), (0, None),
+        "Reset forgets the history and last writes");
+    assert_eq!((state.is_jammed(), state.frozen()), (false, false), "Reset clears the jam and unfreezes");
+}
+
+/// Overflows the history: it keeps the last changes oldest first, and skips writes that change nothing.
+#[test]
+fn recent_changes() {
+    let state = SharedModbusState::new().with_history_capacity(HISTORY_CAPACITY);
+    let map = state.register_map();
+    for index in 1..=4 {
+        state.write_holding_register(map.index_hreg, index);
+    }
+    state.write_holding_register(map.index_hreg, 4);
+    state.write_coil(map.enable_coil, true);
+    let changes = |n| state.recent_changes(n).into_iter()
+        .map(|change| (change.kind, change.address, change.old, change.new))
+        .collect::<Vec<_>>();
+    assert_eq!(changes(10), vec![
+        (ChangeKind::HoldingRegister, map.index_hreg, 2, 3),
+        (ChangeKind::HoldingRegister, map.index_hreg, 3, 4),
+        (ChangeKind::Coil, map.enable_coil, 0, 1),
+    ], "History keeps the last {HISTORY_CAPACITY} changes, oldest first");
+    assert_eq!(changes(2), vec![
+        (ChangeKind::HoldingRegister, map.index_hreg, 3, 4),
+        (ChangeKind::Coil, map.enable_coil, 0, 1),
+    ], "Fewer than the capacity are the newest ones, oldest first");
+}
+
+/// 1000 rapid reads of a non-existent address log one warning, the next after the interval
+/// counting the rest.
+#[tokio::test(start_paused = true)]
+async fn warning_limiter() {
+    let limiter = WarningLimiter::new(WARNING_INTERVAL);
+    let logged = (0..1000).filter(|_| limiter.admit(UNDECLARED_ADDRESS).is_some()).count();
+    assert_eq!(logged, 1, "Rapid repeats of a warning are logged once");
+    assert_eq!(limiter.admit(UNDECLARED_ADDRESS + 1), Some(0), "Other addresses are limited separately");
+    tokio::time::sleep(WARNING_INTERVAL - Duration::from_millis(1)).await;
+    assert_eq!(limiter.admit(UNDECLARED_ADDRESS), None, "Repeats within the interval stay held back");
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert_eq!(limiter.admit(UNDECLARED_ADDRESS), Some(1000), "After the interval the held back repeats are counted");
+
+    // Through the state too, where a flood would show in the log
+    let state = SharedModbusState::new();
+    for _ in 0..1000 {
+        state.read_coils(UNDECLARED_ADDRESS, 1);
+    }
+}
+
+/// Service and test case log lines name the unit they're about.
+#[test]
+fn log_tags() {
+    assert_eq!(log_tag(NO_PEER, 7), format!("[{NO_PEER} unit 7]"), "Service tag names the peer and unit");
+    let state = SharedModbusState::new();
+    assert_eq!(state.log_tag(), "[unit any]".to_string(), "Test case tag without units");
+    let unit = state.independent_copy().with_unit_id(Some(3));
+    assert_eq!(unit.log_tag(), "[unit 3]".to_string(), "Test case tag names the unit");
+    assert_eq!(unit.independent_copy().unit_id(), Some(3), "Unit ID is kept by an independent copy");
+}
@@ -0,0 +1,110 @@
+//! Recorded traffic: replaying a recorded session's responses over the wire, and capturing a
+//! session for replay.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_modbus::client::{self, Reader, Writer};
+use tokio_modbus::ExceptionCode;
+use rtu_sim::connections::ConnectionTracker;
+use rtu_sim::mb_stuff::{ExampleService, SharedModbusState};
+use rtu_sim::traffic_log::{CaptureLog, ReplayLog};
+use common::serve_with;
+
+/// A recorded session on the default register map: a comment, a timestamped read of the index and
+/// fault registers, the same read again, and an exception reading the enable coil.
+const RECORDED: &str = "\
+# arm under test, firmware 1.2
+1700000000000 ReadHoldingRegisters 8 2 => ReadHoldingRegisters 7,8
+1700000000050 ReadHoldingRegisters 8 2 => ReadHoldingRegisters 9,10
+ReadCoils 8 1 => Exception 2
+";
+
+/// How long the capture's writer thread gets to write the session out.
+const CAPTURE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A file in the temp dir named for this test run.
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rtu-sim-test-{}-{name}.log", std::process::id()))
+}
+
+/// Replays recorded responses verbatim and in order, falling through to the state once a
+/// request's recording runs out or for requests never recorded.
+#[tokio::test]
+async fn replay() -> anyhow::Result<()> {
+    let path = temp_path("replay");
+    std::fs::write(&path, RECORDED)?;
+    let replay = ReplayLog::load(&path);
+    let _ = std::fs::remove_file(&path);
+    let replay = Arc::new(replay?);
+
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    state.write_holding_register(map.index_hreg, 1);
+    let service_state = state.clone();
+    let served = serve_with(None, Arc::new(ConnectionTracker::new(None)), move |peer|
+        ExampleService::with_shared_state(service_state.clone(), peer).with_replay(Some(replay.clone()))).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+
+    assert_eq!(ctx.read_holding_registers(map.index_hreg, 2).await??, vec![7, 8], "First recorded response is replayed");
+    assert_eq!(ctx.read_holding_registers(map.index_hreg, 2).await??, vec![9, 10], "Repeats get the next recorded response");
+    assert_eq!(ctx.read_coils(map.enable_coil, 1).await?, Err(ExceptionCode::IllegalDataAddress),
+        "Recorded exception is replayed");
+    assert_eq!(ctx.read_holding_registers(map.index_hreg, 2).await??, vec![1, 0],
+        "Exhausted recording falls through to the state");
+    assert_eq!(ctx.read_holding_registers(map.index_hreg, 1).await??, vec![1],
+        "Request never recorded falls through to the state");
+    Ok(())
+}
+
+/// Captures a session and replays it against a state that never saw it, getting the captured
+/// responses back.
+#[tokio::test]
+async fn capture_replay() -> anyhow::Result<()> {
+    let path = temp_path("capture");
+    let _ = std::fs::remove_file(&path);
+    let result = capture_replay_through(&path).await;
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+async fn capture_replay_through(path: &Path) -> anyhow::Result<()> {
+    let state = SharedModbusState::new();
+    let map = state.register_map();
+    let capture = Arc::new(CaptureLog::open(path)?);
+    let service_state = state.clone();
+    let served = serve_with(None, Arc::new(ConnectionTracker::new(None)), move |peer|
+        ExampleService::with_shared_state(service_state.clone(), peer).with_capture(Some(capture.clone()))).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    ctx.write_single_register(map.index_hreg, 5).await??;
+    ctx.read_holding_registers(map.index_hreg, 2).await??;
+    ctx.read_coils(map.enable_coil, 1).await??;
+
+    let lines = tokio::time::timeout(CAPTURE_FLUSH_TIMEOUT, async {
+        loop {
+            let contents = std::fs::read_to_string(path).unwrap_or_default();
+            if contents.lines().count() >= 3 {
+                return contents.lines().map(str::to_string).collect::<Vec<_>>();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await?;
+    assert_eq!(lines.iter().map(|line| line.split_once(' ').map(|(timestamp, exchange)|
+            (timestamp.parse::<u128>().is_ok(), exchange.to_string()))).collect::<Vec<_>>(), vec![
+            Some((true, format!("WriteSingleRegister {0} 5 => WriteSingleRegister {0} 5", map.index_hreg))),
+            Some((true, format!("ReadHoldingRegisters {} 2 => ReadHoldingRegisters 5,0", map.index_hreg))),
+            Some((true, format!("ReadCoils {} 1 => ReadCoils 0", map.enable_coil))),
+        ], "Each exchange is captured with a timestamp");
+
+    let replay = Arc::new(ReplayLog::load(path)?);
+    let fresh = SharedModbusState::new();
+    let service_state = fresh.clone();
+    let served = serve_with(None, Arc::new(ConnectionTracker::new(None)), move |peer|
+        ExampleService::with_shared_state(service_state.clone(), peer).with_replay(Some(replay.clone()))).await?;
+    let mut ctx = client::tcp::connect(served.addr).await?;
+    assert_eq!(ctx.read_holding_registers(map.index_hreg, 2).await??, vec![5, 0], "Captured read is replayed");
+    assert_eq!(fresh.read_holding_registers(map.index_hreg, 1), vec![0], "Replay answers from the recording, not the state");
+    Ok(())
+}